@@ -0,0 +1,114 @@
+// Fixtures shared by the benchmarks. Each bench target includes this module,
+// using only a part of it.
+#![allow(dead_code, unused_imports)]
+
+/// Terminal sizes the size-dependent benchmarks run at.
+pub const SIZES: &[(usize, usize)] = &[(80, 24), (200, 50), (400, 100)];
+
+/// Output filling a screen of the given size with colored text, like a
+/// typical full-screen program, e.g. a directory listing or an editor.
+pub fn screen(cols: usize, rows: usize) -> String {
+    let mut output = String::from("\x1b[H");
+
+    for row in 0..rows {
+        output.push_str(&format!("\x1b[{};1H", row + 1));
+        output.push_str(&format!("\x1b[{}m", 31 + row % 7));
+
+        for col in 0..cols {
+            let ch = if col % 8 == 7 {
+                ' '
+            } else {
+                (b'a' + (col % 26) as u8) as char
+            };
+            output.push(ch);
+        }
+
+        output.push_str("\x1b[0m");
+    }
+
+    output
+}
+
+/// A chunk of `size` bytes of output as a program writes it: lines of text,
+/// some of them colored.
+pub fn output_chunk(size: usize) -> String {
+    let line = "\x1b[32mok\x1b[0m   test session::test::output_tail ... 0.01s\r\n";
+
+    line.repeat(size / line.len() + 1)[..size].to_owned()
+}
+
+/// `size` bytes of input for a program reading lines.
+pub fn input(size: usize) -> Vec<u8> {
+    let line = b"echo the quick brown fox jumps over the lazy dog 0123456789\n";
+
+    line.iter().copied().cycle().take(size).collect()
+}
+
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use ht_core::pty;
+    use nix::pty::Winsize;
+    use tokio::runtime::Runtime;
+    use tokio::sync::mpsc;
+
+    /// `cat` running in a PTY in raw mode without echo, so that it writes back
+    /// exactly the input it gets.
+    pub struct Cat {
+        runtime: Runtime,
+        input_tx: mpsc::Sender<Vec<u8>>,
+        output_rx: mpsc::Receiver<Vec<u8>>,
+    }
+
+    impl Cat {
+        pub fn spawn() -> Self {
+            let runtime = Runtime::new().unwrap();
+            let (input_tx, input_rx) = mpsc::channel(1024);
+            let (output_tx, mut output_rx) = mpsc::channel(1024);
+            let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+            let winsize = Winsize {
+                ws_row: 24,
+                ws_col: 80,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+
+            let command = "stty raw -echo && printf ready && exec cat".to_owned();
+
+            runtime.block_on(async {
+                let driver =
+                    pty::spawn(command, winsize, input_rx, output_tx, resize_rx, None).unwrap();
+
+                tokio::spawn(driver);
+
+                let mut output = Vec::new();
+
+                while !output.ends_with(b"ready") {
+                    output.extend(output_rx.recv().await.unwrap());
+                }
+            });
+
+            Self {
+                runtime,
+                input_tx,
+                output_rx,
+            }
+        }
+
+        /// Writes the input and waits for all of it to come back.
+        pub fn roundtrip(&mut self, input: &[u8]) {
+            self.runtime.block_on(async {
+                self.input_tx.send(input.to_vec()).await.unwrap();
+
+                let mut left = input.len();
+
+                while left > 0 {
+                    left = left.saturating_sub(self.output_rx.recv().await.unwrap().len());
+                }
+            });
+        }
+    }
+}
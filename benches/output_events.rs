@@ -0,0 +1,173 @@
+// Output processing: feeding output through the session (emulator, event
+// broadcast, watches) and turning the resulting events into JSON or
+// MessagePack for clients. `output_batching` processes a program's small
+// writes in batches of a given size (`--flush-bytes`): the time per batch is
+// the latency the last write of a batch waits for, the throughput what's
+// gained by batching. `event_encoding` compares serializing the events of a
+// session with heavy output directly with going through `serde_json::Value`.
+
+mod common;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures_util::StreamExt;
+use ht_core::session::{Event, Session};
+use ht_core::theme::Theme;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+const CHUNK_SIZES: &[usize] = &[64, 4 * 1024, 64 * 1024];
+
+fn output(c: &mut Criterion) {
+    let mut group = c.benchmark_group("output");
+
+    for &size in CHUNK_SIZES {
+        let chunk = common::output_chunk(size);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        for (name, emulator) in [("emulator", true), ("no_emulator", false)] {
+            let mut session = Session::new(120, 40, Theme::default());
+            session.set_emulator(emulator);
+
+            group.bench_with_input(BenchmarkId::new(name, size), &chunk, |b, chunk| {
+                b.iter(|| session.output(chunk.clone()))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn output_batching(c: &mut Criterion) {
+    const WRITE_SIZE: usize = 64;
+
+    let mut group = c.benchmark_group("output_batching");
+    let write = common::output_chunk(WRITE_SIZE).into_bytes();
+
+    for &batch_size in CHUNK_SIZES {
+        let mut session = Session::new(120, 40, Theme::default());
+        // flushed by size alone
+        session.set_output_batching(batch_size, Duration::from_secs(3600));
+        group.throughput(Throughput::Bytes(batch_size as u64));
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(batch_size),
+            &write,
+            |b, write| {
+                b.iter(|| {
+                    for _ in 0..batch_size / WRITE_SIZE {
+                        session.push_output(write.clone());
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn output_json(c: &mut Criterion) {
+    let mut group = c.benchmark_group("output_json");
+
+    for &size in CHUNK_SIZES {
+        let event = Event::Output(1.0, common::output_chunk(size), None, Vec::new());
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &event, |b, event| {
+            b.iter(|| serde_json::to_string(event).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn output_msgpack(c: &mut Criterion) {
+    let mut group = c.benchmark_group("output_msgpack");
+
+    for &size in CHUNK_SIZES {
+        let event = Event::Output(1.0, common::output_chunk(size), None, Vec::new());
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &event, |b, event| {
+            b.iter(|| ht_core::api::msgpack(event))
+        });
+    }
+
+    group.finish();
+}
+
+/// `output` events of a session which `count` chunks of `size` bytes of
+/// output went through, raw or cooked.
+fn session_output_events(size: usize, count: usize, cooked: bool) -> Vec<Event> {
+    let mut session = Session::new(120, 40, Theme::default());
+    session.set_cooked_output(cooked);
+    let events = session.subscribe().into_stream();
+
+    for _ in 0..count {
+        session.output(common::output_chunk(size));
+    }
+
+    drop(session);
+
+    Runtime::new().unwrap().block_on(
+        events
+            .filter_map(|event| async move {
+                match event {
+                    Ok(event @ Event::Output(..)) => Some(event),
+                    _ => None,
+                }
+            })
+            .collect(),
+    )
+}
+
+fn event_encoding(c: &mut Criterion) {
+    const COUNT: usize = 64;
+
+    let mut group = c.benchmark_group("event_encoding");
+
+    for &size in CHUNK_SIZES {
+        group.throughput(Throughput::Bytes((size * COUNT) as u64));
+
+        for (mode, cooked) in [("raw", false), ("cooked", true)] {
+            let events = session_output_events(size, COUNT, cooked);
+
+            group.bench_with_input(
+                BenchmarkId::new(format!("{mode}/msgpack_direct"), size),
+                &events,
+                |b, events| {
+                    b.iter(|| {
+                        events
+                            .iter()
+                            .map(|e| ht_core::api::msgpack(e).len())
+                            .sum::<usize>()
+                    })
+                },
+            );
+
+            group.bench_with_input(
+                BenchmarkId::new(format!("{mode}/msgpack_via_value"), size),
+                &events,
+                |b, events| {
+                    b.iter(|| {
+                        events
+                            .iter()
+                            .map(|e| ht_core::api::msgpack(&e.to_json()).len())
+                            .sum::<usize>()
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    output,
+    output_batching,
+    output_json,
+    output_msgpack,
+    event_encoding
+);
+criterion_main!(benches);
@@ -0,0 +1,42 @@
+// Snapshot rendering at various terminal sizes, both when the screen changed
+// since the last snapshot and when the cached rendering can be reused.
+
+mod common;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use ht_core::command::ScreenBuffer;
+use ht_core::session::Session;
+use ht_core::theme::Theme;
+
+fn session(cols: usize, rows: usize) -> Session {
+    let mut session = Session::new(cols, rows, Theme::default());
+    session.output(common::screen(cols, rows));
+
+    session
+}
+
+fn snapshot(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot");
+
+    for &(cols, rows) in common::SIZES {
+        let size = format!("{cols}x{rows}");
+        let mut session = session(cols, rows);
+
+        group.bench_function(BenchmarkId::new("changed", &size), |b| {
+            b.iter(|| {
+                // a single cell changed, invalidating the cache
+                session.output("\x1b[Hx".to_owned());
+                session.snapshot(false, ScreenBuffer::Active);
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("unchanged", &size), |b| {
+            b.iter(|| session.snapshot(false, ScreenBuffer::Active))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, snapshot);
+criterion_main!(benches);
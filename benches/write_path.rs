@@ -0,0 +1,136 @@
+// Input write path: throughput of input written to a program through the PTY,
+// and of the non-blocking write loop itself, which either keeps writing until
+// the kernel buffer fills up (the retry loop of the PTY driver) or writes once
+// per writable notification (single pass).
+
+mod common;
+
+#[cfg(unix)]
+criterion::criterion_main!(unix::benches);
+
+#[cfg(not(unix))]
+fn main() {}
+
+#[cfg(unix)]
+mod unix {
+    use super::common::{self, Cat};
+    use criterion::{BenchmarkId, Criterion, Throughput, criterion_group};
+    use ht_core::nbio;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::sync::mpsc;
+    use std::thread;
+    use tokio::io::unix::AsyncFd;
+    use tokio::runtime::Runtime;
+
+    const SIZES: &[usize] = &[1024, 64 * 1024, 1024 * 1024];
+
+    fn pty_cat(c: &mut Criterion) {
+        let mut group = c.benchmark_group("pty_cat");
+        group.sample_size(20);
+        let mut cat = Cat::spawn();
+
+        for &size in SIZES {
+            let input = common::input(size);
+            group.throughput(Throughput::Bytes(size as u64));
+
+            group.bench_with_input(BenchmarkId::from_parameter(size), &input, |b, input| {
+                b.iter(|| cat.roundtrip(input))
+            });
+        }
+
+        group.finish();
+    }
+
+    /// Non-blocking pipe, drained by a thread reporting how much it read.
+    struct Pipe {
+        runtime: Runtime,
+        fd: AsyncFd<OwnedFd>,
+        read_rx: mpsc::Receiver<usize>,
+    }
+
+    impl Pipe {
+        fn new() -> Self {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap();
+
+            let (reader, writer) = nix::unistd::pipe().unwrap();
+            nbio::set_non_blocking(&writer.as_raw_fd()).unwrap();
+            let fd = runtime.block_on(async { AsyncFd::new(writer) }).unwrap();
+            let (read_tx, read_rx) = mpsc::channel();
+
+            thread::spawn(move || {
+                let mut reader = File::from(reader);
+                let mut buf = [0; 64 * 1024];
+
+                while let Ok(n @ 1..) = reader.read(&mut buf) {
+                    if read_tx.send(n).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Self {
+                runtime,
+                fd,
+                read_rx,
+            }
+        }
+
+        /// Writes all of the data, then waits for the reader to get it.
+        fn write_all(&self, data: &[u8], retry: bool) {
+            self.runtime.block_on(async {
+                let mut file = File::from(self.fd.get_ref().try_clone().unwrap());
+                let mut buf = data;
+
+                while !buf.is_empty() {
+                    let mut guard = self.fd.writable().await.unwrap();
+
+                    loop {
+                        match nbio::write(&mut file, buf).unwrap() {
+                            Some(n) => buf = &buf[n..],
+
+                            None => {
+                                guard.clear_ready();
+                                break;
+                            }
+                        }
+
+                        if buf.is_empty() || !retry {
+                            break;
+                        }
+                    }
+                }
+            });
+
+            let mut left = data.len();
+
+            while left > 0 {
+                left -= self.read_rx.recv().unwrap();
+            }
+        }
+    }
+
+    fn write_loop(c: &mut Criterion) {
+        let mut group = c.benchmark_group("write_loop");
+        let pipe = Pipe::new();
+
+        for &size in SIZES {
+            let input = common::input(size);
+            group.throughput(Throughput::Bytes(size as u64));
+
+            for (name, retry) in [("retry", true), ("single_pass", false)] {
+                group.bench_with_input(BenchmarkId::new(name, size), &input, |b, input| {
+                    b.iter(|| pipe.write_all(input, retry))
+                });
+            }
+        }
+
+        group.finish();
+    }
+
+    criterion_group!(benches, pty_cat, write_loop);
+}
@@ -0,0 +1,75 @@
+//! Expands shell aliases in input commands with a command hook, before the
+//! input reaches the program.
+//!
+//! Run with `cargo run --example alias`.
+
+use std::sync::Arc;
+
+use ht_core::Session;
+use ht_core::api::stdio::{self, CommandFilter, OutputFormat};
+use ht_core::command::{Command, InputSeq};
+use ht_core::session::CommandAction;
+use ht_core::theme::Theme;
+
+const ALIASES: &[(&str, &str)] = &[("ll", "ls -l"), ("gs", "git status")];
+
+/// Replaces an alias at the start of a line of input with its expansion.
+fn expand(text: &str) -> Option<String> {
+    ALIASES.iter().find_map(|(alias, expansion)| {
+        let rest = text.strip_prefix(alias)?;
+        rest.starts_with([' ', '\r', '\n'])
+            .then(|| format!("{expansion}{rest}"))
+    })
+}
+
+fn alias_hook(command: &Command) -> CommandAction {
+    let Command::Input(seqs) = command else {
+        return CommandAction::Allow;
+    };
+
+    let mut expanded = false;
+
+    let seqs = seqs
+        .iter()
+        .map(|seq| match seq {
+            InputSeq::Standard(text) => match expand(text) {
+                Some(text) => {
+                    expanded = true;
+                    InputSeq::Standard(text)
+                }
+
+                None => seq.clone(),
+            },
+
+            seq => seq.clone(),
+        })
+        .collect();
+
+    if expanded {
+        CommandAction::Rewrite(Command::Input(seqs))
+    } else {
+        CommandAction::Allow
+    }
+}
+
+fn main() {
+    let mut session = Session::new(80, 24, Theme::default());
+    session.set_command_hook(Some(Arc::new(alias_hook)));
+
+    // the STDIO API passes every command it parses through the hook
+    let hook = session.command_hook();
+
+    for text in ["ll\r", "gs -s\r", "llama\r"] {
+        let line = serde_json::json!({ "type": "input", "payload": text }).to_string();
+        let parsed = stdio::parse_message(
+            line.into_bytes(),
+            OutputFormat::Compact,
+            &CommandFilter::AllowAll,
+            hook.as_ref(),
+        );
+
+        if let Ok(Some(Command::Input(seqs))) = parsed {
+            println!("{text:?} -> {seqs:?}");
+        }
+    }
+}
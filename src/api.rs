@@ -1,15 +1,165 @@
 pub mod http;
 pub mod stdio;
+use crate::session::Event;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
+/// Encoding of events (and, on STDIO, commands).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// JSON
+    #[default]
+    Json,
+
+    /// MessagePack, for controllers which can do without parsing JSON
+    Msgpack,
+}
+
+/// Encodes an event (or any other API message) as MessagePack.
+pub fn msgpack(value: &impl Serialize) -> Vec<u8> {
+    rmp_serde::to_vec_named(value).expect("API messages are always encodable")
+}
+
+/// Event as sent to a client, serialized as it's written out.
+pub struct Message {
+    event: Event,
+    dirty_rows: bool,
+}
+
+impl Serialize for Message {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.event.serialize_with(self.dirty_rows, serializer)
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Subscription {
     init: bool,
     snapshot: bool,
     resize: bool,
     output: bool,
+    screenshot: bool,
+    unknown_sequence: bool,
+    exit: bool,
+    state: bool,
+    grid: bool,
+    error: bool,
+    frame: bool,
+    started: bool,
+    output_tail: bool,
+    marker: bool,
+    muted: bool,
+    output_since: bool,
+    input_and_wait: bool,
+    cwd: bool,
+    env: bool,
+    resize_and_settle: bool,
+    query: bool,
+    input_chunked: bool,
+    save_scrollback: bool,
+    bell: bool,
+    processes: bool,
+    kill_process: bool,
+    refresh: bool,
+    input_pending: bool,
+    command_result: bool,
+    pid: bool,
+    wide_char_wrap: bool,
+    dirty_rows: bool,
 }
 
+/// Event types, which `all` subscribes to. Subscriptions also accept
+/// `dirtyRows`, which adds the rows changed by output to `output` events: it
+/// isn't an event of its own, so it's left out of `all` and has to be named.
+const EVENT_TYPES: &[&str] = &[
+    "init",
+    "output",
+    "resize",
+    "snapshot",
+    "screenshot",
+    "unknownSequence",
+    "exit",
+    "state",
+    "grid",
+    "error",
+    "frame",
+    "started",
+    "outputTail",
+    "marker",
+    "muted",
+    "outputSince",
+    "inputAndWait",
+    "cwd",
+    "env",
+    "resizeAndSettle",
+    "query",
+    "inputChunked",
+    "saveScrollback",
+    "bell",
+    "processes",
+    "killProcess",
+    "refresh",
+    "inputPending",
+    "commandResult",
+    "pid",
+    "wideCharWrap",
+];
+
+impl Subscription {
+    fn flag(&mut self, event: &str) -> Option<&mut bool> {
+        let flag = match event {
+            "init" => &mut self.init,
+            "output" => &mut self.output,
+            "resize" => &mut self.resize,
+            "snapshot" => &mut self.snapshot,
+            "screenshot" => &mut self.screenshot,
+            "unknownSequence" => &mut self.unknown_sequence,
+            "exit" => &mut self.exit,
+            "state" => &mut self.state,
+            "grid" => &mut self.grid,
+            "error" => &mut self.error,
+            "frame" => &mut self.frame,
+            "started" => &mut self.started,
+            "outputTail" => &mut self.output_tail,
+            "marker" => &mut self.marker,
+            "muted" => &mut self.muted,
+            "outputSince" => &mut self.output_since,
+            "inputAndWait" => &mut self.input_and_wait,
+            "cwd" => &mut self.cwd,
+            "env" => &mut self.env,
+            "resizeAndSettle" => &mut self.resize_and_settle,
+            "query" => &mut self.query,
+            "inputChunked" => &mut self.input_chunked,
+            "saveScrollback" => &mut self.save_scrollback,
+            "bell" => &mut self.bell,
+            "processes" => &mut self.processes,
+            "killProcess" => &mut self.kill_process,
+            "refresh" => &mut self.refresh,
+            "inputPending" => &mut self.input_pending,
+            "commandResult" => &mut self.command_result,
+            "pid" => &mut self.pid,
+            "wideCharWrap" => &mut self.wide_char_wrap,
+            "dirtyRows" => &mut self.dirty_rows,
+            _ => return None,
+        };
+
+        Some(flag)
+    }
+
+    /// Message of an event, listing the rows of the screen changed by an
+    /// `output` event (`dirtyRows`) when subscribed to.
+    fn message(&self, event: Event) -> Message {
+        Message {
+            event,
+            dirty_rows: self.dirty_rows,
+        }
+    }
+}
+
+/// Parses a comma separated list of event names, applied from left to right.
+/// `all` subscribes to every event (`EVENT_TYPES`), and a name prefixed with
+/// `-` removes it, e.g. `all,-output`.
 impl FromStr for Subscription {
     type Err = String;
 
@@ -17,15 +167,91 @@ impl FromStr for Subscription {
         let mut sub = Subscription::default();
 
         for event in s.split(',') {
-            match event {
-                "init" => sub.init = true,
-                "output" => sub.output = true,
-                "resize" => sub.resize = true,
-                "snapshot" => sub.snapshot = true,
-                _ => return Err(format!("invalid event name: {event}")),
+            let (name, enabled) = match event.strip_prefix('-') {
+                Some(name) => (name, false),
+                None => (event, true),
+            };
+
+            if name == "all" {
+                for name in EVENT_TYPES {
+                    *sub.flag(name).unwrap() = enabled;
+                }
+
+                continue;
+            }
+
+            match sub.flag(name) {
+                Some(flag) => *flag = enabled,
+                None => return Err(format!("invalid event name: {event}")),
             }
         }
 
         Ok(sub)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{EVENT_TYPES, Subscription};
+    use crate::session::Event;
+
+    #[test]
+    fn parse_subscription() {
+        let sub: Subscription = "output,snapshot".parse().unwrap();
+        assert!(sub.output && sub.snapshot && !sub.init);
+
+        let sub: Subscription = "all".parse().unwrap();
+        assert!(sub.output && sub.snapshot && sub.bell && sub.started);
+        assert!(!sub.dirty_rows);
+
+        let sub: Subscription = "all,dirtyRows".parse().unwrap();
+        assert!(sub.output && sub.dirty_rows);
+
+        let sub: Subscription = "all,-output,-snapshot".parse().unwrap();
+        assert!(!sub.output && !sub.snapshot && sub.init && sub.exit);
+
+        let sub: Subscription = "all,-output,output".parse().unwrap();
+        assert!(sub.output);
+
+        let sub: Subscription = "-output".parse().unwrap();
+        assert!(!sub.output);
+    }
+
+    #[test]
+    fn every_event_type_is_parsed() {
+        let mut sub = Subscription::default();
+
+        for name in EVENT_TYPES.iter().chain(&["dirtyRows"]) {
+            assert!(sub.flag(name).is_some(), "{name}");
+        }
+    }
+
+    #[test]
+    fn output_with_dirty_rows() {
+        let event = Event::Output(0.0, "a".to_owned(), None, vec![0, 2]);
+
+        let sub: Subscription = "output".parse().unwrap();
+        let value = serde_json::to_value(sub.message(event.clone())).unwrap();
+        assert_eq!(value, event.to_json());
+        assert!(value["data"].get("dirtyRows").is_none());
+
+        let sub: Subscription = "output,dirtyRows".parse().unwrap();
+        let value = serde_json::to_value(sub.message(event)).unwrap();
+        assert_eq!(value["data"]["dirtyRows"], serde_json::json!([0, 2]));
+        assert_eq!(value["data"]["seq"], "a");
+    }
+
+    #[test]
+    fn reject_unknown_event() {
+        assert_eq!(
+            "output,title".parse::<Subscription>().unwrap_err(),
+            "invalid event name: title"
+        );
+        assert_eq!(
+            "all,-title".parse::<Subscription>().unwrap_err(),
+            "invalid event name: -title"
+        );
+        assert!("".parse::<Subscription>().is_err());
+        assert!("-".parse::<Subscription>().is_err());
+    }
+}
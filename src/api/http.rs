@@ -1,11 +1,16 @@
-use super::Subscription;
+use super::{Message, Protocol, Subscription};
+use crate::grid;
+use crate::log;
+use crate::record;
+use crate::render;
 use crate::session;
+use crate::theme::Theme;
 use anyhow::Result;
 use axum::{
     Router,
     extract::{Query, State, connect_info::ConnectInfo, ws},
-    http::{StatusCode, Uri, header},
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, Uri, header},
+    response::{IntoResponse, Redirect},
     routing::get,
 };
 use futures_util::{StreamExt, sink, stream};
@@ -16,6 +21,7 @@ use std::borrow::Cow;
 use std::future::{self, Future, IntoFuture};
 use std::io;
 use std::net::{SocketAddr, TcpListener};
+use std::path::{Component, Path, PathBuf};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 
@@ -23,51 +29,108 @@ use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 #[folder = "assets/"]
 struct Assets;
 
+/// Directory with a custom frontend, served instead of the embedded assets.
+#[derive(Debug, Clone)]
+pub struct WebRoot {
+    pub dir: PathBuf,
+
+    /// Serve only files from `dir`, without falling back to embedded assets
+    /// for missing ones.
+    pub exclusive: bool,
+}
+
+/// Starts the HTTP server, serving the same app on each of the listeners.
+///
+/// All routes are mounted under `base_path`, which is either empty (serve at
+/// the root) or a normalized `/prefix` without a trailing slash.
 pub async fn start(
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
+    base_path: String,
+    theme: Theme,
+    web_root: Option<WebRoot>,
     clients_tx: mpsc::Sender<session::Client>,
+    handle: session::Handle,
 ) -> Result<impl Future<Output = io::Result<()>>> {
-    listener.set_nonblocking(true)?;
-    let listener = tokio::net::TcpListener::from_std(listener)?;
-    let addr = listener.local_addr().unwrap();
-    eprintln!("HTTP server listening on {addr}");
-    eprintln!("live preview available at http://{addr}");
+    let listeners = listeners
+        .into_iter()
+        .map(|listener| {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let addr = listener.local_addr()?;
+            log::status!("HTTP server listening on {addr}");
+            log::status!("live preview available at http://{addr}{base_path}/");
+
+            Ok(listener)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
 
     let app: Router<()> = Router::new()
-        .route("/ws/alis", get(alis_handler))
-        .route("/ws/events", get(event_stream_handler))
+        .route(&format!("{base_path}/ws/alis"), get(alis_handler))
+        .route(&format!("{base_path}/ws/events"), get(event_stream_handler))
+        .route(&format!("{base_path}/ws/grid"), get(grid_handler))
+        .route(
+            &format!("{base_path}/snapshot.svg"),
+            get({
+                let theme = theme.clone();
+                move |params, state| svg_snapshot_handler(params, state, theme)
+            }),
+        )
+        .route(
+            &format!("{base_path}/frame.cast"),
+            get(move || frame_cast_handler(handle)),
+        )
+        .route(
+            &format!("{base_path}/theme.css"),
+            get(move || theme_css_handler(theme)),
+        )
         .with_state(clients_tx)
-        .fallback(static_handler);
+        .fallback(move |uri: Uri| static_handler(uri, base_path.clone(), web_root.clone()));
+
+    let servers = listeners.into_iter().map(|listener| {
+        axum::serve(
+            listener,
+            app.clone()
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .into_future()
+    });
 
-    Ok(axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .into_future())
+    let serve = futures_util::future::try_join_all(servers);
+
+    Ok(async move { serve.await.map(|_| ()) })
+}
+
+#[derive(Debug, Deserialize)]
+struct AlisParams {
+    #[serde(default)]
+    replay: bool,
 }
 
 /// ALiS protocol handler
 ///
 /// This endpoint implements ALiS (asciinema live stream) protocol (https://docs.asciinema.org/manual/alis/).
 /// It allows pointing asciinema player directly to ht to get a real-time terminal preview.
+/// Query param `replay=true` starts the stream from the replay buffer.
 async fn alis_handler(
     ws: ws::WebSocketUpgrade,
+    Query(params): Query<AlisParams>,
     ConnectInfo(_addr): ConnectInfo<SocketAddr>,
     State(clients_tx): State<mpsc::Sender<session::Client>>,
 ) -> impl IntoResponse {
     ws.on_upgrade(move |socket| async move {
-        let _ = handle_alis_socket(socket, clients_tx).await;
+        let _ = handle_alis_socket(socket, clients_tx, params.replay).await;
     })
 }
 
 async fn handle_alis_socket(
     socket: ws::WebSocket,
     clients_tx: mpsc::Sender<session::Client>,
+    replay: bool,
 ) -> Result<()> {
     let (sink, stream) = socket.split();
     let drainer = tokio::spawn(stream.map(Ok).forward(sink::drain()));
 
-    let result = session::stream(&clients_tx)
+    let result = session::stream(&clients_tx, replay)
         .await?
         .filter_map(alis_message)
         .chain(stream::once(future::ready(Ok(close_message()))))
@@ -93,7 +156,9 @@ async fn alis_message(
             "init": seq,
         })))),
 
-        Ok(Output(time, data)) => Some(Ok(json_message(json!([time, "o", data])))),
+        // rows caught up with when the display was unfrozen
+        Ok(Output(_, data, _, _)) if data.is_empty() => None,
+        Ok(Output(time, data, _, _)) => Some(Ok(json_message(json!([time, "o", data])))),
 
         Ok(Resize(time, cols, rows)) => Some(Ok(json_message(json!([
             time,
@@ -101,7 +166,44 @@ async fn alis_message(
             format!("{cols}x{rows}")
         ])))),
 
-        Ok(Snapshot(_, _, _, _)) => None,
+        Ok(Snapshot(_, _, _, _, _, _, _, _)) => None,
+
+        Ok(Screenshot(_, _)) => None,
+
+        Ok(UnknownSequence(_, _)) => None,
+
+        Ok(Exit(_, _)) => None,
+
+        Ok(State(_, _, _, _, _, _)) => None,
+
+        Ok(Grid(_, _, _)) => None,
+
+        Ok(Frame(_)) => None,
+
+        Ok(OutputTail(_)) => None,
+        Ok(Marker(_)) => None,
+        Ok(OutputSince(_, _, _, _, _)) => None,
+
+        Ok(TextWait(_, _)) => None,
+
+        Ok(Settle(_, _, _, _)) => None,
+
+        Ok(Cwd(_, _)) => None,
+
+        Ok(Env(_, _)) => None,
+        Ok(Error(_)) => None,
+        Ok(InputChunked(_, _)) => None,
+        Ok(CommandResult(_, _, _, _)) => None,
+        Ok(Pid(_)) => None,
+        Ok(SaveScrollback(_, _)) => None,
+        Ok(Bell(_)) => None,
+        Ok(WideCharWrap(_, _)) => None,
+        Ok(Query(_, _)) => None,
+        Ok(Muted(_, _, _)) => None,
+        Ok(Processes(_)) => None,
+        Ok(KillProcess(_, _, _)) => None,
+        Ok(Refresh(_, _)) => None,
+        Ok(InputPending(_)) => None,
 
         Err(e) => Some(Err(axum::Error::new(e))),
     }
@@ -110,6 +212,16 @@ async fn alis_message(
 #[derive(Debug, Deserialize)]
 struct EventsParams {
     sub: Option<String>,
+    cols: Option<usize>,
+    #[serde(default)]
+    replay: bool,
+    #[serde(default)]
+    protocol: Protocol,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotParams {
+    cols: Option<usize>,
 }
 
 /// Event stream handler
@@ -117,6 +229,11 @@ struct EventsParams {
 /// This endpoint allows the client to subscribe to selected events and have them delivered as they occur.
 /// Query param `sub` should be set to a comma-separated list desired of events.
 /// See above for a list of supported events.
+/// Optional query param `cols` gives the client its own view width: `init`
+/// and `snapshot` events are reflowed to it before being sent.
+/// Query param `replay=true` starts the stream from the replay buffer.
+/// Query param `protocol=msgpack` sends events as MessagePack, in binary
+/// messages.
 async fn event_stream_handler(
     ws: ws::WebSocketUpgrade,
     Query(params): Query<EventsParams>,
@@ -124,9 +241,12 @@ async fn event_stream_handler(
     State(clients_tx): State<mpsc::Sender<session::Client>>,
 ) -> impl IntoResponse {
     let sub: Subscription = params.sub.unwrap_or_default().parse().unwrap_or_default();
+    let cols = params.cols.filter(|&cols| cols > 0);
+    let replay = params.replay;
+    let protocol = params.protocol;
 
     ws.on_upgrade(move |socket| async move {
-        let _ = handle_event_stream_socket(socket, clients_tx, sub).await;
+        let _ = handle_event_stream_socket(socket, clients_tx, sub, cols, replay, protocol).await;
     })
 }
 
@@ -134,13 +254,21 @@ async fn handle_event_stream_socket(
     socket: ws::WebSocket,
     clients_tx: mpsc::Sender<session::Client>,
     sub: Subscription,
+    cols: Option<usize>,
+    replay: bool,
+    protocol: Protocol,
 ) -> Result<()> {
     let (sink, stream) = socket.split();
     let drainer = tokio::spawn(stream.map(Ok).forward(sink::drain()));
 
-    let result = session::stream(&clients_tx)
+    let result = session::api_stream(&clients_tx, replay)
         .await?
-        .filter_map(move |e| event_stream_message(e, sub))
+        .map(move |e| match cols {
+            Some(cols) => e.map(|e| e.reflow(cols)),
+            None => e,
+        })
+        .filter_map(move |e| event_stream_value(e, sub))
+        .map(move |value| value.map(|value| event_message(value, protocol)))
         .chain(stream::once(future::ready(Ok(close_message()))))
         .forward(sink)
         .await;
@@ -151,26 +279,182 @@ async fn handle_event_stream_socket(
     Ok(())
 }
 
-async fn event_stream_message(
+async fn event_stream_value(
     event: Result<session::Event, BroadcastStreamRecvError>,
     sub: Subscription,
-) -> Option<Result<ws::Message, axum::Error>> {
+) -> Option<Result<Message, axum::Error>> {
     use session::Event::*;
 
     match event {
-        Ok(e @ Init(_, _, _, _, _)) if sub.init => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Output(_, _)) if sub.output => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Resize(_, _, _)) if sub.resize => Some(Ok(json_message(e.to_json()))),
-        Ok(e @ Snapshot(_, _, _, _)) if sub.snapshot => Some(Ok(json_message(e.to_json()))),
+        Ok(e @ Init(_, _, _, _, _)) if sub.init => Some(Ok(sub.message(e))),
+        Ok(Output(_, _, Some(cooked), _)) if cooked.is_empty() => None,
+        Ok(Output(_, data, None, _)) if data.is_empty() && !sub.dirty_rows => None,
+        Ok(e @ Output(_, _, _, _)) if sub.output => Some(Ok(sub.message(e))),
+        Ok(e @ Resize(_, _, _)) if sub.resize => Some(Ok(sub.message(e))),
+        Ok(e @ Snapshot(_, _, _, _, _, _, _, _)) if sub.snapshot => Some(Ok(sub.message(e))),
+        Ok(e @ Screenshot(_, _)) if sub.screenshot => Some(Ok(sub.message(e))),
+        Ok(e @ UnknownSequence(_, _)) if sub.unknown_sequence => Some(Ok(sub.message(e))),
+        Ok(e @ Exit(_, _)) if sub.exit => Some(Ok(sub.message(e))),
+        Ok(e @ State(_, _, _, _, _, _)) if sub.state => Some(Ok(sub.message(e))),
+        Ok(e @ Grid(_, _, _)) if sub.grid => Some(Ok(sub.message(e))),
+        Ok(e @ Frame(_)) if sub.frame => Some(Ok(sub.message(e))),
+        Ok(e @ OutputTail(_)) if sub.output_tail => Some(Ok(sub.message(e))),
+        Ok(e @ Marker(_)) if sub.marker => Some(Ok(sub.message(e))),
+        Ok(e @ OutputSince(_, _, _, _, _)) if sub.output_since => Some(Ok(sub.message(e))),
+        Ok(e @ TextWait(_, _)) if sub.input_and_wait => Some(Ok(sub.message(e))),
+        Ok(e @ Settle(_, _, _, _)) if sub.resize_and_settle => Some(Ok(sub.message(e))),
+        Ok(e @ Query(_, _)) if sub.query => Some(Ok(sub.message(e))),
+        Ok(e @ Cwd(_, _)) if sub.cwd => Some(Ok(sub.message(e))),
+        Ok(e @ Env(_, _)) if sub.env => Some(Ok(sub.message(e))),
+        Ok(e @ Error(_)) if sub.error => Some(Ok(sub.message(e))),
+        Ok(e @ InputChunked(_, _)) if sub.input_chunked => Some(Ok(sub.message(e))),
+        Ok(e @ CommandResult(_, _, _, _)) if sub.command_result => Some(Ok(sub.message(e))),
+        Ok(e @ Pid(_)) if sub.pid => Some(Ok(sub.message(e))),
+        Ok(e @ SaveScrollback(_, _)) if sub.save_scrollback => Some(Ok(sub.message(e))),
+        Ok(e @ Bell(_)) if sub.bell => Some(Ok(sub.message(e))),
+        Ok(e @ WideCharWrap(_, _)) if sub.wide_char_wrap => Some(Ok(sub.message(e))),
+        Ok(e @ Muted(_, _, _)) if sub.muted => Some(Ok(sub.message(e))),
+        Ok(e @ Processes(_)) if sub.processes => Some(Ok(sub.message(e))),
+        Ok(e @ KillProcess(_, _, _)) if sub.kill_process => Some(Ok(sub.message(e))),
+        Ok(e @ Refresh(_, _)) if sub.refresh => Some(Ok(sub.message(e))),
+        Ok(e @ InputPending(_)) if sub.input_pending => Some(Ok(sub.message(e))),
         Ok(_) => None,
         Err(e) => Some(Err(axum::Error::new(e))),
     }
 }
 
+/// Grid stream handler
+///
+/// This endpoint streams the terminal grid as binary frames: a full frame
+/// followed by deltas with the cells changed by output (see `grid` module for
+/// the format). Clients have to request the `ht-grid.v1` subprotocol.
+async fn grid_handler(
+    ws: ws::WebSocketUpgrade,
+    headers: HeaderMap,
+    ConnectInfo(_addr): ConnectInfo<SocketAddr>,
+    State(clients_tx): State<mpsc::Sender<session::Client>>,
+) -> axum::response::Response {
+    if !requests_protocol(&headers, grid::PROTOCOL) {
+        let message = format!("{} subprotocol required", grid::PROTOCOL);
+
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+
+    ws.protocols([grid::PROTOCOL])
+        .on_upgrade(move |socket| async move {
+            let _ = handle_grid_socket(socket, clients_tx).await;
+        })
+        .into_response()
+}
+
+async fn handle_grid_socket(
+    socket: ws::WebSocket,
+    clients_tx: mpsc::Sender<session::Client>,
+) -> Result<()> {
+    let (sink, stream) = socket.split();
+    let drainer = tokio::spawn(stream.map(Ok).forward(sink::drain()));
+
+    let result = session::grid_stream(&clients_tx)
+        .await?
+        .map(|frame| match frame {
+            Ok(frame) => Ok(ws::Message::Binary(frame.to_vec())),
+            Err(e) => Err(axum::Error::new(e)),
+        })
+        .chain(stream::once(future::ready(Ok(close_message()))))
+        .forward(sink)
+        .await;
+
+    drainer.abort();
+    result?;
+
+    Ok(())
+}
+
+/// Tells whether a WebSocket handshake lists a subprotocol in its
+/// `Sec-WebSocket-Protocol` header(s).
+fn requests_protocol(headers: &HeaderMap, protocol: &str) -> bool {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|p| p.trim() == protocol)
+}
+
+/// SVG snapshot handler
+///
+/// This endpoint renders the current terminal view as an SVG image.
+/// Optional query param `cols` reflows the view to that width first.
+async fn svg_snapshot_handler(
+    Query(params): Query<SnapshotParams>,
+    State(clients_tx): State<mpsc::Sender<session::Client>>,
+    theme: Theme,
+) -> impl IntoResponse {
+    match session::view(&clients_tx).await {
+        Ok(mut view) => {
+            if let Some(cols) = params.cols.filter(|&cols| cols > 0) {
+                session::reflow(&mut view.vt, cols);
+                // link positions don't survive reflow
+                view.links.clear();
+            }
+
+            (
+                [(header::CONTENT_TYPE, "image/svg+xml")],
+                render::svg(&view.vt, &theme, view.render_cursor, &view.links),
+            )
+                .into_response()
+        }
+
+        Err(e) => unavailable_response(e),
+    }
+}
+
+/// Single-frame cast handler
+///
+/// This endpoint returns the current screen as an asciicast v2 file with a
+/// single output event, which reproduces the screen when played.
+async fn frame_cast_handler(handle: session::Handle) -> impl IntoResponse {
+    match handle.snapshot().await {
+        Ok(snapshot) => (
+            [(header::CONTENT_TYPE, "application/x-asciicast")],
+            record::frame(snapshot.cols, snapshot.rows, &snapshot.seq),
+        )
+            .into_response(),
+
+        Err(e) => unavailable_response(e),
+    }
+}
+
+/// Theme stylesheet handler
+///
+/// This endpoint exposes the configured theme as an asciinema player theme,
+/// so that the live preview uses the same colors as screenshots.
+async fn theme_css_handler(theme: Theme) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/css")], theme.to_css("ht"))
+}
+
+fn unavailable_response(error: anyhow::Error) -> axum::response::Response {
+    let message = match error.downcast_ref::<session::EmulatorDisabled>() {
+        Some(e) => e.to_string(),
+        None => "503".to_owned(),
+    };
+
+    (StatusCode::SERVICE_UNAVAILABLE, message).into_response()
+}
+
 fn json_message(value: serde_json::Value) -> ws::Message {
     ws::Message::Text(value.to_string())
 }
 
+fn event_message(message: Message, protocol: Protocol) -> ws::Message {
+    match protocol {
+        Protocol::Json => ws::Message::Text(
+            serde_json::to_string(&message).expect("events are always serializable"),
+        ),
+        Protocol::Msgpack => ws::Message::Binary(super::msgpack(&message)),
+    }
+}
+
 fn close_message() -> ws::Message {
     ws::Message::Close(Some(ws::CloseFrame {
         code: ws::close_code::NORMAL,
@@ -178,20 +462,183 @@ fn close_message() -> ws::Message {
     }))
 }
 
-async fn static_handler(uri: Uri) -> impl IntoResponse {
-    let mut path = uri.path().trim_start_matches('/');
+async fn static_handler(
+    uri: Uri,
+    base_path: String,
+    web_root: Option<WebRoot>,
+) -> impl IntoResponse {
+    let path = match strip_base_path(uri.path(), &base_path) {
+        Some("") => {
+            // The page references its assets and WebSocket endpoint with
+            // relative URLs, so it has to be served from a directory path.
+            return Redirect::permanent(&format!("{base_path}/")).into_response();
+        }
+
+        Some(path) => path,
+        None => return (StatusCode::NOT_FOUND, "404").into_response(),
+    };
+
+    let mut path = path.trim_start_matches('/');
 
     if path.is_empty() {
         path = "index.html";
     }
 
-    match Assets::get(path) {
-        Some(content) => {
-            let mime = mime_guess::from_path(path).first_or_octet_stream();
+    if let Some(web_root) = web_root {
+        let Some(file) = web_root_file(&web_root.dir, path) else {
+            return (StatusCode::NOT_FOUND, "404").into_response();
+        };
 
-            ([(header::CONTENT_TYPE, mime.as_ref())], content.data).into_response()
+        if let Ok(content) = tokio::fs::read(&file).await {
+            return asset_response(path, content);
         }
 
+        if web_root.exclusive {
+            return (StatusCode::NOT_FOUND, "404").into_response();
+        }
+    }
+
+    match Assets::get(path) {
+        Some(content) => asset_response(path, content.data),
         None => (StatusCode::NOT_FOUND, "404").into_response(),
     }
 }
+
+fn asset_response(path: &str, content: impl Into<axum::body::Body>) -> axum::response::Response {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+    ([(header::CONTENT_TYPE, mime.as_ref())], content.into()).into_response()
+}
+
+/// Maps a request path to a file in the web root. Paths which could reach
+/// outside of it (`..`, absolute paths, drive prefixes) map to nothing.
+fn web_root_file(dir: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+
+    Some(dir.join(path))
+}
+
+fn strip_base_path<'a>(path: &'a str, base_path: &str) -> Option<&'a str> {
+    match path.strip_prefix(base_path) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Protocol, event_message, event_stream_value, requests_protocol, strip_base_path,
+        web_root_file,
+    };
+    use crate::api::stdio::{OutputFormat, format_event};
+    use crate::command::ScreenBuffer;
+    use crate::session::Session;
+    use crate::theme::Theme;
+    use axum::extract::ws;
+    use axum::http::{HeaderMap, HeaderValue, header};
+    use futures_util::StreamExt;
+    use std::path::Path;
+
+    #[test]
+    fn requested_protocol() {
+        let mut headers = HeaderMap::new();
+        assert!(!requests_protocol(&headers, "ht-grid.v1"));
+
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("foo, ht-grid.v1"),
+        );
+        assert!(requests_protocol(&headers, "ht-grid.v1"));
+        assert!(!requests_protocol(&headers, "ht-grid"));
+    }
+
+    #[test]
+    fn strip_root_base_path() {
+        assert_eq!(strip_base_path("/", ""), Some("/"));
+        assert_eq!(strip_base_path("/index.html", ""), Some("/index.html"));
+    }
+
+    #[test]
+    fn strip_prefixed_base_path() {
+        assert_eq!(strip_base_path("/terminal", "/terminal"), Some(""));
+        assert_eq!(strip_base_path("/terminal/", "/terminal"), Some("/"));
+        assert_eq!(
+            strip_base_path("/terminal/asciinema-player.css", "/terminal"),
+            Some("/asciinema-player.css")
+        );
+        assert_eq!(strip_base_path("/terminals/", "/terminal"), None);
+        assert_eq!(strip_base_path("/index.html", "/terminal"), None);
+    }
+
+    #[test]
+    fn web_root_paths() {
+        let root = Path::new("/srv/ht");
+
+        assert_eq!(
+            web_root_file(root, "index.html"),
+            Some(root.join("index.html"))
+        );
+        assert_eq!(
+            web_root_file(root, "js/app.js"),
+            Some(root.join("js/app.js"))
+        );
+        assert_eq!(web_root_file(root, "../secret"), None);
+        assert_eq!(web_root_file(root, "js/../../secret"), None);
+        assert_eq!(web_root_file(root, "./index.html"), None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_json_matches_stdio() {
+        let mut session = Session::new(10, 3, Theme::default());
+        let mut events = Box::pin(session.subscribe().into_stream().skip(1));
+        session.output("ab\r\ncd\x1b[?25l".to_owned());
+        session.snapshot(false, ScreenBuffer::Active);
+
+        let event = loop {
+            let event = events.next().await.unwrap().unwrap();
+
+            if event.to_json()["type"] == "snapshot" {
+                break event;
+            }
+        };
+
+        let stdio = format_event(&event.to_json(), OutputFormat::Compact);
+        let sub = "snapshot".parse().unwrap();
+
+        let message = event_stream_value(Ok(event.clone()), sub)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let ws::Message::Binary(msgpack) = event_message(message, Protocol::Msgpack) else {
+            panic!("expected a binary message");
+        };
+
+        assert_eq!(
+            rmp_serde::from_slice::<serde_json::Value>(&msgpack).unwrap(),
+            event.to_json()
+        );
+
+        let message = event_stream_value(Ok(event), sub).await.unwrap().unwrap();
+
+        let ws::Message::Text(ws) = event_message(message, Protocol::Json) else {
+            panic!("expected a text message");
+        };
+
+        assert_eq!(ws, stdio);
+
+        let value: serde_json::Value = serde_json::from_str(&ws).unwrap();
+        assert_eq!(value["data"]["cols"], 10);
+        assert_eq!(value["data"]["rows"], 3);
+        assert_eq!(
+            value["data"]["cursor"],
+            serde_json::json!({ "col": 2, "row": 1, "visible": false })
+        );
+        assert_eq!(value["data"]["text"].as_str().unwrap().lines().count(), 3);
+    }
+}
@@ -1,18 +1,43 @@
 use super::Subscription;
-use crate::command::{self, Command, InputSeq};
+use crate::command::{
+    Command, EchoTyping, EchoWait, InputSeq, OutputSinceFormat, PASTE_END, PASTE_START, Query,
+    ScreenBuffer, ScrollbackFormat, Settle, TextWait,
+};
+use crate::log;
+use crate::pty::{Backpressure, BackpressurePolicy};
+use crate::render::ImageFormat;
 use crate::session;
 use anyhow::Result;
-use serde::{Deserialize, de::DeserializeOwned};
-use std::io;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::io::{self, BufRead, Read, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::thread;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio_stream::StreamExt;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+
+/// Default limit on the size of a single command line.
+pub const DEFAULT_MAX_COMMAND_BYTES: usize = 16 * 1024 * 1024;
+
+/// Commands read ahead of the ones being handled, at each stage between
+/// STDIN and the session. Once they're full, STDIN is left unread, so that a
+/// controller sending commands faster than they're handled (e.g. input held
+/// back by the `block` backpressure policy) is held up.
+const COMMAND_BUFFER_SIZE: usize = 256;
 
 #[derive(Debug, Deserialize)]
 struct InputArgs {
     payload: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct PasteArgs {
+    payload: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct SendKeysArgs {
     keys: Vec<String>,
@@ -22,30 +47,270 @@ struct SendKeysArgs {
 struct ResizeArgs {
     cols: usize,
     rows: usize,
+    #[serde(default = "default_signal")]
+    signal: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResizeAndSettleArgs {
+    cols: usize,
+    rows: usize,
+    #[serde(default = "default_settle", rename = "settleMs")]
+    settle_ms: u64,
+    #[serde(default = "default_wait_timeout", rename = "timeoutMs")]
+    timeout_ms: u64,
+    id: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshArgs {
+    #[serde(default)]
+    snapshot: bool,
+    #[serde(default = "default_settle", rename = "settleMs")]
+    settle_ms: u64,
+    #[serde(default = "default_wait_timeout", rename = "timeoutMs")]
+    timeout_ms: u64,
+    id: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TakeSnapshotArgs {
+    #[serde(default, rename = "scrollRegion")]
+    scroll_region: bool,
+    buffer: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryArgs {
+    send: String,
+    #[serde(default = "default_query_timeout", rename = "readTimeoutMs")]
+    read_timeout_ms: u64,
+    id: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SleepArgs {
+    ms: u64,
 }
 
+#[derive(Debug, Deserialize)]
+struct InputAndWaitArgs {
+    text: String,
+    expect: String,
+    #[serde(default = "default_wait_timeout", rename = "timeoutMs")]
+    timeout_ms: u64,
+    id: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TypeWithEchoArgs {
+    text: String,
+    #[serde(default = "default_echo_timeout", rename = "echoTimeoutMs")]
+    echo_timeout_ms: u64,
+    #[serde(default = "default_fallback_delay", rename = "fallbackDelayMs")]
+    fallback_delay_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetBackpressureArgs {
+    policy: String,
+    #[serde(rename = "maxBytes")]
+    max_bytes: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetRenderCursorArgs {
+    visible: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnmuteArgs {
+    #[serde(default)]
+    snapshot: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SaveScrollbackArgs {
+    path: std::path::PathBuf,
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetEnvArgs {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KillProcessArgs {
+    pid: u32,
+    signal: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignalArgs {
+    signal: SignalArg,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SignalArg {
+    Name(String),
+    Number(u32),
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputTailArgs {
+    bytes: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputSinceArgs {
+    seq: u64,
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScreenshotArgs {
+    format: Option<String>,
+}
+
+/// Command types understood by `build_command`.
+const COMMAND_TYPES: &[&str] = &[
+    "input",
+    "sendKeys",
+    "paste",
+    "eof",
+    "closeStdin",
+    "resize",
+    "takeSnapshot",
+    "getState",
+    "getGrid",
+    "exportFrame",
+    "getOutputTail",
+    "mark",
+    "mute",
+    "unmute",
+    "freezeDisplay",
+    "unfreezeDisplay",
+    "getOutputSince",
+    "screenshot",
+    "sleep",
+    "inputAndWait",
+    "typeWithEcho",
+    "query",
+    "getCwd",
+    "getEnv",
+    "listProcesses",
+    "killProcess",
+    "signal",
+    "resizeAndSettle",
+    "refresh",
+    "setBackpressure",
+    "getInputPending",
+    "setRenderCursor",
+    "saveScrollback",
+];
+
+/// Restricts which command types are honored. Rejected commands are never
+/// passed on to the session.
+#[derive(Debug, Default, Clone)]
+pub enum CommandFilter {
+    #[default]
+    AllowAll,
+    Allow(CommandList),
+    Deny(CommandList),
+}
+
+/// Comma separated list of command types, as given to `--allow-commands` and
+/// `--deny-commands`.
+#[derive(Debug, Clone)]
+pub struct CommandList(Vec<String>);
+
+/// Reported in the `started` event, once the HTTP server is listening and
+/// the process has been spawned.
+#[derive(Debug, Clone)]
+pub struct Started {
+    pub listen_addrs: Vec<SocketAddr>,
+    pub pid: u32,
+    pub cols: usize,
+    pub rows: usize,
+}
+
+/// How events are written to STDOUT.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// One compact JSON object per line (NDJSON).
+    #[default]
+    Compact,
+
+    /// Multi-line, indented JSON objects, each followed by an empty line.
+    Pretty,
+
+    /// MessagePack maps, each preceded by its size as a 32-bit big-endian
+    /// integer. Commands are read in the same framing.
+    MessagePack,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn start(
     command_tx: mpsc::Sender<Command>,
-    clients_tx: mpsc::Sender<session::Client>,
+    subscription: session::Subscription,
     sub: Subscription,
+    format: OutputFormat,
+    filter: CommandFilter,
+    hook: Option<session::CommandHook>,
+    max_command_bytes: usize,
+    started_rx: oneshot::Receiver<Started>,
 ) -> Result<()> {
-    let (input_tx, mut input_rx) = mpsc::unbounded_channel();
-    thread::spawn(|| read_stdin(input_tx));
-    let mut events = session::stream(&clients_tx).await?;
+    let (input_tx, mut input_rx) = mpsc::channel(COMMAND_BUFFER_SIZE);
+
+    thread::spawn(move || match format {
+        OutputFormat::MessagePack => read_frames(io::stdin().lock(), max_command_bytes, input_tx),
+        _ => read_lines(io::stdin().lock(), max_command_bytes, input_tx),
+    });
+
+    let mut events = subscription.into_api_stream();
+    let (queue_tx, queue_rx) = mpsc::channel(COMMAND_BUFFER_SIZE);
+    // a parsed command the forwarder has no room for yet, holding up reading
+    let mut held: Option<Command> = None;
+    let (closing_tx, closing_rx) = watch::channel(false);
+    let forwarder = tokio::spawn(forward_commands(queue_rx, command_tx, closing_rx));
+
+    // nothing is printed before the started event, which marks ht being ready
+    if let Ok(started) = started_rx.await {
+        if sub.started {
+            print_started(&started, format);
+        }
+    }
 
     loop {
         tokio::select! {
-            line = input_rx.recv() => {
+            line = input_rx.recv(), if held.is_none() => {
                 match line {
                     Some(line) => {
-                        match parse_line(&line) {
-                            Ok(command) => {
-                                // Handle large input commands by chunking
-                                if let Err(e) = send_command_with_chunking(&command_tx, command).await {
-                                    eprintln!("failed to send command: {e}");
+                        let parsed = line
+                            .map_err(|size| {
+                                format!("command too large: {size} bytes, the limit is {max_command_bytes}")
+                            })
+                            .and_then(|message| parse_message(message, format, &filter, hook.as_ref()));
+
+                        match parsed {
+                            Ok(Some(command)) => {
+                                if let Err(TrySendError::Full(command)) = queue_tx.try_send(command) {
+                                    held = Some(command);
                                 }
                             },
-                            Err(e) => eprintln!("command parse error: {e}"),
+
+                            // dropped by the command hook
+                            Ok(None) => (),
+
+                            Err(e) => {
+                                log::warning!("command parse error: {e}");
+
+                                if sub.error {
+                                    print_error(&e, format);
+                                }
+                            }
                         }
                     }
 
@@ -53,24 +318,150 @@ pub async fn start(
                 }
             }
 
+            permit = queue_tx.reserve(), if held.is_some() => {
+                if let (Ok(permit), Some(command)) = (permit, held.take()) {
+                    permit.send(command);
+                }
+            }
+
             event = events.next() => {
                 use session::Event::*;
 
                 match event {
                     Some(Ok(e @ Init(_, _, _, _, _))) if sub.init => {
-                        println!("{}", e.to_json());
+                        print_event(&e, format);
                     }
 
-                    Some(Ok(e @ Output(_, _))) if sub.output => {
-                        println!("{}", e.to_json());
+                    // cooked output of a chunk which didn't change any text
+                    Some(Ok(Output(_, _, Some(cooked), _))) if cooked.is_empty() => (),
+
+                    // rows caught up with when the display was unfrozen,
+                    // for the clients following them only
+                    Some(Ok(Output(_, data, None, _))) if data.is_empty() && !sub.dirty_rows => (),
+
+                    Some(Ok(e @ Output(_, _, _, _))) if sub.output => {
+                        write_event(&sub.message(e), format);
                     }
 
                     Some(Ok(e @ Resize(_, _, _))) if sub.resize => {
-                        println!("{}", e.to_json());
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Snapshot(_, _, _, _, _, _, _, _))) if sub.snapshot => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Screenshot(_, _))) if sub.screenshot => {
+                        print_event(&e, format);
                     }
 
-                    Some(Ok(e @ Snapshot(_, _, _, _))) if sub.snapshot => {
-                        println!("{}", e.to_json());
+                    Some(Ok(e @ UnknownSequence(_, _))) if sub.unknown_sequence => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Exit(_, _))) if sub.exit => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ State(_, _, _, _, _, _))) if sub.state => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Grid(_, _, _))) if sub.grid => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Frame(_))) if sub.frame => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ OutputTail(_))) if sub.output_tail => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Marker(_))) if sub.marker => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ OutputSince(_, _, _, _, _))) if sub.output_since => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ TextWait(_, _))) if sub.input_and_wait => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Settle(_, _, _, _))) if sub.resize_and_settle => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Cwd(_, _))) if sub.cwd => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Env(_, _))) if sub.env => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Error(_))) if sub.error => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ InputChunked(_, _))) if sub.input_chunked => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ CommandResult(_, _, _, _))) if sub.command_result => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Pid(_))) if sub.pid => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ SaveScrollback(_, _))) if sub.save_scrollback => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Bell(_))) if sub.bell => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ WideCharWrap(_, _))) if sub.wide_char_wrap => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Query(_, _))) if sub.query => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Muted(_, _, _))) if sub.muted => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Processes(_))) if sub.processes => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ KillProcess(_, _, _))) if sub.kill_process => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ Refresh(_, _))) if sub.refresh => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Ok(e @ InputPending(_))) if sub.input_pending => {
+                        print_event(&e, format);
+                    }
+
+                    Some(Err(BroadcastStreamRecvError::Lagged(n))) => {
+                        let message = format!("fell behind, {n} events are missing");
+                        log::warning!("{message}");
+
+                        if sub.error {
+                            print_error(&message, format);
+                        }
                     }
 
                     Some(_) => (),
@@ -81,12 +472,299 @@ pub async fn start(
         }
     }
 
+    // deliver commands which are already queued, cancelling pending sleeps
+    let _ = closing_tx.send(true);
+    drop(queue_tx);
+    let _ = forwarder.await;
+
+    Ok(())
+}
+
+/// Passes commands on to the session in order, pausing the queue on `sleep`
+/// commands. Runs separately from event printing, which a sleep mustn't hold up.
+///
+/// `inputAndWait` is split into a text wait followed by the input, so the
+/// watch is in place before the process sees any of the input. The queue
+/// isn't paused for it: the outcome is reported asynchronously.
+///
+/// `typeWithEcho` pauses the queue until all of its text is typed.
+async fn forward_commands(
+    mut queue_rx: mpsc::Receiver<Command>,
+    command_tx: mpsc::Sender<Command>,
+    mut closing: watch::Receiver<bool>,
+) {
+    while let Some(command) = queue_rx.recv().await {
+        match command {
+            Command::Sleep(duration) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(duration) => (),
+                    _ = closing.wait_for(|closing| *closing) => (),
+                }
+            }
+
+            Command::InputAndWait(seqs, wait) => {
+                let result = match command_tx.send(Command::WaitForText(wait)).await {
+                    Ok(()) => send_command_with_chunking(&command_tx, Command::Input(seqs)).await,
+                    Err(e) => Err(e.into()),
+                };
+
+                if let Err(e) = result {
+                    log::warning!("failed to send command: {e}");
+                }
+            }
+
+            Command::TypeWithEcho(text, typing) => {
+                if let Err(e) = type_with_echo(&command_tx, &text, typing, &mut closing).await {
+                    log::warning!("failed to send command: {e}");
+                }
+            }
+
+            command => {
+                // Handle large input commands by chunking
+                if let Err(e) = send_command_with_chunking(&command_tx, command).await {
+                    log::warning!("failed to send command: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Types text a character at a time, for programs which drop input typed too
+/// fast. Each character is sent once the previous one was echoed, which is
+/// taken to be any output, as e.g. a REPL may redraw the whole line rather
+/// than print the character. Without any output for `echo_timeout`, echo is
+/// assumed to be off (e.g. at a password prompt), and the remaining
+/// characters are sent `fallback_delay` apart.
+async fn type_with_echo(
+    command_tx: &mpsc::Sender<Command>,
+    text: &str,
+    typing: EchoTyping,
+    closing: &mut watch::Receiver<bool>,
+) -> Result<()> {
+    let mut echo = true;
+    let mut keys = typed_keys(text).into_iter().peekable();
+
+    while let Some(key) = keys.next() {
+        let input = vec![InputSeq::Text(key.to_owned())];
+
+        let pause = if echo {
+            let (reply, echoed) = oneshot::channel();
+
+            let wait = EchoWait {
+                timeout: typing.echo_timeout,
+                reply,
+            };
+
+            command_tx.send(Command::InputWithEcho(input, wait)).await?;
+
+            tokio::select! {
+                result = echoed => {
+                    echo = result.unwrap_or(false);
+                }
+
+                _ = closing.wait_for(|closing| *closing) => (),
+            }
+
+            Duration::ZERO
+        } else {
+            command_tx.send(Command::Input(input)).await?;
+
+            typing.fallback_delay
+        };
+
+        if *closing.borrow() {
+            break;
+        }
+
+        if keys.peek().is_some() {
+            tokio::select! {
+                _ = tokio::time::sleep(pause) => (),
+                _ = closing.wait_for(|closing| *closing) => break,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits text into the characters typed one at a time by `typeWithEcho`,
+/// keeping CRLF in one piece for newline translation.
+fn typed_keys(text: &str) -> Vec<&str> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+
+    while let Some(ch) = rest.chars().next() {
+        let len = if rest.starts_with("\r\n") {
+            2
+        } else {
+            ch.len_utf8()
+        };
+
+        keys.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    keys
+}
+
+fn print_event(event: &session::Event, format: OutputFormat) {
+    write_event(event, format);
+}
+
+fn print_started(started: &Started, format: OutputFormat) {
+    let value = serde_json::json!({
+        "type": "started",
+        "data": {
+            "listenAddr": started.listen_addrs.first().map(|addr| addr.to_string()),
+            "listenAddrs": started.listen_addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>(),
+            "pid": started.pid,
+            "cols": started.cols,
+            "rows": started.rows,
+        },
+    });
+
+    write_event(&value, format);
+}
+
+fn print_error(message: &str, format: OutputFormat) {
+    let value = serde_json::json!({
+        "type": "error",
+        "data": { "message": message },
+    });
+
+    write_event(&value, format);
+}
+
+fn write_event(value: &impl Serialize, format: OutputFormat) {
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(&encode_event(value, format));
+    let _ = stdout.flush();
+}
+
+/// Bytes written to STDOUT for an event, including the delimiter.
+pub(crate) fn encode_event(value: &impl Serialize, format: OutputFormat) -> Vec<u8> {
+    match format {
+        OutputFormat::MessagePack => {
+            let data = super::msgpack(value);
+            let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+            frame.extend(data);
+
+            frame
+        }
+
+        format => format!("{}\n", format_event(value, format)).into_bytes(),
+    }
+}
+
+/// JSON text of an event, without the line break following it.
+pub(crate) fn format_event(value: &impl Serialize, format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Compact | OutputFormat::MessagePack => {
+            serde_json::to_string(value).expect("events are always serializable")
+        }
+
+        // Pretty-printed JSON never contains an empty line (newlines inside
+        // strings are escaped), which makes it a safe delimiter.
+        OutputFormat::Pretty => {
+            let json = serde_json::to_string_pretty(value).expect("events are always serializable");
+            format!("{json}\n")
+        }
+    }
+}
+
+/// Reads lines, sending lines longer than `max_bytes` as `Err` with their
+/// size instead. Such lines are never buffered: what's over the limit is
+/// skipped up to the next line break, where reading resumes.
+fn read_lines(
+    mut reader: impl BufRead,
+    max_bytes: usize,
+    input_tx: mpsc::Sender<Result<Vec<u8>, usize>>,
+) -> Result<()> {
+    let mut line = Vec::new();
+    let mut size = 0;
+
+    loop {
+        let buf = reader.fill_buf()?;
+
+        if buf.is_empty() {
+            break;
+        }
+
+        let (chunk, complete) = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) => (&buf[..i], true),
+            None => (buf, false),
+        };
+
+        size += chunk.len();
+
+        if size <= max_bytes {
+            line.extend_from_slice(chunk);
+        }
+
+        let consumed = chunk.len() + complete as usize;
+        reader.consume(consumed);
+
+        if complete {
+            send_line(&mut line, size, max_bytes, &input_tx)?;
+            size = 0;
+        }
+    }
+
+    if size > 0 {
+        send_line(&mut line, size, max_bytes, &input_tx)?;
+    }
+
     Ok(())
 }
 
-fn read_stdin(input_tx: mpsc::UnboundedSender<String>) -> Result<()> {
-    for line in io::stdin().lines() {
-        input_tx.send(line?)?;
+fn send_line(
+    line: &mut Vec<u8>,
+    size: usize,
+    max_bytes: usize,
+    input_tx: &mpsc::Sender<Result<Vec<u8>, usize>>,
+) -> Result<()> {
+    let mut line = std::mem::take(line);
+
+    if size > max_bytes {
+        input_tx.blocking_send(Err(size))?;
+    } else {
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        input_tx.blocking_send(Ok(line))?;
+    }
+
+    Ok(())
+}
+
+/// Reads length-prefixed frames (see `OutputFormat::MessagePack`), sending
+/// frames larger than `max_bytes` as `Err` with their size instead, after
+/// skipping them. Reading ends with the input, or with a truncated frame.
+fn read_frames(
+    mut reader: impl Read,
+    max_bytes: usize,
+    input_tx: mpsc::Sender<Result<Vec<u8>, usize>>,
+) -> Result<()> {
+    let mut size = [0; 4];
+
+    loop {
+        match reader.read_exact(&mut size) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        let size = u32::from_be_bytes(size) as usize;
+
+        if size > max_bytes {
+            io::copy(&mut (&mut reader).take(size as u64), &mut io::sink())?;
+            input_tx.blocking_send(Err(size))?;
+        } else {
+            let mut frame = vec![0; size];
+            reader.read_exact(&mut frame)?;
+            input_tx.blocking_send(Ok(frame))?;
+        }
     }
 
     Ok(())
@@ -102,6 +780,8 @@ fn read_stdin(input_tx: mpsc::UnboundedSender<String>) -> Result<()> {
 /// Thresholds:
 /// - < 1500 bytes: Send directly (safe)
 /// - >= 1500 bytes: Chunk into 512-byte pieces with 10ms delays
+///
+/// Chunked inputs are reported with an `inputChunked` event.
 async fn send_command_with_chunking(
     command_tx: &mpsc::Sender<Command>,
     command: Command,
@@ -113,33 +793,23 @@ async fn send_command_with_chunking(
     match command {
         Command::Input(seqs) => {
             // Calculate total size of input
-            let total_size: usize = seqs
-                .iter()
-                .map(|seq| match seq {
-                    InputSeq::Standard(s) => s.len(),
-                    InputSeq::Cursor(s1, _) => s1.len(), // Use first sequence for size
-                })
-                .sum();
+            let total_size: usize = seqs.iter().map(seq_byte_size).sum();
 
             if total_size < CHUNK_THRESHOLD {
                 // Small input - send directly
                 command_tx.send(Command::Input(seqs)).await?;
             } else {
                 // Large input - chunk it, preserving InputSeq variants
-                eprintln!(
-                    "Large input detected ({} bytes), chunking into {}-byte pieces",
-                    total_size, CHUNK_SIZE
-                );
-
                 let mut chunk: Vec<InputSeq> = Vec::new();
                 let mut chunk_size: usize = 0;
                 let mut chunks_sent: usize = 0;
 
-                for seq in seqs {
+                // a paste is split like any other text, between its markers
+                for seq in seqs.into_iter().flat_map(InputSeq::unbracket) {
                     let seq_size = seq_byte_size(&seq);
 
-                    // If this single Standard seq exceeds CHUNK_SIZE, split it
-                    if let InputSeq::Standard(ref s) = seq {
+                    // If this single Standard or Text seq exceeds CHUNK_SIZE, split it
+                    if let InputSeq::Standard(ref s) | InputSeq::Text(ref s) = seq {
                         if seq_size > CHUNK_SIZE {
                             // Flush current chunk first
                             if !chunk.is_empty() {
@@ -157,18 +827,29 @@ async fn send_command_with_chunking(
                             // Split the large string on UTF-8 character boundaries
                             let mut remaining = s.as_str();
                             while !remaining.is_empty() {
-                                let end = remaining
+                                let mut end = remaining
                                     .char_indices()
                                     .take_while(|(idx, _)| *idx < CHUNK_SIZE)
                                     .last()
                                     .map(|(idx, ch)| idx + ch.len_utf8())
                                     .unwrap_or(remaining.len());
+
+                                // keep CRLF in one piece for newline translation
+                                if remaining[..end].ends_with('\r')
+                                    && remaining[end..].starts_with('\n')
+                                {
+                                    end += 1;
+                                }
+
                                 let chunk_str = &remaining[..end];
                                 remaining = &remaining[end..];
 
-                                command_tx
-                                    .send(Command::Input(vec![standard_key(chunk_str)]))
-                                    .await?;
+                                let piece = match seq {
+                                    InputSeq::Text(_) => InputSeq::Text(chunk_str.to_owned()),
+                                    _ => standard_key(chunk_str),
+                                };
+
+                                command_tx.send(Command::Input(vec![piece])).await?;
 
                                 if !remaining.is_empty() {
                                     tokio::time::sleep(tokio::time::Duration::from_millis(
@@ -203,7 +884,10 @@ async fn send_command_with_chunking(
                     chunks_sent += 1;
                 }
 
-                eprintln!("Large input sent successfully in {} chunks", chunks_sent);
+                // reported after the last chunk, so it follows all of the input
+                command_tx
+                    .send(Command::InputChunked(total_size, chunks_sent))
+                    .await?;
             }
         }
         // Other command types pass through directly
@@ -215,44 +899,309 @@ async fn send_command_with_chunking(
     Ok(())
 }
 
-fn seq_byte_size(seq: &InputSeq) -> usize {
-    match seq {
-        InputSeq::Standard(s) => s.len(),
-        // Only measures the normal-mode sequence; the application-mode
-        // alternative (s2) is typically the same length.  Cursor escapes
-        // are small (a few bytes each) so any undercount won't exceed CHUNK_SIZE.
-        InputSeq::Cursor(s1, _) => s1.len(),
+fn seq_byte_size(seq: &InputSeq) -> usize {
+    match seq {
+        InputSeq::Standard(s) | InputSeq::Text(s) => s.len(),
+        // Only measures the normal-mode sequence; the application-mode
+        // alternative (s2) is typically the same length.  Cursor escapes
+        // are small (a few bytes each) so any undercount won't exceed CHUNK_SIZE.
+        InputSeq::Cursor(s1, _) => s1.len(),
+        InputSeq::Paste(s) => PASTE_START.len() + s.len() + PASTE_END.len(),
+    }
+}
+
+/// Parses a command read from STDIN: a line of JSON, or a MessagePack frame.
+/// Allowed commands are passed through the command hook, which may drop
+/// them (`None`) or replace them (see `Session::set_command_hook`).
+pub fn parse_message(
+    message: Vec<u8>,
+    format: OutputFormat,
+    filter: &CommandFilter,
+    hook: Option<&session::CommandHook>,
+) -> Result<Option<Command>, String> {
+    let command = match format {
+        OutputFormat::MessagePack => {
+            let value = rmp_serde::from_slice(&message).map_err(|e| e.to_string())?;
+
+            parse_command_value(value, filter)?
+        }
+
+        _ => {
+            let line = String::from_utf8(message).map_err(|e| {
+                let offset = e.utf8_error().valid_up_to();
+
+                format!("invalid UTF-8 at byte offset {offset}")
+            })?;
+
+            parse_command(&line, filter)?
+        }
+    };
+
+    Ok(session::filter_command(hook, command))
+}
+
+fn parse_command(line: &str, filter: &CommandFilter) -> Result<Command, String> {
+    let value = serde_json::from_str::<serde_json::Value>(line).map_err(|e| e.to_string())?;
+
+    parse_command_value(value, filter)
+}
+
+fn parse_command_value(
+    value: serde_json::Value,
+    filter: &CommandFilter,
+) -> Result<Command, String> {
+    if let Some(command_type) = value["type"].as_str() {
+        if !filter.allows(command_type) {
+            return Err(format!("command not allowed: {command_type}"));
+        }
+    }
+
+    build_command(value)
+}
+
+fn build_command(value: serde_json::Value) -> Result<Command, String> {
+    match value["type"].as_str() {
+        Some("input") => {
+            let args: InputArgs = args_from_json_value(value)?;
+            Ok(Command::Input(vec![InputSeq::Text(args.payload)]))
+        }
+
+        Some("paste") => {
+            let args: PasteArgs = args_from_json_value(value)?;
+            Ok(Command::Input(vec![InputSeq::Paste(args.payload)]))
+        }
+
+        Some("sendKeys") => {
+            let args: SendKeysArgs = args_from_json_value(value)?;
+            let seqs = args.keys.into_iter().map(parse_key).collect();
+            Ok(Command::Input(seqs))
+        }
+
+        Some("resize") => {
+            let args: ResizeArgs = args_from_json_value(value)?;
+            Ok(Command::Resize(args.cols, args.rows, args.signal))
+        }
+
+        Some("resizeAndSettle") => {
+            let args: ResizeAndSettleArgs = args_from_json_value(value)?;
+
+            let settle = Settle {
+                id: args.id,
+                quiet: Duration::from_millis(args.settle_ms),
+                timeout: Duration::from_millis(args.timeout_ms),
+            };
+
+            Ok(Command::ResizeAndSettle(args.cols, args.rows, settle))
+        }
+
+        Some("refresh") => {
+            let args: RefreshArgs = args_from_json_value(value)?;
+
+            let settle = Settle {
+                id: args.id,
+                quiet: Duration::from_millis(args.settle_ms),
+                timeout: Duration::from_millis(args.timeout_ms),
+            };
+
+            Ok(Command::Refresh(settle, args.snapshot))
+        }
+
+        Some("takeSnapshot") => {
+            let args: TakeSnapshotArgs = args_from_json_value(value)?;
+
+            let buffer = match args.buffer.as_deref() {
+                None | Some("active") => ScreenBuffer::Active,
+                Some("main") => ScreenBuffer::Main,
+                Some("alt") => ScreenBuffer::Alt,
+                Some(other) => return Err(format!("unknown screen buffer: {other}")),
+            };
+
+            Ok(Command::Snapshot(args.scroll_region, buffer))
+        }
+
+        Some("eof") => Ok(Command::Eof),
+
+        Some("closeStdin") => Ok(Command::CloseStdin),
+
+        Some("getState") => Ok(Command::State),
+
+        Some("getGrid") => Ok(Command::Grid),
+
+        Some("exportFrame") => Ok(Command::ExportFrame),
+
+        Some("getCwd") => Ok(Command::Cwd),
+
+        Some("getEnv") => {
+            let args: GetEnvArgs = args_from_json_value(value)?;
+            Ok(Command::Env(args.name))
+        }
+
+        Some("listProcesses") => Ok(Command::ListProcesses),
+
+        Some("killProcess") => {
+            let args: KillProcessArgs = args_from_json_value(value)?;
+            let signal = args.signal.unwrap_or_else(|| "TERM".to_owned());
+
+            Ok(Command::KillProcess(args.pid, signal))
+        }
+
+        Some("signal") => {
+            let args: SignalArgs = args_from_json_value(value)?;
+
+            let signal = match args.signal {
+                SignalArg::Name(name) => name,
+                SignalArg::Number(number) => number.to_string(),
+            };
+
+            Ok(Command::Signal(signal))
+        }
+
+        Some("setBackpressure") => {
+            let args: SetBackpressureArgs = args_from_json_value(value)?;
+
+            let policy = match args.policy.as_str() {
+                "block" => BackpressurePolicy::Block,
+                "dropOldest" => BackpressurePolicy::DropOldest,
+                "dropNewest" => BackpressurePolicy::DropNewest,
+                other => return Err(format!("unknown backpressure policy: {other}")),
+            };
+
+            Ok(Command::SetBackpressure(Backpressure {
+                policy,
+                max_bytes: args.max_bytes.unwrap_or(usize::MAX),
+            }))
+        }
+
+        Some("getInputPending") => Ok(Command::InputPending),
+
+        Some("saveScrollback") => {
+            let args: SaveScrollbackArgs = args_from_json_value(value)?;
+
+            let format = match args.format.as_deref() {
+                None | Some("text") => ScrollbackFormat::Text,
+                Some("ansi") => ScrollbackFormat::Ansi,
+                Some(other) => return Err(format!("unsupported scrollback format: {other}")),
+            };
+
+            Ok(Command::SaveScrollback(args.path, format))
+        }
+
+        Some("setRenderCursor") => {
+            let args: SetRenderCursorArgs = args_from_json_value(value)?;
+            Ok(Command::SetRenderCursor(args.visible))
+        }
+
+        Some("getOutputTail") => {
+            let args: OutputTailArgs = args_from_json_value(value)?;
+            Ok(Command::OutputTail(args.bytes))
+        }
+
+        Some("mark") => Ok(Command::Mark),
+
+        Some("mute") => Ok(Command::Mute),
+
+        Some("unmute") => {
+            let args: UnmuteArgs = args_from_json_value(value)?;
+            Ok(Command::Unmute(args.snapshot))
+        }
+
+        Some("freezeDisplay") => Ok(Command::FreezeDisplay),
+
+        Some("unfreezeDisplay") => Ok(Command::UnfreezeDisplay),
+
+        Some("getOutputSince") => {
+            let args: OutputSinceArgs = args_from_json_value(value)?;
+
+            let format = match args.format.as_deref() {
+                None | Some("raw") => OutputSinceFormat::Raw,
+                Some("text") => OutputSinceFormat::Text,
+                Some(other) => return Err(format!("unsupported output format: {other}")),
+            };
+
+            Ok(Command::OutputSince(args.seq, format))
+        }
+
+        Some("sleep") => {
+            let args: SleepArgs = args_from_json_value(value)?;
+            Ok(Command::Sleep(Duration::from_millis(args.ms)))
+        }
+
+        Some("inputAndWait") => {
+            let args: InputAndWaitArgs = args_from_json_value(value)?;
+
+            if args.expect.is_empty() {
+                return Err("expect must not be empty".to_owned());
+            }
+
+            let wait = TextWait {
+                id: args.id,
+                expect: args.expect,
+                timeout: Duration::from_millis(args.timeout_ms),
+            };
+
+            Ok(Command::InputAndWait(vec![InputSeq::Text(args.text)], wait))
+        }
+
+        Some("typeWithEcho") => {
+            let args: TypeWithEchoArgs = args_from_json_value(value)?;
+
+            let typing = EchoTyping {
+                echo_timeout: Duration::from_millis(args.echo_timeout_ms),
+                fallback_delay: Duration::from_millis(args.fallback_delay_ms),
+            };
+
+            Ok(Command::TypeWithEcho(args.text, typing))
+        }
+
+        Some("query") => {
+            let args: QueryArgs = args_from_json_value(value)?;
+
+            if args.send.is_empty() {
+                return Err("send must not be empty".to_owned());
+            }
+
+            Ok(Command::Query(Query {
+                id: args.id,
+                send: args.send,
+                timeout: Duration::from_millis(args.read_timeout_ms),
+            }))
+        }
+
+        Some("screenshot") => {
+            let args: ScreenshotArgs = args_from_json_value(value)?;
+
+            match args.format.as_deref() {
+                None | Some("svg") => Ok(Command::Screenshot(ImageFormat::Svg)),
+                Some(other) => Err(format!("unsupported screenshot format: {other}")),
+            }
+        }
+
+        other => Err(format!("invalid command type: {other:?}")),
     }
 }
 
-fn parse_line(line: &str) -> Result<command::Command, String> {
-    serde_json::from_str::<serde_json::Value>(line)
-        .map_err(|e| e.to_string())
-        .and_then(build_command)
+fn default_wait_timeout() -> u64 {
+    5000
 }
 
-fn build_command(value: serde_json::Value) -> Result<Command, String> {
-    match value["type"].as_str() {
-        Some("input") => {
-            let args: InputArgs = args_from_json_value(value)?;
-            Ok(Command::Input(vec![standard_key(args.payload)]))
-        }
+fn default_query_timeout() -> u64 {
+    500
+}
 
-        Some("sendKeys") => {
-            let args: SendKeysArgs = args_from_json_value(value)?;
-            let seqs = args.keys.into_iter().map(parse_key).collect();
-            Ok(Command::Input(seqs))
-        }
+fn default_settle() -> u64 {
+    200
+}
 
-        Some("resize") => {
-            let args: ResizeArgs = args_from_json_value(value)?;
-            Ok(Command::Resize(args.cols, args.rows))
-        }
+fn default_echo_timeout() -> u64 {
+    200
+}
 
-        Some("takeSnapshot") => Ok(Command::Snapshot),
+fn default_fallback_delay() -> u64 {
+    50
+}
 
-        other => Err(format!("invalid command type: {other:?}")),
-    }
+fn default_signal() -> bool {
+    true
 }
 
 fn args_from_json_value<T>(value: serde_json::Value) -> Result<T, String>
@@ -262,6 +1211,40 @@ where
     serde_json::from_value(value).map_err(|e| e.to_string())
 }
 
+impl CommandFilter {
+    pub fn allows(&self, command_type: &str) -> bool {
+        match self {
+            CommandFilter::AllowAll => true,
+            CommandFilter::Allow(list) => list.contains(command_type),
+            CommandFilter::Deny(list) => !list.contains(command_type),
+        }
+    }
+}
+
+impl CommandList {
+    fn contains(&self, command_type: &str) -> bool {
+        self.0.iter().any(|c| c == command_type)
+    }
+}
+
+impl FromStr for CommandList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut list = Vec::new();
+
+        for command_type in s.split(',') {
+            if !COMMAND_TYPES.contains(&command_type) {
+                return Err(format!("invalid command type: {command_type}"));
+            }
+
+            list.push(command_type.to_owned());
+        }
+
+        Ok(CommandList(list))
+    }
+}
+
 pub fn standard_key<S: ToString>(seq: S) -> InputSeq {
     InputSeq::Standard(seq.to_string())
 }
@@ -382,6 +1365,14 @@ pub fn parse_key(key: String) -> InputSeq {
         "C-PageDown" => "\x1b[6;5~",
         "S-PageDown" => "\x1b[6;2~",
         "A-PageDown" => "\x1b[6;3~",
+        "Insert" => "\x1b[2~",
+        "C-Insert" => "\x1b[2;5~",
+        "S-Insert" => "\x1b[2;2~",
+        "A-Insert" => "\x1b[2;3~",
+        "Delete" => "\x1b[3~",
+        "C-Delete" => "\x1b[3;5~",
+        "S-Delete" => "\x1b[3;2~",
+        "A-Delete" => "\x1b[3;3~",
 
         k => {
             let chars: Vec<char> = k.chars().collect();
@@ -417,13 +1408,222 @@ pub fn parse_key(key: String) -> InputSeq {
 
 #[cfg(test)]
 mod test {
-    use super::{Command, cursor_key, parse_line, standard_key};
-    use crate::command::InputSeq;
+    use super::{
+        Command, CommandFilter, CommandList, OutputFormat, cursor_key, encode_event, format_event,
+        parse_command, parse_message, read_frames, read_lines, session, standard_key, typed_keys,
+    };
+    use crate::command::{
+        InputSeq, Newline, OutputSinceFormat, ScreenBuffer, ScrollbackFormat, seqs_to_bytes,
+    };
+    use crate::pty::{Backpressure, BackpressurePolicy};
+    use crate::render::ImageFormat;
+    use std::time::Duration;
+
+    fn parse_line(line: &str) -> Result<Command, String> {
+        parse_command(line, &CommandFilter::AllowAll)
+    }
+
+    fn read(input: &[u8], max_bytes: usize) -> Vec<Result<String, usize>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        // a tiny buffer exercises lines spanning several reads
+        let reader = std::io::BufReader::with_capacity(4, input);
+        read_lines(reader, max_bytes, tx).unwrap();
+
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|line| line.map(|line| String::from_utf8(line).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn read_limited_lines() {
+        assert_eq!(
+            read(b"short\nthis is too long\r\n\nok\r\nlast", 8),
+            vec![
+                Ok("short".to_owned()),
+                Err(17),
+                Ok(String::new()),
+                Ok("ok".to_owned()),
+                Ok("last".to_owned())
+            ]
+        );
+
+        assert_eq!(
+            read(b"12345678\n123456789", 8),
+            vec![Ok("12345678".to_owned()), Err(9)]
+        );
+    }
+
+    #[test]
+    fn read_limited_frames() {
+        let mut input = Vec::new();
+
+        for frame in [&b"ab"[..], b"too long", b"", b"abcd"] {
+            input.extend((frame.len() as u32).to_be_bytes());
+            input.extend(frame);
+        }
+
+        // truncated frame
+        input.extend([0, 0, 0, 3, b'x']);
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        assert!(read_frames(&input[..], 4, tx).is_err());
+
+        assert_eq!(
+            std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>(),
+            vec![Ok(b"ab".to_vec()), Err(8), Ok(vec![]), Ok(b"abcd".to_vec())]
+        );
+    }
+
+    #[test]
+    fn msgpack_messages() {
+        let command = serde_json::json!({ "type": "resize", "cols": 100, "rows": 30 });
+        let message = crate::api::msgpack(&command);
+
+        assert!(matches!(
+            parse_message(
+                message.clone(),
+                OutputFormat::MessagePack,
+                &CommandFilter::AllowAll,
+                None
+            ),
+            Ok(Some(Command::Resize(100, 30, true)))
+        ));
+        assert!(
+            parse_message(
+                message,
+                OutputFormat::Compact,
+                &CommandFilter::AllowAll,
+                None
+            )
+            .is_err()
+        );
+        assert!(
+            parse_message(
+                b"\xc1".to_vec(),
+                OutputFormat::MessagePack,
+                &CommandFilter::AllowAll,
+                None
+            )
+            .is_err()
+        );
+
+        let event = serde_json::json!({ "type": "output", "data": { "seq": "a" } });
+        let frame = encode_event(&event, OutputFormat::MessagePack);
+
+        assert_eq!(frame[..4], (frame.len() as u32 - 4).to_be_bytes());
+        assert_eq!(
+            rmp_serde::from_slice::<serde_json::Value>(&frame[4..]).unwrap(),
+            event
+        );
+        assert_eq!(
+            encode_event(&event, OutputFormat::Compact),
+            b"{\"data\":{\"seq\":\"a\"},\"type\":\"output\"}\n"
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_lines() {
+        // truncated multibyte sequence in the payload
+        let line = b"{ \"type\": \"input\", \"payload\": \"ab\xc5\" }".to_vec();
+
+        assert_eq!(
+            parse_message(line, OutputFormat::Compact, &CommandFilter::AllowAll, None).unwrap_err(),
+            "invalid UTF-8 at byte offset 33"
+        );
+    }
+
+    #[test]
+    fn command_hook() {
+        let hook: session::CommandHook = std::sync::Arc::new(|command| match command {
+            Command::State => session::CommandAction::Deny,
+            Command::Cwd => session::CommandAction::Rewrite(Command::Env("PWD".to_owned())),
+            _ => session::CommandAction::Allow,
+        });
+
+        let parse = |line: &str, filter: &CommandFilter| {
+            parse_message(
+                line.as_bytes().to_vec(),
+                OutputFormat::Compact,
+                filter,
+                Some(&hook),
+            )
+        };
+
+        assert!(matches!(
+            parse(r#"{"type":"getState"}"#, &CommandFilter::AllowAll),
+            Ok(None)
+        ));
+        assert!(matches!(
+            parse(r#"{"type":"getCwd"}"#, &CommandFilter::AllowAll),
+            Ok(Some(Command::Env(name))) if name == "PWD"
+        ));
+
+        // the filter applies before the hook sees the command
+        let filter = CommandFilter::Deny("getCwd".parse().unwrap());
+        assert!(parse(r#"{"type":"getCwd"}"#, &filter).is_err());
+    }
+
+    #[test]
+    fn filter_commands() {
+        let allow = CommandFilter::Allow("takeSnapshot,getState".parse().unwrap());
+        let deny = CommandFilter::Deny("input,sendKeys".parse().unwrap());
+        let input = r#"{ "type": "input", "payload": "rm -rf ~\r" }"#;
+        let snapshot = r#"{ "type": "takeSnapshot" }"#;
+
+        assert!(matches!(
+            parse_command(snapshot, &allow),
+            Ok(Command::Snapshot(false, ScreenBuffer::Active))
+        ));
+        assert!(matches!(
+            parse_command(snapshot, &deny),
+            Ok(Command::Snapshot(false, ScreenBuffer::Active))
+        ));
+
+        assert_eq!(
+            parse_command(input, &allow).unwrap_err(),
+            "command not allowed: input"
+        );
+        assert_eq!(
+            parse_command(input, &deny).unwrap_err(),
+            "command not allowed: input"
+        );
+    }
+
+    #[test]
+    fn parse_command_list() {
+        assert!("input,resize".parse::<CommandList>().is_ok());
+        assert!("input,nope".parse::<CommandList>().is_err());
+    }
+
+    #[test]
+    fn format_compact_event() {
+        let value = serde_json::json!({ "type": "output", "data": { "seq": "a\nb" } });
+
+        assert_eq!(
+            format_event(&value, OutputFormat::Compact),
+            r#"{"data":{"seq":"a\nb"},"type":"output"}"#
+        );
+    }
+
+    #[test]
+    fn format_pretty_event() {
+        let value = serde_json::json!({ "type": "output", "data": { "seq": "a\n\nb" } });
+        let text = format_event(&value, OutputFormat::Pretty);
+
+        assert!(text.ends_with("}\n"));
+        assert!(!text.trim_end().contains("\n\n"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            value
+        );
+    }
 
     #[test]
     fn parse_input() {
         let command = parse_line(r#"{ "type": "input", "payload": "hello" }"#).unwrap();
-        assert!(matches!(command, Command::Input(input) if input == vec![standard_key("hello")]));
+        assert!(
+            matches!(command, Command::Input(input) if input == vec![InputSeq::Text("hello".to_owned())])
+        );
     }
 
     #[test]
@@ -545,6 +1745,14 @@ mod test {
             ["C-PageDown", "\x1b[6;5~"],
             ["S-PageDown", "\x1b[6;2~"],
             ["A-PageDown", "\x1b[6;3~"],
+            ["Insert", "\x1b[2~"],
+            ["C-Insert", "\x1b[2;5~"],
+            ["S-Insert", "\x1b[2;2~"],
+            ["A-Insert", "\x1b[2;3~"],
+            ["Delete", "\x1b[3~"],
+            ["C-Delete", "\x1b[3;5~"],
+            ["S-Delete", "\x1b[3;2~"],
+            ["A-Delete", "\x1b[3;3~"],
         ];
 
         for [key, chars] in examples {
@@ -597,6 +1805,79 @@ mod test {
         }
     }
 
+    #[test]
+    fn send_keys_to_bytes() {
+        let keys = r#"{ "type": "sendKeys", "keys": ["F5", "Delete", "Home", "Up"] }"#;
+
+        let Command::Input(seqs) = parse_line(keys).unwrap() else {
+            panic!("expected input");
+        };
+
+        assert_eq!(
+            seqs_to_bytes(&seqs, false, Newline::None),
+            b"\x1b[15~\x1b[3~\x1b[H\x1b[A"
+        );
+        assert_eq!(
+            seqs_to_bytes(&seqs, true, Newline::None),
+            b"\x1b[15~\x1b[3~\x1bOH\x1bOA"
+        );
+    }
+
+    #[test]
+    fn paste_to_bytes() {
+        let paste = r#"{ "type": "paste", "payload": "cat <<EOF\nhi\nEOF\n" }"#;
+
+        let Command::Input(seqs) = parse_line(paste).unwrap() else {
+            panic!("expected input");
+        };
+
+        assert_eq!(
+            seqs,
+            vec![InputSeq::Paste("cat <<EOF\nhi\nEOF\n".to_owned())]
+        );
+        assert_eq!(
+            seqs_to_bytes(&seqs, false, Newline::None),
+            b"\x1b[200~cat <<EOF\nhi\nEOF\n\x1b[201~"
+        );
+
+        // an end marker in the payload can't end the paste early
+        let seqs = vec![InputSeq::Paste("a\x1b[201~b".to_owned())];
+        assert_eq!(
+            seqs_to_bytes(&seqs, false, Newline::None),
+            b"\x1b[200~ab\x1b[201~"
+        );
+
+        parse_line(r#"{ "type": "paste" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn only_text_payloads_have_newlines_translated() {
+        let newlines = [Newline::Lf, Newline::Crlf, Newline::Cr, Newline::None];
+        let parse = |line| {
+            let Command::Input(seqs) = parse_line(line).unwrap() else {
+                panic!("expected input");
+            };
+
+            seqs
+        };
+
+        let enter = parse(r#"{ "type": "sendKeys", "keys": ["Enter", "C-m", "C-j"] }"#);
+        let paste = parse(r#"{ "type": "paste", "payload": "a\r\nb" }"#);
+        let input = parse(r#"{ "type": "input", "payload": "a\r\nb" }"#);
+
+        for newline in newlines {
+            assert_eq!(seqs_to_bytes(&enter, false, newline), b"\r\r\n");
+            assert_eq!(
+                seqs_to_bytes(&paste, false, newline),
+                b"\x1b[200~a\r\nb\x1b[201~"
+            );
+        }
+
+        assert_eq!(seqs_to_bytes(&input, false, Newline::Lf), b"a\nb");
+        assert_eq!(seqs_to_bytes(&input, false, Newline::Cr), b"a\rb");
+        assert_eq!(seqs_to_bytes(&input, false, Newline::None), b"a\r\nb");
+    }
+
     #[test]
     fn parse_send_keys_missing_args() {
         parse_line(r#"{ "type": "sendKeys" }"#).expect_err("should fail");
@@ -605,7 +1886,11 @@ mod test {
     #[test]
     fn parse_resize() {
         let command = parse_line(r#"{ "type": "resize", "cols": 80, "rows": 24 }"#).unwrap();
-        assert!(matches!(command, Command::Resize(80, 24)));
+        assert!(matches!(command, Command::Resize(80, 24, true)));
+
+        let command =
+            parse_line(r#"{ "type": "resize", "cols": 80, "rows": 24, "signal": false }"#).unwrap();
+        assert!(matches!(command, Command::Resize(80, 24, false)));
     }
 
     #[test]
@@ -616,7 +1901,354 @@ mod test {
     #[test]
     fn parse_take_snapshot() {
         let command = parse_line(r#"{ "type": "takeSnapshot" }"#).unwrap();
-        assert!(matches!(command, Command::Snapshot));
+        assert!(matches!(
+            command,
+            Command::Snapshot(false, ScreenBuffer::Active)
+        ));
+
+        let command = parse_line(r#"{ "type": "takeSnapshot", "scrollRegion": true }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Snapshot(true, ScreenBuffer::Active)
+        ));
+
+        let command = parse_line(r#"{ "type": "takeSnapshot", "buffer": "main" }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Snapshot(false, ScreenBuffer::Main)
+        ));
+
+        let command = parse_line(r#"{ "type": "takeSnapshot", "buffer": "alt" }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Snapshot(false, ScreenBuffer::Alt)
+        ));
+
+        parse_line(r#"{ "type": "takeSnapshot", "buffer": "other" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_sleep() {
+        let command = parse_line(r#"{ "type": "sleep", "ms": 500 }"#).unwrap();
+        assert!(matches!(command, Command::Sleep(d) if d == Duration::from_millis(500)));
+
+        parse_line(r#"{ "type": "sleep" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_query() {
+        let command = parse_line(
+            r#"{ "type": "query", "send": "\u001b[6n", "readTimeoutMs": 100, "id": 1 }"#,
+        )
+        .unwrap();
+
+        match command {
+            Command::Query(query) => {
+                assert_eq!(query.send, "\x1b[6n");
+                assert_eq!(query.timeout, Duration::from_millis(100));
+                assert_eq!(query.id, Some(serde_json::json!(1)));
+            }
+
+            other => panic!("unexpected command: {other:?}"),
+        }
+
+        let command = parse_line(r#"{ "type": "query", "send": "x" }"#);
+        assert!(
+            matches!(command, Ok(Command::Query(q)) if q.timeout == Duration::from_millis(500))
+        );
+
+        parse_line(r#"{ "type": "query", "send": "" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "query" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_input_and_wait() {
+        let command = parse_line(
+            r#"{ "type": "inputAndWait", "text": "ls\n", "expect": "$ ", "timeoutMs": 3000, "id": "ls" }"#,
+        )
+        .unwrap();
+
+        match command {
+            Command::InputAndWait(seqs, wait) => {
+                assert_eq!(seqs, vec![InputSeq::Text("ls\n".to_owned())]);
+                assert_eq!(wait.expect, "$ ");
+                assert_eq!(wait.timeout, Duration::from_millis(3000));
+                assert_eq!(wait.id, Some(serde_json::json!("ls")));
+            }
+
+            other => panic!("unexpected command: {other:?}"),
+        }
+
+        let command = parse_line(r#"{ "type": "inputAndWait", "text": "x", "expect": "y" }"#);
+        assert!(matches!(command, Ok(Command::InputAndWait(_, w)) if w.id.is_none()));
+
+        parse_line(r#"{ "type": "inputAndWait", "text": "x", "expect": "" }"#)
+            .expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_mute() {
+        let command = parse_line(r#"{ "type": "mute" }"#).unwrap();
+        assert!(matches!(command, Command::Mute));
+
+        let command = parse_line(r#"{ "type": "unmute" }"#).unwrap();
+        assert!(matches!(command, Command::Unmute(false)));
+
+        let command = parse_line(r#"{ "type": "unmute", "snapshot": true }"#).unwrap();
+        assert!(matches!(command, Command::Unmute(true)));
+    }
+
+    #[test]
+    fn parse_type_with_echo() {
+        let command = parse_line(r#"{ "type": "typeWithEcho", "text": "hi" }"#).unwrap();
+
+        assert!(matches!(
+            command,
+            Command::TypeWithEcho(text, typing)
+                if text == "hi"
+                    && typing.echo_timeout == Duration::from_millis(200)
+                    && typing.fallback_delay == Duration::from_millis(50)
+        ));
+
+        let command = parse_line(
+            r#"{ "type": "typeWithEcho", "text": "pw\n", "echoTimeoutMs": 100, "fallbackDelayMs": 5 }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            command,
+            Command::TypeWithEcho(_, typing)
+                if typing.echo_timeout == Duration::from_millis(100)
+                    && typing.fallback_delay == Duration::from_millis(5)
+        ));
+
+        parse_line(r#"{ "type": "typeWithEcho" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn split_typed_keys() {
+        assert_eq!(typed_keys("aé\r\nb\r"), ["a", "é", "\r\n", "b", "\r"]);
+        assert!(typed_keys("").is_empty());
+    }
+
+    #[test]
+    fn parse_freeze_display() {
+        let command = parse_line(r#"{ "type": "freezeDisplay" }"#).unwrap();
+        assert!(matches!(command, Command::FreezeDisplay));
+
+        let command = parse_line(r#"{ "type": "unfreezeDisplay" }"#).unwrap();
+        assert!(matches!(command, Command::UnfreezeDisplay));
+    }
+
+    #[test]
+    fn parse_output_since() {
+        let command = parse_line(r#"{ "type": "mark" }"#).unwrap();
+        assert!(matches!(command, Command::Mark));
+
+        let command = parse_line(r#"{ "type": "getOutputSince", "seq": 42 }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::OutputSince(42, OutputSinceFormat::Raw)
+        ));
+
+        let command =
+            parse_line(r#"{ "type": "getOutputSince", "seq": 0, "format": "text" }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::OutputSince(0, OutputSinceFormat::Text)
+        ));
+
+        parse_line(r#"{ "type": "getOutputSince" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "getOutputSince", "seq": 0, "format": "html" }"#)
+            .expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_export_frame() {
+        let command = parse_line(r#"{ "type": "exportFrame" }"#).unwrap();
+        assert!(matches!(command, Command::ExportFrame));
+    }
+
+    #[test]
+    fn parse_get_output_tail() {
+        let command = parse_line(r#"{ "type": "getOutputTail", "bytes": 4096 }"#).unwrap();
+        assert!(matches!(command, Command::OutputTail(Some(4096))));
+
+        let command = parse_line(r#"{ "type": "getOutputTail" }"#).unwrap();
+        assert!(matches!(command, Command::OutputTail(None)));
+    }
+
+    #[test]
+    fn parse_get_cwd_and_env() {
+        let command = parse_line(r#"{ "type": "getCwd" }"#).unwrap();
+        assert!(matches!(command, Command::Cwd));
+
+        let command = parse_line(r#"{ "type": "getEnv", "name": "PATH" }"#).unwrap();
+        assert!(matches!(command, Command::Env(name) if name == "PATH"));
+
+        parse_line(r#"{ "type": "getEnv" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_processes() {
+        let command = parse_line(r#"{ "type": "eof" }"#).unwrap();
+        assert!(matches!(command, Command::Eof));
+
+        let command = parse_line(r#"{ "type": "closeStdin" }"#).unwrap();
+        assert!(matches!(command, Command::CloseStdin));
+
+        let command = parse_line(r#"{ "type": "listProcesses" }"#).unwrap();
+        assert!(matches!(command, Command::ListProcesses));
+
+        let command =
+            parse_line(r#"{ "type": "killProcess", "pid": 42, "signal": "KILL" }"#).unwrap();
+        assert!(matches!(command, Command::KillProcess(42, signal) if signal == "KILL"));
+
+        let command = parse_line(r#"{ "type": "killProcess", "pid": 42 }"#).unwrap();
+        assert!(matches!(command, Command::KillProcess(42, signal) if signal == "TERM"));
+
+        parse_line(r#"{ "type": "killProcess" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "killProcess", "pid": -1 }"#).expect_err("should fail");
+
+        let command = parse_line(r#"{ "type": "signal", "signal": "USR1" }"#).unwrap();
+        assert!(matches!(command, Command::Signal(signal) if signal == "USR1"));
+
+        let command = parse_line(r#"{ "type": "signal", "signal": 10 }"#).unwrap();
+        assert!(matches!(command, Command::Signal(signal) if signal == "10"));
+
+        parse_line(r#"{ "type": "signal" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "signal", "signal": -1 }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_set_backpressure() {
+        let command = parse_line(
+            r#"{ "type": "setBackpressure", "policy": "dropNewest", "maxBytes": 4096 }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            command,
+            Command::SetBackpressure(Backpressure {
+                policy: BackpressurePolicy::DropNewest,
+                max_bytes: 4096
+            })
+        ));
+
+        let command = parse_line(r#"{ "type": "setBackpressure", "policy": "block" }"#).unwrap();
+
+        assert!(matches!(
+            command,
+            Command::SetBackpressure(Backpressure {
+                policy: BackpressurePolicy::Block,
+                max_bytes: usize::MAX
+            })
+        ));
+
+        parse_line(r#"{ "type": "setBackpressure", "policy": "drop" }"#).expect_err("should fail");
+        parse_line(r#"{ "type": "setBackpressure", "maxBytes": 1 }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_save_scrollback() {
+        let command =
+            parse_line(r#"{ "type": "saveScrollback", "path": "/tmp/log", "format": "ansi" }"#)
+                .unwrap();
+
+        assert!(matches!(
+            command,
+            Command::SaveScrollback(path, ScrollbackFormat::Ansi) if path.to_str() == Some("/tmp/log")
+        ));
+
+        let command = parse_line(r#"{ "type": "saveScrollback", "path": "log" }"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::SaveScrollback(_, ScrollbackFormat::Text)
+        ));
+
+        parse_line(r#"{ "type": "saveScrollback", "path": "log", "format": "html" }"#)
+            .expect_err("should fail");
+        parse_line(r#"{ "type": "saveScrollback" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_set_render_cursor() {
+        let command = parse_line(r#"{ "type": "setRenderCursor", "visible": false }"#).unwrap();
+        assert!(matches!(command, Command::SetRenderCursor(false)));
+
+        parse_line(r#"{ "type": "setRenderCursor" }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_resize_and_settle() {
+        let command = parse_line(
+            r#"{ "type": "resizeAndSettle", "cols": 80, "rows": 24, "settleMs": 100, "id": 7 }"#,
+        )
+        .unwrap();
+
+        match command {
+            Command::ResizeAndSettle(80, 24, settle) => {
+                assert_eq!(settle.quiet, Duration::from_millis(100));
+                assert_eq!(settle.timeout, Duration::from_millis(5000));
+                assert_eq!(settle.id, Some(serde_json::json!(7)));
+            }
+
+            other => panic!("unexpected command: {other:?}"),
+        }
+
+        parse_line(r#"{ "type": "resizeAndSettle", "cols": 80 }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_get_input_pending() {
+        let command = parse_line(r#"{ "type": "getInputPending" }"#).unwrap();
+        assert!(matches!(command, Command::InputPending));
+    }
+
+    #[test]
+    fn parse_refresh() {
+        let command = parse_line(r#"{ "type": "refresh" }"#).unwrap();
+        assert!(matches!(command, Command::Refresh(ref settle, false) if settle.id.is_none()));
+
+        let command = parse_line(
+            r#"{ "type": "refresh", "snapshot": true, "settleMs": 100, "timeoutMs": 1000, "id": 1 }"#,
+        )
+        .unwrap();
+
+        match command {
+            Command::Refresh(settle, true) => {
+                assert_eq!(settle.quiet, Duration::from_millis(100));
+                assert_eq!(settle.timeout, Duration::from_millis(1000));
+                assert_eq!(settle.id, Some(serde_json::json!(1)));
+            }
+
+            other => panic!("unexpected command: {other:?}"),
+        }
+
+        parse_line(r#"{ "type": "refresh", "snapshot": 1 }"#).expect_err("should fail");
+    }
+
+    #[test]
+    fn parse_get_state() {
+        let command = parse_line(r#"{ "type": "getState" }"#).unwrap();
+        assert!(matches!(command, Command::State));
+    }
+
+    #[test]
+    fn parse_get_grid() {
+        let command = parse_line(r#"{ "type": "getGrid" }"#).unwrap();
+        assert!(matches!(command, Command::Grid));
+    }
+
+    #[test]
+    fn parse_screenshot() {
+        let command = parse_line(r#"{ "type": "screenshot", "format": "svg" }"#).unwrap();
+        assert!(matches!(command, Command::Screenshot(ImageFormat::Svg)));
+
+        let command = parse_line(r#"{ "type": "screenshot" }"#).unwrap();
+        assert!(matches!(command, Command::Screenshot(ImageFormat::Svg)));
+
+        parse_line(r#"{ "type": "screenshot", "format": "png" }"#).expect_err("should fail");
     }
 
     #[test]
@@ -1,29 +1,266 @@
-use crate::api::Subscription;
+use crate::api::stdio::{self, CommandList};
+use crate::api::{Protocol, Subscription};
+use crate::command::Newline;
+use crate::config;
 use crate::pty::Winsize;
+use crate::session;
 use anyhow::bail;
-use clap::Parser;
-use std::{fmt::Display, net::SocketAddr, ops::Deref, str::FromStr};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use std::{fmt::Display, net::SocketAddr, ops::Deref, path::PathBuf, str::FromStr};
 
 #[derive(Debug, Parser)]
 #[clap(version, about)]
 #[command(name = "ht")]
 pub struct Cli {
-    /// Terminal size
-    #[arg(long, value_name = "COLSxROWS", default_value = Some("120x40"))]
-    pub size: Size,
+    /// Read options from this TOML file, named after the command line ones (which take precedence)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Print the options in effect, in config file format, and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Terminal size [default: 120x40, or the size of ht's own terminal, see --auto-size]
+    #[arg(long, value_name = "COLSxROWS", env = "HT_SIZE")]
+    pub size: Option<Size>,
+
+    /// When to take the terminal size from the terminal ht runs in, if --size isn't given
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = AutoSize::Auto)]
+    pub auto_size: AutoSize,
+
+    /// Shell running the command line with -c [default: $SHELL, or else /bin/sh]
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    pub shell: Option<PathBuf>,
+
+    /// Run the command with its arguments as given, without a shell
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "shell")]
+    pub no_shell: bool,
+
+    /// Working directory of the command [default: ht's own]
+    #[arg(long, value_name = "PATH")]
+    pub cwd: Option<PathBuf>,
 
     /// Command to run inside the terminal
     #[cfg_attr(unix, arg(default_value = "bash"))]
     #[cfg_attr(windows, arg(default_value = "cmd.exe"))]
     pub command: Vec<String>,
 
-    /// Enable HTTP server
-    #[arg(short, long, value_name = "LISTEN_ADDR", default_missing_value = "127.0.0.1:0", num_args = 0..=1)]
-    pub listen: Option<SocketAddr>,
+    /// Enable HTTP server, listening on the given address (repeatable)
+    #[arg(short, long, value_name = "LISTEN_ADDR", env = "HT_LISTEN", default_missing_value = "127.0.0.1:0", num_args = 0..=1, value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
 
     /// Subscribe to events
-    #[arg(long, value_name = "EVENTS")]
+    #[arg(long, value_name = "EVENTS", env = "HT_SUBSCRIBE")]
     pub subscribe: Option<Subscription>,
+
+    /// Accept only these command types on STDIN, rejecting all others
+    #[arg(long, value_name = "COMMANDS", conflicts_with = "deny_commands")]
+    pub allow_commands: Option<CommandList>,
+
+    /// Reject these command types on STDIN
+    #[arg(long, value_name = "COMMANDS")]
+    pub deny_commands: Option<CommandList>,
+
+    /// Reject commands on STDIN longer than this, without buffering them
+    #[arg(long, value_name = "BYTES", default_value_t = stdio::DEFAULT_MAX_COMMAND_BYTES)]
+    pub max_command_bytes: usize,
+
+    /// Pretty-print events on STDOUT, separated by an empty line (for debugging)
+    #[arg(long)]
+    pub pretty: bool,
+
+    /// Print what ht is doing to STDERR: the command it launches, where the live preview is, and shutting down
+    #[arg(long, conflicts_with = "quiet")]
+    pub banner: bool,
+
+    /// Print nothing to STDERR, not even warnings
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Encoding of events on STDOUT and commands on STDIN
+    #[arg(long, value_enum, default_value_t = Protocol::Json)]
+    pub protocol: Protocol,
+
+    /// Form of output events: raw sequences or changed rows of visible text
+    #[arg(long, value_enum, default_value_t = OutputMode::Raw)]
+    pub output_mode: OutputMode,
+
+    /// Translate line breaks in input to this newline sequence before writing it to the terminal
+    #[arg(long, value_enum, value_name = "NEWLINE", default_value_t = Newline::None)]
+    pub input_newline: Newline,
+
+    /// Keep this many of the most recent raw output bytes for the getOutputTail command
+    #[arg(long, value_name = "BYTES", default_value_t = session::DEFAULT_OUTPUT_TAIL_SIZE)]
+    pub output_tail_bytes: usize,
+
+    /// Keep this many bytes of recent output for clients connecting with replay (0 disables replay)
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    pub replay_output_bytes: usize,
+
+    /// Keep up to this many recent resize events for clients connecting with replay
+    #[arg(long, value_name = "N", default_value_t = session::DEFAULT_REPLAY_RESIZE_EVENTS)]
+    pub replay_resize_events: usize,
+
+    /// Exit with the exit code of the command (128 + signal number if it was killed by a signal)
+    #[arg(long)]
+    pub propagate_exit_code: bool,
+
+    /// Record the session to an asciicast v2 file
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
+    /// Limit pauses in the recording to this many seconds
+    #[arg(long, value_name = "SECONDS", requires = "record", value_parser = parse_idle_limit)]
+    pub record_idle_limit: Option<f64>,
+
+    /// Merge output events less than this many milliseconds apart in the recording
+    #[arg(long, value_name = "MS", requires = "record")]
+    pub record_coalesce_ms: Option<u64>,
+
+    /// Append the command's raw output, as read from the terminal, to this file
+    #[arg(long, value_name = "FILE")]
+    pub output_log: Option<PathBuf>,
+
+    /// Leave escape sequences and control characters out of the output log, writing plain text
+    #[arg(long, requires = "output_log")]
+    pub strip_ansi: bool,
+
+    /// Compress the recording with gzip (implied by a .gz extension of the recording file)
+    #[arg(long, requires = "record")]
+    pub record_compress: bool,
+
+    /// Leave output muted with the mute command out of the recording, repainting the screen on unmute
+    #[arg(long, requires = "record")]
+    pub record_pause_on_mute: bool,
+
+    /// Record this terminal width instead of the actual one
+    #[arg(long, value_name = "COLS", requires = "record", value_parser = clap::value_parser!(u16).range(1..))]
+    pub record_cols: Option<u16>,
+
+    /// Record this terminal height instead of the actual one
+    #[arg(long, value_name = "ROWS", requires = "record", value_parser = clap::value_parser!(u16).range(1..))]
+    pub record_rows: Option<u16>,
+
+    /// Serve the HTTP API and live preview under a path prefix (e.g. /terminal)
+    #[arg(long, value_name = "PREFIX", env = "HT_BASE_PATH", value_parser = parse_base_path)]
+    pub base_path: Option<String>,
+
+    /// Serve the live preview from this directory, falling back to the built-in files for missing ones
+    #[arg(long, value_name = "DIR")]
+    pub web_root: Option<PathBuf>,
+
+    /// Serve only files from --web-root, without the built-in fallback
+    #[arg(long, requires = "web_root")]
+    pub web_root_only: bool,
+
+    /// Color theme for screenshots and live preview: dark, light, solarized or a path to a JSON theme file
+    #[arg(long, value_name = "NAME|FILE", env = "HT_THEME")]
+    pub theme: Option<String>,
+
+    /// Don't draw the cursor in screenshots and SVG snapshots
+    #[arg(long)]
+    pub hide_cursor: bool,
+
+    /// Pass output through without feeding it to the terminal emulator, disabling snapshots, state and screenshots
+    #[arg(long)]
+    pub no_emulator: bool,
+
+    /// Start with the screen showing this file's content (text with ANSI escape sequences), which only the terminal emulator sees
+    #[arg(long, value_name = "FILE")]
+    pub initial_screen: Option<PathBuf>,
+
+    /// Emit unknownSequence events for escape sequences the terminal emulator ignores
+    #[arg(long)]
+    pub debug_sequences: bool,
+
+    /// Coalesce resize commands arriving within this many milliseconds (0 to disable)
+    #[arg(long, value_name = "MS", default_value_t = 50)]
+    pub resize_debounce: u64,
+
+    /// Hold output for up to this many milliseconds to process it in larger batches (0 to process it as read)
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub flush_interval_ms: u64,
+
+    /// Process held output as soon as this many bytes are pending, without waiting for --flush-interval-ms
+    #[arg(long, value_name = "BYTES", default_value_t = session::DEFAULT_FLUSH_BYTES)]
+    pub flush_bytes: usize,
+
+    /// Size of the buffer output is read from the terminal into, the most read at once (4 KiB to 4 MiB)
+    #[cfg(unix)]
+    #[arg(long, value_name = "BYTES", default_value_t = crate::pty::DEFAULT_READ_BUFFER_SIZE, value_parser = parse_buffer_size)]
+    pub read_buffer_size: usize,
+
+    /// Most input written to the terminal at once, when draining pending input (4 KiB to 4 MiB) [default: all of it]
+    #[cfg(unix)]
+    #[arg(long, value_name = "BYTES", value_parser = parse_buffer_size)]
+    pub write_chunk_size: Option<usize>,
+
+    /// Shell command line typed into the terminal before the session starts, with its output left out of events and recordings
+    #[cfg(unix)]
+    #[arg(long, value_name = "COMMAND")]
+    pub setup_command: Option<String>,
+
+    /// Give up (and exit) when --setup-command hasn't finished within this many milliseconds
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 30_000,
+        requires = "setup_command"
+    )]
+    pub setup_timeout: u64,
+
+    /// Run each line of this file as a command in the shell, one at a time, reporting its output and exit status, then end the session
+    #[cfg(unix)]
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<PathBuf>,
+
+    /// Run the command in a new session with the terminal as its controlling terminal (default)
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "process_group")]
+    pub setsid: bool,
+
+    /// Run the command in a new process group only, without a controlling terminal
+    #[cfg(unix)]
+    #[arg(long)]
+    pub process_group: bool,
+
+    /// Input processing of the terminal set up before the command starts
+    #[cfg(unix)]
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = crate::pty::PtyMode::Cooked)]
+    pub pty_mode: crate::pty::PtyMode,
+
+    /// Pass this open file descriptor of ht on to the command (can be repeated)
+    #[cfg(unix)]
+    #[arg(long = "pass-fd", value_name = "FD", value_parser = parse_pass_fd)]
+    pub pass_fds: Vec<i32>,
+
+    /// Set an environment variable for the command, overriding an inherited one (can be repeated)
+    #[cfg(unix)]
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_env_var)]
+    pub env: Vec<(String, String)>,
+
+    /// Remove an environment variable inherited from ht from the command's environment (can be repeated)
+    #[cfg(unix)]
+    #[arg(long, value_name = "NAME", value_parser = parse_env_name)]
+    pub unset_env: Vec<String>,
+
+    /// Directories put in front of the command's PATH, before it's looked up (colon separated, can be repeated)
+    #[cfg(unix)]
+    #[arg(long, value_name = "DIRS", value_delimiter = ':', value_parser = parse_path_dir)]
+    pub path_prepend: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputMode {
+    /// Output as written by the program, including control sequences
+    Raw,
+
+    /// Rows of visible text changed by the output, after terminal processing
+    Cooked,
 }
 
 impl Default for Cli {
@@ -32,9 +269,122 @@ impl Default for Cli {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum AutoSize {
+    /// When STDOUT or STDERR is a terminal, i.e. ht isn't run by a controller
+    Auto,
+    /// Always, failing when there's no terminal to take the size from
+    Always,
+    /// Never, using the 120x40 default
+    Never,
+}
+
 impl Cli {
+    /// Parses the command line, along with the config file given with
+    /// `--config`, exiting on errors and with `--print-config`.
     pub fn new() -> Self {
-        Cli::parse()
+        let command = Cli::command();
+
+        let args =
+            config::merge_args(&command, std::env::args_os().collect()).unwrap_or_else(|e| {
+                command
+                    .clone()
+                    .error(ErrorKind::Io, format!("{e:#}"))
+                    .exit()
+            });
+
+        let matches = command.clone().get_matches_from(args);
+
+        if matches.get_flag("print_config") {
+            print!("{}", config::to_toml(&command, &matches));
+            std::process::exit(0);
+        }
+
+        Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+}
+
+/// Normalizes a base path to the `/prefix` form (leading slash, no trailing
+/// slash). The root path normalizes to an empty string.
+fn parse_base_path(s: &str) -> Result<String, String> {
+    let trimmed = s.trim_matches('/');
+
+    if trimmed.contains(['?', '#']) {
+        return Err(format!("invalid base path: {s}"));
+    }
+
+    if trimmed.is_empty() {
+        Ok(String::new())
+    } else {
+        Ok(format!("/{trimmed}"))
+    }
+}
+
+fn parse_idle_limit(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(limit) if limit > 0.0 && limit.is_finite() => Ok(limit),
+        _ => Err(format!(
+            "invalid idle limit: {s} (expected a positive number of seconds)"
+        )),
+    }
+}
+
+#[cfg(unix)]
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    const MIN: usize = 4 * 1024;
+    const MAX: usize = 4 * 1024 * 1024;
+
+    match s.parse::<usize>() {
+        Ok(size @ MIN..=MAX) => Ok(size),
+        _ => Err(format!(
+            "invalid buffer size: {s} (expected {MIN} to {MAX} bytes)"
+        )),
+    }
+}
+
+#[cfg(unix)]
+fn parse_pass_fd(s: &str) -> Result<i32, String> {
+    match s.parse::<i32>() {
+        Ok(fd @ 0..=2) => Err(format!(
+            "{fd} is the command's terminal, only descriptors from 3 up can be passed"
+        )),
+
+        Ok(fd) if fd > 2 && crate::pty::is_open_fd(fd) => Ok(fd),
+        Ok(fd) if fd > 2 => Err(format!("{fd} is not an open file descriptor")),
+        _ => Err(format!("invalid file descriptor: {s}")),
+    }
+}
+
+#[cfg(unix)]
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = s.split_once('=') else {
+        return Err(format!(
+            "{s}: expected NAME=VALUE (NAME= sets an empty value, --unset-env removes it)"
+        ));
+    };
+
+    Ok((parse_env_name(name)?, value.to_owned()))
+}
+
+#[cfg(unix)]
+fn parse_env_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(['=', '\0']) {
+        Err(format!("invalid environment variable name: {s:?}"))
+    } else {
+        Ok(s.to_owned())
+    }
+}
+
+#[cfg(unix)]
+fn parse_path_dir(s: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+
+    if path.is_absolute() {
+        Ok(path)
+    } else if s.is_empty() {
+        Err("empty directory, PATH entries can't be empty".to_owned())
+    } else {
+        Err(format!("{s} is not an absolute path"))
     }
 }
 
@@ -51,6 +401,25 @@ impl Size {
     }
 }
 
+impl Default for Size {
+    fn default() -> Self {
+        Size::from(Winsize {
+            ws_col: 120,
+            ws_row: 40,
+            #[cfg(unix)]
+            ws_xpixel: 0,
+            #[cfg(unix)]
+            ws_ypixel: 0,
+        })
+    }
+}
+
+impl From<Winsize> for Size {
+    fn from(winsize: Winsize) -> Self {
+        Size(winsize)
+    }
+}
+
 impl FromStr for Size {
     type Err = anyhow::Error;
 
@@ -92,3 +461,60 @@ impl Display for Size {
         write!(f, "{}x{}", self.0.ws_col, self.0.ws_row)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{AutoSize, Cli, Size, parse_base_path};
+    use clap::Parser;
+
+    #[cfg(unix)]
+    #[test]
+    fn pass_fd_validation() {
+        use super::parse_pass_fd;
+        use std::os::fd::AsRawFd;
+
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+        let fd = file.as_raw_fd();
+
+        assert_eq!(parse_pass_fd(&fd.to_string()), Ok(fd));
+        assert!(parse_pass_fd("1").is_err());
+        assert!(parse_pass_fd("-3").is_err());
+        assert!(parse_pass_fd("fd").is_err());
+
+        drop(file);
+        assert!(parse_pass_fd(&fd.to_string()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn buffer_size_validation() {
+        use super::parse_buffer_size;
+
+        assert_eq!(parse_buffer_size("4096"), Ok(4096));
+        assert_eq!(parse_buffer_size("4194304"), Ok(4194304));
+        assert!(parse_buffer_size("4095").is_err());
+        assert!(parse_buffer_size("4194305").is_err());
+        assert!(parse_buffer_size("64K").is_err());
+    }
+
+    #[test]
+    fn base_path_normalization() {
+        assert_eq!(parse_base_path("/terminal/").unwrap(), "/terminal");
+        assert_eq!(parse_base_path("terminal").unwrap(), "/terminal");
+        assert_eq!(parse_base_path("/a/b").unwrap(), "/a/b");
+        assert_eq!(parse_base_path("/").unwrap(), "");
+        parse_base_path("/x?y").expect_err("should fail");
+    }
+
+    #[test]
+    fn size_options() {
+        let cli = Cli::try_parse_from(["ht"]).unwrap();
+        assert!(cli.size.is_none());
+        assert_eq!(cli.auto_size, AutoSize::Auto);
+        assert_eq!(Size::default().to_string(), "120x40");
+
+        let cli = Cli::try_parse_from(["ht", "--size", "80x24", "--auto-size", "never"]).unwrap();
+        assert_eq!(cli.size.unwrap().to_string(), "80x24");
+        assert_eq!(cli.auto_size, AutoSize::Never);
+    }
+}
@@ -0,0 +1,74 @@
+// Source of event timestamps. Sessions use the monotonic clock, while tests
+// can drive a manual one to get the same timestamps on every run, e.g. for
+// comparing recordings with golden files.
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Time elapsed since the start of a session.
+pub trait Clock: Send {
+    fn elapsed(&self) -> Duration;
+}
+
+/// Real time, measured from the clock's creation.
+pub struct MonotonicClock(Instant);
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// Virtual time, standing still until advanced. Clones share the time, so a
+/// test can keep one to advance the clock of a session it handed another to.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock(Arc<Mutex<Duration>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn elapsed(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, ManualClock};
+    use std::time::Duration;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+
+        shared.advance(Duration::from_millis(1500));
+        assert_eq!(clock.elapsed(), Duration::from_millis(1500));
+    }
+}
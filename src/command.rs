@@ -1,30 +1,336 @@
+use crate::pty::Backpressure;
+use crate::render::ImageFormat;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
 #[derive(Debug)]
 pub enum Command {
     Input(Vec<InputSeq>),
-    Snapshot,
-    Resize(usize, usize),
+    Eof,
+    CloseStdin,
+    Snapshot(bool, ScreenBuffer),
+    State,
+    Grid,
+    ExportFrame,
+    OutputTail(Option<usize>),
+    Mark,
+    Mute,
+    Unmute(bool),
+    FreezeDisplay,
+    UnfreezeDisplay,
+    OutputSince(u64, OutputSinceFormat),
+    Resize(usize, usize, bool),
+    Screenshot(ImageFormat),
+    Sleep(Duration),
+    InputAndWait(Vec<InputSeq>, TextWait),
+    TypeWithEcho(String, EchoTyping),
+    InputWithEcho(Vec<InputSeq>, EchoWait),
+    Query(Query),
+    WaitForText(TextWait),
+    Cwd,
+    Env(String),
+    ListProcesses,
+    KillProcess(u32, String),
+    Signal(String),
+    ResizeAndSettle(usize, usize, Settle),
+    Refresh(Settle, bool),
+    InputPending,
+    SetBackpressure(Backpressure),
+    InputChunked(usize, usize),
+    ScriptLine(usize, String, oneshot::Sender<Option<i32>>),
+    SetRenderCursor(bool),
+    SaveScrollback(PathBuf, ScrollbackFormat),
+}
+
+impl Command {
+    /// Tells whether the command needs the terminal emulator's screen, which
+    /// isn't kept with `--no-emulator`.
+    pub fn needs_emulator(&self) -> bool {
+        matches!(
+            self,
+            Command::Snapshot(_, _)
+                | Command::State
+                | Command::Grid
+                | Command::ExportFrame
+                | Command::Screenshot(_)
+                | Command::SaveScrollback(_, _)
+                | Command::Unmute(true)
+                | Command::Refresh(_, true)
+                | Command::FreezeDisplay
+                | Command::UnfreezeDisplay
+        )
+    }
+
+    /// Tells whether the command sends input to the program.
+    pub fn sends_input(&self) -> bool {
+        matches!(
+            self,
+            Command::Input(_)
+                | Command::Eof
+                | Command::ScriptLine(_, _, _)
+                | Command::Query(_)
+                | Command::InputWithEcho(_, _)
+        )
+    }
+}
+
+/// Which screen buffer `takeSnapshot` renders. Full-screen programs like `less`
+/// draw on the alternate screen, while the main one keeps the shell's content
+/// behind it, to be shown again once they exit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenBuffer {
+    /// The buffer currently shown.
+    Active,
+
+    Main,
+
+    Alt,
+}
+
+/// How `saveScrollback` writes the terminal's lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScrollbackFormat {
+    /// Plain text, with trailing whitespace of each line trimmed.
+    Text,
+
+    /// Text with SGR sequences for colors and text attributes, viewable with
+    /// e.g. `less -R`.
+    Ansi,
+}
+
+/// How `getOutputSince` returns the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputSinceFormat {
+    /// The literal bytes read from the terminal, base64 encoded.
+    Raw,
+
+    /// Text printed outside of escape sequences, with control characters
+    /// other than line feed dropped.
+    Text,
+}
+
+/// Wait for text to appear in the output, reported with an `inputAndWait` event
+/// carrying `id` either once it does or after `timeout`.
+#[derive(Debug, Clone)]
+pub struct TextWait {
+    pub id: Option<serde_json::Value>,
+    pub expect: String,
+    pub timeout: Duration,
+}
+
+/// Pacing of `typeWithEcho`, which types text a character at a time: each one
+/// is sent once the previous one was echoed, or `echo_timeout` passed without
+/// any output. Echo is then taken to be off, and the remaining characters are
+/// sent `fallback_delay` apart.
+#[derive(Debug, Clone, Copy)]
+pub struct EchoTyping {
+    pub echo_timeout: Duration,
+    pub fallback_delay: Duration,
 }
 
-#[derive(Debug, PartialEq)]
+/// Wait for the echo of input, answered with `true` on the first output or
+/// with `false` after `timeout`.
+#[derive(Debug)]
+pub struct EchoWait {
+    pub timeout: Duration,
+    pub reply: oneshot::Sender<bool>,
+}
+
+/// Wait for output to pause for `quiet` (e.g. a program done redrawing after
+/// a resize), reported with a `resizeAndSettle` event carrying `id` either
+/// once it does or after `timeout`.
+#[derive(Debug, Clone)]
+pub struct Settle {
+    pub id: Option<serde_json::Value>,
+    pub quiet: Duration,
+    pub timeout: Duration,
+}
+
+/// Query sequence written to the terminal as is, reported with a `query` event
+/// carrying `id` and all the output which arrived within `timeout` after it,
+/// taken to be the response.
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub id: Option<serde_json::Value>,
+    pub send: String,
+    pub timeout: Duration,
+}
+
+/// Newline sequence which line breaks in input are translated to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Newline {
+    /// Line feed (\n)
+    Lf,
+
+    /// Carriage return followed by line feed (\r\n)
+    Crlf,
+
+    /// Carriage return (\r)
+    Cr,
+
+    /// No translation, input is passed through as is
+    #[default]
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum InputSeq {
     Standard(String),
     Cursor(String, String),
+    Paste(String),
+    /// Text payload (e.g. of the `input` command), the only input whose line
+    /// breaks are translated (see `Newline`). Keys and pasted text are sent
+    /// as they are.
+    Text(String),
+}
+
+/// Markers of bracketed paste, around text which the program should take as
+/// pasted rather than typed.
+pub const PASTE_START: &str = "\x1b[200~";
+pub const PASTE_END: &str = "\x1b[201~";
+
+impl InputSeq {
+    /// Splits a paste into its markers and the text in between, which can
+    /// then be written in pieces. Other seqs are returned as they are.
+    pub fn unbracket(self) -> Vec<InputSeq> {
+        match self {
+            InputSeq::Paste(text) => vec![
+                InputSeq::Standard(PASTE_START.to_owned()),
+                InputSeq::Standard(pasted_text(&text)),
+                InputSeq::Standard(PASTE_END.to_owned()),
+            ],
+
+            seq => vec![seq],
+        }
+    }
+}
+
+/// Leaves out end markers from pasted text, so that it can't end the paste
+/// early and have the rest taken as typed.
+fn pasted_text(text: &str) -> String {
+    text.replace(PASTE_END, "")
+}
+
+/// Default VEOF character (Ctrl-D), which a terminal in canonical mode turns
+/// into end of input for a read, instead of passing it on.
+pub const VEOF: u8 = 0x04;
+
+/// Input delivering end of file to a program reading the terminal, after
+/// input which may have left a partial line (`line_pending`). VEOF only ends
+/// input when the line is empty, otherwise it hands over the line as it is,
+/// so it's sent twice then.
+#[cfg_attr(windows, allow(dead_code))]
+pub fn eof_bytes(line_pending: bool) -> Vec<u8> {
+    if line_pending {
+        vec![VEOF, VEOF]
+    } else {
+        vec![VEOF]
+    }
 }
 
-pub fn seqs_to_bytes(seqs: &[InputSeq], app_mode: bool) -> Vec<u8> {
+/// Tells whether a line is left pending after input, i.e. it doesn't end
+/// with a line break or VEOF. Empty input changes nothing.
+#[cfg_attr(windows, allow(dead_code))]
+pub fn leaves_line_pending(bytes: &[u8], line_pending: bool) -> bool {
+    match bytes.last() {
+        Some(b'\r' | b'\n' | &VEOF) => false,
+        Some(_) => true,
+        None => line_pending,
+    }
+}
+
+/// Encodes input, with line breaks in text payloads translated to `newline`.
+pub fn seqs_to_bytes(seqs: &[InputSeq], app_mode: bool, newline: Newline) -> Vec<u8> {
     let mut bytes = Vec::new();
 
     for seq in seqs {
-        bytes.extend_from_slice(seq_as_bytes(seq, app_mode));
+        match (seq, app_mode) {
+            (InputSeq::Standard(seq), _) => bytes.extend_from_slice(seq.as_bytes()),
+            (InputSeq::Text(text), _) => {
+                bytes.extend(translate_newlines(text.as_bytes().to_vec(), newline));
+            }
+
+            (InputSeq::Cursor(seq1, _seq2), false) => bytes.extend_from_slice(seq1.as_bytes()),
+            (InputSeq::Cursor(_seq1, seq2), true) => bytes.extend_from_slice(seq2.as_bytes()),
+
+            (InputSeq::Paste(text), _) => {
+                bytes.extend_from_slice(PASTE_START.as_bytes());
+                bytes.extend_from_slice(pasted_text(text).as_bytes());
+                bytes.extend_from_slice(PASTE_END.as_bytes());
+            }
+        }
     }
 
     bytes
 }
 
-fn seq_as_bytes(seq: &InputSeq, app_mode: bool) -> &[u8] {
-    match (seq, app_mode) {
-        (InputSeq::Standard(seq), _) => seq.as_bytes(),
-        (InputSeq::Cursor(seq1, _seq2), false) => seq1.as_bytes(),
-        (InputSeq::Cursor(_seq1, seq2), true) => seq2.as_bytes(),
+/// Replaces every line break in the input (`\r\n`, a lone `\r` or a lone
+/// `\n`) with the given newline sequence.
+pub fn translate_newlines(bytes: Vec<u8>, newline: Newline) -> Vec<u8> {
+    let target: &[u8] = match newline {
+        Newline::Lf => b"\n",
+        Newline::Crlf => b"\r\n",
+        Newline::Cr => b"\r",
+        Newline::None => return bytes,
+    };
+
+    let mut translated = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter().peekable();
+
+    while let Some(&b) = iter.next() {
+        match b {
+            b'\r' => {
+                iter.next_if_eq(&&b'\n');
+                translated.extend_from_slice(target);
+            }
+
+            b'\n' => translated.extend_from_slice(target),
+            b => translated.push(b),
+        }
+    }
+
+    translated
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Newline, VEOF, eof_bytes, leaves_line_pending, translate_newlines};
+
+    fn translate(input: &str, newline: Newline) -> String {
+        String::from_utf8(translate_newlines(input.as_bytes().to_vec(), newline)).unwrap()
+    }
+
+    const INPUT: &str = "a\r\nb\nc\rd\n\n";
+
+    #[test]
+    fn newline_lf() {
+        assert_eq!(translate(INPUT, Newline::Lf), "a\nb\nc\nd\n\n");
+    }
+
+    #[test]
+    fn newline_crlf() {
+        assert_eq!(translate(INPUT, Newline::Crlf), "a\r\nb\r\nc\r\nd\r\n\r\n");
+    }
+
+    #[test]
+    fn newline_cr() {
+        assert_eq!(translate(INPUT, Newline::Cr), "a\rb\rc\rd\r\r");
+    }
+
+    #[test]
+    fn newline_none() {
+        assert_eq!(translate(INPUT, Newline::None), INPUT);
+    }
+
+    #[test]
+    fn eof_after_partial_line() {
+        assert!(leaves_line_pending(b"abc", false));
+        assert!(!leaves_line_pending(b"abc\r", true));
+        assert!(!leaves_line_pending(&[b'a', VEOF], true));
+        assert!(leaves_line_pending(b"", true));
+
+        assert_eq!(eof_bytes(false), vec![VEOF]);
+        assert_eq!(eof_bytes(true), vec![VEOF, VEOF]);
     }
 }
@@ -0,0 +1,262 @@
+// Config file given with `--config <FILE>`: a TOML file setting the same
+// options as the command line, named after them, e.g.
+//
+//   size = "100x30"
+//   listen = ["127.0.0.1:8080"]
+//   subscribe = "output,snapshot"
+//   record = "session.cast"
+//   record-compress = true
+//   command = ["bash", "-l"]
+//
+// The file is turned into command line arguments placed before the real ones,
+// so clap validates them like any other. Options given on the command line or
+// with their environment variable are left out, which makes the precedence:
+// command line > environment > config file > defaults.
+
+use anyhow::{Context, Result, bail};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Options which make no sense in the config file itself.
+const IGNORED: &[&str] = &["config", "print_config", "help", "version"];
+
+/// Command line arguments with the options of the config file (if given with
+/// `--config`) merged in.
+pub fn merge_args(command: &clap::Command, args: Vec<OsString>) -> Result<Vec<OsString>> {
+    // errors (including --help) are reported by the real parse
+    let Ok(matches) = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+    else {
+        return Ok(args);
+    };
+
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(args);
+    };
+
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read config file {}", path.display()))?;
+
+    let (options, program) = file_args(command, &matches, &content)
+        .with_context(|| format!("invalid config file {}", path.display()))?;
+
+    let mut args = args.into_iter();
+    let mut merged: Vec<OsString> = args.next().into_iter().collect();
+    merged.extend(options);
+    merged.extend(args);
+
+    if !program.is_empty() {
+        merged.push("--".into());
+        merged.extend(program);
+    }
+
+    Ok(merged)
+}
+
+/// Arguments for the options set in a config file, and the command to run,
+/// if it's set there.
+fn file_args(
+    command: &clap::Command,
+    matches: &ArgMatches,
+    content: &str,
+) -> Result<(Vec<OsString>, Vec<OsString>)> {
+    let table: toml::Table = content.parse()?;
+    let mut options = Vec::new();
+    let mut program = Vec::new();
+
+    for (key, value) in table {
+        let Some(arg) = command
+            .get_arguments()
+            .filter(|arg| !IGNORED.contains(&arg.get_id().as_str()))
+            .find(|arg| {
+                arg.get_long() == Some(&key) || (arg.is_positional() && arg.get_id() == &key)
+            })
+        else {
+            bail!("unknown option: {key}");
+        };
+
+        let overridden = matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        );
+
+        if overridden {
+            continue;
+        }
+
+        if arg.is_positional() {
+            program = values(&key, value)?.into_iter().map(Into::into).collect();
+        } else if matches!(arg.get_action(), clap::ArgAction::SetTrue) {
+            match value {
+                toml::Value::Boolean(true) => options.push(format!("--{key}").into()),
+                toml::Value::Boolean(false) => (),
+                _ => bail!("{key}: expected true or false"),
+            }
+        } else {
+            for value in values(&key, value)? {
+                options.push(format!("--{key}={value}").into());
+            }
+        }
+    }
+
+    Ok((options, program))
+}
+
+fn values(key: &str, value: toml::Value) -> Result<Vec<String>> {
+    match value {
+        toml::Value::Array(values) => values.into_iter().map(|v| scalar(key, v)).collect(),
+        value => Ok(vec![scalar(key, value)?]),
+    }
+}
+
+fn scalar(key: &str, value: toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => bail!("{key}: expected a string, number or boolean"),
+    }
+}
+
+/// The options in effect, in config file format, for `--print-config`.
+pub fn to_toml(command: &clap::Command, matches: &ArgMatches) -> String {
+    let mut table = toml::Table::new();
+
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+
+        if IGNORED.contains(&id) {
+            continue;
+        }
+
+        let Some(raw) = matches.get_raw(id) else {
+            continue;
+        };
+
+        let key = arg.get_long().unwrap_or(id).to_owned();
+        let mut values: Vec<toml::Value> = raw.map(|v| value(&v.to_string_lossy())).collect();
+        let many = arg.is_positional() || matches!(arg.get_action(), clap::ArgAction::Append);
+
+        let value = if many {
+            toml::Value::Array(values)
+        } else {
+            values.swap_remove(0)
+        };
+
+        table.insert(key, value);
+    }
+
+    table.to_string()
+}
+
+fn value(raw: &str) -> toml::Value {
+    if let Ok(b) = raw.parse() {
+        toml::Value::Boolean(b)
+    } else if let Ok(i) = raw.parse() {
+        toml::Value::Integer(i)
+    } else {
+        match raw.parse::<f64>() {
+            Ok(f) if f.is_finite() => toml::Value::Float(f),
+            _ => toml::Value::String(raw.to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{merge_args, to_toml};
+    use crate::cli::Cli;
+    use clap::CommandFactory;
+    use std::ffi::OsString;
+
+    fn merge(content: &str, args: &[&str]) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("ht-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+
+        let mut all: Vec<OsString> = vec!["ht".into(), "--config".into(), path.clone().into()];
+        all.extend(args.iter().map(Into::into));
+        let merged = merge_args(&Cli::command(), all);
+        std::fs::remove_file(&path).unwrap();
+
+        merged
+            .unwrap()
+            .into_iter()
+            .map(|a| a.into_string().unwrap())
+            .filter(|a| !a.ends_with(".toml") && a != "--config")
+            .collect()
+    }
+
+    #[test]
+    fn file_options_come_before_command_line() {
+        let content = r#"
+            size = "100x30"
+            listen = ["127.0.0.1:1", "127.0.0.1:2"]
+            record-compress = true
+            pretty = false
+            resize-debounce = 10
+            command = ["bash", "-l"]
+        "#;
+
+        let args = merge(content, &["--resize-debounce", "20"]);
+        assert_eq!(
+            args,
+            [
+                "ht",
+                "--listen=127.0.0.1:1",
+                "--listen=127.0.0.1:2",
+                "--record-compress",
+                "--size=100x30",
+                "--resize-debounce",
+                "20",
+                "--",
+                "bash",
+                "-l"
+            ]
+        );
+
+        let args = merge(content, &["--listen", "127.0.0.1:3", "sh"]);
+        assert!(!args.iter().any(|a| a.starts_with("--listen=")));
+        assert_eq!(args.last().unwrap(), "sh");
+    }
+
+    #[test]
+    fn invalid_files() {
+        let path = std::env::temp_dir().join(format!("ht-config-{}.toml", uuid::Uuid::new_v4()));
+        let args = |path: &std::path::Path| -> Vec<OsString> {
+            vec!["ht".into(), "--config".into(), path.into()]
+        };
+
+        merge_args(&Cli::command(), args(&path)).expect_err("missing file");
+
+        for content in [
+            "sizee = \"1x1\"",
+            "pretty = 1",
+            "size = {}",
+            "config = \"x\"",
+            "[",
+        ] {
+            std::fs::write(&path, content).unwrap();
+            merge_args(&Cli::command(), args(&path)).expect_err(content);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn print_config() {
+        let matches = Cli::command().get_matches_from(["ht", "--size", "80x24", "--pretty", "vim"]);
+        let config: toml::Table = to_toml(&Cli::command(), &matches).parse().unwrap();
+
+        assert_eq!(config["size"].as_str(), Some("80x24"));
+        assert_eq!(config["pretty"].as_bool(), Some(true));
+        assert_eq!(config["resize-debounce"].as_integer(), Some(50));
+        assert_eq!(config["command"].as_array().unwrap().len(), 1);
+        assert!(!config.contains_key("record"));
+        assert!(!config.contains_key("config"));
+    }
+}
@@ -0,0 +1,321 @@
+// Lightweight escape sequence scanner, run alongside avt to pick up sequences
+// which avt parses but doesn't expose (or silently ignores).
+
+const MAX_LEN: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sequence {
+    Esc(String, char),
+    Csi(String, String, char),
+    Osc(String),
+    Dcs(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scanner {
+    state: State,
+    params: String,
+    intermediates: String,
+    bells: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Csi,
+    Osc,
+    OscEscape,
+    Dcs,
+    DcsEscape,
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, input: &str) -> Vec<Sequence> {
+        let mut seqs = Vec::new();
+
+        for ch in input.chars() {
+            if let Some(seq) = self.feed_char(ch) {
+                seqs.push(seq);
+            }
+        }
+
+        seqs
+    }
+
+    /// Returns the number of BEL characters rung (i.e. not terminating an OSC
+    /// sequence) since the previous call.
+    pub fn take_bells(&mut self) -> usize {
+        std::mem::take(&mut self.bells)
+    }
+
+    /// Feeds input like `feed`, returning the text printed outside of escape
+    /// sequences instead. Control characters other than line feed are dropped.
+    pub fn feed_text(&mut self, input: &str) -> String {
+        let mut text = String::new();
+
+        for ch in input.chars() {
+            let printed = self.state == State::Ground && (ch == '\n' || !ch.is_control());
+            self.feed_char(ch);
+
+            if printed {
+                text.push(ch);
+            }
+        }
+
+        text
+    }
+
+    /// Feeds input like `feed`, returning it with every complete sequence
+    /// replaced with what `f` returns for it (kept as is for `None`). Aborted
+    /// sequences are dropped.
+    pub fn rewrite(
+        &mut self,
+        input: &str,
+        mut f: impl FnMut(&Sequence) -> Option<String>,
+    ) -> String {
+        let mut output = String::with_capacity(input.len());
+
+        for ch in input.chars() {
+            // C0 controls are executed in the middle of ESC and CSI sequences
+            let passed = match self.state {
+                State::Ground => ch != '\x1b' && ch != '\x18' && ch != '\x1a',
+                State::Escape | State::Csi => {
+                    ch < '\x20' && !matches!(ch, '\x18' | '\x1a' | '\x1b')
+                }
+                _ => false,
+            };
+
+            match self.feed_char(ch) {
+                Some(seq) => match f(&seq) {
+                    Some(replacement) => output.push_str(&replacement),
+                    None => output.push_str(&seq.to_string()),
+                },
+
+                None if passed => output.push(ch),
+                None => (),
+            }
+        }
+
+        output
+    }
+
+    fn feed_char(&mut self, ch: char) -> Option<Sequence> {
+        match (&self.state, ch) {
+            (State::Osc, '\x07') => return Some(Sequence::Osc(self.finish())),
+            (State::OscEscape, '\\') => return Some(Sequence::Osc(self.finish())),
+            (State::DcsEscape, '\\') => return Some(Sequence::Dcs(self.finish())),
+
+            (State::Osc, '\x1b') => self.state = State::OscEscape,
+            (State::Dcs, '\x1b') => self.state = State::DcsEscape,
+
+            (_, '\x1b') => {
+                self.finish();
+                self.state = State::Escape;
+            }
+
+            (_, '\x18' | '\x1a') => {
+                self.finish();
+            }
+
+            (State::Ground | State::Escape | State::Csi, '\x07') => self.bells += 1,
+
+            (State::Ground, _) => (),
+
+            (State::Escape, '[') if self.intermediates.is_empty() => self.state = State::Csi,
+            (State::Escape, ']') if self.intermediates.is_empty() => self.state = State::Osc,
+            (State::Escape, 'P') if self.intermediates.is_empty() => self.state = State::Dcs,
+            (State::Escape, '\x20'..='\x2f') => self.intermediates.push(ch),
+
+            (State::Escape, '\x30'..='\x7e') => {
+                let intermediates = std::mem::take(&mut self.intermediates);
+                self.finish();
+
+                return Some(Sequence::Esc(intermediates, ch));
+            }
+
+            (State::Csi, '\x30'..='\x3f') => self.push_param(ch),
+            (State::Csi, '\x20'..='\x2f') => self.intermediates.push(ch),
+
+            (State::Csi, '\x40'..='\x7e') => {
+                let params = std::mem::take(&mut self.params);
+                let intermediates = std::mem::take(&mut self.intermediates);
+                self.finish();
+
+                return Some(Sequence::Csi(params, intermediates, ch));
+            }
+
+            (State::Osc | State::Dcs, _) => self.push_param(ch),
+
+            (State::OscEscape, _) => {
+                self.finish();
+            }
+
+            (State::DcsEscape, _) => {
+                self.finish();
+            }
+
+            // C0 controls are executed in the middle of escape sequences
+            (_, '\x00'..='\x1f') => (),
+
+            _ => {
+                self.finish();
+            }
+        }
+
+        None
+    }
+
+    fn push_param(&mut self, ch: char) {
+        if self.params.len() < MAX_LEN {
+            self.params.push(ch);
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        self.state = State::Ground;
+        self.intermediates.clear();
+
+        std::mem::take(&mut self.params)
+    }
+}
+
+impl Sequence {
+    /// Tells whether avt acts on the sequence, as opposed to parsing and
+    /// silently discarding it.
+    pub fn is_handled_by_vt(&self) -> bool {
+        match self {
+            Sequence::Esc(i, f) => matches!(
+                (i.as_str(), f),
+                ("", '7' | '8' | 'c' | 'D' | 'E' | 'H' | 'M') | ("#", '8') | ("(" | ")", _)
+            ),
+
+            Sequence::Csi(params, i, f) if i.is_empty() => match params.chars().next() {
+                Some('?') => {
+                    matches!(f, 'h' | 'l')
+                        && modes(&params[1..])
+                            .all(|m| matches!(m, Some(1 | 6 | 7 | 25 | 47 | 1047 | 1048 | 1049)))
+                }
+
+                Some('<' | '=' | '>') => false,
+
+                _ => match f {
+                    'h' | 'l' => modes(params).all(|m| matches!(m, Some(4 | 20))),
+                    '@' | 'A'..='M' | 'P' | 'S' | 'T' | 'W' | 'X' | 'Z' | '`' | 'a' | 'b' => true,
+                    'd' | 'e' | 'f' | 'g' | 'm' | 'r' | 's' | 't' | 'u' => true,
+                    _ => false,
+                },
+            },
+
+            Sequence::Csi(params, i, f) => params.is_empty() && i == "!" && *f == 'p',
+            Sequence::Osc(_) | Sequence::Dcs(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Sequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sequence::Esc(i, c) => write!(f, "\x1b{i}{c}"),
+            Sequence::Csi(p, i, c) => write!(f, "\x1b[{p}{i}{c}"),
+            Sequence::Osc(data) => write!(f, "\x1b]{data}\x1b\\"),
+            Sequence::Dcs(data) => write!(f, "\x1bP{data}\x1b\\"),
+        }
+    }
+}
+
+fn modes(params: &str) -> impl Iterator<Item = Option<u16>> + '_ {
+    params.split(';').map(|p| p.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Scanner, Sequence};
+
+    #[test]
+    fn scan_sequences() {
+        let mut scanner = Scanner::new();
+
+        let seqs = scanner.feed("a\x1b[1;31mb\x1b]0;title\x07c\x1b7\x1bP1$r\x1b\\");
+
+        assert_eq!(
+            seqs,
+            vec![
+                Sequence::Csi("1;31".to_owned(), "".to_owned(), 'm'),
+                Sequence::Osc("0;title".to_owned()),
+                Sequence::Esc("".to_owned(), '7'),
+                Sequence::Dcs("1$r".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_split_sequence() {
+        let mut scanner = Scanner::new();
+
+        assert!(scanner.feed("x\x1b[?20").is_empty());
+        assert_eq!(
+            scanner.feed("04hy"),
+            vec![Sequence::Csi("?2004".to_owned(), "".to_owned(), 'h')]
+        );
+    }
+
+    #[test]
+    fn scan_text() {
+        let mut scanner = Scanner::new();
+
+        assert_eq!(
+            scanner.feed_text("\x1b[1;32m$ \x1b[0mls\r\n\x1b]0;ti"),
+            "$ ls\n"
+        );
+        assert_eq!(scanner.feed_text("tle\x07a\x1b[Kb"), "ab");
+    }
+
+    #[test]
+    fn rewrite_sequences() {
+        let mut scanner = Scanner::new();
+
+        let output = scanner.rewrite("a\x1b[1mb\x1b[2Jc\x1b]8;;u\x07d\x1b[", |seq| match seq {
+            Sequence::Csi(_, _, 'm') => Some(String::new()),
+            Sequence::Osc(_) => Some("<link>".to_owned()),
+            _ => None,
+        });
+
+        assert_eq!(output, "ab\x1b[2Jc<link>d");
+        assert_eq!(
+            scanner.rewrite("\r1;\nH\x1b[5\x18x", |_| None),
+            "\r\n\x1b[1;Hx"
+        );
+    }
+
+    #[test]
+    fn count_bells() {
+        let mut scanner = Scanner::new();
+
+        scanner.feed("a\x07b\x1b]0;title\x07\x1b[1\x07m");
+        assert_eq!(scanner.take_bells(), 2);
+        assert_eq!(scanner.take_bells(), 0);
+
+        scanner.feed("\x1b]0;x");
+        scanner.feed("\x07\x1bP1$r\x07\x1b\\");
+        assert_eq!(scanner.take_bells(), 0);
+    }
+
+    #[test]
+    fn handled_by_vt() {
+        let mut scanner = Scanner::new();
+        let seqs = scanner.feed("\x1b[2J\x1b[?25l\x1b[?1049h\x1b(B\x1b[!p");
+
+        assert!(seqs.iter().all(|s| s.is_handled_by_vt()));
+
+        let seqs = scanner.feed("\x1b[?2004h\x1b]2;t\x07\x1b=\x1b[>c\x1b[5 q\x1b[q");
+
+        assert_eq!(seqs.len(), 6);
+        assert!(seqs.iter().all(|s| !s.is_handled_by_vt()));
+    }
+}
@@ -0,0 +1,319 @@
+// Binary encoding of the terminal grid, for the `/ws/grid` WebSocket endpoint.
+//
+// Clients get a full frame with every cell of the screen, followed by delta
+// frames with only the cells which changed. The session runs a single encoder
+// on its own terminal emulator for all clients, while any are connected, and
+// passes it the rows the emulator's dirty tracking reported for each chunk of
+// output. Cells of those rows are then compared with the ones sent last, so
+// that e.g. typing a character sends that cell alone. A client connecting
+// gets a full frame of the screen the following deltas build on.
+//
+// All integers are little-endian. A frame is:
+//
+//   kind: u8                   0 = full, 1 = delta
+//   cols: u16, rows: u16       full frames only
+//   cursor col: u16, row: u16, visible: u8
+//   span count: u16
+//   spans
+//
+// A span covers cells of a single row, `len` columns from `col`:
+//
+//   row: u16, col: u16, len: u16
+//   runs, covering the `len` columns
+//
+// A run is a number of consecutive cells with the same character and pen:
+//
+//   count: u16, char: u32 (Unicode scalar value, 0 for the column covered
+//                              by the wide character before it)
+//   attrs: u8                  bits: 0 bold, 1 faint, 2 italic, 3 underline,
+//                              4 strikethrough, 5 blink, 6 inverse
+//   foreground: color, background: color
+//
+// A color is a kind byte optionally followed by its value:
+//
+//   0                          default
+//   1, index: u8               palette color
+//   2, r: u8, g: u8, b: u8     RGB color
+//
+// A full frame has a span for every row and replaces the client's whole grid,
+// at a possibly new size.
+
+use crate::session::{self, Cursor};
+
+/// WebSocket subprotocol a client has to request to get grid frames.
+pub const PROTOCOL: &str = "ht-grid.v1";
+
+const FULL: u8 = 0;
+const DELTA: u8 = 1;
+
+type Cell = avt::Cell;
+
+/// Grid last sent to clients, which delta frames are computed against.
+#[derive(Default)]
+pub struct Encoder {
+    lines: Vec<Vec<Cell>>,
+    cursor: Cursor,
+}
+
+impl Encoder {
+    /// Encodes a full frame of the terminal's screen, which following deltas
+    /// build on.
+    pub fn full(&mut self, vt: &avt::Vt) -> Vec<u8> {
+        let (cols, rows) = vt.size();
+        self.lines = vt
+            .view()
+            .iter()
+            .map(|l| l.cells().copied().collect())
+            .collect();
+        self.cursor = session::cursor(vt);
+
+        let mut frame = vec![FULL];
+        push_u16(&mut frame, cols);
+        push_u16(&mut frame, rows);
+        push_cursor(&mut frame, &self.cursor);
+        push_u16(&mut frame, self.lines.len());
+
+        for (row, cells) in self.lines.iter().enumerate() {
+            push_span(&mut frame, row, 0, cells);
+        }
+
+        frame
+    }
+
+    /// Encodes the change to the terminal's screen since the last frame,
+    /// given the rows which may have changed, if there's any. The screen's
+    /// size has to be the same as for the last frame.
+    pub fn delta(&mut self, vt: &avt::Vt, dirty_rows: &[usize]) -> Option<Vec<u8>> {
+        let mut spans = Vec::new();
+
+        for &row in dirty_rows {
+            let Some(line) = vt.view().get(row) else {
+                continue;
+            };
+
+            let cells: Vec<Cell> = line.cells().copied().collect();
+            let old = &self.lines[row];
+            let changed = |col: &usize| cells.get(*col) != old.get(*col);
+
+            if let Some(start) = (0..cells.len()).find(changed) {
+                let end = (0..cells.len()).rfind(changed).unwrap() + 1;
+                spans.push((row, start, end));
+            }
+
+            self.lines[row] = cells;
+        }
+
+        let cursor = session::cursor(vt);
+
+        if spans.is_empty() && cursor == self.cursor {
+            return None;
+        }
+
+        self.cursor = cursor;
+
+        let mut frame = vec![DELTA];
+        push_cursor(&mut frame, &self.cursor);
+        push_u16(&mut frame, spans.len());
+
+        for (row, start, end) in spans {
+            push_span(&mut frame, row, start, &self.lines[row][start..end]);
+        }
+
+        Some(frame)
+    }
+}
+
+fn push_cursor(frame: &mut Vec<u8>, cursor: &Cursor) {
+    push_u16(frame, cursor.col);
+    push_u16(frame, cursor.row);
+    frame.push(cursor.visible as u8);
+}
+
+fn push_span(frame: &mut Vec<u8>, row: usize, col: usize, cells: &[Cell]) {
+    push_u16(frame, row);
+    push_u16(frame, col);
+    push_u16(frame, cells.len());
+
+    for run in cells.chunk_by(|a, b| a == b) {
+        let (ch, pen) = (run[0].char(), run[0].pen());
+
+        push_u16(frame, run.len());
+        frame.extend_from_slice(&(ch as u32).to_le_bytes());
+        frame.push(attrs(pen));
+        push_color(frame, pen.foreground());
+        push_color(frame, pen.background());
+    }
+}
+
+fn attrs(pen: &avt::Pen) -> u8 {
+    [
+        pen.is_bold(),
+        pen.is_faint(),
+        pen.is_italic(),
+        pen.is_underline(),
+        pen.is_strikethrough(),
+        pen.is_blink(),
+        pen.is_inverse(),
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |attrs, (bit, &set)| attrs | (set as u8) << bit)
+}
+
+fn push_color(frame: &mut Vec<u8>, color: Option<avt::Color>) {
+    match color {
+        None => frame.push(0),
+        Some(avt::Color::Indexed(c)) => frame.extend_from_slice(&[1, c]),
+        Some(avt::Color::RGB(c)) => frame.extend_from_slice(&[2, c.r, c.g, c.b]),
+    }
+}
+
+fn push_u16(frame: &mut Vec<u8>, value: usize) {
+    frame.extend_from_slice(&(value as u16).to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::Encoder;
+
+    /// Decoded span: row, col and runs of (count, char, attrs, fg, bg).
+    type Span = (u16, u16, Vec<(u16, char, u8, Vec<u8>, Vec<u8>)>);
+
+    struct Frame {
+        kind: u8,
+        size: Option<(u16, u16)>,
+        cursor: (u16, u16, bool),
+        spans: Vec<Span>,
+    }
+
+    struct Reader<'a>(std::slice::Iter<'a, u8>);
+
+    impl Reader<'_> {
+        fn u8(&mut self) -> u8 {
+            *self.0.next().unwrap()
+        }
+
+        fn u16(&mut self) -> u16 {
+            u16::from_le_bytes([self.u8(), self.u8()])
+        }
+
+        fn char(&mut self) -> char {
+            let bytes = [self.u8(), self.u8(), self.u8(), self.u8()];
+
+            char::from_u32(u32::from_le_bytes(bytes)).unwrap()
+        }
+
+        fn color(&mut self) -> Vec<u8> {
+            match self.u8() {
+                0 => vec![],
+                1 => vec![self.u8()],
+                _ => vec![self.u8(), self.u8(), self.u8()],
+            }
+        }
+    }
+
+    fn decode(frame: &[u8]) -> Frame {
+        let mut r = Reader(frame.iter());
+
+        let kind = r.u8();
+        let size = (kind == 0).then(|| (r.u16(), r.u16()));
+        let cursor = (r.u16(), r.u16(), r.u8() == 1);
+        let mut spans = Vec::new();
+
+        for _ in 0..r.u16() {
+            let (row, col, mut left) = (r.u16(), r.u16(), r.u16());
+            let mut runs = Vec::new();
+
+            while left > 0 {
+                let run = (r.u16(), r.char(), r.u8(), r.color(), r.color());
+                left -= run.0;
+                runs.push(run);
+            }
+
+            spans.push((row, col, runs));
+        }
+
+        assert!(r.0.next().is_none());
+
+        Frame {
+            kind,
+            size,
+            cursor,
+            spans,
+        }
+    }
+
+    fn vt(cols: usize, rows: usize, seq: &str) -> avt::Vt {
+        let mut vt = avt::Vt::builder().size(cols, rows).build();
+        vt.feed_str(seq);
+
+        vt
+    }
+
+    /// Feeds output to the terminal and encodes the change it made.
+    fn output(encoder: &mut Encoder, vt: &mut avt::Vt, data: &str) -> Option<Vec<u8>> {
+        let (dirty_rows, _) = vt.feed_str(data);
+
+        encoder.delta(vt, &dirty_rows)
+    }
+
+    #[test]
+    fn full_frame() {
+        let mut encoder = Encoder::default();
+        let frame = decode(&encoder.full(&vt(4, 2, "\x1b[1;31mab\x1b[0m")));
+
+        assert_eq!(frame.kind, 0);
+        assert_eq!(frame.size, Some((4, 2)));
+        assert_eq!(frame.cursor, (2, 0, true));
+        assert_eq!(
+            frame.spans,
+            vec![
+                (
+                    0,
+                    0,
+                    vec![
+                        (1, 'a', 1, vec![1], vec![]),
+                        (1, 'b', 1, vec![1], vec![]),
+                        (2, ' ', 0, vec![], vec![]),
+                    ]
+                ),
+                (1, 0, vec![(4, ' ', 0, vec![], vec![])]),
+            ]
+        );
+    }
+
+    #[test]
+    fn delta_frames() {
+        let mut encoder = Encoder::default();
+        let mut vt = vt(10, 3, "hello");
+        encoder.full(&vt);
+
+        let frame =
+            decode(&output(&mut encoder, &mut vt, "\x1b[1;2Hx\x1b[38;2;1;2;3mxx\x1b[0m").unwrap());
+
+        assert_eq!(frame.kind, 1);
+        assert_eq!(frame.size, None);
+        assert_eq!(frame.cursor, (4, 0, true));
+        assert_eq!(
+            frame.spans,
+            vec![(
+                0,
+                1,
+                vec![
+                    (1, 'x', 0, vec![], vec![]),
+                    (2, 'x', 0, vec![1, 2, 3], vec![])
+                ]
+            )]
+        );
+
+        // rewriting the same text only moves the cursor
+        let frame = decode(&output(&mut encoder, &mut vt, "\x1b[1;2Hx").unwrap());
+        assert_eq!(frame.cursor, (2, 0, true));
+        assert!(frame.spans.is_empty());
+
+        assert!(output(&mut encoder, &mut vt, "\x1b[1;3H").is_none());
+
+        let frame = decode(&output(&mut encoder, &mut vt, "\x1b[?25l").unwrap());
+        assert_eq!(frame.cursor, (2, 0, false));
+    }
+}
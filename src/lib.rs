@@ -1,9 +1,21 @@
 pub mod api;
 pub mod cli;
+pub mod clock;
 pub mod command;
+pub mod config;
+pub mod escape;
+pub mod grid;
+pub mod links;
+pub mod log;
 pub mod nbio;
+pub mod output_log;
+pub mod process;
 pub mod pty;
+pub mod record;
+pub mod render;
+pub mod script;
 pub mod session;
+pub mod theme;
 
 pub use cli::Size;
-pub use session::Session;
+pub use session::{Handle, Session, Snapshot};
@@ -0,0 +1,216 @@
+// Tracking of OSC 8 hyperlinks (`ESC ] 8 ; params ; url ST text ESC ] 8 ; ; ST`),
+// which avt ignores.
+//
+// Links belong to cells, so they have to move along with the text when it
+// scrolls, gets erased or overwritten. Rather than mirroring all of that, the
+// output is fed to a second virtual terminal in which the program's own
+// colors are stripped and the foreground color is set to an ID of the active
+// link instead. That terminal's screen then tells which cells are part of
+// which link.
+
+use crate::escape::{Scanner, Sequence};
+use std::collections::HashMap;
+
+/// Link on the visible screen, covering columns `start..end` of `row`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub row: usize,
+    pub start: usize,
+    pub end: usize,
+    pub url: String,
+}
+
+#[derive(Clone)]
+pub struct Tracker {
+    vt: avt::Vt,
+    scanner: Scanner,
+    urls: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl Tracker {
+    /// Starts tracking from the current state of `vt`, which has no links.
+    pub fn new(vt: &avt::Vt) -> Self {
+        let (cols, rows) = vt.size();
+
+        let mut tracker = Self {
+            vt: avt::Vt::builder().size(cols, rows).resizable(true).build(),
+            scanner: Scanner::new(),
+            urls: Vec::new(),
+            ids: HashMap::new(),
+        };
+
+        tracker.feed(&vt.dump());
+
+        tracker
+    }
+
+    pub fn feed(&mut self, data: &str) {
+        let Self {
+            scanner, urls, ids, ..
+        } = self;
+
+        let data = scanner.rewrite(data, |seq| match seq {
+            Sequence::Osc(data) => {
+                let url = data.strip_prefix("8;")?.split_once(';')?.1;
+
+                if url.is_empty() {
+                    return Some("\x1b[39m".to_owned());
+                }
+
+                let id = *ids.entry(url.to_owned()).or_insert_with(|| {
+                    urls.push(url.to_owned());
+                    urls.len()
+                });
+
+                Some(format!(
+                    "\x1b[38;2;{};{};{}m",
+                    (id >> 16) & 0xff,
+                    (id >> 8) & 0xff,
+                    id & 0xff
+                ))
+            }
+
+            Sequence::Csi(_, intermediates, 'm') if intermediates.is_empty() => Some(String::new()),
+
+            _ => None,
+        });
+
+        self.vt.feed_str(&data);
+    }
+
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        self.vt.feed_str(&format!("\x1b[8;{rows};{cols}t"));
+    }
+
+    /// Links on the visible screen, in reading order. Blank cells at either
+    /// end of a link (erased while the link was active) aren't part of it.
+    pub fn links(&self) -> Vec<Link> {
+        let mut links = Vec::new();
+
+        for (row, line) in self.vt.view().iter().enumerate() {
+            let cells: Vec<(char, Option<usize>)> = line
+                .cells()
+                .map(|cell| (cell.char(), self.link_id(*cell.pen())))
+                .collect();
+
+            let mut col = 0;
+
+            while col < cells.len() {
+                let id = cells[col].1;
+                let mut start = col;
+
+                while col < cells.len() && cells[col].1 == id {
+                    col += 1;
+                }
+
+                let Some(id) = id else {
+                    continue;
+                };
+
+                let mut end = col;
+
+                while start < end && cells[start].0 == ' ' {
+                    start += 1;
+                }
+
+                while end > start && cells[end - 1].0 == ' ' {
+                    end -= 1;
+                }
+
+                if start < end {
+                    links.push(Link {
+                        row,
+                        start,
+                        end,
+                        url: self.urls[id - 1].clone(),
+                    });
+                }
+            }
+        }
+
+        links
+    }
+
+    fn link_id(&self, pen: avt::Pen) -> Option<usize> {
+        match pen.foreground() {
+            Some(avt::Color::RGB(c)) => {
+                let id = (c.r as usize) << 16 | (c.g as usize) << 8 | c.b as usize;
+                (id > 0 && id <= self.urls.len()).then_some(id)
+            }
+
+            _ => None,
+        }
+    }
+}
+
+/// Tells whether output contains the start of an OSC 8 sequence, i.e. may
+/// define a link.
+pub fn has_link(data: &str) -> bool {
+    data.contains("\x1b]8;")
+}
+
+/// Finds the URL of the link covering a cell.
+pub fn url_at(links: &[Link], row: usize, col: usize) -> Option<&str> {
+    links
+        .iter()
+        .find(|l| l.row == row && (l.start..l.end).contains(&col))
+        .map(|l| l.url.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Link, Tracker};
+
+    fn link(row: usize, start: usize, end: usize, url: &str) -> Link {
+        Link {
+            row,
+            start,
+            end,
+            url: url.to_owned(),
+        }
+    }
+
+    #[test]
+    fn track_links() {
+        let mut tracker = Tracker::new(&avt::Vt::new(20, 3));
+
+        tracker.feed("see \x1b]8;;https://a.example\x07\x1b[1;34mdocs\x1b[0m\x1b]8;;\x07 or ");
+        tracker.feed("\x1b]8;id=x;https://b.example\x1b\\b\x1b]8;;\x1b\\");
+
+        assert_eq!(
+            tracker.links(),
+            vec![
+                link(0, 4, 8, "https://a.example"),
+                link(0, 12, 13, "https://b.example")
+            ]
+        );
+    }
+
+    #[test]
+    fn links_move_with_text() {
+        let mut tracker = Tracker::new(&avt::Vt::new(20, 2));
+
+        tracker.feed("\x1b]8;;https://a.example\x07link\x1b]8;;\x07\r\nx\r\ny");
+        assert_eq!(tracker.links(), vec![]);
+
+        let mut tracker = Tracker::new(&avt::Vt::new(20, 2));
+
+        tracker.feed("a\r\n\x1b]8;;https://a.example\x07link\x1b]8;;\x07\r\nx");
+        assert_eq!(tracker.links(), vec![link(0, 0, 4, "https://a.example")]);
+
+        tracker.feed("\x1b[H\x1b[2K");
+        assert_eq!(tracker.links(), vec![]);
+    }
+
+    #[test]
+    fn starts_from_existing_screen() {
+        let mut vt = avt::Vt::new(20, 2);
+        vt.feed_str("\x1b[38;2;0;0;1mcolored\x1b[0m ");
+
+        let mut tracker = Tracker::new(&vt);
+        tracker.feed("\x1b]8;;https://a.example\x07x\x1b]8;;\x07");
+
+        assert_eq!(tracker.links(), vec![link(0, 8, 9, "https://a.example")]);
+    }
+}
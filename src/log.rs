@@ -0,0 +1,51 @@
+// Messages written to STDERR.
+//
+// STDERR carries no unstructured status by default: a controller learns what
+// it needs from events (e.g. `started` with the listening addresses). Status
+// messages, about ht starting up and shutting down, are printed with
+// `--banner`. Warnings, about something going wrong without stopping ht, are
+// printed unless `--quiet` is given.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    /// No messages at all.
+    Quiet,
+
+    /// Warnings only.
+    Warn,
+
+    /// Warnings and status messages.
+    Status,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Prints a status message, with `--banner`.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Status) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a warning, unless `--quiet` is given.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {status, warning};
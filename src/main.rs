@@ -1,48 +1,478 @@
 mod api;
 mod cli;
+mod clock;
 mod command;
+mod config;
+mod escape;
+mod grid;
+mod links;
 mod locale;
+mod log;
 mod nbio;
+mod output_log;
+mod process;
 mod pty;
+mod record;
+mod render;
+mod script;
 mod session;
+mod theme;
 use anyhow::{Context, Result};
-use command::Command;
+use command::{Command, ScreenBuffer};
 use session::Session;
+use std::collections::VecDeque;
 use std::net::{SocketAddr, TcpListener};
-use tokio::{sync::mpsc, task::JoinHandle};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use theme::Theme;
+use tokio::{
+    sync::{mpsc, mpsc::error::TrySendError, oneshot, watch},
+    task::JoinHandle,
+    time::Instant,
+};
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     locale::check_utf8_locale()?;
     let cli = cli::Cli::new();
+    log::set_level(log_level(&cli));
+    let (size, follow_terminal) = terminal_size(cli.size.clone(), cli.auto_size)?;
 
     let (input_tx, input_rx) = mpsc::channel(1024);
-    let (output_tx, output_rx) = mpsc::channel(1024);
+    let (backpressure_tx, backpressure_rx) = watch::channel(pty::Backpressure::default());
+    let (rejected_tx, rejected_rx) = mpsc::unbounded_channel();
+    let (close_stdin_tx, close_stdin_rx) = watch::channel(false);
+    let input_pending = Arc::new(AtomicUsize::new(0));
+    let (output_tx, mut output_rx) = mpsc::channel(1024);
     let (command_tx, command_rx) = mpsc::channel(1024);
+    let (snapshot_tx, snapshot_rx) = mpsc::channel(16);
     let (clients_tx, clients_rx) = mpsc::channel(1);
-    let (resize_tx, resize_rx) = mpsc::channel::<(u16, u16)>(16);
-
-    start_http_api(cli.listen, clients_tx.clone()).await?;
-    let api = start_stdio_api(command_tx, clients_tx, cli.subscribe.unwrap_or_default());
-    let pty = start_pty(cli.command, &cli.size, input_rx, output_tx, resize_rx)?;
-    let session = build_session(&cli.size);
-    run_event_loop(
-        output_rx, input_tx, command_rx, clients_rx, session, api, resize_tx,
+    let handle = session::Handle::new(command_tx.clone(), snapshot_tx);
+    let (resize_tx, resize_rx) = mpsc::channel::<(u16, u16, bool)>(16);
+
+    let theme = load_theme(cli.theme.as_deref());
+    let initial_screen = read_initial_screen(cli.initial_screen.as_deref())?;
+    let output_format = output_format(cli.pretty, cli.protocol)?;
+    if cli.no_emulator {
+        check_no_emulator_options(&cli)?;
+    }
+    let options = spawn_options(
+        &cli,
+        pty::InputControl {
+            backpressure: backpressure_rx,
+            rejected_tx,
+            pending: input_pending.clone(),
+            close_stdin: close_stdin_rx,
+        },
+    );
+
+    #[cfg(unix)]
+    let setup = cli
+        .setup_command
+        .clone()
+        .map(|command| (command, cli.setup_timeout));
+    #[cfg(windows)]
+    let setup: Option<(String, u64)> = None;
+    #[cfg(unix)]
+    let script = cli.script.as_deref().map(script::read).transpose()?;
+
+    let (started_tx, started_rx) = oneshot::channel();
+    let listen_addrs = start_http_api(
+        cli.listen,
+        cli.base_path.unwrap_or_default(),
+        theme.clone(),
+        web_root(cli.web_root, cli.web_root_only),
+        clients_tx,
+        handle.clone(),
     )
     .await?;
-    pty.await?
+    let (pid, pty) = start_pty(cli.command, &size, input_rx, output_tx, resize_rx, options)?;
+    let setup_rest = match setup {
+        Some((command, timeout)) => {
+            let timeout = Duration::from_millis(timeout);
+            run_setup(&command, timeout, &input_tx, &mut output_rx).await?
+        }
+
+        None => Vec::new(),
+    };
+    // the session starts (and its clock with it) once the setup is done
+    let mut session = build_session(&size, theme);
+    if let Some((path, content)) = initial_screen {
+        session
+            .prefill(&content)
+            .with_context(|| format!("invalid initial screen {}", path.display()))?;
+    }
+    session.set_emulator(!cli.no_emulator);
+    session.set_debug_sequences(cli.debug_sequences);
+    session.set_cooked_output(cli.output_mode == cli::OutputMode::Cooked);
+    session.set_input_newline(cli.input_newline);
+    session.set_render_cursor(!cli.hide_cursor);
+    session.set_output_tail_size(cli.output_tail_bytes);
+    if let Some(path) = &cli.output_log {
+        session.set_output_log(output_log::OutputLog::open(path, cli.strip_ansi)?);
+    }
+    session.set_replay_limits(cli.replay_output_bytes, cli.replay_resize_events);
+    if follow_terminal {
+        follow_terminal_size(handle.clone());
+    }
+
+    #[cfg(unix)]
+    if let Some(commands) = script {
+        script::start(commands, handle);
+    }
+    // subscribe upfront so no event is missed, even if the process exits immediately
+    let mut api = start_stdio_api(
+        command_tx,
+        session.subscribe(),
+        cli.subscribe.unwrap_or_default(),
+        output_format,
+        command_filter(cli.allow_commands, cli.deny_commands),
+        session.command_hook(),
+        cli.max_command_bytes,
+        started_rx,
+    );
+    let recorder = start_recorder(
+        cli.record.as_deref(),
+        cli.record_idle_limit,
+        cli.record_coalesce_ms,
+        cli.record_pause_on_mute,
+        (
+            cli.record_cols.map(usize::from),
+            cli.record_rows.map(usize::from),
+        ),
+        cli.record_compress,
+        &session,
+    )?;
+    session.set_pid(pid);
+    let _ = started_tx.send(api::stdio::Started {
+        listen_addrs,
+        pid,
+        cols: size.cols(),
+        rows: size.rows(),
+    });
+    #[cfg(unix)]
+    let quiet_resize = cli.process_group;
+    #[cfg(windows)]
+    let quiet_resize = true;
+    let resizer = Resizer::new(
+        resize_tx,
+        Duration::from_millis(cli.resize_debounce),
+        quiet_resize,
+    );
+    session.set_output_batching(
+        cli.flush_bytes,
+        Duration::from_millis(cli.flush_interval_ms),
+    );
+    if !setup_rest.is_empty() {
+        session.push_output(setup_rest);
+    }
+    let shutdown = run_event_loop(
+        output_rx,
+        Input {
+            tx: input_tx,
+            backpressure_tx,
+            rejected_rx,
+            pending: input_pending,
+            queue: VecDeque::new(),
+            close_stdin_tx,
+            closed: false,
+        },
+        command_rx,
+        snapshot_rx,
+        clients_rx,
+        &mut session,
+        &mut api,
+        resizer,
+    )
+    .await?;
+    let status = match pty.await? {
+        Ok(outcome) => outcome.status,
+
+        Err(e) => {
+            session.error(format!("{e:#}"));
+            drop(session);
+            let _ = tokio::time::timeout(Duration::from_secs(1), api).await;
+
+            return Err(e);
+        }
+    };
+
+    if let Shutdown::ProcessExited = shutdown {
+        session.exit(status.clone());
+        drop(session);
+        // the API stops once the event stream ends, after printing the exit event
+        let _ = tokio::time::timeout(Duration::from_secs(1), api).await;
+    }
+
+    if let Some(recorder) = recorder {
+        if let Ok(Ok(Err(e))) = tokio::time::timeout(Duration::from_secs(1), recorder).await {
+            log::warning!("recording failed: {e:#}");
+        }
+    }
+
+    if cli.propagate_exit_code {
+        Ok(ExitCode::from(status.exit_code()))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// Picks the size of the terminal: the one given with `--size`, or else the
+/// size of ht's own terminal, according to `--auto-size`. The second value
+/// tells whether it was taken from ht's terminal, whose resizes are then
+/// followed.
+fn terminal_size(size: Option<cli::Size>, auto: cli::AutoSize) -> Result<(cli::Size, bool)> {
+    use std::io::{self, IsTerminal};
+
+    if let Some(size) = size {
+        return Ok((size, false));
+    }
+
+    let detect = match auto {
+        cli::AutoSize::Auto => io::stdout().is_terminal() || io::stderr().is_terminal(),
+        cli::AutoSize::Always => true,
+        cli::AutoSize::Never => false,
+    };
+
+    if detect {
+        match pty::terminal_size() {
+            Ok(winsize) => return Ok((winsize.into(), true)),
+
+            Err(e) if auto == cli::AutoSize::Always => {
+                return Err(e).context("cannot get the size of ht's terminal");
+            }
+
+            Err(_) => (),
+        }
+    }
+
+    Ok((cli::Size::default(), false))
+}
+
+/// Resizes the virtual terminal along with ht's own terminal, like the
+/// `resize` command would, so subject to `--resize-debounce` too. Signals
+/// which don't come with a new size (e.g. sent by a multiplexer on redraw)
+/// are ignored.
+#[cfg(unix)]
+fn follow_terminal_size(handle: session::Handle) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let Ok(mut signals) = signal(SignalKind::window_change()) else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut last_size = pty::terminal_size()
+            .map(|winsize| (winsize.ws_col, winsize.ws_row))
+            .ok();
+
+        while signals.recv().await.is_some() {
+            if let Ok(winsize) = pty::terminal_size() {
+                let size = (winsize.ws_col, winsize.ws_row);
+
+                if last_size.replace(size) == Some(size) {
+                    continue;
+                }
+
+                let (cols, rows) = (winsize.ws_col as usize, winsize.ws_row as usize);
+
+                if handle.resize(cols, rows).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(windows)]
+fn follow_terminal_size(_handle: session::Handle) {}
+
+fn build_session(size: &cli::Size, theme: Theme) -> Session {
+    Session::new(size.cols(), size.rows(), theme)
+}
+
+/// Rejects options which need the terminal emulator along with
+/// `--no-emulator`.
+fn check_no_emulator_options(cli: &cli::Cli) -> Result<()> {
+    let option = if cli.output_mode == cli::OutputMode::Cooked {
+        "--output-mode cooked"
+    } else if cli.replay_output_bytes > 0 {
+        "--replay-output-bytes"
+    } else if cli.initial_screen.is_some() {
+        "--initial-screen"
+    } else if cli.record_pause_on_mute {
+        "--record-pause-on-mute"
+    } else {
+        return Ok(());
+    };
+
+    anyhow::bail!("{option} needs the terminal emulator, which --no-emulator disables")
+}
+
+fn read_initial_screen(
+    path: Option<&std::path::Path>,
+) -> Result<Option<(&std::path::Path, String)>> {
+    path.map(|path| {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read initial screen {}", path.display()))?;
+
+        Ok((path, content))
+    })
+    .transpose()
 }
 
-fn build_session(size: &cli::Size) -> Session {
-    Session::new(size.cols(), size.rows())
+fn load_theme(spec: Option<&str>) -> Theme {
+    match spec.map(Theme::load) {
+        Some(Ok(theme)) => theme,
+
+        Some(Err(e)) => {
+            log::warning!("{e:#}, falling back to dark theme");
+            Theme::dark()
+        }
+
+        None => Theme::dark(),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn start_stdio_api(
     command_tx: mpsc::Sender<Command>,
-    clients_tx: mpsc::Sender<session::Client>,
+    subscription: session::Subscription,
     sub: api::Subscription,
+    format: api::stdio::OutputFormat,
+    filter: api::stdio::CommandFilter,
+    hook: Option<session::CommandHook>,
+    max_command_bytes: usize,
+    started_rx: oneshot::Receiver<api::stdio::Started>,
 ) -> JoinHandle<Result<()>> {
-    tokio::spawn(api::stdio::start(command_tx, clients_tx, sub))
+    tokio::spawn(api::stdio::start(
+        command_tx,
+        subscription,
+        sub,
+        format,
+        filter,
+        hook,
+        max_command_bytes,
+        started_rx,
+    ))
+}
+
+fn command_filter(
+    allow: Option<api::stdio::CommandList>,
+    deny: Option<api::stdio::CommandList>,
+) -> api::stdio::CommandFilter {
+    match (allow, deny) {
+        (Some(list), _) => api::stdio::CommandFilter::Allow(list),
+        (None, Some(list)) => api::stdio::CommandFilter::Deny(list),
+        (None, None) => api::stdio::CommandFilter::AllowAll,
+    }
+}
+
+fn start_recorder(
+    path: Option<&std::path::Path>,
+    idle_limit: Option<f64>,
+    coalesce_ms: Option<u64>,
+    pause_on_mute: bool,
+    size_override: (Option<usize>, Option<usize>),
+    compress: bool,
+    session: &Session,
+) -> Result<Option<JoinHandle<Result<()>>>> {
+    path.map(|path| {
+        let compress = compress || record::is_compressed_path(path);
+        let coalesce_window = coalesce_ms.map(|ms| ms as f64 / 1000.0);
+
+        record::start(
+            path,
+            session.subscribe(),
+            idle_limit,
+            coalesce_window,
+            pause_on_mute,
+            size_override,
+            compress,
+        )
+    })
+    .transpose()
+}
+
+fn log_level(cli: &cli::Cli) -> log::Level {
+    if cli.quiet {
+        log::Level::Quiet
+    } else if cli.banner {
+        log::Level::Status
+    } else {
+        log::Level::Warn
+    }
+}
+
+fn output_format(pretty: bool, protocol: api::Protocol) -> Result<api::stdio::OutputFormat> {
+    match (protocol, pretty) {
+        (api::Protocol::Msgpack, true) => {
+            anyhow::bail!("--pretty can't be combined with --protocol msgpack")
+        }
+        (api::Protocol::Msgpack, false) => Ok(api::stdio::OutputFormat::MessagePack),
+        (api::Protocol::Json, true) => Ok(api::stdio::OutputFormat::Pretty),
+        (api::Protocol::Json, false) => Ok(api::stdio::OutputFormat::Compact),
+    }
+}
+
+/// Runs `--setup-command` in the shell started in the terminal, followed by a
+/// command printing a marker, and drops the output read until the marker
+/// shows up. The echo of the typed line doesn't contain the marker, as its two
+/// halves are separate arguments there. Output read along with the marker,
+/// following it, is returned.
+///
+/// Both lines start with a space, which keeps them out of the shell's history
+/// when it's set up to ignore such lines (e.g. bash's `ignorespace`).
+async fn run_setup(
+    command: &str,
+    timeout: Duration,
+    input_tx: &mpsc::Sender<Vec<u8>>,
+    output_rx: &mut mpsc::Receiver<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let id = uuid::Uuid::new_v4().simple();
+    let marker = format!("ht-setup-{id}").into_bytes();
+    let input = format!(" {command}\r printf '%s-%s\\n' ht-setup {id}\r");
+    input_tx.send(input.into_bytes()).await?;
+
+    let deadline = Instant::now() + timeout;
+    let mut seen = Vec::new();
+
+    loop {
+        let data = tokio::time::timeout_at(deadline, output_rx.recv())
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "setup command didn't finish within {} ms",
+                    timeout.as_millis()
+                )
+            })?;
+
+        // the process exited, which the event loop reports
+        let Some(data) = data else {
+            return Ok(Vec::new());
+        };
+
+        seen.extend_from_slice(&data);
+
+        if let Some(rest) = after_marker(&seen, &marker) {
+            return Ok(rest.to_vec());
+        }
+
+        // keep what could be the start of the marker and its line break
+        seen.drain(..seen.len().saturating_sub(marker.len() + 1));
+    }
+}
+
+/// Output following the marker and the line break after it, once both are
+/// there.
+fn after_marker<'a>(output: &'a [u8], marker: &[u8]) -> Option<&'a [u8]> {
+    let pos = output.windows(marker.len()).position(|w| w == marker)?;
+    let rest = &output[pos + marker.len()..];
+
+    rest.strip_prefix(b"\r\n")
+        .or_else(|| rest.strip_prefix(b"\n"))
 }
 
 fn start_pty(
@@ -50,14 +480,15 @@ fn start_pty(
     size: &cli::Size,
     input_rx: mpsc::Receiver<Vec<u8>>,
     output_tx: mpsc::Sender<Vec<u8>>,
-    resize_rx: mpsc::Receiver<(u16, u16)>,
-) -> Result<JoinHandle<Result<()>>> {
+    resize_rx: mpsc::Receiver<(u16, u16, bool)>,
+    options: pty::SpawnOptions,
+) -> Result<(u32, JoinHandle<Result<pty::SessionOutcome>>)> {
     let winsize = **size;
 
     #[cfg(unix)]
     let (command_str, initial_input) = {
         let cmd = command.join(" ");
-        eprintln!("launching \"{}\" in terminal of size {}", cmd, size);
+        log::status!("launching \"{}\" in terminal of size {}", cmd, size);
         (cmd, None)
     };
 
@@ -70,15 +501,16 @@ fn start_pty(
                     .map(|s| pty::escape_arg(s))
                     .collect::<Vec<_>>()
                     .join(" ");
-                eprintln!("launching \"{}\" in terminal of size {}", cmd, size);
+                log::status!("launching \"{}\" in terminal of size {}", cmd, size);
                 (cmd, None)
             }
             pty::CommandKind::ShellSyntax => {
                 let user_cmd = command.join(" ");
-                eprintln!(
+                log::status!(
                     "launching cmd.exe for shell command \"{}\" \
                      in terminal of size {}",
-                    user_cmd, size
+                    user_cmd,
+                    size
                 );
                 let inject = format!("{}\r\nexit\r\n", user_cmd);
                 ("cmd.exe".to_string(), Some(inject.into_bytes()))
@@ -89,10 +521,11 @@ fn start_pty(
                     .map(|s| pty::escape_arg(s))
                     .collect::<Vec<_>>()
                     .join(" ");
-                eprintln!(
+                log::status!(
                     "launching cmd.exe for builtin \"{}\" \
                      in terminal of size {}",
-                    user_cmd, size
+                    user_cmd,
+                    size
                 );
                 let inject = format!("{}\r\nexit\r\n", user_cmd);
                 ("cmd.exe".to_string(), Some(inject.into_bytes()))
@@ -100,83 +533,210 @@ fn start_pty(
         }
     };
 
-    Ok(tokio::spawn(pty::spawn(
+    let (pid, future) = pty::spawn_with_options(
         command_str,
         winsize,
         input_rx,
         output_tx,
         resize_rx,
         initial_input,
-    )?))
+        options,
+    )?;
+
+    Ok((pid, tokio::spawn(future)))
+}
+
+fn spawn_options(cli: &cli::Cli, input_control: pty::InputControl) -> pty::SpawnOptions {
+    #[cfg(unix)]
+    let process_group = if cli.process_group {
+        pty::ProcessGroup::Group
+    } else {
+        pty::ProcessGroup::Session
+    };
+
+    pty::SpawnOptions {
+        #[cfg(unix)]
+        process_group,
+        #[cfg(unix)]
+        pty_mode: cli.pty_mode,
+        #[cfg(unix)]
+        pass_fds: cli.pass_fds.clone(),
+        #[cfg(unix)]
+        env: cli
+            .unset_env
+            .iter()
+            .map(|name| (name.clone(), None))
+            .chain(
+                cli.env
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Some(value.clone()))),
+            )
+            .collect(),
+        #[cfg(unix)]
+        path_prepend: cli.path_prepend.clone(),
+        #[cfg(unix)]
+        shell: shell(cli.shell.clone()),
+        #[cfg(unix)]
+        argv: cli.no_shell.then(|| cli.command.clone()),
+        #[cfg(unix)]
+        read_buffer_size: Some(cli.read_buffer_size),
+        #[cfg(unix)]
+        write_chunk_size: cli.write_chunk_size,
+        cwd: cli.cwd.clone(),
+        input_control: Some(input_control),
+    }
 }
 
+/// Shell running the command line: the one given with `--shell`, or else the
+/// user's one from `$SHELL`, or else `/bin/sh` (left to `pty`).
+#[cfg(unix)]
+fn shell(shell: Option<std::path::PathBuf>) -> Option<std::path::PathBuf> {
+    shell.or_else(|| {
+        std::env::var_os("SHELL")
+            .filter(|shell| !shell.is_empty())
+            .map(Into::into)
+    })
+}
+
+fn web_root(dir: Option<std::path::PathBuf>, exclusive: bool) -> Option<api::http::WebRoot> {
+    dir.map(|dir| api::http::WebRoot { dir, exclusive })
+}
+
+/// Binds all of the listen addresses before serving on any, so that a single
+/// address which can't be bound fails the start instead of leaving the server
+/// reachable on only some of them.
 async fn start_http_api(
-    listen_addr: Option<SocketAddr>,
+    listen_addrs: Vec<SocketAddr>,
+    base_path: String,
+    theme: Theme,
+    web_root: Option<api::http::WebRoot>,
     clients_tx: mpsc::Sender<session::Client>,
-) -> Result<()> {
-    if let Some(addr) = listen_addr {
-        let listener = TcpListener::bind(addr).context("cannot start HTTP listener")?;
-        tokio::spawn(api::http::start(listener, clients_tx).await?);
+    handle: session::Handle,
+) -> Result<Vec<SocketAddr>> {
+    if listen_addrs.is_empty() {
+        return Ok(Vec::new());
     }
 
-    Ok(())
+    let listeners = listen_addrs
+        .into_iter()
+        .map(|addr| {
+            TcpListener::bind(addr).with_context(|| format!("cannot start HTTP listener on {addr}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let addrs = listeners
+        .iter()
+        .map(|listener| listener.local_addr())
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    tokio::spawn(
+        api::http::start(listeners, base_path, theme, web_root, clients_tx, handle).await?,
+    );
+
+    Ok(addrs)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_event_loop(
     mut output_rx: mpsc::Receiver<Vec<u8>>,
-    input_tx: mpsc::Sender<Vec<u8>>,
+    mut input: Input,
     mut command_rx: mpsc::Receiver<Command>,
+    mut snapshot_rx: mpsc::Receiver<session::SnapshotRequest>,
     mut clients_rx: mpsc::Receiver<session::Client>,
-    mut session: Session,
-    mut api_handle: JoinHandle<Result<()>>,
-    resize_tx: mpsc::Sender<(u16, u16)>,
-) -> Result<()> {
+    session: &mut Session,
+    api_handle: &mut JoinHandle<Result<()>>,
+    mut resizer: Resizer,
+) -> Result<Shutdown> {
     let mut serving = true;
+    // reserves room for queued input without borrowing `input` in the select
+    let queue_tx = input.tx.clone();
+    let mut held = VecDeque::new();
 
     loop {
+        let watch_deadline = session.watch_deadline();
+        let flush_deadline = session.flush_deadline();
+
         tokio::select! {
             result = output_rx.recv() => {
                 match result {
                     Some(data) => {
-                        session.output(String::from_utf8_lossy(&data).to_string());
+                        session.push_output(data);
                     },
 
                     None => {
-                        eprintln!("process exited, shutting down...");
-                        break;
+                        session.flush_output();
+                        log::status!("process exited, shutting down...");
+                        return Ok(Shutdown::ProcessExited);
                     }
                 }
             }
 
-            command = command_rx.recv() => {
+            // Input commands are held while the input queue is full, in order,
+            // and the commands after them are handled meanwhile. Once too many
+            // are held, commands are left unread, holding up the controller
+            // sending them.
+            command = command_rx.recv(), if held.len() < MAX_HELD_COMMANDS => {
                 match command {
-                    Some(Command::Input(seqs)) => {
-                        let data = command::seqs_to_bytes(&seqs, session.cursor_key_app_mode());
-                        input_tx.send(data).await?;
-                    }
-
-                    Some(Command::Snapshot) => {
-                        session.snapshot();
+                    Some(command) if input.holds(&command, !held.is_empty()) => {
+                        held.push_back(command);
                     }
 
-                    Some(Command::Resize(cols, rows)) => {
-                        session.resize(cols, rows);
-                        let cols_u16 = u16::try_from(cols).unwrap_or(u16::MAX);
-                        let rows_u16 = u16::try_from(rows).unwrap_or(u16::MAX);
-                        let _ = resize_tx.send((cols_u16, rows_u16)).await;
+                    Some(command) => {
+                        handle_command(command, session, &mut input, &mut resizer).await?;
                     }
 
                     None => {
-                        eprintln!("stdin closed, shutting down...");
-                        break;
+                        log::status!("stdin closed, shutting down...");
+                        return Ok(Shutdown::StdinClosed);
                     }
                 }
             }
 
+            // served apart from the commands, which may be held up
+            Some(reply) = snapshot_rx.recv() => {
+                session.flush_output();
+                resizer.apply(session).await;
+                let _ = reply.send(session.screen_snapshot());
+            }
+
+            // held commands are handled as the queue makes room
+            _ = std::future::ready(()), if held.front().is_some_and(|c| !input.holds(c, false)) => {
+                if let Some(command) = held.pop_front() {
+                    handle_command(command, session, &mut input, &mut resizer).await?;
+                }
+            }
+
+            _ = tokio::time::sleep_until(resizer.deadline), if resizer.pending.is_some() => {
+                resizer.apply(session).await;
+            }
+
+            _ = sleep_until(flush_deadline), if flush_deadline.is_some() => {
+                session.flush_output();
+            }
+
+            // queued input is sent on as the PTY side makes room for it
+            permit = queue_tx.reserve(), if !input.queue.is_empty() => {
+                if let Some(data) = input.queue.pop_front() {
+                    permit?.send(data);
+                }
+
+                if input.closed && input.queue.is_empty() {
+                    input.close();
+                }
+            }
+
+            Some(bytes) = input.rejected_rx.recv() => {
+                session.error(format!("input rejected, {bytes} bytes over the backpressure limit"));
+            }
+
+            _ = sleep_until(watch_deadline), if watch_deadline.is_some() => {
+                session.expire_watches();
+            }
+
             client = clients_rx.recv(), if serving => {
                 match client {
                     Some(client) => {
-                        client.accept(session.subscribe());
+                        session.accept(client);
                     }
 
                     None => {
@@ -185,12 +745,342 @@ async fn run_event_loop(
                 }
             }
 
-            _ = &mut api_handle => {
-                eprintln!("stdin closed, shutting down...");
-                break;
+            _ = &mut *api_handle => {
+                session.flush_output();
+                log::status!("stdin closed, shutting down...");
+                return Ok(Shutdown::StdinClosed);
+            }
+        }
+    }
+}
+
+async fn handle_command(
+    command: Command,
+    session: &mut Session,
+    input: &mut Input,
+    resizer: &mut Resizer,
+) -> Result<()> {
+    // process held output first so the command sees all of it
+    session.flush_output();
+
+    if command.needs_emulator() && !session.has_emulator() {
+        session.error(format!(
+            "command not available, {}",
+            session::EmulatorDisabled
+        ));
+        return Ok(());
+    }
+
+    if !matches!(command, Command::Resize(..)) {
+        // apply pending resize first so the command sees the final size
+        resizer.apply(session).await;
+    }
+
+    match command {
+        Command::Input(seqs) => {
+            let data = session.input_bytes(&seqs);
+            input.send(session, data)?;
+        }
+
+        Command::Eof => {
+            input.send(session, vec![command::VEOF])?;
+        }
+
+        Command::CloseStdin => {
+            input.close();
+        }
+
+        Command::Snapshot(scroll_region, buffer) => {
+            session.snapshot(scroll_region, buffer);
+        }
+
+        Command::State => {
+            session.state();
+        }
+
+        Command::Grid => {
+            session.grid();
+        }
+
+        Command::ExportFrame => {
+            session.export_frame();
+        }
+
+        Command::Cwd => {
+            session.cwd();
+        }
+
+        Command::Env(name) => {
+            session.env(name);
+        }
+
+        Command::ListProcesses => {
+            session.processes();
+        }
+
+        Command::KillProcess(pid, signal) => {
+            session.kill_process(pid, signal);
+        }
+
+        Command::Signal(signal) => {
+            session.signal(signal);
+        }
+
+        Command::Refresh(settle, snapshot) => {
+            session.refresh(settle, snapshot);
+        }
+
+        Command::SetBackpressure(backpressure) => {
+            input.backpressure_tx.send_replace(backpressure);
+        }
+
+        Command::InputPending => {
+            session.input_pending(input.pending_bytes());
+        }
+
+        Command::SaveScrollback(path, format) => {
+            session.save_scrollback(path, format);
+        }
+
+        Command::SetRenderCursor(visible) => {
+            session.set_render_cursor(visible);
+        }
+
+        Command::InputChunked(total_bytes, chunks) => {
+            session.input_chunked(total_bytes, chunks);
+        }
+
+        Command::ScriptLine(index, command, reply) => {
+            let line = session.watch_script_command(index, command, reply);
+            input.send(session, line.into_bytes())?;
+        }
+
+        Command::OutputTail(size) => {
+            session.output_tail(size);
+        }
+
+        Command::Mute => {
+            session.set_muted(true);
+        }
+
+        Command::Unmute(snapshot) => {
+            session.set_muted(false);
+
+            if snapshot {
+                session.snapshot(false, ScreenBuffer::Active);
+            }
+        }
+
+        Command::FreezeDisplay => {
+            session.set_frozen(true);
+        }
+
+        Command::UnfreezeDisplay => {
+            session.set_frozen(false);
+        }
+
+        Command::Mark => {
+            session.mark();
+        }
+
+        Command::OutputSince(seq, format) => {
+            session.output_since(seq, format);
+        }
+
+        Command::InputWithEcho(seqs, wait) => {
+            session.watch_echo(wait);
+
+            let data = session.input_bytes(&seqs);
+            input.send(session, data)?;
+        }
+
+        Command::Query(query) => {
+            // watch first, so that no part of the response is missed
+            session.watch_query(&query);
+            input.send(session, query.send.into_bytes())?;
+        }
+
+        Command::WaitForText(wait) => {
+            session.watch_text(wait);
+        }
+
+        // consumed by the STDIO API, never forwarded
+        Command::Sleep(_) | Command::InputAndWait(_, _) | Command::TypeWithEcho(_, _) => (),
+
+        Command::Screenshot(format) => {
+            session.screenshot(format);
+        }
+
+        Command::ResizeAndSettle(cols, rows, settle) => {
+            // the wait starts once the program has been told about the new size
+            resizer.request(cols, rows, true);
+            resizer.apply(session).await;
+            session.watch_settle(settle);
+        }
+
+        Command::Resize(_, _, false) if !resizer.quiet_resize => {
+            session.error("resize without SIGWINCH not supported, the command has the terminal as its controlling terminal (see --process-group)".to_owned());
+        }
+
+        Command::Resize(cols, rows, signal) => {
+            resizer.request(cols, rows, signal);
+
+            if resizer.debounce.is_zero() {
+                resizer.apply(session).await;
             }
         }
     }
 
     Ok(())
 }
+
+async fn sleep_until(deadline: Option<std::time::Instant>) {
+    if let Some(deadline) = deadline {
+        tokio::time::sleep_until(Instant::from_std(deadline)).await;
+    }
+}
+
+enum Shutdown {
+    ProcessExited,
+    StdinClosed,
+}
+
+/// Input commands held at most while the input queue is full (see
+/// `Input::holds`).
+const MAX_HELD_COMMANDS: usize = 256;
+
+/// Input side of the PTY: where input goes and how it's held back when the
+/// process doesn't read it fast enough.
+struct Input {
+    tx: mpsc::Sender<Vec<u8>>,
+    backpressure_tx: watch::Sender<pty::Backpressure>,
+    rejected_rx: mpsc::UnboundedReceiver<usize>,
+    pending: Arc<AtomicUsize>,
+    /// Input the PTY side can't take yet (e.g. blocked by backpressure), sent
+    /// on as it makes room, so that the event loop never waits for it. It's
+    /// capped at the backpressure's `max_bytes` (see `has_room`).
+    queue: VecDeque<Vec<u8>>,
+    /// Tells the PTY side to end the input once it got the input sent before
+    /// (see `pty::InputControl`).
+    close_stdin_tx: watch::Sender<bool>,
+    /// Set by `closeStdin`: no more input is sent. The PTY itself stays open,
+    /// closing it would hang up the terminal.
+    closed: bool,
+}
+
+impl Input {
+    fn send(&mut self, session: &Session, data: Vec<u8>) -> Result<()> {
+        if self.closed {
+            session.error("input ignored, stdin is closed".to_owned());
+
+            return Ok(());
+        }
+
+        // queued input goes first, keeping the order
+        let data = if self.queue.is_empty() {
+            match self.tx.try_send(data) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(data)) => data,
+                Err(e @ TrySendError::Closed(_)) => return Err(e.into()),
+            }
+        } else {
+            data
+        };
+
+        self.queue.push_back(data);
+
+        Ok(())
+    }
+
+    /// Closes stdin, once the input queued before is on its way to the PTY.
+    fn close(&mut self) {
+        self.closed = true;
+
+        if self.queue.is_empty() {
+            let _ = self.close_stdin_tx.send(true);
+        }
+    }
+
+    /// Tells whether `command` has to wait before it's handled: it sends input
+    /// while the queue is full, or it has to stay behind held input (`behind`),
+    /// like `closeStdin`.
+    fn holds(&self, command: &Command, behind: bool) -> bool {
+        if command.sends_input() {
+            behind || !self.has_room()
+        } else {
+            behind && matches!(command, Command::CloseStdin)
+        }
+    }
+
+    /// Tells whether more input can be taken in, i.e. the queue is empty or
+    /// below the backpressure limit. Once it's reached, input commands are
+    /// held until the PTY side catches up, so that the input they'd bring
+    /// doesn't pile up in memory.
+    fn has_room(&self) -> bool {
+        self.queue.is_empty() || self.queued_bytes() < self.backpressure_tx.borrow().max_bytes
+    }
+
+    fn queued_bytes(&self) -> usize {
+        self.queue.iter().map(Vec::len).sum()
+    }
+
+    /// Input taken in but not written to the PTY yet.
+    fn pending_bytes(&self) -> usize {
+        self.pending.load(Ordering::Relaxed) + self.queued_bytes()
+    }
+}
+
+/// Coalesces resize commands arriving within the debounce window, so that
+/// only the final size reaches the PTY and the session.
+///
+/// `quiet_resize` tells whether the size can be changed without the program
+/// getting SIGWINCH, which the kernel sends whenever the command has the
+/// terminal as its controlling terminal.
+struct Resizer {
+    resize_tx: mpsc::Sender<(u16, u16, bool)>,
+    debounce: Duration,
+    quiet_resize: bool,
+    pending: Option<(usize, usize, bool)>,
+    deadline: Instant,
+}
+
+impl Resizer {
+    fn new(
+        resize_tx: mpsc::Sender<(u16, u16, bool)>,
+        debounce: Duration,
+        quiet_resize: bool,
+    ) -> Self {
+        Self {
+            resize_tx,
+            debounce,
+            quiet_resize,
+            pending: None,
+            deadline: Instant::now(),
+        }
+    }
+
+    /// Queues a resize. The program is signalled if any of the coalesced
+    /// requests asked for it.
+    fn request(&mut self, cols: usize, rows: usize, signal: bool) {
+        let signal = match self.pending {
+            Some((_, _, pending_signal)) => signal || pending_signal,
+
+            None => {
+                self.deadline = Instant::now() + self.debounce;
+
+                signal
+            }
+        };
+
+        self.pending = Some((cols, rows, signal));
+    }
+
+    async fn apply(&mut self, session: &mut Session) {
+        if let Some((cols, rows, signal)) = self.pending.take() {
+            session.resize(cols, rows);
+            let cols_u16 = u16::try_from(cols).unwrap_or(u16::MAX);
+            let rows_u16 = u16::try_from(rows).unwrap_or(u16::MAX);
+            let _ = self.resize_tx.send((cols_u16, rows_u16, signal)).await;
+        }
+    }
+}
@@ -16,6 +16,13 @@ pub fn set_non_blocking(fd: &RawFd) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Tells whether the error is EIO, which is what reading or writing a PTY
+/// master reports (on Linux) once no process has the slave side open.
+#[cfg(unix)]
+pub fn is_eio(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(nix::libc::EIO)
+}
+
 #[allow(dead_code)]
 pub fn read<R: Read + ?Sized>(source: &mut R, buf: &mut [u8]) -> io::Result<Option<usize>> {
     match source.read(buf) {
@@ -24,8 +31,6 @@ pub fn read<R: Read + ?Sized>(source: &mut R, buf: &mut [u8]) -> io::Result<Opti
         Err(e) => {
             if e.kind() == ErrorKind::WouldBlock {
                 Ok(None)
-            } else if e.raw_os_error().is_some_and(|code| code == 5) {
-                Ok(Some(0))
             } else {
                 Err(e)
             }
@@ -41,8 +46,6 @@ pub fn write<W: Write + ?Sized>(sink: &mut W, buf: &[u8]) -> io::Result<Option<u
         Err(e) => {
             if e.kind() == ErrorKind::WouldBlock {
                 Ok(None)
-            } else if e.raw_os_error().is_some_and(|code| code == 5) {
-                Ok(Some(0))
             } else {
                 Err(e)
             }
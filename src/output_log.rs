@@ -0,0 +1,81 @@
+// Plain capture of the command's output, for `--output-log <FILE>`: the bytes
+// read from the terminal, appended to the file as they are, without timing or
+// any framing, so that it can be viewed with `cat` or `less -R`. With
+// `--strip-ansi`, escape sequences and control characters (apart from line
+// feeds) are left out instead, the same way as for `outputSince` in `text`
+// format, leaving plain text.
+
+use crate::escape::Scanner;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+pub struct OutputLog<W: Write = BufWriter<File>> {
+    writer: W,
+    scanner: Option<Scanner>,
+}
+
+impl OutputLog {
+    /// Opens the log file for appending, creating it if needed, so that an
+    /// unwritable path is reported before the session starts.
+    pub fn open(path: &Path, strip_ansi: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open output log {}", path.display()))?;
+
+        Ok(Self::new(BufWriter::new(file), strip_ansi))
+    }
+}
+
+impl<W: Write> OutputLog<W> {
+    pub fn new(writer: W, strip_ansi: bool) -> Self {
+        Self {
+            writer,
+            scanner: strip_ansi.then(Scanner::new),
+        }
+    }
+
+    /// Writes output, flushing the writer so that the log is complete up to
+    /// it even if ht is killed.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.scanner {
+            Some(scanner) => {
+                let text = scanner.feed_text(&String::from_utf8_lossy(data));
+                self.writer.write_all(text.as_bytes())?;
+            }
+
+            None => self.writer.write_all(data)?,
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OutputLog;
+
+    #[test]
+    fn raw() {
+        let mut log = OutputLog::new(Vec::new(), false);
+        log.write(b"\x1b[1mbold\x1b[0m\r\n").unwrap();
+        log.write(&[0xc3]).unwrap();
+        log.write(&[0xa9]).unwrap();
+
+        assert_eq!(log.writer, b"\x1b[1mbold\x1b[0m\r\n\xc3\xa9");
+    }
+
+    #[test]
+    fn strip_ansi() {
+        let mut log = OutputLog::new(Vec::new(), true);
+        log.write(b"\x1b[1;32m$ \x1b[0mls\r\n\x1b]0;ti").unwrap();
+        log.write(b"tle\x07a\x1b[Kb\n").unwrap();
+
+        assert_eq!(log.writer, b"$ ls\nab\n");
+    }
+}
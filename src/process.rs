@@ -0,0 +1,304 @@
+// Introspection of the process running in the terminal, read from /proc
+// where available. Other platforms report io::ErrorKind::Unsupported.
+//
+// The process looked at is the leader of the terminal's foreground process
+// group: the shell while it waits at a prompt, or the job it's running. That's
+// not necessarily the process ht started, which may be a wrapper (`sh -c`)
+// around it. Without a controlling terminal (--process-group) it is.
+//
+// Processes of the session, as listed by `processes`, are all processes
+// started from the terminal, including background jobs which a shell puts in
+// process groups of their own: they're in the terminal's session led by the
+// process ht started, or in its process group with --process-group.
+
+use std::io;
+use std::path::PathBuf;
+
+/// Current working directory of the process.
+#[cfg(target_os = "linux")]
+pub fn cwd(pid: u32) -> io::Result<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/cwd", foreground_pid(pid)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cwd(_pid: u32) -> io::Result<PathBuf> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Value of an environment variable of the process (`None` if it's not set).
+///
+/// This is the environment the process was started with: changes it makes
+/// later, like `export` in a shell, aren't visible from the outside.
+#[cfg(target_os = "linux")]
+pub fn env(pid: u32, name: &str) -> io::Result<Option<String>> {
+    let environ = std::fs::read(format!("/proc/{}/environ", foreground_pid(pid)))?;
+
+    Ok(find_var(&environ, name))
+}
+
+/// Process started in the session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    pub pgid: u32,
+    pub command: String,
+}
+
+/// Processes of the session started by the process `pid`, ordered by PID.
+#[cfg(target_os = "linux")]
+pub fn processes(pid: u32) -> io::Result<Vec<ProcessInfo>> {
+    let mut processes = Vec::new();
+
+    for entry in std::fs::read_dir("/proc")? {
+        let Some(other) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+
+        // processes may exit while being looked at
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{other}/stat")) else {
+            continue;
+        };
+
+        let Some(ids) = stat_ids(&stat).filter(|ids| ids.sid == pid || ids.pgid == pid) else {
+            continue;
+        };
+
+        let cmdline = std::fs::read(format!("/proc/{other}/cmdline")).unwrap_or_default();
+
+        processes.push(ProcessInfo {
+            pid: other,
+            ppid: ids.ppid,
+            pgid: ids.pgid,
+            command: command_line(&cmdline).unwrap_or(ids.comm),
+        });
+    }
+
+    processes.sort_by_key(|p| p.pid);
+
+    Ok(processes)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn processes(_pid: u32) -> io::Result<Vec<ProcessInfo>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sends a signal (name like `TERM` or `SIGTERM`) to the process `target`,
+/// which must be one of the `processes` of `pid`.
+#[cfg(target_os = "linux")]
+pub fn kill(pid: u32, target: u32, signal: &str) -> io::Result<()> {
+    use nix::sys::signal;
+    use nix::unistd::Pid;
+
+    let signal = parse_signal(signal)?;
+
+    if !processes(pid)?.iter().any(|p| p.pid == target) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("process {target} isn't part of the session"),
+        ));
+    }
+
+    signal::kill(Pid::from_raw(target as i32), signal)?;
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn kill(_pid: u32, _target: u32, _signal: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sends a signal (name like `USR1` or `SIGUSR1`, or number) to the process
+/// itself, regardless of what runs in the foreground.
+#[cfg(unix)]
+pub fn signal(pid: u32, signal: &str) -> io::Result<()> {
+    use nix::unistd::Pid;
+
+    nix::sys::signal::kill(Pid::from_raw(pid as i32), parse_signal(signal)?)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn signal(_pid: u32, _signal: &str) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn parse_signal(signal: &str) -> io::Result<nix::sys::signal::Signal> {
+    use nix::sys::signal::Signal;
+
+    let parsed = match signal.parse::<i32>() {
+        Ok(number) => Signal::try_from(number).ok(),
+        Err(_) => format!("SIG{}", signal.strip_prefix("SIG").unwrap_or(signal))
+            .parse()
+            .ok(),
+    };
+
+    parsed.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown signal: {signal}"),
+        )
+    })
+}
+
+/// Sends SIGWINCH to the terminal's foreground process group without changing
+/// the size, which makes programs handling it redraw the screen.
+#[cfg(target_os = "linux")]
+pub fn redraw(pid: u32) -> io::Result<()> {
+    use nix::sys::signal::{self, Signal};
+    use nix::unistd::Pid;
+
+    signal::killpg(Pid::from_raw(foreground_pid(pid) as i32), Signal::SIGWINCH)?;
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn redraw(_pid: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn foreground_pid(pid: u32) -> u32 {
+    std::fs::read_to_string(format!("/proc/{pid}/stat"))
+        .ok()
+        .and_then(|stat| foreground_group(&stat))
+        .filter(|pgid| std::path::Path::new(&format!("/proc/{pgid}")).exists())
+        .unwrap_or(pid)
+}
+
+/// Extracts the foreground process group of the process's controlling
+/// terminal (`tpgid`) from `/proc/<pid>/stat`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn foreground_group(stat: &str) -> Option<u32> {
+    // the command name may contain spaces and parens, fields follow the last paren
+    let fields = &stat[stat.rfind(')')? + 1..];
+
+    fields
+        .split_whitespace()
+        .nth(5)?
+        .parse::<i64>()
+        .ok()
+        .filter(|&tpgid| tpgid > 0)
+        .map(|tpgid| tpgid as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn env(_pid: u32, _name: &str) -> io::Result<Option<String>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+struct StatIds {
+    comm: String,
+    ppid: u32,
+    pgid: u32,
+    sid: u32,
+}
+
+/// Extracts the command name, parent PID, process group and session from
+/// `/proc/<pid>/stat`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn stat_ids(stat: &str) -> Option<StatIds> {
+    let comm = &stat[stat.find('(')? + 1..stat.rfind(')')?];
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(1);
+    let mut next = || fields.next()?.parse().ok();
+
+    Some(StatIds {
+        comm: comm.to_owned(),
+        ppid: next()?,
+        pgid: next()?,
+        sid: next()?,
+    })
+}
+
+/// Joins NUL separated arguments from `/proc/<pid>/cmdline` with spaces
+/// (`None` when there are none, e.g. for a zombie).
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn command_line(cmdline: &[u8]) -> Option<String> {
+    let args: Vec<_> = cmdline
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+
+    (!args.is_empty()).then(|| args.join(" "))
+}
+
+/// Looks a variable up in NUL separated `NAME=value` entries.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn find_var(environ: &[u8], name: &str) -> Option<String> {
+    environ
+        .split(|&b| b == 0)
+        .find_map(|entry| entry.strip_prefix(name.as_bytes())?.strip_prefix(b"="))
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StatIds, command_line, find_var, foreground_group, stat_ids};
+
+    #[test]
+    fn find_env_var() {
+        let environ = b"HOME=/root\0PATH=/bin:/usr/bin\0PATHEXT=x\0EMPTY=\0";
+
+        assert_eq!(find_var(environ, "PATH"), Some("/bin:/usr/bin".to_owned()));
+        assert_eq!(find_var(environ, "EMPTY"), Some(String::new()));
+        assert_eq!(find_var(environ, "PAT"), None);
+        assert_eq!(find_var(environ, "SHELL"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_signal_names_and_numbers() {
+        use nix::sys::signal::Signal;
+
+        assert_eq!(super::parse_signal("USR1").unwrap(), Signal::SIGUSR1);
+        assert_eq!(super::parse_signal("SIGTERM").unwrap(), Signal::SIGTERM);
+        assert_eq!(super::parse_signal("9").unwrap(), Signal::SIGKILL);
+        assert!(super::parse_signal("NOPE").is_err());
+        assert!(super::parse_signal("0").is_err());
+        assert!(super::parse_signal("999").is_err());
+        assert!(super::parse_signal("usr1").is_err());
+    }
+
+    #[test]
+    fn parse_foreground_group() {
+        let stat = "4242 (my (odd) cmd) S 4241 4242 4242 34816 4300 4194560 0 0";
+        assert_eq!(foreground_group(stat), Some(4300));
+
+        let stat = "4242 (sh) S 4241 4242 4242 0 -1 4194560 0 0";
+        assert_eq!(foreground_group(stat), None);
+    }
+
+    #[test]
+    fn parse_stat_ids() {
+        let stat = "4300 (my (odd) cmd) S 4242 4300 4241 34816 4300 4194560 0 0";
+
+        assert_eq!(
+            stat_ids(stat),
+            Some(StatIds {
+                comm: "my (odd) cmd".to_owned(),
+                ppid: 4242,
+                pgid: 4300,
+                sid: 4241,
+            })
+        );
+
+        assert_eq!(stat_ids("4300 (sh) S 4242"), None);
+    }
+
+    #[test]
+    fn join_command_line() {
+        assert_eq!(
+            command_line(b"sleep\x0010\x00"),
+            Some("sleep 10".to_owned())
+        );
+        assert_eq!(command_line(b""), None);
+    }
+}
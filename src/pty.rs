@@ -1,9 +1,20 @@
+#[cfg(windows)]
+use anyhow::Context;
 use anyhow::Result;
+#[cfg(unix)]
+use std::collections::VecDeque;
 use std::future::Future;
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
 
 // Platform-specific imports and implementations
 #[cfg(unix)]
+use crate::command;
+#[cfg(unix)]
+use crate::log;
+#[cfg(unix)]
 use crate::nbio;
 #[cfg(unix)]
 use nix::libc;
@@ -16,9 +27,11 @@ use nix::sys::wait;
 #[cfg(unix)]
 use nix::unistd::{self, ForkResult, Pid};
 #[cfg(unix)]
+use std::convert::Infallible;
+#[cfg(unix)]
 use std::env;
 #[cfg(unix)]
-use std::ffi::{CString, NulError};
+use std::ffi::{CString, NulError, OsStr, OsString};
 #[cfg(unix)]
 use std::fs::File;
 #[cfg(unix)]
@@ -26,54 +39,42 @@ use std::io;
 #[cfg(unix)]
 use std::mem::ManuallyDrop;
 #[cfg(unix)]
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
 #[cfg(unix)]
 use tokio::io::unix::AsyncFd;
 
 #[cfg(windows)]
 use std::ffi::c_void;
 #[cfg(windows)]
-use std::mem::{size_of, zeroed};
-#[cfg(windows)]
-use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
-#[cfg(windows)]
-use windows::Win32::Foundation::{HANDLE, INVALID_HANDLE_VALUE, WAIT_OBJECT_0};
+use std::fs::File;
 #[cfg(windows)]
-use windows::Win32::System::Console::COORD;
+use std::io::{Read, Write};
 #[cfg(windows)]
-use windows::Win32::System::Threading::{
-    CREATE_NEW_CONSOLE, CREATE_NEW_PROCESS_GROUP, CreateProcessW, PROCESS_INFORMATION,
-    STARTUPINFOW, TerminateProcess, WaitForSingleObject,
-};
+use std::mem::{size_of, zeroed};
 #[cfg(windows)]
-use windows::core::PWSTR;
-
-// Scrape backend imports
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
 #[cfg(windows)]
-use windows::Win32::Foundation::GENERIC_READ;
+use std::ptr::null_mut;
 #[cfg(windows)]
-use windows::Win32::Foundation::GENERIC_WRITE;
+use tokio::sync::oneshot;
 #[cfg(windows)]
-use windows::Win32::Storage::FileSystem::{
-    CreateFileW, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
-};
+use windows::Win32::Foundation::{HANDLE, INVALID_HANDLE_VALUE};
 #[cfg(windows)]
 use windows::Win32::System::Console::{
-    ATTACH_PARENT_PROCESS, AttachConsole, CHAR_INFO, CONSOLE_MODE, CONSOLE_SCREEN_BUFFER_INFO,
-    CTRL_C_EVENT, ENABLE_PROCESSED_INPUT, ENABLE_PROCESSED_OUTPUT, ENABLE_VIRTUAL_TERMINAL_INPUT,
-    ENABLE_VIRTUAL_TERMINAL_PROCESSING, FreeConsole, GenerateConsoleCtrlEvent, GetConsoleMode,
-    GetConsoleScreenBufferInfo, GetStdHandle, INPUT_RECORD, KEY_EVENT_RECORD, ReadConsoleOutputW,
-    SMALL_RECT, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, SetConsoleCtrlHandler, SetConsoleMode,
-    SetConsoleScreenBufferSize, SetConsoleWindowInfo, WriteConsoleInputW,
+    COORD, ClosePseudoConsole, CreatePseudoConsole, HPCON, ResizePseudoConsole,
 };
 #[cfg(windows)]
-use windows::Win32::System::Threading::STARTF_USESHOWWINDOW;
+use windows::Win32::System::Pipes::CreatePipe;
 #[cfg(windows)]
-use windows::Win32::UI::Input::KeyboardAndMouse::{MAPVK_VK_TO_VSC, MapVirtualKeyW, VkKeyScanW};
-#[cfg(windows)]
-use windows::Win32::UI::WindowsAndMessaging::SW_HIDE;
+use windows::Win32::System::Threading::{
+    CreateProcessW, DeleteProcThreadAttributeList, EXTENDED_STARTUPINFO_PRESENT,
+    GetExitCodeProcess, INFINITE, InitializeProcThreadAttributeList, LPPROC_THREAD_ATTRIBUTE_LIST,
+    PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE, PROCESS_INFORMATION, STARTF_USESTDHANDLES, STARTUPINFOEXW,
+    TerminateProcess, UpdateProcThreadAttribute, WaitForSingleObject,
+};
 #[cfg(windows)]
-use windows::core::PCWSTR;
+use windows::core::{PCWSTR, PWSTR};
 
 /// A `Send`-safe wrapper for Windows `HANDLE`.
 ///
@@ -96,9 +97,6 @@ impl SendHandle {
     fn to_handle(self) -> HANDLE {
         HANDLE(self.0 as *mut c_void)
     }
-    fn is_null(self) -> bool {
-        self.0 == 0
-    }
 }
 
 // Common winsize structure that works across platforms
@@ -112,103 +110,765 @@ pub struct Winsize {
     pub ws_col: u16,
 }
 
+/// How the child process terminated.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(windows, allow(dead_code))]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled {
+        signal: i32,
+        name: String,
+        core_dumped: bool,
+    },
+    Unknown,
+}
+
+impl ExitStatus {
+    /// Exit code a shell would report for the process: its own exit code, or
+    /// 128 plus the signal number if it was killed by a signal.
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            ExitStatus::Exited(code) => Some(*code),
+            ExitStatus::Signaled { signal, .. } => Some(128 + signal),
+            ExitStatus::Unknown => None,
+        }
+    }
+
+    /// Exit code for ht to exit with when propagating the process's: `code`
+    /// when it fits in the 8 bits an exit code keeps, 1 otherwise, so that a
+    /// failure (e.g. 256, or an NTSTATUS value on Windows) never comes out as
+    /// success, nor as an unrelated code.
+    pub fn exit_code(&self) -> u8 {
+        self.code()
+            .and_then(|code| u8::try_from(code).ok())
+            .unwrap_or(1)
+    }
+}
+
+/// How a session in a PTY ended, as the future driving it resolves to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionOutcome {
+    pub status: ExitStatus,
+
+    /// Bytes of input written to the terminal.
+    pub bytes_in: u64,
+
+    /// Bytes of output read from the terminal.
+    pub bytes_out: u64,
+
+    /// Time from spawning the process (or starting to drive it) until it
+    /// was reaped.
+    pub duration: Duration,
+}
+
+/// Bytes passed through the terminal, counted by the driver.
+#[derive(Debug, Default)]
+struct Traffic {
+    input: AtomicU64,
+    output: AtomicU64,
+}
+
+impl Traffic {
+    fn add_input(&self, n: usize) {
+        self.input.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn add_output(&self, n: usize) {
+        self.output.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn outcome(&self, status: ExitStatus, started: Instant) -> SessionOutcome {
+        SessionOutcome {
+            status,
+            bytes_in: self.input.load(Ordering::Relaxed),
+            bytes_out: self.output.load(Ordering::Relaxed),
+            duration: started.elapsed(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<wait::WaitStatus> for ExitStatus {
+    fn from(status: wait::WaitStatus) -> Self {
+        match status {
+            wait::WaitStatus::Exited(_, code) => ExitStatus::Exited(code),
+
+            wait::WaitStatus::Signaled(_, signal, core_dumped) => ExitStatus::Signaled {
+                signal: signal as i32,
+                name: signal.as_str().trim_start_matches("SIG").to_owned(),
+                core_dumped,
+            },
+
+            _ => ExitStatus::Unknown,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<u32> for ExitStatus {
+    /// Maps the exit code of a process, as reported by `GetExitCodeProcess`.
+    /// Codes which are NTSTATUS values, like `0xC0000005` for an access
+    /// violation, come out negative, as `$LASTEXITCODE` shows them.
+    fn from(code: u32) -> Self {
+        if code == windows::Win32::Foundation::STILL_ACTIVE.0 as u32 {
+            ExitStatus::Unknown
+        } else {
+            ExitStatus::Exited(code as i32)
+        }
+    }
+}
+
+/// How the child process is grouped relative to ht.
+#[cfg(unix)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ProcessGroup {
+    /// The child starts a new session (`setsid`) and becomes its leader, with
+    /// the PTY as its controlling terminal. Job control works as in a regular
+    /// terminal, e.g. ^C in the input sends SIGINT to the foreground job.
+    #[default]
+    Session,
+
+    /// The child stays in ht's session but starts a new process group
+    /// (`setpgid`). The PTY is not its controlling terminal, so there's no job
+    /// control and control characters in the input don't generate signals.
+    Group,
+}
+
+/// Input processing by the terminal's line discipline, set up before the
+/// command starts. Programs may change it later (shells, editors and other
+/// full-screen programs usually do).
+#[cfg(unix)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PtyMode {
+    /// Line-buffered input with echo and line editing; control characters
+    /// like ^C and ^Z generate signals
+    #[default]
+    Cooked,
+
+    /// Character-at-a-time input without echo; control characters still
+    /// generate signals
+    Cbreak,
+
+    /// Character-at-a-time input without echo, with all control characters
+    /// passed through to the program as data (output processing is kept)
+    Raw,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SpawnOptions {
+    #[cfg(unix)]
+    pub process_group: ProcessGroup,
+
+    #[cfg(unix)]
+    pub pty_mode: PtyMode,
+
+    /// File descriptors of ht passed on to the child, which inherits no other
+    /// ones besides STDIN, STDOUT and STDERR.
+    #[cfg(unix)]
+    pub pass_fds: Vec<RawFd>,
+
+    /// Environment variables set for the child, on top of the environment
+    /// inherited from ht, or removed from it with `None`. Later ones win.
+    #[cfg(unix)]
+    pub env: Vec<(String, Option<String>)>,
+
+    /// Directories put in front of the child's PATH (inherited from ht, or
+    /// set with `env`), so that the shell running the command looks there
+    /// first.
+    #[cfg(unix)]
+    pub path_prepend: Vec<PathBuf>,
+
+    /// Shell running the command line, with `-c` (`/bin/sh` if not given).
+    #[cfg(unix)]
+    pub shell: Option<PathBuf>,
+
+    /// Program to run, followed by its arguments, without a shell: when
+    /// given, it's looked up on PATH and run as is, instead of the command
+    /// line.
+    #[cfg(unix)]
+    pub argv: Option<Vec<String>>,
+
+    /// Size of the buffer output is read into, i.e. the most output read
+    /// from the PTY at once (`DEFAULT_READ_BUFFER_SIZE` if not given).
+    #[cfg(unix)]
+    pub read_buffer_size: Option<usize>,
+
+    /// Most input written to the PTY at once (all pending input if not
+    /// given).
+    #[cfg(unix)]
+    pub write_chunk_size: Option<usize>,
+
+    /// Working directory of the child (ht's own if not given).
+    pub cwd: Option<PathBuf>,
+
+    /// Runtime control of input backpressure (Unix only).
+    #[cfg_attr(windows, allow(dead_code))]
+    pub input_control: Option<InputControl>,
+}
+
+/// What happens to input the child doesn't read as fast as it arrives.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum BackpressurePolicy {
+    /// Pending input is capped by not accepting more until the child catches
+    /// up, which holds up the senders.
+    #[default]
+    Block,
+
+    /// Input beyond the cap is discarded, keeping what's pending.
+    DropNewest,
+
+    /// The oldest pending input is discarded to make room for new input.
+    DropOldest,
+}
+
+/// Limit of input pending to be written to the PTY.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backpressure {
+    pub policy: BackpressurePolicy,
+    pub max_bytes: usize,
+}
+
+/// Lets the backpressure of a driven PTY be changed while it runs, and reports
+/// the number of input bytes rejected by the `DropNewest` policy, and the
+/// number of bytes pending, i.e. taken in but not written to the PTY yet.
+///
+/// Setting `close_stdin` ends the input: what was sent until then is written,
+/// followed by the end of input (see `end_of_input`), and further input is
+/// discarded.
+#[derive(Debug, Clone)]
+#[cfg_attr(windows, allow(dead_code))]
+pub struct InputControl {
+    pub backpressure: watch::Receiver<Backpressure>,
+    pub rejected_tx: mpsc::UnboundedSender<usize>,
+    pub pending: Arc<AtomicUsize>,
+    pub close_stdin: watch::Receiver<bool>,
+}
+
+/// Input side of a driven PTY.
+#[cfg(unix)]
+struct PtyInput {
+    rx: mpsc::Receiver<Vec<u8>>,
+    initial: Option<Vec<u8>>,
+    control: Option<InputControl>,
+    write_chunk_size: usize,
+}
+
+/// Output side of a driven PTY.
+#[cfg(unix)]
+struct PtyOutput {
+    tx: mpsc::Sender<Vec<u8>>,
+    read_buffer_size: usize,
+    /// Output read while the channel was full, in order, see `push`.
+    pending: VecDeque<Vec<u8>>,
+    pending_bytes: usize,
+}
+
+#[cfg(unix)]
+impl PtyOutput {
+    fn new(tx: mpsc::Sender<Vec<u8>>, read_buffer_size: usize) -> Self {
+        Self {
+            tx,
+            read_buffer_size,
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    /// Passes output on without waiting for the receiver, which would hold up
+    /// input (and everything else) the program may need to get that far:
+    /// while the channel is full the output is queued instead, to be sent
+    /// with `send_pending`.
+    fn push(&mut self, data: Vec<u8>) -> Result<()> {
+        let data = if self.pending.is_empty() {
+            match self.tx.try_send(data) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::TrySendError::Full(data)) => data,
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            data
+        };
+
+        self.pending_bytes += data.len();
+        self.pending.push_back(data);
+
+        Ok(())
+    }
+
+    /// Sends the oldest queued output once there's room in the channel.
+    async fn send_pending(&mut self) -> Result<()> {
+        let permit = self.tx.reserve().await?;
+
+        if let Some(data) = self.pending.pop_front() {
+            self.pending_bytes -= data.len();
+            permit.send(data);
+        }
+
+        Ok(())
+    }
+
+    /// Whether reading more output should wait for the queue to shrink,
+    /// leaving the program's further output in the PTY, which eventually
+    /// holds up the program itself.
+    fn is_full(&self) -> bool {
+        self.pending_bytes >= OUTPUT_HIGH_WATER_MARK
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        while !self.pending.is_empty() {
+            self.send_pending().await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Backpressure {
+    /// Unbounded, i.e. all input is kept until the child reads it.
+    fn default() -> Self {
+        Self {
+            policy: BackpressurePolicy::Block,
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+impl Backpressure {
+    /// Tells whether more input can be accepted with `pending` bytes waiting.
+    #[cfg_attr(windows, allow(dead_code))]
+    fn accepts(&self, pending: usize) -> bool {
+        self.policy != BackpressurePolicy::Block || pending < self.max_bytes
+    }
+
+    /// Adds input to the pending bytes, returning how many bytes had to be
+    /// dropped to stay within the limit.
+    #[cfg_attr(windows, allow(dead_code))]
+    fn push(&self, pending: &mut Vec<u8>, data: &[u8]) -> usize {
+        match self.policy {
+            BackpressurePolicy::Block => {
+                pending.extend_from_slice(data);
+
+                0
+            }
+
+            BackpressurePolicy::DropNewest => {
+                let room = self.max_bytes.saturating_sub(pending.len());
+                let taken = room.min(data.len());
+                pending.extend_from_slice(&data[..taken]);
+
+                data.len() - taken
+            }
+
+            BackpressurePolicy::DropOldest => {
+                pending.extend_from_slice(data);
+                let excess = pending.len().saturating_sub(self.max_bytes);
+                pending.drain(..excess);
+
+                excess
+            }
+        }
+    }
+}
+
 // Unix implementation
 #[cfg(unix)]
+#[allow(dead_code)]
 pub fn spawn(
     command: String,
     winsize: Winsize,
     input_rx: mpsc::Receiver<Vec<u8>>,
     output_tx: mpsc::Sender<Vec<u8>>,
-    // TODO: implement resize on Unix by consuming resize_rx and calling TIOCSWINSZ on the master fd
-    _resize_rx: mpsc::Receiver<(u16, u16)>,
+    resize_rx: mpsc::Receiver<(u16, u16, bool)>,
     initial_input: Option<Vec<u8>>,
-) -> Result<impl Future<Output = Result<()>>> {
-    let result = unsafe { pty::forkpty(Some(&winsize), None) }?;
+) -> Result<impl Future<Output = Result<SessionOutcome>>> {
+    spawn_with_options(
+        command,
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        initial_input,
+        SpawnOptions::default(),
+    )
+    .map(|(_pid, future)| future)
+}
 
-    match result.fork_result {
-        ForkResult::Parent { child } => Ok(drive_child(
-            child,
-            result.master,
-            input_rx,
-            output_tx,
-            initial_input,
-        )),
+/// Spawns the command in a new PTY, returning the PID of the child along with
+/// the future driving it, which resolves to the `SessionOutcome` once the
+/// child is reaped.
+///
+/// In both process group modes the child is the leader of its process
+/// group, and on shutdown SIGHUP is sent to the whole group, so that
+/// descendants which didn't move to another group (e.g. background commands
+/// of a non-interactive shell) are cleaned up too.
+///
+/// Sizes received with `resize_rx` come with whether the program is to be
+/// told about the change with SIGWINCH, see `resize`.
+#[cfg(unix)]
+pub fn spawn_with_options(
+    command: String,
+    winsize: Winsize,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    output_tx: mpsc::Sender<Vec<u8>>,
+    resize_rx: mpsc::Receiver<(u16, u16, bool)>,
+    initial_input: Option<Vec<u8>>,
+    options: SpawnOptions,
+) -> Result<(u32, impl Future<Output = Result<SessionOutcome>>)> {
+    let started = Instant::now();
+    // prepared before forking, so that only the parent deals with a bad command
+    let args = command_args(&options, command)?;
+    let vars = child_env(&options)?;
+    let exec_command = || {
+        exec(
+            &args,
+            &vars,
+            options.cwd.as_deref(),
+            options.pty_mode,
+            &options.pass_fds,
+        )
+    };
+
+    let (fork_result, master) = match options.process_group {
+        ProcessGroup::Session => {
+            let result = unsafe { pty::forkpty(Some(&winsize), None) }?;
+
+            (result.fork_result, result.master)
+        }
+
+        ProcessGroup::Group => {
+            let result = pty::openpty(Some(&winsize), None)?;
 
-        ForkResult::Child => {
-            exec(command)?;
-            unreachable!();
+            match unsafe { unistd::fork() }? {
+                ForkResult::Child => run_child(|| {
+                    drop(result.master);
+                    unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))?;
+
+                    for fd in 0..=2 {
+                        unistd::dup2(result.slave.as_raw_fd(), fd)?;
+                    }
+
+                    drop(result.slave);
+                    exec_command()
+                }),
+
+                fork_result => (fork_result, result.master),
+            }
         }
+    };
+
+    match fork_result {
+        ForkResult::Parent { child } => Ok((
+            child.as_raw() as u32,
+            drive_child(
+                child,
+                true,
+                started,
+                master,
+                PtyInput {
+                    rx: input_rx,
+                    initial: initial_input,
+                    control: options.input_control,
+                    write_chunk_size: options.write_chunk_size.unwrap_or(usize::MAX),
+                },
+                PtyOutput::new(
+                    output_tx,
+                    options.read_buffer_size.unwrap_or(DEFAULT_READ_BUFFER_SIZE),
+                ),
+                resize_rx,
+            ),
+        )),
+
+        ForkResult::Child => run_child(exec_command),
     }
 }
 
+/// Drives an already allocated PTY with a known child process, for
+/// applications which spawn processes on their own.
+///
+/// Takes ownership of `master`: it's made non-blocking, registered with the
+/// tokio reactor and closed when the returned future completes. The caller
+/// must not use or close the FD afterwards, and should have closed its own
+/// copies of the slave side so that the child exiting is observed as EOF.
+///
+/// Once the PTY is closed (or `input_rx` is dropped, and the input still
+/// pending then is written and responded to) the child is sent SIGHUP and
+/// reaped with `waitpid`, so it must be a child of the current process.
+/// Its exit status is part of the `SessionOutcome` the future resolves to.
 #[cfg(unix)]
-async fn drive_child(
-    child: Pid,
+#[allow(dead_code)]
+pub fn drive(
     master: OwnedFd,
+    child: Pid,
     input_rx: mpsc::Receiver<Vec<u8>>,
     output_tx: mpsc::Sender<Vec<u8>>,
-    initial_input: Option<Vec<u8>>,
-) -> Result<()> {
-    let result = do_drive_child(master, input_rx, output_tx, initial_input).await;
-    eprintln!("sending HUP signal to the child process");
-    unsafe { libc::kill(child.as_raw(), libc::SIGHUP) };
-    eprintln!("waiting for the child process to exit");
+) -> impl Future<Output = Result<SessionOutcome>> {
+    let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+    let input = PtyInput {
+        rx: input_rx,
+        initial: None,
+        control: None,
+        write_chunk_size: usize::MAX,
+    };
 
-    tokio::task::spawn_blocking(move || {
-        let _ = wait::waitpid(child, None);
-    })
+    let output = PtyOutput::new(output_tx, DEFAULT_READ_BUFFER_SIZE);
+
+    drive_child(
+        child,
+        false,
+        Instant::now(),
+        master,
+        input,
+        output,
+        resize_rx,
+    )
+}
+
+#[cfg(unix)]
+async fn drive_child(
+    child: Pid,
+    group_leader: bool,
+    started: Instant,
+    master: OwnedFd,
+    input: PtyInput,
+    mut output: PtyOutput,
+    resize_rx: mpsc::Receiver<(u16, u16, bool)>,
+) -> Result<SessionOutcome> {
+    let traffic = Traffic::default();
+    let result = match do_drive_child(
+        child,
+        group_leader,
+        master,
+        input,
+        &mut output,
+        resize_rx,
+        &traffic,
+    )
     .await
-    .unwrap();
+    {
+        Ok(()) => output.flush().await,
+        Err(e) => Err(e),
+    };
+    if group_leader {
+        log::status!("sending HUP signal to the child process group");
+        unsafe { libc::killpg(child.as_raw(), libc::SIGHUP) };
+    } else {
+        log::status!("sending HUP signal to the child process");
+        unsafe { libc::kill(child.as_raw(), libc::SIGHUP) };
+    }
 
-    result
+    log::status!("waiting for the child process to exit");
+
+    let status = tokio::task::spawn_blocking(move || wait::waitpid(child, None))
+        .await
+        .unwrap();
+
+    result?;
+    let status = status.map(ExitStatus::from).unwrap_or(ExitStatus::Unknown);
+
+    Ok(traffic.outcome(status, started))
 }
 
+/// How much output read from the PTY is queued at most while the output
+/// channel is full, see `PtyOutput::is_full`.
+#[cfg(unix)]
+const OUTPUT_HIGH_WATER_MARK: usize = 1024 * 1024;
+
+/// Size of the buffer output is read into, unless set with
+/// `SpawnOptions::read_buffer_size`.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 128 * 1024;
+
+/// How long input still pending when the input channel closes is given to be
+/// written, before it's dropped (e.g. when the program doesn't read it).
+#[cfg(unix)]
+const INPUT_FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How many times, and how often, the child is checked for having exited
+/// after EIO from the PTY (see `check_eio`).
+#[cfg(unix)]
+const EXIT_CHECK_ATTEMPTS: usize = 20;
+
 #[cfg(unix)]
-const READ_BUF_SIZE: usize = 128 * 1024;
+const EXIT_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// How long output is still read after the child exited, before the session
+/// ends regardless of processes it left behind with the terminal open. Also
+/// how long it's read after the input left when the input channel closed is
+/// written.
+#[cfg(unix)]
+const EXIT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
 
 #[cfg(unix)]
 async fn do_drive_child(
+    child: Pid,
+    group_leader: bool,
     master: OwnedFd,
-    mut input_rx: mpsc::Receiver<Vec<u8>>,
-    output_tx: mpsc::Sender<Vec<u8>>,
-    initial_input: Option<Vec<u8>>,
+    pty_input: PtyInput,
+    pty_output: &mut PtyOutput,
+    mut resize_rx: mpsc::Receiver<(u16, u16, bool)>,
+    traffic: &Traffic,
 ) -> Result<()> {
-    let mut buf = [0u8; READ_BUF_SIZE];
-    let mut input: Vec<u8> = initial_input.unwrap_or_default();
+    let mut buf = vec![0u8; pty_output.read_buffer_size];
+    let write_chunk_size = pty_input.write_chunk_size;
+    let mut resizing = true;
+    let mut input_rx = pty_input.rx;
+    let mut input: Vec<u8> = pty_input.initial.unwrap_or_default();
+    // without control the default (unbounded) backpressure never changes,
+    // and stdin isn't closed
+    let (_backpressure_tx, default_backpressure) = watch::channel(Backpressure::default());
+    let (_close_stdin_tx, default_close_stdin) = watch::channel(false);
+    let (mut backpressure_rx, rejected_tx, pending, mut close_stdin_rx) = match pty_input.control {
+        Some(control) => (
+            control.backpressure,
+            Some(control.rejected_tx),
+            Some(control.pending),
+            control.close_stdin,
+        ),
+        None => (default_backpressure, None, None, default_close_stdin),
+    };
+    let mut backpressure = *backpressure_rx.borrow_and_update();
     nbio::set_non_blocking(&master.as_raw_fd())?;
     let master_fd = AsyncFd::new(master)?;
     let raw_fd = master_fd.get_ref().as_raw_fd();
     // ManuallyDrop: AsyncFd owns the FD; this File borrows it for read/write without closing on drop.
     let mut master_file = ManuallyDrop::new(unsafe { File::from_raw_fd(raw_fd) });
+    let mut input_closed = false;
+    // whether the input written so far ends in the middle of a line, which
+    // the line discipline holds until it's complete
+    let mut line_pending = false;
+    let mut stdin_closed = false;
+    // set when stdin was closed with input left to write before its end
+    let mut eof_pending = false;
+    // set while input is left to write after the input channel closed
+    let mut flush_deadline: Option<tokio::time::Instant> = None;
+    let mut child_exit = ChildExit::new(child)?;
+    // set once the child exited, or the input left when the input channel
+    // closed is written
+    let mut drain_deadline: Option<tokio::time::Instant> = None;
 
     loop {
+        if let Some(pending) = &pending {
+            pending.store(input.len(), Ordering::Relaxed);
+        }
+
         tokio::select! {
-            result = input_rx.recv() => {
+            result = input_rx.recv(), if !input_closed && backpressure.accepts(input.len()) => {
                 match result {
+                    Some(_) if stdin_closed => (),
+
                     Some(data) => {
+                        let dropped = backpressure.push(&mut input, &data);
+
+                        if dropped > 0 && backpressure.policy == BackpressurePolicy::DropNewest {
+                            if let Some(rejected_tx) = &rejected_tx {
+                                let _ = rejected_tx.send(dropped);
+                            }
+                        }
+                    }
+
+                    None => {
+                        if input.is_empty() {
+                            return Ok(());
+                        }
+
+                        // the last input sent before closing mustn't be lost
+                        input_closed = true;
+                        flush_deadline = Some(tokio::time::Instant::now() + INPUT_FLUSH_TIMEOUT);
+                    }
+                }
+            }
+
+            _ = tokio::time::sleep_until(flush_deadline.unwrap_or_else(tokio::time::Instant::now)), if flush_deadline.is_some() => {
+                log::warning!("input channel closed, dropping {} bytes of input the program didn't read", input.len());
+
+                return Ok(());
+            }
+
+            _ = child_exit.wait(), if drain_deadline.is_none() => {
+                // What it wrote before exiting is all read, however much is
+                // queued already. Its last output may still be on the way,
+                // so the terminal is read a moment longer. Ending the session
+                // doesn't wait for processes it left behind (e.g. in the
+                // background) to close the terminal too.
+                if read_remaining(&mut master_file, &mut buf, pty_output, traffic)? {
+                    return Ok(());
+                }
+
+                drain_deadline = Some(tokio::time::Instant::now() + EXIT_DRAIN_TIMEOUT);
+            }
+
+            _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)), if drain_deadline.is_some() => {
+                // output which arrived while waiting for the deadline, but
+                // wasn't read yet
+                read_remaining(&mut master_file, &mut buf, pty_output, traffic)?;
+
+                return Ok(());
+            }
+
+            Ok(()) = backpressure_rx.changed() => {
+                backpressure = *backpressure_rx.borrow_and_update();
+            }
+
+            Ok(()) = close_stdin_rx.changed(), if !stdin_closed => {
+                if *close_stdin_rx.borrow_and_update() {
+                    // input sent before closing is in the channel already
+                    while let Ok(data) = input_rx.try_recv() {
                         input.extend_from_slice(&data);
                     }
 
+                    stdin_closed = true;
+
+                    if input.is_empty() {
+                        input = end_of_input(master_fd.get_ref(), line_pending);
+                    } else {
+                        eof_pending = true;
+                    }
+                }
+            }
+
+            result = resize_rx.recv(), if resizing => {
+                match result {
+                    Some((cols, rows, signal)) => {
+                        resize(child, group_leader, raw_fd, cols, rows, signal)?;
+                    }
+
                     None => {
-                        return Ok(());
+                        resizing = false;
                     }
                 }
             }
 
-            result = master_fd.readable() => {
+            result = pty_output.send_pending(), if !pty_output.pending.is_empty() => {
+                result?;
+            }
+
+            result = master_fd.readable(), if !pty_output.is_full() => {
                 let mut guard = result?;
 
                 loop {
-                    match nbio::read(&mut *master_file, &mut buf)? {
-                        Some(0) => {
+                    match nbio::read(&mut *master_file, &mut buf) {
+                        Ok(Some(0)) => {
                             return Ok(());
                         }
 
-                        Some(n) => {
-                            output_tx.send(buf[0..n].to_vec()).await?;
+                        Ok(Some(n)) => {
+                            traffic.add_output(n);
+                            pty_output.push(buf[0..n].to_vec())?;
+
+                            if pty_output.is_full() {
+                                break;
+                            }
+                        }
+
+                        Err(e) if nbio::is_eio(&e) => {
+                            return check_eio(child, e).await;
+                        }
+
+                        Err(e) => {
+                            return Err(e.into());
                         }
 
-                        None => {
+                        Ok(None) => {
                             guard.clear_ready();
                             break;
                         }
@@ -219,14 +879,20 @@ async fn do_drive_child(
             result = master_fd.writable(), if !input.is_empty() => {
                 let mut guard = result?;
                 let mut buf: &[u8] = input.as_ref();
+                // the mode the line discipline takes the input in, checked
+                // before the program can read it and switch modes
+                let canonical = is_canonical(master_fd.get_ref());
 
                 loop {
-                    match nbio::write(&mut *master_file, buf)? {
-                        Some(0) => {
+                    let chunk = &buf[..buf.len().min(write_chunk_size)];
+
+                    match nbio::write(&mut *master_file, chunk) {
+                        Ok(Some(0)) => {
                             return Ok(());
                         }
 
-                        Some(n) => {
+                        Ok(Some(n)) => {
+                            traffic.add_input(n);
                             buf = &buf[n..];
 
                             if buf.is_empty() {
@@ -234,17 +900,46 @@ async fn do_drive_child(
                             }
                         }
 
-                        None => {
+                        Err(e) if nbio::is_eio(&e) => {
+                            return check_eio(child, e).await;
+                        }
+
+                        Err(e) => {
+                            return Err(e.into());
+                        }
+
+                        Ok(None) => {
+                            // The buffer is full until the program reads from
+                            // it. Clearing readiness makes `writable` wait for
+                            // the kernel to report room again, rather than
+                            // return right away and spin while it's full.
                             guard.clear_ready();
                             break;
                         }
                     }
                 }
 
+                // What's left stays queued, ahead of input received in the
+                // meantime, until the terminal is writable again.
                 let left = buf.len();
+                let written = &input[..input.len() - left];
+
+                // input read character at a time never leaves a line pending
+                line_pending = canonical && command::leaves_line_pending(written, line_pending);
 
                 if left == 0 {
                     input.clear();
+
+                    if eof_pending {
+                        eof_pending = false;
+                        input = end_of_input(master_fd.get_ref(), line_pending);
+                    }
+
+                    if flush_deadline.take().is_some() {
+                        // the program gets a moment to read it, and its
+                        // response to be read, before the session ends
+                        drain_deadline.get_or_insert(tokio::time::Instant::now() + EXIT_DRAIN_TIMEOUT);
+                    }
                 } else {
                     input.drain(..input.len() - left);
                 }
@@ -253,22 +948,425 @@ async fn do_drive_child(
     }
 }
 
+/// Input ending the input of a program reading the terminal, after input
+/// which may have left a partial line. In canonical mode VEOF ends input on
+/// an empty line, and only hands over a partial one, so it's sent twice then.
+/// A program reading in raw mode gets VEOF as a key press (Ctrl-D) instead.
 #[cfg(unix)]
-fn exec(command: String) -> io::Result<()> {
-    let command = ["/bin/sh".to_owned(), "-c".to_owned(), command]
-        .iter()
-        .map(|s| CString::new(s.as_bytes()))
-        .collect::<Result<Vec<CString>, NulError>>()?;
+fn end_of_input(fd: impl AsFd, line_pending: bool) -> Vec<u8> {
+    command::eof_bytes(line_pending && is_canonical(fd))
+}
 
-    unsafe { env::set_var("TERM", "xterm-256color") };
-    unsafe { signal::signal(Signal::SIGPIPE, SigHandler::SigDfl) }?;
-    unistd::execvp(&command[0], &command)?;
-    unsafe { libc::_exit(1) }
+/// Tells whether the terminal's line discipline is in canonical mode, i.e.
+/// it passes input on to the program a line at a time.
+#[cfg(unix)]
+fn is_canonical(fd: impl AsFd) -> bool {
+    use nix::sys::termios::{self, LocalFlags};
+
+    termios::tcgetattr(fd).is_ok_and(|attrs| attrs.local_flags.contains(LocalFlags::ICANON))
 }
 
-// Windows implementation
+/// Reads all output the terminal holds, without stopping when the output
+/// queue is full, so that none is lost when the session ends. Tells whether
+/// the terminal was closed (EIO included, as it's only called once the child
+/// exited or the session is about to end).
+#[cfg(unix)]
+fn read_remaining(
+    master: &mut File,
+    buf: &mut [u8],
+    pty_output: &mut PtyOutput,
+    traffic: &Traffic,
+) -> Result<bool> {
+    loop {
+        match nbio::read(master, buf) {
+            Ok(Some(0)) => return Ok(true),
 
-/// Escapes a single argument for a Windows command line following msvcrt conventions.
+            Ok(Some(n)) => {
+                traffic.add_output(n);
+                pty_output.push(buf[0..n].to_vec())?;
+            }
+
+            Ok(None) => return Ok(false),
+            Err(e) if nbio::is_eio(&e) => return Ok(true),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Tells a clean end of the PTY from a failure, on EIO from its master side.
+///
+/// On Linux, EIO is how the master reports that no process has the slave side
+/// open anymore, normally because the child exited, which ends the session
+/// cleanly. The child closes its files just before it becomes waitable, so it
+/// gets a moment to show up as exited. If it's still running after that, the
+/// EIO is reported as an error.
+#[cfg(unix)]
+async fn check_eio(child: Pid, error: io::Error) -> Result<()> {
+    for _ in 0..EXIT_CHECK_ATTEMPTS {
+        if has_exited(child) {
+            return Ok(());
+        }
+
+        tokio::time::sleep(EXIT_CHECK_INTERVAL).await;
+    }
+
+    Err(anyhow::Error::new(error).context("PTY failed while the child process is still running"))
+}
+
+/// Notices the child exiting as soon as it does, with SIGCHLD, rather than
+/// when the terminal is closed, which processes it started may keep open. The
+/// child is left to be reaped by `drive_child`.
+#[cfg(target_os = "linux")]
+struct ChildExit {
+    child: Pid,
+    signal: tokio::signal::unix::Signal,
+    exited: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl ChildExit {
+    fn new(child: Pid) -> io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        // the signal is listened to first, so that no exit goes unnoticed
+        let signal = signal(SignalKind::child())?;
+
+        Ok(Self {
+            child,
+            signal,
+            exited: has_exited(child),
+        })
+    }
+
+    /// Completes once the child exited. Cancel safe.
+    async fn wait(&mut self) {
+        while !self.exited {
+            if self.signal.recv().await.is_none() {
+                std::future::pending::<()>().await;
+            }
+
+            self.exited = has_exited(self.child);
+        }
+    }
+}
+
+// elsewhere there's no telling whether the child exited without reaping it,
+// so the end of the session is noticed with the terminal's closing alone
+#[cfg(all(unix, not(target_os = "linux")))]
+struct ChildExit;
+
+#[cfg(all(unix, not(target_os = "linux")))]
+impl ChildExit {
+    fn new(_child: Pid) -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    async fn wait(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// Tells whether the child exited, leaving it to be reaped. When that can't
+/// be told (e.g. it isn't a child of this process) it's assumed to have
+/// exited.
+#[cfg(target_os = "linux")]
+fn has_exited(child: Pid) -> bool {
+    use nix::sys::wait::{Id, WaitPidFlag, WaitStatus, waitid};
+
+    let flags = WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT;
+
+    !matches!(waitid(Id::Pid(child), flags), Ok(WaitStatus::StillAlive))
+}
+
+// elsewhere EIO doesn't reliably mean the slave side was closed, nor is
+// waitid available everywhere, so it's taken as the end of the session
+#[cfg(all(unix, not(target_os = "linux")))]
+fn has_exited(_child: Pid) -> bool {
+    true
+}
+
+/// Size of the terminal ht itself runs in, i.e. its controlling terminal.
+#[cfg(unix)]
+pub fn terminal_size() -> io::Result<Winsize> {
+    let tty = File::open("/dev/tty")?;
+
+    let mut winsize = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    if unsafe { libc::ioctl(tty.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if winsize.ws_col == 0 || winsize.ws_row == 0 {
+        return Err(io::Error::other("terminal reports no size"));
+    }
+
+    Ok(winsize)
+}
+
+#[cfg(windows)]
+pub fn terminal_size() -> std::io::Result<Winsize> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Sets the size of the terminal, sending SIGWINCH along with it when
+/// `signal` is set.
+///
+/// The kernel delivers SIGWINCH to the foreground process group of the
+/// terminal by itself, and there's no holding it back: the size can only be
+/// changed quietly when there's no such group, i.e. the child doesn't have the
+/// terminal as its controlling terminal (`ProcessGroup::Group`). Then it's up
+/// to ht to signal the child (or its group) instead.
+#[cfg(unix)]
+fn resize(
+    child: Pid,
+    group_leader: bool,
+    fd: i32,
+    cols: u16,
+    rows: u16,
+    signal: bool,
+) -> io::Result<()> {
+    set_winsize(fd, cols, rows)?;
+
+    if signal && unsafe { libc::tcgetpgrp(fd) } <= 0 {
+        if group_leader {
+            unsafe { libc::killpg(child.as_raw(), libc::SIGWINCH) };
+        } else {
+            unsafe { libc::kill(child.as_raw(), libc::SIGWINCH) };
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_winsize(fd: i32, cols: u16, rows: u16) -> io::Result<()> {
+    let winsize = Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsize) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Arguments the child execs: the ones of `argv` if given, or else the shell
+/// running the command line with `-c`.
+#[cfg(unix)]
+fn command_args(options: &SpawnOptions, command: String) -> io::Result<Vec<CString>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let args: Vec<Vec<u8>> = match &options.argv {
+        Some(argv) => argv.iter().map(|arg| arg.clone().into_bytes()).collect(),
+
+        None => {
+            let shell = options.shell.as_deref().unwrap_or(Path::new("/bin/sh"));
+
+            vec![
+                shell.as_os_str().as_bytes().to_vec(),
+                b"-c".to_vec(),
+                command.into_bytes(),
+            ]
+        }
+    };
+
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no command given",
+        ));
+    }
+
+    args.into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<CString>, NulError>>()
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "command or shell contains a NUL byte",
+            )
+        })
+}
+
+/// Runs in the forked child, never returning into the code of the parent (and
+/// its async runtime) copied along with it: when `f` fails to exec the
+/// command, the error is printed to the terminal and the child exits with
+/// status 1.
+#[cfg(unix)]
+fn run_child(f: impl FnOnce() -> io::Result<Infallible>) -> ! {
+    let Err(error) = f();
+    let message = format!("ht: cannot start command: {error}\r\n");
+
+    unsafe {
+        libc::write(2, message.as_ptr().cast(), message.len());
+        libc::_exit(1)
+    }
+}
+
+/// Changes the child makes to the environment inherited from ht: the
+/// variables of `env`, in order, followed by PATH with `path_prepend` in front
+/// of it, if there's any.
+#[cfg(unix)]
+fn child_env(options: &SpawnOptions) -> io::Result<Vec<(OsString, Option<OsString>)>> {
+    let mut vars: Vec<(OsString, Option<OsString>)> = options
+        .env
+        .iter()
+        .map(|(name, value)| (name.into(), value.as_ref().map(Into::into)))
+        .collect();
+
+    if !options.path_prepend.is_empty() {
+        let path = match vars.iter().rev().find(|(name, _)| name == "PATH") {
+            Some((_, value)) => value.clone(),
+            None => env::var_os("PATH"),
+        };
+
+        let path = search_path(&options.path_prepend, &path.unwrap_or_default())?;
+        vars.push(("PATH".into(), Some(path)));
+    }
+
+    Ok(vars)
+}
+
+/// PATH with `prepend` in front of `path`.
+#[cfg(unix)]
+fn search_path(prepend: &[PathBuf], path: &OsStr) -> io::Result<OsString> {
+    let paths = prepend
+        .iter()
+        .cloned()
+        .chain(env::split_paths(path).filter(|p| !p.as_os_str().is_empty()));
+
+    env::join_paths(paths).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(unix)]
+fn exec(
+    args: &[CString],
+    vars: &[(OsString, Option<OsString>)],
+    cwd: Option<&Path>,
+    mode: PtyMode,
+    pass_fds: &[RawFd],
+) -> io::Result<Infallible> {
+    set_pty_mode(mode)?;
+    close_fds(pass_fds)?;
+
+    if let Some(cwd) = cwd {
+        unistd::chdir(cwd).map_err(|e| {
+            io::Error::new(
+                io::Error::from(e).kind(),
+                format!("cannot change to directory {}: {}", cwd.display(), e.desc()),
+            )
+        })?;
+    }
+
+    unsafe { env::set_var("TERM", "xterm-256color") };
+
+    for (name, value) in vars {
+        match value {
+            Some(value) => unsafe { env::set_var(name, value) },
+            None => unsafe { env::remove_var(name) },
+        }
+    }
+
+    unsafe { signal::signal(Signal::SIGPIPE, SigHandler::SigDfl) }?;
+
+    Ok(unistd::execvp(&args[0], args)?)
+}
+
+/// Closes the file descriptors the child would inherit from ht, apart from
+/// STDIN, STDOUT and STDERR (the PTY's slave side) and `pass_fds`, which are
+/// kept open across exec instead.
+#[cfg(unix)]
+fn close_fds(pass_fds: &[RawFd]) -> io::Result<()> {
+    use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+
+    let mut pass_fds = pass_fds.to_vec();
+    pass_fds.sort_unstable();
+    let mut first = 3;
+
+    for fd in pass_fds {
+        close_range(first, fd - 1);
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+        first = fd + 1;
+    }
+
+    close_range(first, RawFd::MAX);
+
+    Ok(())
+}
+
+/// Closes file descriptors from `first` to `last`, inclusive, with a single
+/// system call where it's available.
+#[cfg(unix)]
+fn close_range(first: RawFd, last: RawFd) {
+    if first > last {
+        return;
+    }
+
+    #[cfg(target_os = "linux")]
+    if unsafe { libc::syscall(libc::SYS_close_range, first as u32, last as u32, 0) } == 0 {
+        return;
+    }
+
+    // the limit may be unlimited, there are hardly ever that many open files
+    let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) }.clamp(0, 65536) as RawFd;
+
+    for fd in first..=last.min(max - 1) {
+        unsafe { libc::close(fd) };
+    }
+}
+
+/// Tells whether `fd` is an open file descriptor of ht, which can be passed on
+/// to the child.
+#[cfg(unix)]
+pub fn is_open_fd(fd: RawFd) -> bool {
+    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD).is_ok()
+}
+
+/// Configures the line discipline of the terminal on STDIN (the PTY's slave
+/// side, in the child).
+#[cfg(unix)]
+fn set_pty_mode(mode: PtyMode) -> io::Result<()> {
+    use nix::sys::termios::{self, LocalFlags, SetArg};
+
+    let stdin = io::stdin();
+    let mut attrs = termios::tcgetattr(&stdin)?;
+
+    match mode {
+        PtyMode::Cooked => return Ok(()),
+
+        PtyMode::Cbreak => {
+            attrs
+                .local_flags
+                .remove(LocalFlags::ICANON | LocalFlags::ECHO);
+            attrs.control_chars[termios::SpecialCharacterIndices::VMIN as usize] = 1;
+            attrs.control_chars[termios::SpecialCharacterIndices::VTIME as usize] = 0;
+        }
+
+        PtyMode::Raw => {
+            let output_flags = attrs.output_flags;
+            termios::cfmakeraw(&mut attrs);
+            // only input is raw, output line breaks still render as CR LF
+            attrs.output_flags = output_flags;
+        }
+    }
+
+    termios::tcsetattr(&stdin, SetArg::TCSANOW, &attrs)?;
+
+    Ok(())
+}
+
+// Windows implementation
+
+/// Escapes a single argument for a Windows command line following msvcrt conventions.
 /// This replicates the logic from std::sys::windows::args::append_arg.
 #[cfg(any(windows, test))]
 #[allow(dead_code)]
@@ -415,1392 +1513,359 @@ pub(crate) fn classify_command(args: &[String]) -> CommandKind {
     CommandKind::Direct
 }
 
-// ── Screen-scraping PTY ─────────────────────────────────────────────
-
-#[cfg(windows)]
-static SCRAPE_ACTIVE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
-
-#[cfg(windows)]
-#[derive(Clone, PartialEq)]
-struct Cell {
-    ch: char,
-    width: u8,
-    attr: u16,
-}
-
-/// Converts a Windows console attribute word to an ANSI SGR escape sequence.
-#[cfg(windows)]
-fn attr_to_sgr(attr: u16) -> String {
-    // Windows BGR bit ordering for foreground (bits 0-3) and background (bits 4-7)
-    const WIN_FG_BLUE: u16 = 0x0001;
-    const WIN_FG_GREEN: u16 = 0x0002;
-    const WIN_FG_RED: u16 = 0x0004;
-    const WIN_FG_INTENSITY: u16 = 0x0008;
-    const WIN_BG_BLUE: u16 = 0x0010;
-    const WIN_BG_GREEN: u16 = 0x0020;
-    const WIN_BG_RED: u16 = 0x0040;
-    const WIN_BG_INTENSITY: u16 = 0x0080;
-    const COMMON_LVB_REVERSE_VIDEO: u16 = 0x4000;
-    const COMMON_LVB_UNDERSCORE: u16 = 0x8000;
-
-    // Map Windows BGR 3-bit to ANSI color index (0-7)
-    fn win_to_ansi(blue: bool, green: bool, red: bool) -> u8 {
-        // ANSI: 0=black 1=red 2=green 3=yellow 4=blue 5=magenta 6=cyan 7=white
-        let mut idx = 0u8;
-        if red {
-            idx |= 1;
-        }
-        if green {
-            idx |= 2;
-        }
-        if blue {
-            idx |= 4;
-        }
-        idx
-    }
-
-    let fg_idx = win_to_ansi(
-        attr & WIN_FG_BLUE != 0,
-        attr & WIN_FG_GREEN != 0,
-        attr & WIN_FG_RED != 0,
-    );
-    let fg_bright = attr & WIN_FG_INTENSITY != 0;
-    let bg_idx = win_to_ansi(
-        attr & WIN_BG_BLUE != 0,
-        attr & WIN_BG_GREEN != 0,
-        attr & WIN_BG_RED != 0,
-    );
-    let bg_bright = attr & WIN_BG_INTENSITY != 0;
-
-    let fg_code = if fg_bright {
-        90 + fg_idx as u32
-    } else {
-        30 + fg_idx as u32
-    };
-    let bg_code = if bg_bright {
-        100 + bg_idx as u32
-    } else {
-        40 + bg_idx as u32
-    };
-
-    let mut sgr = format!("\x1b[0;{};{}", fg_code, bg_code);
-    if attr & COMMON_LVB_REVERSE_VIDEO != 0 {
-        sgr.push_str(";7");
-    }
-    if attr & COMMON_LVB_UNDERSCORE != 0 {
-        sgr.push_str(";4");
-    }
-    sgr.push('m');
-    sgr
-}
-
-/// Decodes a row of CHAR_INFO into a Vec<Cell>.
-#[cfg(windows)]
-fn decode_char_info_row(row: &[CHAR_INFO]) -> Vec<Cell> {
-    const LVB_LEADING_BYTE: u16 = 0x0100;
-    const LVB_TRAILING_BYTE: u16 = 0x0200;
-    // Mask to remove width-hint LVB bits but preserve colors and style flags
-    const ATTR_MASK: u16 = 0xCCFF;
-
-    let mut cells = Vec::with_capacity(row.len());
-    let mut i = 0;
-    while i < row.len() {
-        let ci = &row[i];
-        let attrs = ci.Attributes;
-
-        if attrs & LVB_TRAILING_BYTE != 0 {
-            // Padding cell for a wide character, skip
-            i += 1;
-            continue;
-        }
-
-        let raw_char = unsafe { ci.Char.UnicodeChar };
-        let masked_attr = attrs & ATTR_MASK;
-
-        if attrs & LVB_LEADING_BYTE != 0 {
-            // Wide character — might be a surrogate pair
-            let ch = if (0xD800..=0xDBFF).contains(&raw_char) && i + 1 < row.len() {
-                let next_raw = unsafe { row[i + 1].Char.UnicodeChar };
-                // Decode UTF-16 surrogate pair
-                char::decode_utf16([raw_char, next_raw])
-                    .next()
-                    .and_then(|r| r.ok())
-                    .unwrap_or('\u{FFFD}')
-            } else {
-                char::from_u32(raw_char as u32).unwrap_or('\u{FFFD}')
-            };
-            cells.push(Cell {
-                ch,
-                width: 2,
-                attr: masked_attr,
-            });
-        } else {
-            // Normal single-width character — check for surrogate pair
-            let ch = if (0xD800..=0xDBFF).contains(&raw_char) && i + 1 < row.len() {
-                let next_raw = unsafe { row[i + 1].Char.UnicodeChar };
-                if (0xDC00..=0xDFFF).contains(&next_raw) {
-                    i += 1; // consume the low surrogate
-                    char::decode_utf16([raw_char, next_raw])
-                        .next()
-                        .and_then(|r| r.ok())
-                        .unwrap_or('\u{FFFD}')
-                } else {
-                    char::from_u32(raw_char as u32).unwrap_or('\u{FFFD}')
-                }
-            } else {
-                char::from_u32(raw_char as u32).unwrap_or('\u{FFFD}')
-            };
-            cells.push(Cell {
-                ch,
-                width: 1,
-                attr: masked_attr,
-            });
-        }
-        i += 1;
-    }
-    cells
-}
-
-/// Diffs previous and current viewport buffers and emits ANSI escape sequences.
-/// Cursor positions are 1-based (ANSI convention).
-#[cfg(windows)]
-fn diff_and_emit(
-    prev: &[Vec<Cell>],
-    curr: &[Vec<Cell>],
-    cursor_row: u16,
-    cursor_col: u16,
-    cols: u16,
-) -> String {
-    let mut out = String::new();
-    let mut last_attr: Option<u16> = None;
-
-    for (row_idx, row) in curr.iter().enumerate() {
-        let changed = if row_idx >= prev.len() {
-            true
-        } else {
-            prev[row_idx] != *row
-        };
-
-        if !changed {
-            continue;
-        }
-
-        // Move cursor to start of this row (1-based)
-        out.push_str(&format!("\x1b[{};1H", row_idx + 1));
-
-        for cell in row {
-            // Emit SGR if attribute changed
-            if last_attr != Some(cell.attr) {
-                out.push_str(&attr_to_sgr(cell.attr));
-                last_attr = Some(cell.attr);
-            }
-            out.push(cell.ch);
-        }
-
-        // Pad with spaces if row is shorter than viewport width
-        let row_visual_width: u16 = row.iter().map(|c| c.width as u16).sum();
-        if row_visual_width < cols {
-            // Erase to end of line to clear stale content
-            out.push_str("\x1b[K");
-        }
-    }
-
-    // Position cursor
-    out.push_str(&format!("\x1b[{};{}H", cursor_row, cursor_col));
-    out
-}
-
-// ── Input parser ────────────────────────────────────────────────────────
-
-/// Returns the expected byte length of a UTF-8 sequence given its start byte,
-/// or 0 if the byte is not a valid UTF-8 start byte (continuation or invalid).
-#[cfg(windows)]
-fn utf8_seq_len(b: u8) -> usize {
-    match b {
-        0xC0..=0xDF => 2,
-        0xE0..=0xEF => 3,
-        0xF0..=0xF7 => 4,
-        _ => 0,
-    }
-}
-
-#[cfg(windows)]
-enum InputAction {
-    KeyEvent(KEY_EVENT_RECORD),
-    GenerateCtrlC,
-}
-
-// Virtual key codes
-#[cfg(windows)]
-const VK_BACK: u16 = 0x08;
-#[cfg(windows)]
-const VK_TAB: u16 = 0x09;
-#[cfg(windows)]
-const VK_RETURN: u16 = 0x0D;
-#[cfg(windows)]
-const VK_ESCAPE: u16 = 0x1B;
-#[cfg(windows)]
-const VK_PRIOR: u16 = 0x21; // Page Up
-#[cfg(windows)]
-const VK_NEXT: u16 = 0x22; // Page Down
-#[cfg(windows)]
-const VK_END: u16 = 0x23;
-#[cfg(windows)]
-const VK_HOME: u16 = 0x24;
-#[cfg(windows)]
-const VK_LEFT: u16 = 0x25;
-#[cfg(windows)]
-const VK_UP: u16 = 0x26;
-#[cfg(windows)]
-const VK_RIGHT: u16 = 0x27;
-#[cfg(windows)]
-const VK_DOWN: u16 = 0x28;
-#[cfg(windows)]
-const VK_INSERT: u16 = 0x2D;
-#[cfg(windows)]
-const VK_DELETE: u16 = 0x2E;
-#[cfg(windows)]
-const VK_F1: u16 = 0x70;
-#[cfg(windows)]
-const VK_F2: u16 = 0x71;
-#[cfg(windows)]
-const VK_F3: u16 = 0x72;
-#[cfg(windows)]
-const VK_F4: u16 = 0x73;
-#[cfg(windows)]
-const VK_F5: u16 = 0x74;
-#[cfg(windows)]
-const VK_F6: u16 = 0x75;
-#[cfg(windows)]
-const VK_F7: u16 = 0x76;
-#[cfg(windows)]
-const VK_F8: u16 = 0x77;
-#[cfg(windows)]
-const VK_F9: u16 = 0x78;
-#[cfg(windows)]
-const VK_F10: u16 = 0x79;
-#[cfg(windows)]
-const VK_F11: u16 = 0x7A;
-#[cfg(windows)]
-const VK_F12: u16 = 0x7B;
-#[cfg(windows)]
-const VK_PACKET: u16 = 0xE7;
+// ── ConPTY ──────────────────────────────────────────────────────────
 
-// Modifier flags for dwControlKeyState
-#[cfg(windows)]
-const LEFT_ALT_PRESSED: u32 = 0x0002;
-#[cfg(windows)]
-const LEFT_CTRL_PRESSED: u32 = 0x0008;
+/// How long output is still read after the child exited, while the pseudo
+/// console is closed, before the session ends regardless.
 #[cfg(windows)]
-const SHIFT_PRESSED: u32 = 0x0010;
+const CONSOLE_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// A pseudo console (ConPTY), closed when dropped, which ends the processes
+/// still attached to it.
 #[cfg(windows)]
-struct InputParser {
-    pending: Vec<u8>,
-}
+struct PseudoConsole(HPCON);
 
 #[cfg(windows)]
-impl InputParser {
-    fn new() -> Self {
-        Self {
-            pending: Vec::new(),
-        }
-    }
-
-    fn has_pending(&self) -> bool {
-        !self.pending.is_empty()
-    }
-
-    fn flush_pending(&mut self) -> Vec<InputAction> {
-        let pending = std::mem::take(&mut self.pending);
-        let mut actions = Vec::new();
-        let mut i = 0;
-        while i < pending.len() {
-            let b = pending[i];
-            if b == 0x1b {
-                actions.push(self.make_key_action(0x1b_u16, 0x1b, 0));
-                i += 1;
-            } else if b >= 0x80 {
-                let seq_len = utf8_seq_len(b);
-                if seq_len > 0 && i + seq_len <= pending.len() {
-                    if let Ok(s) = std::str::from_utf8(&pending[i..i + seq_len]) {
-                        for ch in s.chars() {
-                            actions.extend(self.char_to_actions(ch));
-                        }
-                        i += seq_len;
-                        continue;
-                    }
-                }
-                // Invalid or incomplete — send byte as VK_PACKET
-                actions.push(self.make_vk_packet_action(b as u16));
-                i += 1;
-            } else {
-                actions.extend(self.byte_to_actions(b));
-                i += 1;
-            }
-        }
-        actions
-    }
-
-    fn parse(&mut self, data: &[u8], conin: HANDLE) -> Vec<InputAction> {
-        let mut input = Vec::new();
-        input.append(&mut self.pending);
-        input.extend_from_slice(data);
-
-        let mut actions = Vec::new();
-        let mut i = 0;
-
-        while i < input.len() {
-            let b = input[i];
-
-            if b == 0x1b {
-                // ESC — check for sequence continuation
-                if i + 1 >= input.len() {
-                    // ESC at end of input — buffer it
-                    self.pending = input[i..].to_vec();
-                    break;
-                }
-
-                let next = input[i + 1];
-
-                if next == b'[' {
-                    // CSI sequence
-                    match self.parse_csi(&input[i..]) {
-                        ParseResult::Complete(acts, consumed) => {
-                            actions.extend(acts);
-                            i += consumed;
-                        }
-                        ParseResult::Incomplete => {
-                            self.pending = input[i..].to_vec();
-                            break;
-                        }
-                        ParseResult::Unrecognized(consumed) => {
-                            // Passthrough: ESC as VK_ESCAPE, then remaining bytes
-                            actions.push(self.make_key_action(0x1b_u16, 0x1b, 0));
-                            for &pb in &input[i + 1..i + consumed] {
-                                actions.extend(self.byte_to_actions(pb));
-                            }
-                            i += consumed;
-                        }
-                    }
-                } else if next == b'O' {
-                    // SS3 sequence
-                    if i + 2 >= input.len() {
-                        self.pending = input[i..].to_vec();
-                        break;
-                    }
-                    let final_byte = input[i + 2];
-                    match final_byte {
-                        b'A' => {
-                            actions.push(self.make_key_action(VK_UP, 0, 0));
-                            i += 3;
-                        }
-                        b'B' => {
-                            actions.push(self.make_key_action(VK_DOWN, 0, 0));
-                            i += 3;
-                        }
-                        b'C' => {
-                            actions.push(self.make_key_action(VK_RIGHT, 0, 0));
-                            i += 3;
-                        }
-                        b'D' => {
-                            actions.push(self.make_key_action(VK_LEFT, 0, 0));
-                            i += 3;
-                        }
-                        b'H' => {
-                            actions.push(self.make_key_action(VK_HOME, 0, 0));
-                            i += 3;
-                        }
-                        b'F' => {
-                            actions.push(self.make_key_action(VK_END, 0, 0));
-                            i += 3;
-                        }
-                        b'P' => {
-                            actions.push(self.make_key_action(VK_F1, 0, 0));
-                            i += 3;
-                        }
-                        b'Q' => {
-                            actions.push(self.make_key_action(VK_F2, 0, 0));
-                            i += 3;
-                        }
-                        b'R' => {
-                            actions.push(self.make_key_action(VK_F3, 0, 0));
-                            i += 3;
-                        }
-                        b'S' => {
-                            actions.push(self.make_key_action(VK_F4, 0, 0));
-                            i += 3;
-                        }
-                        _ => {
-                            // Unknown SS3 — passthrough
-                            actions.push(self.make_key_action(VK_ESCAPE, 0x1b, 0));
-                            actions.extend(self.byte_to_actions(b'O'));
-                            actions.extend(self.byte_to_actions(final_byte));
-                            i += 3;
-                        }
-                    }
-                } else if (0x20..=0x7E).contains(&next) {
-                    // Alt+printable character
-                    let ch = next as char;
-                    let (vk, mut mods) = self.vkscan_char(ch);
-                    mods |= LEFT_ALT_PRESSED;
-                    actions.push(self.make_key_action(vk, next as u16, mods));
-                    i += 2;
-                } else {
-                    // ESC followed by non-printable — send ESC then the byte
-                    actions.push(self.make_key_action(VK_ESCAPE, 0x1b, 0));
-                    i += 1;
-                }
-            } else if b == 0x03 {
-                // Ctrl+C — check console mode
-                let processed = self.is_processed_input(conin);
-                if processed {
-                    actions.push(InputAction::GenerateCtrlC);
-                } else {
-                    // Raw mode — send as key event
-                    actions.push(self.make_key_action(b'C' as u16, 0x03, LEFT_CTRL_PRESSED));
-                }
-                i += 1;
-            } else if b >= 0x80 {
-                // UTF-8 multi-byte sequence
-                let seq_len = utf8_seq_len(b);
-                if seq_len == 0 {
-                    // Invalid start byte — send as VK_PACKET
-                    actions.push(self.make_vk_packet_action(b as u16));
-                    i += 1;
-                } else if i + seq_len > input.len() {
-                    // Incomplete UTF-8 sequence at end of input — buffer it
-                    self.pending = input[i..].to_vec();
-                    break;
-                } else {
-                    match std::str::from_utf8(&input[i..i + seq_len]) {
-                        Ok(s) => {
-                            for ch in s.chars() {
-                                actions.extend(self.char_to_actions(ch));
-                            }
-                            i += seq_len;
-                        }
-                        Err(_) => {
-                            // Invalid UTF-8 — send first byte as VK_PACKET, advance one byte
-                            actions.push(self.make_vk_packet_action(b as u16));
-                            i += 1;
-                        }
-                    }
-                }
-            } else {
-                actions.extend(self.byte_to_actions(b));
-                i += 1;
-            }
-        }
-
-        actions
-    }
-
-    fn is_processed_input(&self, conin: HANDLE) -> bool {
-        let mut mode = CONSOLE_MODE(0);
-        let result = unsafe { GetConsoleMode(conin, &mut mode) };
-        if result.is_ok() {
-            mode & ENABLE_PROCESSED_INPUT != CONSOLE_MODE(0)
-        } else {
-            true // default assumption: processed input
-        }
-    }
-
-    fn byte_to_actions(&self, b: u8) -> Vec<InputAction> {
-        match b {
-            0x0D => vec![self.make_key_action(VK_RETURN, b as u16, 0)],
-            0x0A => {
-                // Ctrl+J (NOT VK_RETURN)
-                vec![self.make_key_action(b'J' as u16, 0x0A, LEFT_CTRL_PRESSED)]
-            }
-            0x09 => vec![self.make_key_action(VK_TAB, b as u16, 0)],
-            0x08 | 0x7F => vec![self.make_key_action(VK_BACK, 0x08, 0)],
-            0x1B => vec![self.make_key_action(VK_ESCAPE, 0x1B, 0)],
-            0x1A => {
-                // Ctrl+Z
-                vec![self.make_key_action(b'Z' as u16, 0x1A, LEFT_CTRL_PRESSED)]
-            }
-            0x01..=0x02 | 0x04..=0x08 | 0x0B..=0x0C | 0x0E..=0x19 => {
-                // Other Ctrl+letter combinations
-                let letter = b'A' + (b - 1);
-                vec![self.make_key_action(letter as u16, b as u16, LEFT_CTRL_PRESSED)]
-            }
-            0x20..=0x7E => {
-                // Printable ASCII
-                self.char_to_actions(b as char)
-            }
-            _ => {
-                // Lone high byte (invalid UTF-8) — send as VK_PACKET
-                vec![self.make_vk_packet_action(b as u16)]
-            }
-        }
-    }
-
-    fn char_to_actions(&self, ch: char) -> Vec<InputAction> {
-        let (vk, mods) = self.vkscan_char(ch);
-        if vk == VK_PACKET {
-            self.vk_packet_for_char(ch)
-        } else {
-            vec![self.make_key_action(vk, ch as u16, mods)]
-        }
-    }
-
-    /// Uses VkKeyScanW to get VK code and modifier state for a character.
-    /// Returns (vk_code, dwControlKeyState). Returns (VK_PACKET, 0) on failure.
-    fn vkscan_char(&self, ch: char) -> (u16, u32) {
-        let result = unsafe { VkKeyScanW(ch as u16) };
-        if result as u16 == 0xFFFF {
-            return (VK_PACKET, 0);
-        }
-        let vk = (result & 0xFF) as u16;
-        let shift_state = ((result >> 8) & 0xFF) as u8;
-        let mut mods = 0u32;
-        if shift_state & 1 != 0 {
-            mods |= SHIFT_PRESSED;
-        }
-        if shift_state & 2 != 0 {
-            mods |= LEFT_CTRL_PRESSED;
-        }
-        if shift_state & 4 != 0 {
-            mods |= LEFT_ALT_PRESSED;
-        }
-        (vk, mods)
-    }
-
-    fn vk_packet_for_char(&self, ch: char) -> Vec<InputAction> {
-        let mut buf = [0u16; 2];
-        let encoded = ch.encode_utf16(&mut buf);
-        encoded
-            .iter()
-            .map(|&u| self.make_vk_packet_action(u))
-            .collect()
-    }
-
-    fn make_vk_packet_action(&self, unicode_char: u16) -> InputAction {
-        let mut ke: KEY_EVENT_RECORD = unsafe { zeroed() };
-        ke.wVirtualKeyCode = VK_PACKET;
-        ke.wVirtualScanCode = 0;
-        ke.uChar.UnicodeChar = unicode_char;
-        ke.dwControlKeyState = 0;
-        ke.wRepeatCount = 1;
-        InputAction::KeyEvent(ke)
-    }
-
-    fn make_key_action(&self, vk: u16, unicode_char: u16, mods: u32) -> InputAction {
-        let scan = unsafe { MapVirtualKeyW(vk as u32, MAPVK_VK_TO_VSC) } as u16;
-        let mut ke: KEY_EVENT_RECORD = unsafe { zeroed() };
-        ke.wVirtualKeyCode = vk;
-        ke.wVirtualScanCode = scan;
-        ke.uChar.UnicodeChar = unicode_char;
-        ke.dwControlKeyState = mods;
-        ke.wRepeatCount = 1;
-        InputAction::KeyEvent(ke)
-    }
-
-    /// Parses modifier parameter value to dwControlKeyState flags.
-    fn modifier_to_flags(modifier: u8) -> u32 {
-        match modifier {
-            2 => SHIFT_PRESSED,
-            3 => LEFT_ALT_PRESSED,
-            4 => LEFT_ALT_PRESSED | SHIFT_PRESSED,
-            5 => LEFT_CTRL_PRESSED,
-            6 => LEFT_CTRL_PRESSED | SHIFT_PRESSED,
-            7 => LEFT_CTRL_PRESSED | LEFT_ALT_PRESSED,
-            8 => LEFT_CTRL_PRESSED | LEFT_ALT_PRESSED | SHIFT_PRESSED,
-            _ => 0,
-        }
-    }
-
-    fn parse_csi(&self, input: &[u8]) -> ParseResult {
-        // input starts with \x1b[
-        debug_assert!(input.len() >= 2 && input[0] == 0x1b && input[1] == b'[');
-
-        // Find the final byte (0x40-0x7E)
-        let mut param_end = 2;
-        while param_end < input.len() {
-            let b = input[param_end];
-            if (0x40..=0x7E).contains(&b) {
-                break;
-            }
-            if !(b.is_ascii_digit() || b == b';') {
-                // Invalid CSI parameter character
-                return ParseResult::Unrecognized(param_end + 1);
-            }
-            param_end += 1;
-        }
-
-        if param_end >= input.len() {
-            return ParseResult::Incomplete;
-        }
-
-        let final_byte = input[param_end];
-        let params_str = std::str::from_utf8(&input[2..param_end]).unwrap_or("");
-        let consumed = param_end + 1;
-
-        // Parse parameters
-        let params: Vec<&str> = if params_str.is_empty() {
-            vec![]
-        } else {
-            params_str.split(';').collect()
-        };
-
-        // Handle arrow keys: CSI A/B/C/D or CSI 1;mod A/B/C/D
-        match final_byte {
-            b'A' | b'B' | b'C' | b'D' => {
-                let vk = match final_byte {
-                    b'A' => VK_UP,
-                    b'B' => VK_DOWN,
-                    b'C' => VK_RIGHT,
-                    b'D' => VK_LEFT,
-                    _ => unreachable!(),
-                };
-                let mods = self.extract_modifier(&params);
-                ParseResult::Complete(vec![self.make_key_action(vk, 0, mods)], consumed)
-            }
-            b'H' => {
-                let mods = self.extract_modifier(&params);
-                ParseResult::Complete(vec![self.make_key_action(VK_HOME, 0, mods)], consumed)
-            }
-            b'F' => {
-                let mods = self.extract_modifier(&params);
-                ParseResult::Complete(vec![self.make_key_action(VK_END, 0, mods)], consumed)
-            }
-            b'P' | b'Q' | b'R' | b'S' => {
-                // F1-F4 (CSI form with modifier: CSI 1;mod P/Q/R/S)
-                let vk = match final_byte {
-                    b'P' => VK_F1,
-                    b'Q' => VK_F2,
-                    b'R' => VK_F3,
-                    b'S' => VK_F4,
-                    _ => unreachable!(),
-                };
-                let mods = self.extract_modifier(&params);
-                ParseResult::Complete(vec![self.make_key_action(vk, 0, mods)], consumed)
-            }
-            b'~' => {
-                // Tilde sequences: CSI num ~ or CSI num;mod ~
-                let num: u16 = params.first().and_then(|s| s.parse().ok()).unwrap_or(0);
-                let mods = if params.len() >= 2 {
-                    params[1]
-                        .parse::<u8>()
-                        .ok()
-                        .map(Self::modifier_to_flags)
-                        .unwrap_or(0)
-                } else {
-                    0
-                };
-                let vk = match num {
-                    2 => Some(VK_INSERT),
-                    3 => Some(VK_DELETE),
-                    5 => Some(VK_PRIOR),
-                    6 => Some(VK_NEXT),
-                    15 => Some(VK_F5),
-                    17 => Some(VK_F6),
-                    18 => Some(VK_F7),
-                    19 => Some(VK_F8),
-                    20 => Some(VK_F9),
-                    21 => Some(VK_F10),
-                    23 => Some(VK_F11),
-                    24 => Some(VK_F12),
-                    _ => None,
-                };
-                if let Some(vk) = vk {
-                    ParseResult::Complete(vec![self.make_key_action(vk, 0, mods)], consumed)
-                } else {
-                    ParseResult::Unrecognized(consumed)
-                }
-            }
-            _ => ParseResult::Unrecognized(consumed),
-        }
-    }
-
-    fn extract_modifier(&self, params: &[&str]) -> u32 {
-        if params.len() >= 2 {
-            params[1]
-                .parse::<u8>()
-                .ok()
-                .map(Self::modifier_to_flags)
-                .unwrap_or(0)
-        } else {
-            0
-        }
-    }
-}
-
-#[cfg(windows)]
-enum ParseResult {
-    Complete(Vec<InputAction>, usize), // actions, bytes consumed
-    Incomplete,
-    Unrecognized(usize), // bytes consumed
-}
-
-/// Expands a KEY_EVENT_RECORD into key-down + key-up INPUT_RECORD pair.
-#[cfg(windows)]
-fn expand_key_event(ke: &KEY_EVENT_RECORD) -> [INPUT_RECORD; 2] {
-    let mut down: INPUT_RECORD = unsafe { zeroed() };
-    down.EventType = 0x0001; // KEY_EVENT
-
-    let mut key_down = *ke;
-    key_down.bKeyDown = windows::Win32::Foundation::BOOL(1);
-    down.Event.KeyEvent = key_down;
-
-    let mut up = down;
-    let mut key_up = *ke;
-    key_up.bKeyDown = windows::Win32::Foundation::BOOL(0);
-    up.Event.KeyEvent = key_up;
-
-    [down, up]
-}
-
-// ── ScrapePty struct ────────────────────────────────────────────────────
-
-#[cfg(windows)]
-struct ScrapePty {
-    child_pid: u32,
-    proc_handle: Option<OwnedHandle>,
-    thread_handle: Option<OwnedHandle>,
-    conout: SendHandle,
-    conin: SendHandle,
-    needs_cleanup: bool,
-}
-
-#[cfg(windows)]
-unsafe impl Send for ScrapePty {}
-
-#[cfg(windows)]
-impl Drop for ScrapePty {
-    fn drop(&mut self) {
-        if self.needs_cleanup {
-            unsafe {
-                if !self.conout.is_null() {
-                    let _ = windows::Win32::Foundation::CloseHandle(self.conout.to_handle());
-                }
-                if !self.conin.is_null() {
-                    let _ = windows::Win32::Foundation::CloseHandle(self.conin.to_handle());
-                }
-                if let Some(ref h) = self.proc_handle {
-                    let _ = TerminateProcess(HANDLE(h.as_raw_handle()), 1);
-                }
-                let _ = SetConsoleCtrlHandler(None, false);
-                let _ = FreeConsole();
-                let _ = AttachConsole(ATTACH_PARENT_PROCESS);
-            }
-            SCRAPE_ACTIVE.store(false, std::sync::atomic::Ordering::Release);
-        }
-    }
-}
-
-/// RAII guard for console attachment during ScrapePty::new().
-#[cfg(windows)]
-struct ConsoleGuard {
-    detached: bool,
-    ctrl_handler_set: bool,
-}
-
-#[cfg(windows)]
-impl ConsoleGuard {
-    fn new() -> Self {
-        Self {
-            detached: false,
-            ctrl_handler_set: false,
-        }
-    }
-    fn mark_detached(&mut self) {
-        self.detached = true;
-    }
-    fn mark_ctrl_handler_set(&mut self) {
-        self.ctrl_handler_set = true;
-    }
-    fn disarm(self) {
-        std::mem::forget(self);
-    }
-}
-
-#[cfg(windows)]
-impl Drop for ConsoleGuard {
-    fn drop(&mut self) {
-        unsafe {
-            if self.ctrl_handler_set {
-                let _ = SetConsoleCtrlHandler(None, false);
-            }
-            if self.detached {
-                let _ = FreeConsole();
-                let _ = AttachConsole(ATTACH_PARENT_PROCESS);
-            }
-        }
-        SCRAPE_ACTIVE.store(false, std::sync::atomic::Ordering::Release);
-    }
-}
-
-#[cfg(windows)]
-impl ScrapePty {
-    fn new(winsize: Winsize, command: &str) -> Result<Self> {
-        use std::sync::atomic::Ordering;
-
-        // 1. Acquire single-instance lock
-        if SCRAPE_ACTIVE
-            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-            .is_err()
-        {
-            anyhow::bail!("only one ht instance can be active at a time");
-        }
-
-        let mut guard = ConsoleGuard::new();
-
-        // 2. Verify stdio is pipe-backed (not console)
-        unsafe {
-            for &std_handle in &[STD_INPUT_HANDLE, STD_OUTPUT_HANDLE] {
-                let h = GetStdHandle(std_handle)?;
-                if h != INVALID_HANDLE_VALUE && !h.is_invalid() {
-                    let mut mode = CONSOLE_MODE(0);
-                    if GetConsoleMode(h, &mut mode).is_ok() {
-                        anyhow::bail!(
-                            "ht requires redirected stdio (pipes or files).\n\
-                             It cannot be used from an interactive console.\n\
-                             Typical usage: orchestrator spawns `ht ...` with piped stdin/stdout."
-                        );
-                    }
-                }
-            }
-        }
+impl Drop for PseudoConsole {
+    fn drop(&mut self) {
+        unsafe { ClosePseudoConsole(self.0) };
+    }
+}
 
-        // 3. FreeConsole — detach from current console
-        let _ = unsafe { FreeConsole() };
-        guard.mark_detached();
+/// Child process attached to a pseudo console, which ht talks to through a
+/// pair of pipes, like a terminal through a Unix PTY: input is written as is
+/// (key presses as VT sequences), and output, VT sequences included, is read
+/// as the console renders it.
+#[cfg(windows)]
+struct ConPty {
+    child_pid: u32,
+    process: OwnedHandle,
+    console: PseudoConsole,
+    input: File,
+    output: File,
+}
 
-        // 4. CreateProcessW with CREATE_NEW_CONSOLE | CREATE_NEW_PROCESS_GROUP
+#[cfg(windows)]
+impl ConPty {
+    fn new(winsize: Winsize, command: &str, cwd: Option<&Path>) -> Result<Self> {
         assert!(
             !command.is_empty(),
             "command should not be empty; caller provides a default"
         );
-        let mut cmd_wide: Vec<u16> = command
-            .encode_utf16()
-            .chain(std::iter::once(0u16))
-            .collect();
-        let cmd_pwstr = PWSTR(cmd_wide.as_mut_ptr());
 
-        let mut si: STARTUPINFOW = unsafe { zeroed() };
-        si.cb = size_of::<STARTUPINFOW>() as u32;
-        si.dwFlags = STARTF_USESHOWWINDOW;
-        si.wShowWindow = SW_HIDE.0 as u16;
-
-        let mut proc_info: PROCESS_INFORMATION = unsafe { zeroed() };
-
-        unsafe {
-            CreateProcessW(
-                None,
-                cmd_pwstr,
-                None,
-                None,
-                false,
-                CREATE_NEW_CONSOLE | CREATE_NEW_PROCESS_GROUP,
-                None,
-                None,
-                &si,
-                &mut proc_info,
-            )
-        }?;
-
-        let child_pid = proc_info.dwProcessId;
-        let proc_handle =
-            Some(unsafe { OwnedHandle::from_raw_handle(proc_info.hProcess.0 as *mut _) });
-        let thread_handle =
-            Some(unsafe { OwnedHandle::from_raw_handle(proc_info.hThread.0 as *mut _) });
-
-        // 5. Polling attach loop — retry AttachConsole every 50ms for up to 5s
-        let attach_deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
-        let mut attached = false;
-        while std::time::Instant::now() < attach_deadline {
-            // Check if child already exited
-            let wait_result = unsafe { WaitForSingleObject(proc_info.hProcess, 0) };
-            if wait_result == WAIT_OBJECT_0 {
-                anyhow::bail!("child process exited before console was ready");
-            }
-            if unsafe { AttachConsole(child_pid) }.is_ok() {
-                attached = true;
-                break;
-            }
-            std::thread::sleep(std::time::Duration::from_millis(50));
-        }
-        if !attached {
-            anyhow::bail!("failed to attach to child console after 5 seconds");
-        }
+        let (input_read, input) = pipe()?;
+        let (output, output_write) = pipe()?;
 
-        // 6. SetConsoleCtrlHandler — ignore Ctrl+C/Break in HT
-        unsafe { SetConsoleCtrlHandler(None, true) }?;
-        guard.mark_ctrl_handler_set();
-
-        // 7. Open CONOUT$ and CONIN$
-        let conout_name: Vec<u16> = "CONOUT$\0".encode_utf16().collect();
-        let conout = unsafe {
-            CreateFileW(
-                PCWSTR(conout_name.as_ptr()),
-                GENERIC_READ.0 | GENERIC_WRITE.0,
-                FILE_SHARE_READ | FILE_SHARE_WRITE,
-                None,
-                OPEN_EXISTING,
-                Default::default(),
-                None,
-            )
-        }?;
-        let conout_handle = SendHandle::from_handle(conout);
-
-        let conin_name: Vec<u16> = "CONIN$\0".encode_utf16().collect();
-        let conin = unsafe {
-            CreateFileW(
-                PCWSTR(conin_name.as_ptr()),
-                GENERIC_READ.0 | GENERIC_WRITE.0,
-                FILE_SHARE_READ | FILE_SHARE_WRITE,
-                None,
-                OPEN_EXISTING,
-                Default::default(),
-                None,
+        let console = unsafe {
+            CreatePseudoConsole(
+                console_size(winsize.ws_col, winsize.ws_row),
+                HANDLE(input_read.as_raw_handle()),
+                HANDLE(output_write.as_raw_handle()),
+                0,
             )
-        }?;
-        let conin_handle = SendHandle::from_handle(conin);
-
-        // 8. Enable VT processing
-        unsafe {
-            let mut mode = CONSOLE_MODE(0);
-            if GetConsoleMode(conout, &mut mode).is_ok() {
-                let _ = SetConsoleMode(
-                    conout,
-                    mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING | ENABLE_PROCESSED_OUTPUT,
-                );
-            }
-            let mut in_mode = CONSOLE_MODE(0);
-            if GetConsoleMode(conin, &mut in_mode).is_ok() {
-                let _ = SetConsoleMode(conin, in_mode | ENABLE_VIRTUAL_TERMINAL_INPUT);
-            }
         }
+        .context("cannot create pseudo console")?;
+        let console = PseudoConsole(console);
 
-        // 9. Apply terminal size — viewport = requested size, buffer height = maximum
-        let buf_width = (winsize.ws_col.min(i16::MAX as u16).max(1)) as i16;
-        let buf_height: i16 = i16::MAX;
-
-        unsafe {
-            // Shrink window to 1×1 (always valid)
-            let small = SMALL_RECT {
-                Left: 0,
-                Top: 0,
-                Right: 0,
-                Bottom: 0,
-            };
-            let _ = SetConsoleWindowInfo(conout, true, &small);
-
-            // Set buffer size
-            let _ = SetConsoleScreenBufferSize(
-                conout,
-                COORD {
-                    X: buf_width,
-                    Y: buf_height,
-                },
-            );
-
-            // Set viewport
-            let viewport = SMALL_RECT {
-                Left: 0,
-                Top: 0,
-                Right: buf_width - 1,
-                Bottom: (winsize.ws_row.min(i16::MAX as u16).max(1)) as i16 - 1,
-            };
-            let _ = SetConsoleWindowInfo(conout, true, &viewport);
-        }
+        // the console keeps its own copies of its ends of the pipes
+        drop(input_read);
+        drop(output_write);
 
-        // 10. Disarm guard — ownership transfers to ScrapePty
-        guard.disarm();
+        let (child_pid, process) = spawn_attached(&console, command, cwd)?;
 
-        Ok(ScrapePty {
+        Ok(ConPty {
             child_pid,
-            proc_handle,
-            thread_handle,
-            conout: conout_handle,
-            conin: conin_handle,
-            needs_cleanup: true,
+            process,
+            console,
+            input,
+            output,
         })
     }
 
     async fn drive(
-        mut self,
+        self,
         input_rx: mpsc::Receiver<Vec<u8>>,
         output_tx: mpsc::Sender<Vec<u8>>,
-        resize_rx: mpsc::Receiver<(u16, u16)>,
+        mut resize_rx: mpsc::Receiver<(u16, u16, bool)>,
         initial_input: Option<Vec<u8>>,
-    ) -> Result<()> {
-        let conout = self.conout;
-        let conin = self.conin;
-        let child_pid = self.child_pid;
-
-        // Take ownership of process/thread handles out of self.
-        // self.proc_handle becomes None, so Drop won't try to TerminateProcess.
-        // proc_owned keeps the OS handle alive; proc_send is the Send-safe copy
-        // for spawned tasks.  The handle is closed exactly once via drop(proc_owned).
-        let proc_owned = self
-            .proc_handle
-            .take()
-            .ok_or_else(|| anyhow::anyhow!("proc_handle missing"))?;
-        let proc_send = SendHandle::from_handle(HANDLE(proc_owned.as_raw_handle()));
-        let thread_owned = self.thread_handle.take();
-
-        // Shared flag for signaling poll thread to stop
-        let stop_flag = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-        let stop_flag_poll = stop_flag.clone();
-
-        // Screen poll thread (spawn_blocking)
-        let output_tx_poll = output_tx.clone();
-        let mut poll_handle = tokio::task::spawn_blocking(move || {
-            let mut prev_viewport: Vec<Vec<Cell>> = Vec::new();
-            let mut prev_sr_window_top: i16 = 0;
-            let mut first_poll = true;
-
-            while !stop_flag_poll.load(std::sync::atomic::Ordering::Relaxed) {
-                std::thread::sleep(std::time::Duration::from_millis(40));
-
-                let conout_h = conout.to_handle();
-
-                // Get current screen buffer info
-                let mut csbi: CONSOLE_SCREEN_BUFFER_INFO = unsafe { zeroed() };
-                if unsafe { GetConsoleScreenBufferInfo(conout_h, &mut csbi) }.is_err() {
-                    break;
-                }
+        traffic: Arc<Traffic>,
+    ) -> Result<ExitStatus> {
+        let ConPty {
+            process,
+            console,
+            mut input,
+            mut output,
+            ..
+        } = self;
+
+        let process = SendHandle::from_handle(HANDLE(process.as_raw_handle()));
+
+        // Output is read until the console is closed, which ends the pipe.
+        let output_traffic = traffic.clone();
+        let mut reader = tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0u8; DEFAULT_READ_BUFFER_SIZE];
 
-                let sr = csbi.srWindow;
-                let viewport_width = (sr.Right - sr.Left + 1) as usize;
-                let viewport_height = (sr.Bottom - sr.Top + 1) as usize;
+            loop {
+                match output.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
 
-                if viewport_width == 0 || viewport_height == 0 {
-                    continue;
-                }
+                    Ok(n) => {
+                        output_traffic.add_output(n);
 
-                let mut output_data = String::new();
-
-                // Scroll detection: recover scrolled-out rows
-                if !first_poll && sr.Top > prev_sr_window_top {
-                    let scroll_start = prev_sr_window_top;
-                    let scroll_end = sr.Top;
-                    let scroll_rows = (scroll_end - scroll_start) as usize;
-
-                    // Read scrolled-out rows from scrollback buffer
-                    let read_height = scroll_rows.min(8192);
-                    let mut scroll_buf =
-                        vec![unsafe { zeroed::<CHAR_INFO>() }; viewport_width * read_height];
-                    let scroll_size = COORD {
-                        X: viewport_width as i16,
-                        Y: read_height as i16,
-                    };
-                    let scroll_coord = COORD { X: 0, Y: 0 };
-                    let mut scroll_rect = SMALL_RECT {
-                        Left: sr.Left,
-                        Top: scroll_start,
-                        Right: sr.Right,
-                        Bottom: scroll_start + read_height as i16 - 1,
-                    };
-
-                    if unsafe {
-                        ReadConsoleOutputW(
-                            conout_h,
-                            scroll_buf.as_mut_ptr(),
-                            scroll_size,
-                            scroll_coord,
-                            &mut scroll_rect,
-                        )
-                    }
-                    .is_ok()
-                    {
-                        for row_idx in 0..read_height {
-                            let start = row_idx * viewport_width;
-                            let end = start + viewport_width;
-                            let row_cells = decode_char_info_row(&scroll_buf[start..end]);
-                            // Emit scrolled row with full color
-                            let mut last_attr: Option<u16> = None;
-                            for cell in &row_cells {
-                                if last_attr != Some(cell.attr) {
-                                    output_data.push_str(&attr_to_sgr(cell.attr));
-                                    last_attr = Some(cell.attr);
-                                }
-                                output_data.push(cell.ch);
-                            }
-                            output_data.push_str("\r\n");
+                        if output_tx.blocking_send(buf[..n].to_vec()).is_err() {
+                            break;
                         }
                     }
-
-                    // Force full viewport redraw after scroll
-                    prev_viewport.clear();
-                }
-
-                prev_sr_window_top = sr.Top;
-
-                // Read current viewport via ReadConsoleOutputW
-                let mut buf =
-                    vec![unsafe { zeroed::<CHAR_INFO>() }; viewport_width * viewport_height];
-                let buf_size = COORD {
-                    X: viewport_width as i16,
-                    Y: viewport_height as i16,
-                };
-                let buf_coord = COORD { X: 0, Y: 0 };
-                let mut read_region = SMALL_RECT {
-                    Left: sr.Left,
-                    Top: sr.Top,
-                    Right: sr.Right,
-                    Bottom: sr.Bottom,
-                };
-
-                if unsafe {
-                    ReadConsoleOutputW(
-                        conout_h,
-                        buf.as_mut_ptr(),
-                        buf_size,
-                        buf_coord,
-                        &mut read_region,
-                    )
-                }
-                .is_err()
-                {
-                    break;
-                }
-
-                // Decode CHAR_INFO buffer into Cell grid
-                let mut curr_viewport: Vec<Vec<Cell>> = Vec::with_capacity(viewport_height);
-                for row_idx in 0..viewport_height {
-                    let start = row_idx * viewport_width;
-                    let end = start + viewport_width;
-                    curr_viewport.push(decode_char_info_row(&buf[start..end]));
-                }
-
-                // Convert cursor to 1-based ANSI coordinates
-                let cursor_row = (csbi.dwCursorPosition.Y - sr.Top + 1).max(1) as u16;
-                let cursor_col = (csbi.dwCursorPosition.X - sr.Left + 1).max(1) as u16;
-
-                // Diff and emit
-                let diff = diff_and_emit(
-                    &prev_viewport,
-                    &curr_viewport,
-                    cursor_row,
-                    cursor_col,
-                    viewport_width as u16,
-                );
-
-                output_data.push_str(&diff);
-
-                if !output_data.is_empty()
-                    && output_tx_poll
-                        .blocking_send(output_data.into_bytes())
-                        .is_err()
-                {
-                    break;
                 }
-
-                prev_viewport = curr_viewport;
-                first_poll = false;
             }
         });
 
-        // Input relay task (tokio::spawn — async for timeout support)
-        let conin_input = conin;
-        let mut input_relay = tokio::spawn(async move {
-            let mut parser = InputParser::new();
+        // Writes block while the console doesn't take the input, so they're
+        // done in a thread of their own. It ends when the input channel
+        // closes, or the console goes away.
+        let (input_closed_tx, mut input_closed_rx) = oneshot::channel::<()>();
+        std::thread::spawn(move || {
             let mut input_rx = input_rx;
 
-            // Handle initial input
-            if let Some(data) = initial_input {
-                let conin_h = conin_input.to_handle();
-                let actions = parser.parse(&data, conin_h);
-                Self::dispatch_actions(&actions, conin_h, child_pid);
-            }
-
-            loop {
-                let result =
-                    tokio::time::timeout(std::time::Duration::from_millis(1000), input_rx.recv())
-                        .await;
-
-                // Reconstruct HANDLE after .await (HANDLE is !Send)
-                let conin_h = conin_input.to_handle();
-                match result {
-                    Ok(Some(data)) => {
-                        let actions = parser.parse(&data, conin_h);
-                        Self::dispatch_actions(&actions, conin_h, child_pid);
-                    }
-                    Ok(None) => {
-                        // Channel closed — flush pending
-                        if parser.has_pending() {
-                            let actions = parser.flush_pending();
-                            Self::dispatch_actions(&actions, conin_h, child_pid);
-                        }
-                        break;
-                    }
-                    Err(_timeout) => {
-                        if parser.has_pending() {
-                            let actions = parser.flush_pending();
-                            Self::dispatch_actions(&actions, conin_h, child_pid);
-                        }
-                    }
+            for data in initial_input
+                .into_iter()
+                .chain(std::iter::from_fn(|| input_rx.blocking_recv()))
+            {
+                if input.write_all(&data).is_err() {
+                    return;
                 }
+
+                traffic.add_input(data.len());
             }
+
+            let _ = input_closed_tx.send(());
         });
 
-        // Resize task
-        let resize_conout = conout;
-        let resize_task = tokio::spawn(async move {
-            let mut resize_rx = resize_rx;
-            while let Some((new_cols, new_rows)) = resize_rx.recv().await {
-                let conout_h = resize_conout.to_handle();
-                let new_buf_width = (new_cols.min(i16::MAX as u16).max(1)) as i16;
-                let buf_height: i16 = i16::MAX;
-
-                unsafe {
-                    // Shrink to 1×1
-                    let small = SMALL_RECT {
-                        Left: 0,
-                        Top: 0,
-                        Right: 0,
-                        Bottom: 0,
-                    };
-                    let _ = SetConsoleWindowInfo(conout_h, true, &small);
-
-                    // New buffer size
-                    let _ = SetConsoleScreenBufferSize(
-                        conout_h,
-                        COORD {
-                            X: new_buf_width,
-                            Y: buf_height,
-                        },
-                    );
-
-                    // Get current top for viewport positioning
-                    let mut csbi: CONSOLE_SCREEN_BUFFER_INFO = zeroed();
-                    let top = if GetConsoleScreenBufferInfo(conout_h, &mut csbi).is_ok() {
-                        csbi.srWindow.Top
-                    } else {
-                        0
-                    };
-
-                    let new_rows_i16 = (new_rows.min(i16::MAX as u16).max(1)) as i16;
-                    let viewport = SMALL_RECT {
-                        Left: 0,
-                        Top: top,
-                        Right: new_buf_width - 1,
-                        Bottom: top + new_rows_i16 - 1,
-                    };
-                    let _ = SetConsoleWindowInfo(conout_h, true, &viewport);
-                }
+        let hpcon = console.0;
+        let resizer = tokio::spawn(async move {
+            // there's no SIGWINCH to send or hold back on Windows
+            while let Some((cols, rows, _signal)) = resize_rx.recv().await {
+                let _ = unsafe { ResizePseudoConsole(hpcon, console_size(cols, rows)) };
             }
         });
 
-        // Process wait thread
-        let mut wait_handle = tokio::task::spawn_blocking(move || unsafe {
-            WaitForSingleObject(proc_send.to_handle(), u32::MAX);
+        let mut exited = tokio::task::spawn_blocking(move || unsafe {
+            WaitForSingleObject(process.to_handle(), INFINITE);
         });
 
-        // Wait for any thread to finish
-        #[derive(PartialEq)]
-        enum Finished {
-            Poll,
-            Input,
-            Wait,
-        }
-        let finished = tokio::select! {
-            _ = &mut poll_handle => Finished::Poll,
-            _ = &mut input_relay => Finished::Input,
-            _ = &mut wait_handle => Finished::Wait,
-        };
+        tokio::select! {
+            _ = &mut exited => (),
 
-        // --- Cleanup sequence ---
-
-        // 1. Signal poll thread to stop and abort resize task
-        stop_flag.store(true, std::sync::atomic::Ordering::Relaxed);
-        resize_task.abort();
-
-        // 2. Terminate child if still alive (use proc_send — single owner)
-        {
-            let proc_raw = proc_send.to_handle();
-            let wait_result = unsafe { WaitForSingleObject(proc_raw, 0) };
-            if wait_result != WAIT_OBJECT_0 {
-                let _ = unsafe { TerminateProcess(proc_raw, 1) };
-                tokio::task::spawn_blocking(move || unsafe {
-                    WaitForSingleObject(proc_send.to_handle(), 5000);
-                })
-                .await?;
+            // the session ends with the input, as on Unix
+            Ok(()) = &mut input_closed_rx => {
+                let _ = unsafe { TerminateProcess(process.to_handle(), 1) };
+                let _ = exited.await;
             }
         }
 
-        // 3. Await remaining threads (all must finish before closing handles)
-        if finished != Finished::Poll {
-            let _ = poll_handle.await;
-        }
-        if finished != Finished::Input {
-            input_relay.abort();
-            let _ = input_relay.await;
-        }
-        if finished != Finished::Wait {
-            let _ = wait_handle.await;
-        }
-        let _ = resize_task.await;
+        resizer.abort();
 
-        // 4. Close handles
-        unsafe {
-            if !self.conout.is_null() {
-                let _ = windows::Win32::Foundation::CloseHandle(self.conout.to_handle());
-                self.conout = SendHandle(0);
-            }
-            if !self.conin.is_null() {
-                let _ = windows::Win32::Foundation::CloseHandle(self.conin.to_handle());
-                self.conin = SendHandle(0);
-            }
-        }
+        let mut exit_code = 0u32;
+        let status = match unsafe { GetExitCodeProcess(process.to_handle(), &mut exit_code) } {
+            Ok(()) => ExitStatus::from(exit_code),
+            Err(_) => ExitStatus::Unknown,
+        };
 
-        // 5. Re-enable Ctrl+C
-        let _ = unsafe { SetConsoleCtrlHandler(None, false) };
+        // Closing the console flushes its output and ends the pipe, while the
+        // reader keeps draining it (older Windows versions wait for that).
+        let closed = tokio::task::spawn_blocking(move || drop(console));
+        let _ = tokio::time::timeout(CONSOLE_CLOSE_TIMEOUT, &mut reader).await;
+        let _ = closed.await;
 
-        // 6. Detach from child's console
-        let _ = unsafe { FreeConsole() };
+        Ok(status)
+    }
+}
 
-        // 7. Reattach to parent console
-        let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+#[cfg(windows)]
+fn console_size(cols: u16, rows: u16) -> COORD {
+    COORD {
+        X: cols.clamp(1, i16::MAX as u16) as i16,
+        Y: rows.clamp(1, i16::MAX as u16) as i16,
+    }
+}
 
-        // 8. Cleanup complete
-        self.needs_cleanup = false;
-        SCRAPE_ACTIVE.store(false, std::sync::atomic::Ordering::Release);
+#[cfg(windows)]
+fn pipe() -> Result<(File, File)> {
+    let (mut read, mut write) = (HANDLE::default(), HANDLE::default());
+    unsafe { CreatePipe(&mut read, &mut write, None, 0) }.context("cannot create pipe")?;
 
-        // Drop handles (proc_owned/thread_owned own the OS handles; closing happens here)
-        drop(proc_owned);
-        drop(thread_owned);
+    Ok(unsafe {
+        (
+            File::from_raw_handle(read.0),
+            File::from_raw_handle(write.0),
+        )
+    })
+}
 
-        Ok(())
-    }
+/// Starts the command attached to the pseudo console, returning its PID and
+/// process handle.
+#[cfg(windows)]
+fn spawn_attached(
+    console: &PseudoConsole,
+    command: &str,
+    cwd: Option<&Path>,
+) -> Result<(u32, OwnedHandle)> {
+    let mut cmd_wide: Vec<u16> = command
+        .encode_utf16()
+        .chain(std::iter::once(0u16))
+        .collect();
+    let cwd_wide: Option<Vec<u16>> = cwd.map(|cwd| {
+        use std::os::windows::ffi::OsStrExt;
+
+        cwd.as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0u16))
+            .collect()
+    });
+    let cwd_pcwstr = cwd_wide.as_ref().map(|w| PCWSTR(w.as_ptr()));
+
+    // The first call only reports the size the attribute list needs. It's
+    // kept in a buffer of usize for the alignment of its pointers.
+    let mut size = 0;
+    let _ = unsafe {
+        InitializeProcThreadAttributeList(LPPROC_THREAD_ATTRIBUTE_LIST(null_mut()), 1, 0, &mut size)
+    };
+    let mut attributes = vec![0usize; size.div_ceil(size_of::<usize>())];
+    let attribute_list = LPPROC_THREAD_ATTRIBUTE_LIST(attributes.as_mut_ptr().cast());
+    unsafe { InitializeProcThreadAttributeList(attribute_list, 1, 0, &mut size) }
+        .context("cannot attach command to pseudo console")?;
+
+    let result = unsafe {
+        UpdateProcThreadAttribute(
+            attribute_list,
+            0,
+            PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE as usize,
+            Some(console.0.0 as *const c_void),
+            size_of::<HPCON>(),
+            None,
+            None,
+        )
+    }
+    .context("cannot attach command to pseudo console")
+    .and_then(|()| {
+        let mut si: STARTUPINFOEXW = unsafe { zeroed() };
+        si.StartupInfo.cb = size_of::<STARTUPINFOEXW>() as u32;
+        // Without standard handles of its own the child would get ht's
+        // (e.g. redirected) ones instead of the console.
+        si.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
+        si.StartupInfo.hStdInput = INVALID_HANDLE_VALUE;
+        si.StartupInfo.hStdOutput = INVALID_HANDLE_VALUE;
+        si.StartupInfo.hStdError = INVALID_HANDLE_VALUE;
+        si.lpAttributeList = attribute_list;
 
-    fn dispatch_actions(actions: &[InputAction], conin: HANDLE, child_pid: u32) {
-        for action in actions {
-            match action {
-                InputAction::KeyEvent(ke) => {
-                    let records = expand_key_event(ke);
-                    let mut written = 0u32;
-                    let _ = unsafe { WriteConsoleInputW(conin, &records, &mut written) };
-                }
-                InputAction::GenerateCtrlC => {
-                    let _ = unsafe { GenerateConsoleCtrlEvent(CTRL_C_EVENT, child_pid) };
-                }
-            }
+        let mut proc_info: PROCESS_INFORMATION = unsafe { zeroed() };
+
+        unsafe {
+            CreateProcessW(
+                None,
+                PWSTR(cmd_wide.as_mut_ptr()),
+                None,
+                None,
+                false,
+                EXTENDED_STARTUPINFO_PRESENT,
+                None,
+                cwd_pcwstr.unwrap_or(PCWSTR::null()),
+                &si.StartupInfo,
+                &mut proc_info,
+            )
         }
-    }
+        .with_context(|| match cwd {
+            Some(cwd) => format!("cannot start command in directory {}", cwd.display()),
+            None => "cannot start command".to_owned(),
+        })?;
+
+        // only the process handle is needed
+        drop(unsafe { OwnedHandle::from_raw_handle(proc_info.hThread.0) });
+        let process = unsafe { OwnedHandle::from_raw_handle(proc_info.hProcess.0) };
+
+        Ok((proc_info.dwProcessId, process))
+    });
+
+    unsafe { DeleteProcThreadAttributeList(attribute_list) };
+
+    result
 }
 
 #[cfg(windows)]
+#[allow(dead_code)]
 pub fn spawn(
     command: String,
     winsize: Winsize,
     input_rx: mpsc::Receiver<Vec<u8>>,
     output_tx: mpsc::Sender<Vec<u8>>,
-    resize_rx: mpsc::Receiver<(u16, u16)>,
+    resize_rx: mpsc::Receiver<(u16, u16, bool)>,
+    initial_input: Option<Vec<u8>>,
+) -> Result<impl Future<Output = Result<SessionOutcome>>> {
+    spawn_with_options(
+        command,
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        initial_input,
+        SpawnOptions::default(),
+    )
+    .map(|(_pid, future)| future)
+}
+
+#[cfg(windows)]
+pub fn spawn_with_options(
+    command: String,
+    winsize: Winsize,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    output_tx: mpsc::Sender<Vec<u8>>,
+    resize_rx: mpsc::Receiver<(u16, u16, bool)>,
     initial_input: Option<Vec<u8>>,
-) -> Result<impl Future<Output = Result<()>>> {
-    let scrape = ScrapePty::new(winsize, &command)?;
-    Ok(scrape.drive(input_rx, output_tx, resize_rx, initial_input))
+    options: SpawnOptions,
+) -> Result<(u32, impl Future<Output = Result<SessionOutcome>>)> {
+    let started = Instant::now();
+    let pty = ConPty::new(winsize, &command, options.cwd.as_deref())?;
+    let pid = pty.child_pid;
+    let traffic = Arc::new(Traffic::default());
+    let drive = pty.drive(
+        input_rx,
+        output_tx,
+        resize_rx,
+        initial_input,
+        traffic.clone(),
+    );
+
+    Ok((pid, async move {
+        let status = drive.await?;
+
+        Ok(traffic.outcome(status, started))
+    }))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // ── exit status ─────────────────────────────────────────────────
+
+    #[test]
+    fn exit_code_fits_in_a_byte() {
+        assert_eq!(ExitStatus::Exited(0).exit_code(), 0);
+        assert_eq!(ExitStatus::Exited(42).exit_code(), 42);
+        assert_eq!(ExitStatus::Exited(255).exit_code(), 255);
+        assert_eq!(ExitStatus::Exited(256).exit_code(), 1);
+        assert_eq!(ExitStatus::Exited(512).exit_code(), 1);
+        assert_eq!(ExitStatus::Exited(0xC000_0005_u32 as i32).exit_code(), 1);
+        assert_eq!(ExitStatus::Unknown.exit_code(), 1);
+
+        let killed = ExitStatus::Signaled {
+            signal: 9,
+            name: "SIGKILL".to_owned(),
+            core_dumped: false,
+        };
+        assert_eq!(killed.exit_code(), 137);
+    }
+
     // ── escape_arg ────────────────────────────────────────────────
 
     #[test]
@@ -2051,447 +2116,95 @@ mod tests {
     fn no_env_var_paren_at_start() {
         assert!(!contains_env_var("%(x86)%"));
     }
-}
-
-#[cfg(all(test, windows))]
-mod scrape_tests {
-    use super::*;
-
-    // ── attr_to_sgr ─────────────────────────────────────────────────
-
-    #[test]
-    fn attr_default_white_on_black() {
-        // Default console: white fg (RGB=111) = 0x07, black bg = 0x00
-        let sgr = attr_to_sgr(0x0007);
-        assert!(sgr.contains("37"), "expected white fg (37), got: {sgr}");
-        assert!(sgr.contains("40"), "expected black bg (40), got: {sgr}");
-    }
-
-    #[test]
-    fn attr_red_foreground() {
-        // Red fg = FOREGROUND_RED (0x04)
-        let sgr = attr_to_sgr(0x0004);
-        assert!(sgr.contains("31"), "expected red fg (31), got: {sgr}");
-    }
-
-    #[test]
-    fn attr_blue_background() {
-        // Blue bg = BACKGROUND_BLUE (0x10)
-        let sgr = attr_to_sgr(0x0017); // white fg + blue bg
-        assert!(sgr.contains("44"), "expected blue bg (44), got: {sgr}");
-    }
-
-    #[test]
-    fn attr_bright_green_foreground() {
-        // Bright green = GREEN(0x02) | INTENSITY(0x08)
-        let sgr = attr_to_sgr(0x000A);
-        assert!(
-            sgr.contains("92"),
-            "expected bright green fg (92), got: {sgr}"
-        );
-    }
-
-    #[test]
-    fn attr_reverse_video() {
-        let sgr = attr_to_sgr(0x4007); // COMMON_LVB_REVERSE_VIDEO | white on black
-        assert!(
-            sgr.contains(";7"),
-            "expected reverse video (;7), got: {sgr}"
-        );
-    }
 
-    #[test]
-    fn attr_underscore() {
-        let sgr = attr_to_sgr(0x8007); // COMMON_LVB_UNDERSCORE | white on black
-        assert!(sgr.contains(";4"), "expected underline (;4), got: {sgr}");
-    }
+    // ── spawn ───────────────────────────────────────────────────────
 
+    #[cfg(unix)]
     #[test]
-    fn attr_combined_reverse_underline() {
-        let sgr = attr_to_sgr(0xC004); // REVERSE + UNDERSCORE + red fg
-        assert!(sgr.contains(";7"), "expected reverse, got: {sgr}");
-        assert!(sgr.contains(";4"), "expected underline, got: {sgr}");
-        assert!(sgr.contains("31"), "expected red fg, got: {sgr}");
-    }
-
-    // ── diff_and_emit ───────────────────────────────────────────────
+    fn spawn_rejects_nul_in_command() {
+        let (_input_tx, input_rx) = mpsc::channel(1);
+        let (output_tx, _output_rx) = mpsc::channel(1);
+        let (_resize_tx, resize_rx) = mpsc::channel(1);
 
-    #[test]
-    fn diff_first_frame_emits_all() {
-        let row = vec![
-            Cell {
-                ch: 'A',
-                width: 1,
-                attr: 0x07,
-            },
-            Cell {
-                ch: 'B',
-                width: 1,
-                attr: 0x07,
+        let result = spawn(
+            "echo a\0b".to_owned(),
+            Winsize {
+                ws_row: 24,
+                ws_col: 80,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
             },
-        ];
-        let curr = vec![row];
-        let result = diff_and_emit(&[], &curr, 1, 1, 2);
-        assert!(result.contains('A'), "should contain 'A': {result}");
-        assert!(result.contains('B'), "should contain 'B': {result}");
-        // Should have cursor positioning
-        assert!(
-            result.contains("\x1b[1;1H"),
-            "should position cursor: {result}"
-        );
-    }
-
-    #[test]
-    fn diff_no_change_emits_cursor_only() {
-        let row = vec![Cell {
-            ch: 'X',
-            width: 1,
-            attr: 0x07,
-        }];
-        let viewport = vec![row.clone()];
-        let result = diff_and_emit(&viewport, &viewport, 1, 1, 1);
-        // Should only contain cursor positioning, not 'X'
-        assert!(
-            !result.contains('X'),
-            "unchanged row should not re-emit: {result}"
-        );
-    }
-
-    #[test]
-    fn diff_changed_row_has_erase() {
-        let old = vec![vec![Cell {
-            ch: 'A',
-            width: 1,
-            attr: 0x07,
-        }]];
-        let new = vec![vec![Cell {
-            ch: 'B',
-            width: 1,
-            attr: 0x07,
-        }]];
-        let result = diff_and_emit(&old, &new, 1, 1, 3);
-        assert!(result.contains('B'), "should contain new char: {result}");
-        assert!(
-            result.contains("\x1b[K"),
-            "should have erase-to-end: {result}"
-        );
-    }
-
-    #[test]
-    fn diff_cursor_position_1based() {
-        let row = vec![Cell {
-            ch: ' ',
-            width: 1,
-            attr: 0x07,
-        }];
-        let viewport = vec![row];
-        let result = diff_and_emit(&viewport, &viewport, 3, 5, 1);
-        assert!(
-            result.contains("\x1b[3;5H"),
-            "cursor should be at row 3 col 5: {result}"
+            input_rx,
+            output_tx,
+            resize_rx,
+            None,
         );
-    }
-
-    // ── InputParser ─────────────────────────────────────────────────
-
-    #[test]
-    fn parse_printable_ascii() {
-        let parser = InputParser::new();
-        let actions = parser.byte_to_actions(b'a');
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(unsafe { ke.uChar.UnicodeChar }, b'a' as u16);
-                assert_eq!(ke.wRepeatCount, 1);
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
-
-    #[test]
-    fn parse_enter() {
-        let parser = InputParser::new();
-        let actions = parser.byte_to_actions(0x0D);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x0D); // VK_RETURN
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
 
-    #[test]
-    fn parse_ctrl_z() {
-        let parser = InputParser::new();
-        let actions = parser.byte_to_actions(0x1A);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, b'Z' as u16);
-                assert_eq!(ke.dwControlKeyState & 0x0008, 0x0008); // LEFT_CTRL_PRESSED
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
-
-    #[test]
-    fn parse_arrow_keys_csi() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        let actions = parser.parse(b"\x1b[A", conin);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x26); // VK_UP
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
-
-    #[test]
-    fn parse_arrow_keys_ss3() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        let actions = parser.parse(b"\x1bOA", conin);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x26); // VK_UP
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
-
-    #[test]
-    fn parse_ctrl_up_modifier() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        let actions = parser.parse(b"\x1b[1;5A", conin); // Ctrl+Up
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x26); // VK_UP
-                assert_eq!(ke.dwControlKeyState & 0x0008, 0x0008); // LEFT_CTRL_PRESSED
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
-
-    #[test]
-    fn parse_ctrl_alt_up_modifier() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        let actions = parser.parse(b"\x1b[1;7A", conin); // Ctrl+Alt+Up
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x26); // VK_UP
-                assert_eq!(ke.dwControlKeyState & 0x0008, 0x0008); // LEFT_CTRL_PRESSED
-                assert_eq!(ke.dwControlKeyState & 0x0002, 0x0002); // LEFT_ALT_PRESSED
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
-
-    #[test]
-    fn parse_function_keys() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-
-        // F5 = \x1b[15~
-        let actions = parser.parse(b"\x1b[15~", conin);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x74); // VK_F5
-            }
-            _ => panic!("expected KeyEvent for F5"),
-        }
-
-        // F12 = \x1b[24~
-        let actions = parser.parse(b"\x1b[24~", conin);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x7B); // VK_F12
-            }
-            _ => panic!("expected KeyEvent for F12"),
-        }
+        let error = result.err().expect("spawn should fail");
+        assert!(error.to_string().contains("NUL"), "{error}");
     }
 
-    #[test]
-    fn parse_standalone_escape() {
-        let parser = InputParser::new();
-        let actions = parser.byte_to_actions(0x1B);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(unsafe { ke.uChar.UnicodeChar }, 0x1B);
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
+    // ── Backpressure ────────────────────────────────────────────────
 
-    #[test]
-    fn parse_alt_letter() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        let actions = parser.parse(b"\x1bf", conin); // Alt+f
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.dwControlKeyState & 0x0002, 0x0002); // LEFT_ALT_PRESSED
-            }
-            _ => panic!("expected KeyEvent"),
-        }
+    fn backpressure(policy: BackpressurePolicy, max_bytes: usize) -> Backpressure {
+        Backpressure { policy, max_bytes }
     }
 
     #[test]
-    fn expand_key_event_produces_down_up() {
-        let mut ke: KEY_EVENT_RECORD = unsafe { zeroed() };
-        ke.wVirtualKeyCode = 0x41; // 'A'
-        ke.wRepeatCount = 1;
-        ke.uChar.UnicodeChar = b'a' as u16;
-        let records = expand_key_event(&ke);
-        assert_eq!(records.len(), 2);
-        // First is key-down
-        unsafe {
-            assert_eq!(records[0].Event.KeyEvent.bKeyDown.0, 1);
-            assert_eq!(records[1].Event.KeyEvent.bKeyDown.0, 0);
-        }
-    }
+    fn backpressure_block_keeps_all_input() {
+        let bp = backpressure(BackpressurePolicy::Block, 4);
+        let mut pending = b"abc".to_vec();
 
-    #[test]
-    fn parse_cross_chunk_buffering() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-
-        // First chunk: just ESC
-        let actions1 = parser.parse(b"\x1b", conin);
-        assert!(actions1.is_empty(), "ESC alone should be buffered");
-        assert!(parser.has_pending());
-
-        // Second chunk: completes the sequence
-        let actions2 = parser.parse(b"[A", conin);
-        assert_eq!(actions2.len(), 1);
-        match &actions2[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0x26); // VK_UP
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-        assert!(!parser.has_pending());
+        assert!(bp.accepts(pending.len()));
+        assert_eq!(bp.push(&mut pending, b"def"), 0);
+        assert_eq!(pending, b"abcdef");
+        assert!(!bp.accepts(pending.len()));
     }
 
     #[test]
-    fn flush_pending_emits_esc() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-
-        // Buffer an ESC
-        let _ = parser.parse(b"\x1b", conin);
-        assert!(parser.has_pending());
-
-        let actions = parser.flush_pending();
-        assert!(!actions.is_empty());
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(unsafe { ke.uChar.UnicodeChar }, 0x1B);
-            }
-            _ => panic!("expected ESC KeyEvent"),
-        }
-        assert!(!parser.has_pending());
-    }
-
-    // ── UTF-8 decoding in InputParser ────────────────────────────────
+    fn backpressure_drop_newest() {
+        let bp = backpressure(BackpressurePolicy::DropNewest, 4);
+        let mut pending = b"abc".to_vec();
 
-    #[test]
-    fn parse_utf8_two_byte_char() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        // é = U+00E9 = UTF-8 0xC3 0xA9
-        let actions = parser.parse(&[0xC3, 0xA9], conin);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0xE7); // VK_PACKET
-                assert_eq!(unsafe { ke.uChar.UnicodeChar }, 0x00E9); // é
-            }
-            _ => panic!("expected KeyEvent"),
-        }
+        assert_eq!(bp.push(&mut pending, b"def"), 2);
+        assert_eq!(pending, b"abcd");
+        assert!(bp.accepts(pending.len()));
+        assert_eq!(bp.push(&mut pending, b"g"), 1);
+        assert_eq!(pending, b"abcd");
     }
 
     #[test]
-    fn parse_utf8_three_byte_char() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        // € = U+20AC = UTF-8 0xE2 0x82 0xAC
-        let actions = parser.parse(&[0xE2, 0x82, 0xAC], conin);
-        assert_eq!(actions.len(), 1);
-        match &actions[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(ke.wVirtualKeyCode, 0xE7); // VK_PACKET
-                assert_eq!(unsafe { ke.uChar.UnicodeChar }, 0x20AC); // €
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
+    fn backpressure_drop_oldest() {
+        let bp = backpressure(BackpressurePolicy::DropOldest, 4);
+        let mut pending = b"abc".to_vec();
 
-    #[test]
-    fn parse_utf8_four_byte_char_produces_surrogate_pair() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        // 🎉 = U+1F389 = UTF-8 0xF0 0x9F 0x8E 0x89
-        let actions = parser.parse(&[0xF0, 0x9F, 0x8E, 0x89], conin);
-        // U+1F389 > U+FFFF, so vk_packet_for_char produces 2 VK_PACKET events (surrogate pair)
-        assert_eq!(actions.len(), 2);
+        assert_eq!(bp.push(&mut pending, b"def"), 2);
+        assert_eq!(pending, b"cdef");
+        assert_eq!(bp.push(&mut pending, b"ghijkl"), 6);
+        assert_eq!(pending, b"ijkl");
     }
 
     #[test]
-    fn parse_utf8_split_across_chunks() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        // é = 0xC3 0xA9, split across two parse() calls
-        let actions1 = parser.parse(&[0xC3], conin);
-        assert!(actions1.is_empty(), "incomplete UTF-8 should be buffered");
-        assert!(parser.has_pending());
-
-        let actions2 = parser.parse(&[0xA9], conin);
-        assert_eq!(actions2.len(), 1);
-        match &actions2[0] {
-            InputAction::KeyEvent(ke) => {
-                assert_eq!(unsafe { ke.uChar.UnicodeChar }, 0x00E9);
-            }
-            _ => panic!("expected KeyEvent"),
-        }
-    }
+    fn backpressure_default_is_unbounded() {
+        let bp = Backpressure::default();
+        let mut pending = vec![0; 1 << 20];
 
-    #[test]
-    fn parse_mixed_ascii_and_utf8() {
-        let mut parser = InputParser::new();
-        let conin = HANDLE(std::ptr::null_mut());
-        // "aé" = 0x61 0xC3 0xA9
-        let actions = parser.parse(&[0x61, 0xC3, 0xA9], conin);
-        assert_eq!(actions.len(), 2); // 'a' + 'é'
+        assert!(bp.accepts(pending.len()));
+        assert_eq!(bp.push(&mut pending, b"x"), 0);
     }
+}
 
-    // ── decode_char_info_row ────────────────────────────────────────
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use super::*;
 
     #[test]
-    fn decode_simple_ascii_row() {
-        let mut ci: CHAR_INFO = unsafe { zeroed() };
-        ci.Char.UnicodeChar = b'H' as u16;
-        ci.Attributes = 0x07;
-
-        let mut ci2: CHAR_INFO = unsafe { zeroed() };
-        ci2.Char.UnicodeChar = b'i' as u16;
-        ci2.Attributes = 0x07;
-
-        let cells = decode_char_info_row(&[ci, ci2]);
-        assert_eq!(cells.len(), 2);
-        assert_eq!(cells[0].ch, 'H');
-        assert_eq!(cells[0].width, 1);
-        assert_eq!(cells[1].ch, 'i');
+    fn exit_status_from_exit_code() {
+        assert_eq!(ExitStatus::from(42), ExitStatus::Exited(42));
+        assert_eq!(
+            ExitStatus::from(0xC000_0005),
+            ExitStatus::Exited(-1073741819)
+        );
+        assert_eq!(ExitStatus::from(259), ExitStatus::Unknown);
     }
 }
@@ -0,0 +1,555 @@
+use crate::log;
+use crate::session::{Event, Subscription};
+use anyhow::{Context, Result};
+use flate2::{Compression, write::GzEncoder};
+use futures_util::{Stream, StreamExt};
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+
+/// Writes session events as an asciicast v2 recording.
+///
+/// Timestamps are taken from the events, relative to the start of the
+/// session. With an idle limit, the gap between consecutive events is capped
+/// at the limit when writing, shifting everything after a long pause earlier
+/// while keeping the order of events.
+///
+/// With a coalesce window, output events following the first one of a burst
+/// within the window are merged into it, written as a single event with the
+/// first one's time once the burst is over.
+///
+/// Output muted for API clients is recorded like any other, unless the
+/// recorder pauses on mute: it then leaves it out, and repaints the whole
+/// screen once output is unmuted, so that playback skips the muted part.
+///
+/// The recorded terminal size can be overridden, per dimension, independently
+/// of the session's one. Resizes of an overridden dimension are then left out
+/// of the recording.
+pub struct Recorder<W: Write> {
+    writer: W,
+    idle_limit: Option<f64>,
+    coalesce_window: Option<f64>,
+    pause_on_mute: bool,
+    paused: bool,
+    size_override: (Option<usize>, Option<usize>),
+    size: (usize, usize),
+    pending_output: Option<(f64, String)>,
+    last_event_time: f64,
+    time: f64,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(writer: W, idle_limit: Option<f64>) -> Self {
+        Self {
+            writer,
+            idle_limit,
+            coalesce_window: None,
+            pause_on_mute: false,
+            paused: false,
+            size_override: (None, None),
+            size: (0, 0),
+            pending_output: None,
+            last_event_time: 0.0,
+            time: 0.0,
+        }
+    }
+
+    /// Sets the window (in seconds) for merging output events, `None`
+    /// writing every output event as it is.
+    pub fn set_coalesce_window(&mut self, window: Option<f64>) {
+        self.coalesce_window = window;
+    }
+
+    pub fn set_pause_on_mute(&mut self, pause: bool) {
+        self.pause_on_mute = pause;
+    }
+
+    /// Sets the width and height written to the recording instead of the
+    /// session's ones, `None` keeping the session's.
+    pub fn set_size_override(&mut self, cols: Option<usize>, rows: Option<usize>) {
+        self.size_override = (cols, rows);
+    }
+
+    /// Writes an event, flushing the writer so the recording is complete up
+    /// to the last event even if ht is killed (except for output still being
+    /// merged, see `finish`).
+    pub fn event(&mut self, event: &Event) -> Result<()> {
+        match event {
+            Event::Init(_time, cols, rows, _seq, _text) => {
+                let size = self.recorded_size(*cols, *rows);
+
+                if size != (*cols, *rows) {
+                    log::warning!(
+                        "recording at {}x{} while the terminal is {cols}x{rows}, playback may show artifacts",
+                        size.0,
+                        size.1
+                    );
+                }
+
+                self.size = size;
+                self.header(size.0, size.1)?;
+            }
+
+            Event::Output(time, data, _, _) if !self.paused && !data.is_empty() => {
+                self.output(*time, data)?
+            }
+
+            Event::Muted(time, muted, seq) if self.pause_on_mute => {
+                self.write_pending_output()?;
+
+                if self.paused && !muted {
+                    // reset the player's terminal before restoring the screen
+                    self.write(*time, "o", &format!("\x1bc{seq}"))?;
+                }
+
+                self.paused = *muted;
+            }
+
+            Event::Resize(time, cols, rows) => {
+                let size = self.recorded_size(*cols, *rows);
+
+                if size == self.size && self.size_override != (None, None) {
+                    return Ok(());
+                }
+
+                self.size = size;
+                self.write_pending_output()?;
+                self.write(*time, "r", &format!("{}x{}", size.0, size.1))?;
+            }
+
+            _ => return Ok(()),
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes output held back for merging, once no more events come.
+    pub fn finish(&mut self) -> Result<()> {
+        self.write_pending_output()?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    fn recorded_size(&self, cols: usize, rows: usize) -> (usize, usize) {
+        (
+            self.size_override.0.unwrap_or(cols),
+            self.size_override.1.unwrap_or(rows),
+        )
+    }
+
+    fn output(&mut self, time: f64, data: &str) -> Result<()> {
+        let Some(window) = self.coalesce_window else {
+            return self.write(time, "o", data);
+        };
+
+        if let Some((start, pending)) = &mut self.pending_output {
+            if time - *start <= window {
+                pending.push_str(data);
+
+                return Ok(());
+            }
+        }
+
+        self.write_pending_output()?;
+        self.pending_output = Some((time, data.to_owned()));
+
+        Ok(())
+    }
+
+    fn write_pending_output(&mut self) -> Result<()> {
+        if let Some((time, data)) = self.pending_output.take() {
+            self.write(time, "o", &data)?;
+        }
+
+        Ok(())
+    }
+
+    fn header(&mut self, cols: usize, rows: usize) -> Result<()> {
+        writeln!(self.writer, "{}", header(cols, rows))?;
+
+        Ok(())
+    }
+
+    fn write(&mut self, event_time: f64, code: &str, data: &str) -> Result<()> {
+        let gap = (event_time - self.last_event_time).max(0.0);
+        let gap = self.idle_limit.map_or(gap, |limit| gap.min(limit));
+        self.last_event_time = event_time;
+        self.time += gap;
+        let time = (self.time * 1_000_000.0).round() / 1_000_000.0;
+
+        writeln!(self.writer, "{}", json!([time, code, data]))?;
+
+        Ok(())
+    }
+}
+
+/// Builds a single-frame asciicast, which reproduces the screen described by
+/// `seq` (as produced by `avt::Vt::dump`) when played.
+pub fn frame(cols: usize, rows: usize, seq: &str) -> String {
+    format!("{}\n{}\n", header(cols, rows), json!([0.0, "o", seq]))
+}
+
+fn header(cols: usize, rows: usize) -> serde_json::Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    json!({
+        "version": 2,
+        "width": cols,
+        "height": rows,
+        "timestamp": timestamp,
+        "env": { "TERM": "xterm-256color" },
+    })
+}
+
+/// Starts recording the session to a file, which is created (or truncated)
+/// right away so that an unwritable path is reported before the session
+/// starts.
+///
+/// With `compress`, the recording is written through a gzip encoder. Each
+/// event is followed by a sync flush, so that a killed ht leaves a stream
+/// which decompresses up to the last event, only missing the gzip trailer
+/// (written once the session ends).
+pub fn start(
+    path: &Path,
+    subscription: Subscription,
+    idle_limit: Option<f64>,
+    coalesce_window: Option<f64>,
+    pause_on_mute: bool,
+    size_override: (Option<usize>, Option<usize>),
+    compress: bool,
+) -> Result<JoinHandle<Result<()>>> {
+    let file = File::create(path)
+        .with_context(|| format!("cannot create recording {}", path.display()))?;
+
+    let writer = BufWriter::new(file);
+    let events = subscription.into_stream();
+
+    Ok(if compress {
+        let writer = GzEncoder::new(writer, Compression::default());
+
+        tokio::spawn(async move {
+            record(
+                events,
+                writer,
+                idle_limit,
+                coalesce_window,
+                pause_on_mute,
+                size_override,
+            )
+            .await?
+            .finish()?
+            .flush()?;
+
+            Ok(())
+        })
+    } else {
+        tokio::spawn(async move {
+            record(
+                events,
+                writer,
+                idle_limit,
+                coalesce_window,
+                pause_on_mute,
+                size_override,
+            )
+            .await?;
+
+            Ok(())
+        })
+    })
+}
+
+/// Tells whether a recording path calls for compression (`.gz` extension).
+pub fn is_compressed_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+async fn record<W: Write>(
+    mut events: impl Stream<Item = Result<Event, BroadcastStreamRecvError>> + Unpin,
+    writer: W,
+    idle_limit: Option<f64>,
+    coalesce_window: Option<f64>,
+    pause_on_mute: bool,
+    size_override: (Option<usize>, Option<usize>),
+) -> Result<W> {
+    let mut recorder = Recorder::new(writer, idle_limit);
+    recorder.set_coalesce_window(coalesce_window);
+    recorder.set_pause_on_mute(pause_on_mute);
+    recorder.set_size_override(size_override.0, size_override.1);
+
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(event) => recorder.event(&event)?,
+
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                log::warning!("recording fell behind, {n} events are missing from it");
+            }
+        }
+    }
+
+    recorder.finish()?;
+
+    Ok(recorder.into_writer())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Recorder, frame, is_compressed_path};
+    use crate::session::Event;
+    use std::io::Read;
+    use std::path::Path;
+
+    fn record(idle_limit: Option<f64>, events: &[Event]) -> Vec<serde_json::Value> {
+        record_coalesced(idle_limit, None, events)
+    }
+
+    fn record_coalesced(
+        idle_limit: Option<f64>,
+        coalesce_window: Option<f64>,
+        events: &[Event],
+    ) -> Vec<serde_json::Value> {
+        let mut recorder = Recorder::new(Vec::new(), idle_limit);
+        recorder.set_coalesce_window(coalesce_window);
+
+        for event in events {
+            recorder.event(event).unwrap();
+        }
+
+        recorder.finish().unwrap();
+
+        String::from_utf8(recorder.writer)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    fn output(time: f64, data: &str) -> Event {
+        Event::Output(time, data.to_owned(), None, Vec::new())
+    }
+
+    #[test]
+    fn asciicast_v2() {
+        let lines = record(
+            None,
+            &[
+                Event::Init(0.0, 80, 24, String::new(), String::new()),
+                output(0.5, "hello"),
+                Event::Resize(1.25, 100, 30),
+            ],
+        );
+
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[1], serde_json::json!([0.5, "o", "hello"]));
+        assert_eq!(lines[2], serde_json::json!([1.25, "r", "100x30"]));
+    }
+
+    #[test]
+    fn idle_time_is_clamped() {
+        let events = [
+            Event::Init(0.0, 80, 24, String::new(), String::new()),
+            output(0.5, "a"),
+            output(60.5, "b"),
+            output(61.0, "c"),
+        ];
+
+        let lines = record(Some(2.0), &events);
+
+        assert_eq!(lines[1], serde_json::json!([0.5, "o", "a"]));
+        assert_eq!(lines[2], serde_json::json!([2.5, "o", "b"]));
+        assert_eq!(lines[3], serde_json::json!([3.0, "o", "c"]));
+
+        let lines = record(None, &events);
+
+        assert_eq!(lines[2], serde_json::json!([60.5, "o", "b"]));
+    }
+
+    #[test]
+    fn output_is_coalesced() {
+        let mut events = vec![Event::Init(0.0, 80, 24, String::new(), String::new())];
+
+        // a burst of single bytes, 10 ms apart
+        for (i, ch) in "hello".chars().enumerate() {
+            events.push(output(1.0 + i as f64 * 0.01, &ch.to_string()));
+        }
+
+        events.push(Event::Resize(1.045, 100, 30));
+        events.push(output(1.05, "!"));
+        events.push(output(1.2, "?"));
+        events.push(output(1.22, "."));
+
+        let lines = record_coalesced(None, Some(0.035), &events);
+
+        assert_eq!(
+            lines[1..],
+            [
+                serde_json::json!([1.0, "o", "hell"]),
+                serde_json::json!([1.04, "o", "o"]),
+                serde_json::json!([1.045, "r", "100x30"]),
+                serde_json::json!([1.05, "o", "!"]),
+                serde_json::json!([1.2, "o", "?."]),
+            ]
+        );
+
+        assert_eq!(record(None, &events).len(), 10);
+    }
+
+    #[test]
+    fn pause_on_mute() {
+        let events = [
+            Event::Init(0.0, 80, 24, String::new(), String::new()),
+            output(0.5, "a"),
+            Event::Muted(1.0, true, "a".to_owned()),
+            output(1.5, "noise"),
+            Event::Muted(2.0, false, "a-screen".to_owned()),
+            output(2.5, "b"),
+        ];
+
+        let mut recorder = Recorder::new(Vec::new(), None);
+        recorder.set_pause_on_mute(true);
+
+        for event in &events {
+            recorder.event(event).unwrap();
+        }
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(recorder.writer)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(
+            lines[1..],
+            [
+                serde_json::json!([0.5, "o", "a"]),
+                serde_json::json!([2.0, "o", "\x1bca-screen"]),
+                serde_json::json!([2.5, "o", "b"]),
+            ]
+        );
+
+        // muted output is recorded by default
+        assert_eq!(record(None, &events).len(), 4);
+    }
+
+    #[test]
+    fn size_override() {
+        let events = [
+            Event::Init(0.0, 200, 50, String::new(), String::new()),
+            output(0.5, "a"),
+            Event::Resize(1.0, 150, 50),
+            Event::Resize(1.5, 150, 40),
+        ];
+
+        let mut recorder = Recorder::new(Vec::new(), None);
+        recorder.set_size_override(Some(80), Some(24));
+
+        for event in &events {
+            recorder.event(event).unwrap();
+        }
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(recorder.writer)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[1..], [serde_json::json!([0.5, "o", "a"])]);
+
+        // resizes of the other dimension are kept
+        let mut recorder = Recorder::new(Vec::new(), None);
+        recorder.set_size_override(Some(80), None);
+
+        for event in &events {
+            recorder.event(event).unwrap();
+        }
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(recorder.writer)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 50);
+        assert_eq!(lines[2..], [serde_json::json!([1.5, "r", "80x40"])]);
+    }
+
+    #[test]
+    fn single_frame_cast() {
+        let mut vt = avt::Vt::new(20, 5);
+        vt.feed_str("\x1b[31mred\x1b[0m\r\nplain");
+
+        let cast = frame(20, 5, &vt.dump());
+        let lines: Vec<serde_json::Value> = cast
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["width"], 20);
+        assert_eq!(lines[0]["height"], 5);
+        assert_eq!(lines[1][0], 0.0);
+        assert_eq!(lines[1][1], "o");
+
+        let mut replayed = avt::Vt::new(20, 5);
+        replayed.feed_str(lines[1][2].as_str().unwrap());
+
+        assert_eq!(replayed.dump(), vt.dump());
+        assert_eq!(replayed.cursor(), vt.cursor());
+    }
+
+    #[test]
+    fn compressed_path() {
+        assert!(is_compressed_path(Path::new("session.cast.gz")));
+        assert!(is_compressed_path(Path::new("SESSION.CAST.GZ")));
+        assert!(!is_compressed_path(Path::new("session.cast")));
+        assert!(!is_compressed_path(Path::new("gz")));
+    }
+
+    #[test]
+    fn gzip_stream_is_readable_before_finish() {
+        let writer = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut recorder = Recorder::new(writer, None);
+
+        recorder
+            .event(&Event::Init(0.0, 80, 24, String::new(), String::new()))
+            .unwrap();
+        recorder.event(&output(0.5, "hello")).unwrap();
+
+        // no trailer yet, as if ht was killed at this point
+        let compressed = recorder.writer.get_ref().clone();
+        let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+        let mut text = String::new();
+        let _ = decoder.read_to_string(&mut text);
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], r#"[0.5,"o","hello"]"#);
+
+        let compressed = recorder.writer.finish().unwrap();
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text.lines().count(), 2);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::links::{self, Link};
+use crate::theme::{Rgb, Theme};
+use avt::Pen;
+use std::fmt::Write;
+
+const FONT_SIZE: f64 = 14.0;
+const CELL_WIDTH: f64 = 8.4;
+const CELL_HEIGHT: f64 = 17.0;
+const PADDING: f64 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Svg,
+}
+
+impl ImageFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+        }
+    }
+}
+
+/// Renders the visible screen. The cursor is drawn only with `show_cursor`
+/// set, and when the program hasn't hidden it. Text covered by `links` is
+/// made clickable.
+pub fn render(
+    vt: &avt::Vt,
+    format: ImageFormat,
+    theme: &Theme,
+    show_cursor: bool,
+    links: &[Link],
+) -> String {
+    match format {
+        ImageFormat::Svg => svg(vt, theme, show_cursor, links),
+    }
+}
+
+/// Renders the visible screen as a self-contained SVG image.
+///
+/// Text is laid out on a fixed cell grid using the viewer's monospace font,
+/// with each run of identically styled cells positioned at its column so
+/// that alignment doesn't depend on the exact font metrics. Runs covered by a
+/// link are wrapped in `<a>` elements.
+pub fn svg(vt: &avt::Vt, theme: &Theme, show_cursor: bool, links: &[Link]) -> String {
+    let (cols, rows) = vt.size();
+    let width = cols as f64 * CELL_WIDTH + 2.0 * PADDING;
+    let height = rows as f64 * CELL_HEIGHT + 2.0 * PADDING;
+    let mut backgrounds = String::new();
+    let mut texts = String::new();
+
+    for (row, line) in vt.view().iter().enumerate() {
+        let cells: Vec<&avt::Cell> = line.cells().collect();
+        let y = PADDING + row as f64 * CELL_HEIGHT;
+        let mut col = 0;
+
+        while col < cells.len() {
+            let pen = *cells[col].pen();
+            let url = links::url_at(links, row, col);
+            let start = col;
+
+            while col < cells.len()
+                && *cells[col].pen() == pen
+                && links::url_at(links, row, col) == url
+            {
+                col += 1;
+            }
+
+            let (fg, bg) = colors(&pen, theme);
+            let x = PADDING + start as f64 * CELL_WIDTH;
+
+            if bg != theme.background {
+                let _ = write!(
+                    backgrounds,
+                    r#"<rect x="{x:.1}" y="{y:.1}" width="{:.1}" height="{CELL_HEIGHT:.1}" fill="{bg}"/>"#,
+                    (col - start) as f64 * CELL_WIDTH,
+                );
+            }
+
+            // a wide character's glyph covers its continuation cell
+            let mut text: String = cells[start..col]
+                .iter()
+                .flat_map(|cell| cell.chars())
+                .collect();
+
+            if !pen.is_underline() && !pen.is_strikethrough() {
+                text.truncate(text.trim_end().len());
+
+                if text.is_empty() {
+                    continue;
+                }
+            }
+
+            if let Some(url) = url {
+                let _ = write!(texts, r#"<a href="{}">"#, escape(url));
+            }
+
+            let _ = write!(
+                texts,
+                r#"<text x="{x:.1}" y="{:.1}" fill="{fg}"{}>{}</text>"#,
+                y + FONT_SIZE,
+                text_attrs(&pen),
+                escape(&text)
+            );
+
+            if url.is_some() {
+                texts.push_str("</a>");
+            }
+        }
+    }
+
+    let cursor = vt.cursor();
+    let mut cursor_rect = String::new();
+
+    if show_cursor && cursor.visible && cursor.col < cols && cursor.row < rows {
+        let _ = write!(
+            cursor_rect,
+            r#"<rect x="{:.1}" y="{:.1}" width="{CELL_WIDTH:.1}" height="{CELL_HEIGHT:.1}" fill="{}" fill-opacity="0.6"/>"#,
+            PADDING + cursor.col as f64 * CELL_WIDTH,
+            PADDING + cursor.row as f64 * CELL_HEIGHT,
+            theme.foreground,
+        );
+    }
+
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w:.1}" height="{h:.1}" viewBox="0 0 {w:.1} {h:.1}" xml:space="preserve">"#,
+            r#"<style>text{{font-family:ui-monospace,Menlo,Consolas,"DejaVu Sans Mono",monospace;font-size:{fs}px;white-space:pre}}</style>"#,
+            r#"<rect width="100%" height="100%" fill="{bg}"/>"#,
+            "<g>{backgrounds}</g><g>{cursor}</g><g>{texts}</g></svg>"
+        ),
+        w = width,
+        h = height,
+        fs = FONT_SIZE,
+        bg = theme.background,
+        backgrounds = backgrounds,
+        cursor = cursor_rect,
+        texts = texts,
+    )
+}
+
+/// Renders lines as text with SGR sequences reproducing their colors and
+/// attributes, one line per row. Each line starts from default attributes,
+/// so any of them can be viewed on its own.
+pub fn ansi(lines: &[avt::Line]) -> String {
+    let mut output = String::new();
+
+    for line in lines {
+        let cells: Vec<&avt::Cell> = line.cells().collect();
+        let len = cells
+            .iter()
+            .rposition(|cell| !cell.is_default())
+            .map_or(0, |i| i + 1);
+
+        let mut last_pen = Pen::default();
+
+        for cell in &cells[..len] {
+            if *cell.pen() != last_pen {
+                output.push_str(&sgr(cell.pen()));
+                last_pen = *cell.pen();
+            }
+
+            output.extend(cell.chars());
+        }
+
+        if last_pen != Pen::default() {
+            output.push_str("\x1b[0m");
+        }
+
+        output.push('\n');
+    }
+
+    output
+}
+
+fn sgr(pen: &Pen) -> String {
+    let mut params = vec!["0".to_owned()];
+
+    if pen.is_bold() {
+        params.push("1".to_owned());
+    }
+
+    if pen.is_faint() {
+        params.push("2".to_owned());
+    }
+
+    if pen.is_italic() {
+        params.push("3".to_owned());
+    }
+
+    if pen.is_underline() {
+        params.push("4".to_owned());
+    }
+
+    if pen.is_blink() {
+        params.push("5".to_owned());
+    }
+
+    if pen.is_inverse() {
+        params.push("7".to_owned());
+    }
+
+    if pen.is_strikethrough() {
+        params.push("9".to_owned());
+    }
+
+    if let Some(color) = pen.foreground() {
+        params.push(sgr_color(color, 30));
+    }
+
+    if let Some(color) = pen.background() {
+        params.push(sgr_color(color, 40));
+    }
+
+    format!("\x1b[{}m", params.join(";"))
+}
+
+fn sgr_color(color: avt::Color, base: u8) -> String {
+    match color {
+        avt::Color::Indexed(c) if c < 8 => (base + c).to_string(),
+        avt::Color::Indexed(c) if c < 16 => (base + 60 + c - 8).to_string(),
+        avt::Color::Indexed(c) => format!("{};5;{c}", base + 8),
+        avt::Color::RGB(c) => format!("{};2;{};{};{}", base + 8, c.r, c.g, c.b),
+    }
+}
+
+fn colors(pen: &Pen, theme: &Theme) -> (Rgb, Rgb) {
+    let fg = pen
+        .foreground()
+        .map(|c| theme.color(c))
+        .unwrap_or(theme.foreground);
+    let bg = pen
+        .background()
+        .map(|c| theme.color(c))
+        .unwrap_or(theme.background);
+
+    if pen.is_inverse() { (bg, fg) } else { (fg, bg) }
+}
+
+fn text_attrs(pen: &Pen) -> String {
+    let mut attrs = String::new();
+
+    if pen.is_bold() {
+        attrs.push_str(r#" font-weight="bold""#);
+    }
+
+    if pen.is_faint() {
+        attrs.push_str(r#" fill-opacity="0.5""#);
+    }
+
+    if pen.is_italic() {
+        attrs.push_str(r#" font-style="italic""#);
+    }
+
+    match (pen.is_underline(), pen.is_strikethrough()) {
+        (true, true) => attrs.push_str(r#" text-decoration="underline line-through""#),
+        (true, false) => attrs.push_str(r#" text-decoration="underline""#),
+        (false, true) => attrs.push_str(r#" text-decoration="line-through""#),
+        (false, false) => (),
+    }
+
+    attrs
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c if c.is_control() => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ansi, svg};
+    use crate::links::Link;
+    use crate::theme::Theme;
+
+    fn vt(cols: usize, rows: usize, input: &str) -> avt::Vt {
+        let mut vt = avt::Vt::new(cols, rows);
+        vt.feed_str(input);
+
+        vt
+    }
+
+    fn svg_of(vt: &avt::Vt) -> String {
+        svg(vt, &Theme::dark(), true, &[])
+    }
+
+    #[test]
+    fn svg_contains_text() {
+        let image = svg_of(&vt(10, 2, "hello"));
+
+        assert!(image.starts_with("<svg "));
+        assert!(image.ends_with("</svg>"));
+        assert!(image.contains(">hello</text>"));
+    }
+
+    #[test]
+    fn svg_escapes_markup() {
+        let image = svg_of(&vt(10, 2, "<a&b>"));
+
+        assert!(image.contains("&lt;a&amp;b&gt;"));
+    }
+
+    #[test]
+    fn svg_renders_colors_and_attributes() {
+        let image = svg_of(&vt(10, 2, "\x1b[1;31;44mred\x1b[0m"));
+
+        assert!(image.contains(r##"fill="#dd3c69" font-weight="bold">red"##));
+        assert!(image.contains(r##"fill="#26b0d7"/>"##));
+    }
+
+    #[test]
+    fn svg_renders_cursor_only_when_visible() {
+        assert!(svg_of(&vt(10, 2, "x")).contains("fill-opacity=\"0.6\""));
+        assert!(!svg_of(&vt(10, 2, "x\x1b[?25l")).contains("fill-opacity=\"0.6\""));
+    }
+
+    #[test]
+    fn svg_hides_cursor_on_request() {
+        let image = svg(&vt(10, 2, "x"), &Theme::dark(), false, &[]);
+
+        assert!(!image.contains("fill-opacity=\"0.6\""));
+        assert!(image.contains(">x</text>"));
+    }
+
+    #[test]
+    fn svg_uses_theme_colors() {
+        let image = svg(&vt(10, 2, "\x1b[32mok"), &Theme::solarized(), true, &[]);
+
+        assert!(image.contains(r##"fill="#002b36""##));
+        assert!(image.contains(r##"fill="#859900">ok"##));
+    }
+
+    #[test]
+    fn svg_renders_links() {
+        let link = Link {
+            row: 0,
+            start: 4,
+            end: 8,
+            url: "https://example.com/?a=1&b=2".to_owned(),
+        };
+
+        let image = svg(&vt(20, 2, "see docs here"), &Theme::dark(), false, &[link]);
+
+        assert!(image.contains(">see</text>"));
+        assert!(image.contains(
+            r##"<a href="https://example.com/?a=1&amp;b=2"><text x="41.6" y="22.0" fill="#cccccc">docs</text></a>"##
+        ));
+        assert!(image.contains("> here</text>"));
+    }
+
+    #[test]
+    fn ansi_lines() {
+        let vt = vt(
+            20,
+            3,
+            "plain  \r\n\x1b[1;31mred\x1b[0m \x1b[48;5;200mbg\x1b[0m\r\n\x1b[4m  ",
+        );
+        let output = ansi(vt.lines());
+        let lines: Vec<&str> = output.split_terminator('\n').collect();
+
+        assert_eq!(lines[0], "plain");
+        assert_eq!(lines[1], "\x1b[0;1;31mred\x1b[0m \x1b[0;48;5;200mbg\x1b[0m");
+        assert_eq!(lines[2], "\x1b[0;4m  \x1b[0m");
+
+        let mut replayed = avt::Vt::new(20, 3);
+        replayed.feed_str(&output.trim_end().replace('\n', "\r\n"));
+        assert_eq!(replayed.text(), vt.text());
+    }
+}
@@ -0,0 +1,289 @@
+// Batch mode, for `--script <FILE>`: every line of the file is run as a
+// command in the shell started in the terminal, one at a time, and reported
+// with a `commandResult` event carrying its output and exit status.
+//
+// Commands are delimited with markers, like `--setup-command`. Each one is
+// typed as a single line
+//
+//    printf '%s-%s\n' ht-start <id>; eval '<command>'; printf '\n%s-%s-%s\n' ht-end <id> $?
+//
+// so the output is whatever shows up between `ht-start-<id>` and
+// `ht-end-<id>-<status>` (less the line break printed before the latter). The
+// echo of the typed line contains neither, as the halves of the markers are
+// separate arguments there, and as it's a single line, it's echoed in full
+// before the command runs. `eval` lets the command be any line the shell
+// accepts, including ones ending with a comment or `&`.
+//
+// The session watches the output for the markers (see
+// `Session::watch_script_command`), so that a command cut short by the shell
+// exiting is reported before the `exit` event. The script itself is run by a
+// task sending the commands one at a time, each once the session replied with
+// the previous one's exit status.
+//
+// This needs a POSIX shell (sh, bash, zsh, ...) reading commands from the
+// terminal. Commands run in the same shell, so e.g. `cd` carries over to the
+// following ones. Once they're all done, the shell is told to exit, which
+// ends the session.
+
+use crate::command::Command;
+use crate::escape::Scanner;
+use crate::session::Handle;
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Most output of a command kept for its `commandResult` event. Beyond that,
+/// the oldest output is dropped.
+const MAX_OUTPUT_SIZE: usize = 1024 * 1024;
+
+/// Reads the commands of a script file, one per line, skipping blank lines
+/// and comments (lines starting with `#`).
+#[cfg_attr(windows, allow(dead_code))]
+pub fn read(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read script {}", path.display()))?;
+
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+/// Runs `commands` through the session's command channel, waiting for the
+/// exit status of each.
+#[cfg_attr(windows, allow(dead_code))]
+pub fn start(commands: Vec<String>, handle: Handle) -> JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        for (index, command) in commands.into_iter().enumerate() {
+            let (reply, status) = oneshot::channel();
+            handle
+                .send(Command::ScriptLine(index, command, reply))
+                .await?;
+
+            // without a status, the shell is gone, and the session with it
+            if !matches!(status.await, Ok(Some(_))) {
+                return Ok(());
+            }
+        }
+
+        handle.send_input(" exit\r").await
+    })
+}
+
+/// The line typed to run `command`, see the top of the file. It starts with a
+/// space, which keeps it out of the shell's history when it's set up to
+/// ignore such lines (e.g. bash's `ignorespace`).
+pub fn typed_line(command: &str, id: &str) -> String {
+    let quoted = command.replace('\'', r"'\''");
+
+    format!(
+        " printf '%s-%s\\n' ht-start {id}; eval '{quoted}'; printf '\\n%s-%s-%s\\n' ht-end {id} $?\r"
+    )
+}
+
+/// Output of a single command, picked out of the terminal's output by its
+/// markers.
+pub struct Capture {
+    start: String,
+    end: String,
+    started: bool,
+    /// Output since the start marker or, until it's seen, the last of the
+    /// output, which may hold the beginning of it.
+    seen: String,
+    /// Where the search for a marker resumes in `seen`, none of it starting
+    /// before.
+    searched: usize,
+}
+
+impl Capture {
+    pub fn new(id: &str) -> Self {
+        Self {
+            start: format!("ht-start-{id}"),
+            end: format!("\nht-end-{id}-"),
+            started: false,
+            seen: String::new(),
+            searched: 0,
+        }
+    }
+
+    /// Adds output, returning the command's output (as plain text) and exit
+    /// status once the end marker is complete. Only the output added is
+    /// searched for the markers, along with the end of the output before,
+    /// where a marker may begin.
+    pub fn feed(&mut self, data: &str) -> Option<(String, i32)> {
+        self.seen.push_str(data);
+
+        if !self.started {
+            let Some(pos) = find(&self.seen, &self.start, &mut self.searched) else {
+                let start = self.searched;
+                self.seen.drain(..start);
+                self.searched = 0;
+
+                return None;
+            };
+
+            let rest = &self.seen[pos + self.start.len()..];
+            let Some(rest) = rest
+                .strip_prefix("\r\n")
+                .or_else(|| rest.strip_prefix('\n'))
+            else {
+                // the line break is still to come
+                self.searched = pos;
+
+                return None;
+            };
+
+            self.seen = rest.to_owned();
+            self.started = true;
+            self.searched = 0;
+        }
+
+        let Some(pos) = find(&self.seen, &self.end, &mut self.searched) else {
+            self.drop_oldest();
+
+            return None;
+        };
+
+        let Some((status, _)) = self.seen[pos + self.end.len()..].split_once('\n') else {
+            // the status is still to come
+            self.searched = pos;
+
+            return None;
+        };
+
+        let status = status.trim_end_matches('\r').parse().ok()?;
+        // the line break printed before the marker
+        self.seen.truncate(pos);
+        self.seen
+            .truncate(self.seen.strip_suffix('\r').unwrap_or(&self.seen).len());
+
+        Some((self.output(), status))
+    }
+
+    /// Keeps the output within `MAX_OUTPUT_SIZE`.
+    fn drop_oldest(&mut self) {
+        let excess = self.seen.len().saturating_sub(MAX_OUTPUT_SIZE);
+
+        if excess > 0 {
+            let excess = ceil_char_boundary(&self.seen, excess.min(self.searched));
+            self.seen.drain(..excess);
+            self.searched -= excess;
+        }
+    }
+
+    /// Output seen since the command started, as plain text.
+    pub fn output(&self) -> String {
+        if self.started {
+            Scanner::new().feed_text(&self.seen)
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// Finds `marker` in `seen`, from where the last search ended (`searched`).
+/// When it's not there, the next search starts where the end of `seen` could
+/// be the beginning of it.
+fn find(seen: &str, marker: &str, searched: &mut usize) -> Option<usize> {
+    match seen[*searched..].find(marker) {
+        Some(pos) => Some(*searched + pos),
+
+        None => {
+            *searched = floor_char_boundary(seen, seen.len().saturating_sub(marker.len() - 1));
+
+            None
+        }
+    }
+}
+
+fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+
+    index
+}
+
+fn ceil_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index += 1;
+    }
+
+    index
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Capture, MAX_OUTPUT_SIZE, typed_line};
+
+    #[test]
+    fn capture() {
+        let mut capture = Capture::new("42");
+        let echo =
+            "$  printf '%s-%s\\n' ht-start 42; eval 'ls'; printf '\\n%s-%s-%s\\n' ht-end 42 $?\r\n";
+
+        assert_eq!(capture.feed(echo), None);
+        assert_eq!(capture.feed("ht-sta"), None);
+        assert_eq!(capture.feed("rt-42\r\n\x1b[1mfoo\x1b[0m\r\nbar\r\n"), None);
+        assert_eq!(capture.feed("\r\nht-end-42-1"), None);
+        assert_eq!(
+            capture.feed("27\r\n$ "),
+            Some(("foo\nbar\n".to_owned(), 127))
+        );
+    }
+
+    #[test]
+    fn capture_without_line_break() {
+        let mut capture = Capture::new("42");
+
+        assert_eq!(
+            capture.feed("ht-start-42\r\nfoo\r\nht-end-42-0\r\n"),
+            Some(("foo".to_owned(), 0))
+        );
+
+        let mut capture = Capture::new("42");
+        assert_eq!(capture.output(), "");
+        assert_eq!(capture.feed("ht-start-42\r\nfoo"), None);
+        assert_eq!(capture.output(), "foo");
+    }
+
+    #[test]
+    fn capture_keeps_bounded_output() {
+        let mut capture = Capture::new("42");
+
+        for _ in 0..1000 {
+            assert_eq!(capture.feed(&"zażółć ".repeat(100)), None);
+        }
+
+        assert!(capture.seen.len() < 20, "{}", capture.seen.len());
+
+        assert_eq!(capture.feed("ht-start-42\r\n"), None);
+
+        for _ in 0..1000 {
+            assert_eq!(capture.feed(&"zażółć ".repeat(100)), None);
+        }
+
+        // the end marker split between two pieces of output
+        assert_eq!(capture.feed("done\r\nht-en"), None);
+        let (output, status) = capture.feed("d-42-0\r\n").unwrap();
+
+        assert!(output.len() <= MAX_OUTPUT_SIZE);
+        assert!(output.len() > MAX_OUTPUT_SIZE - 100);
+        assert!(
+            output.ends_with("zażółć done"),
+            "{}",
+            &output[output.len() - 20..]
+        );
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            typed_line("echo 'a b' # c", "42"),
+            " printf '%s-%s\\n' ht-start 42; eval 'echo '\\''a b'\\'' # c'; printf '\\n%s-%s-%s\\n' ht-end 42 $?\r"
+        );
+    }
+}
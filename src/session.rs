@@ -1,178 +1,3751 @@
+use crate::clock::{Clock, MonotonicClock};
+use crate::command::{
+    self, Command, EchoWait, InputSeq, Newline, OutputSinceFormat, Query, ScreenBuffer,
+    ScrollbackFormat, Settle, TextWait,
+};
+use crate::escape;
+use crate::grid;
+use crate::links::{self, Link};
+use crate::log;
+use crate::output_log::OutputLog;
+use crate::process;
+use crate::pty::ExitStatus;
+use crate::record;
+use crate::render::{self, ImageFormat};
+use crate::script;
+use crate::theme::Theme;
 use anyhow::Result;
+use base64::Engine;
 use futures_util::{Stream, StreamExt, stream};
 use serde_json::json;
+use std::collections::VecDeque;
 use std::future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
 
+pub const DEFAULT_OUTPUT_TAIL_SIZE: usize = 64 * 1024;
+pub const DEFAULT_FLUSH_BYTES: usize = 64 * 1024;
+pub const DEFAULT_REPLAY_RESIZE_EVENTS: usize = 100;
+
+/// Number of events a subscriber can fall behind before it starts missing
+/// them.
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// Largest screen, in cells, `getGrid` sends. Every cell takes some 100 bytes
+/// of JSON.
+const MAX_GRID_CELLS: usize = 100_000;
+
+/// Bells rung within this time after a `bell` event don't trigger another one.
+const BELL_COALESCE_WINDOW: Duration = Duration::from_millis(100);
+
+/// Decision of a command hook (see `Session::set_command_hook`) about a
+/// command.
+#[allow(dead_code)]
+pub enum CommandAction {
+    /// Apply the command as it is.
+    Allow,
+
+    /// Drop the command, without applying it or reporting anything.
+    Deny,
+
+    /// Apply this command instead.
+    Rewrite(Command),
+}
+
+pub type CommandHook = Arc<dyn Fn(&Command) -> CommandAction + Send + Sync>;
+
 pub struct Session {
     vt: avt::Vt,
+    theme: Theme,
+    scanner: escape::Scanner,
+    unknown_sequences: usize,
+    debug_sequences: bool,
+    scroll_region: ScrollRegion,
+    cooked_view: Option<CookedView>,
+    /// Grid frame encoder for `/ws/grid` clients, running while any are
+    /// connected.
+    grid: Option<grid::Encoder>,
+    grid_tx: broadcast::Sender<Arc<[u8]>>,
+    input_newline: Newline,
+    output_tail: OutputTail,
+    output_batch: OutputBatch,
+    output_log: Option<OutputLog>,
+    script_watch: Option<ScriptWatch>,
+    generation: u64,
+    snapshot_cache: Option<SnapshotCache>,
+    text_watches: Vec<TextWatch>,
+    settle_watches: Vec<SettleWatch>,
+    query_watches: Vec<QueryWatch>,
+    echo_watches: Vec<EchoWatch>,
+    replay: Option<Replay>,
+    pid: Option<u32>,
+    render_cursor: bool,
+    emulator: bool,
+    muted: bool,
+    /// Terminal emulator the output keeps going to while the display (`vt`)
+    /// is frozen.
+    frozen: Option<Live>,
+    links: Option<links::Tracker>,
+    last_bell: Option<Instant>,
+    /// Directory reported with OSC 7 by the output being processed.
+    reported_cwd: Option<String>,
+    last_reported_cwd: Option<String>,
     broadcast_tx: broadcast::Sender<Event>,
-    stream_time: f64,
-    start_time: Instant,
-    last_event_time: Instant,
+    clock: Box<dyn Clock>,
+    command_hook: Option<CommandHook>,
 }
 
 #[derive(Clone)]
 pub enum Event {
     Init(f64, usize, usize, String, String),
-    Output(f64, String),
+    Output(f64, String, Option<Cooked>, Vec<usize>),
     Resize(f64, usize, usize),
-    Snapshot(usize, usize, String, String),
+    Snapshot(
+        usize,
+        usize,
+        String,
+        String,
+        Cursor,
+        Option<ScrollRegion>,
+        u64,
+        Vec<Link>,
+    ),
+    Screenshot(ImageFormat, String),
+    UnknownSequence(String, usize),
+    Exit(f64, ExitStatus),
+    State(usize, usize, Cursor, ScrollRegion, Vec<usize>, Charsets),
+    Grid(usize, usize, Result<Vec<Vec<avt::Cell>>, String>),
+    Frame(String),
+    OutputTail(Vec<u8>),
+    Marker(u64),
+    OutputSince(u64, u64, u64, OutputSinceFormat, Result<Vec<u8>, String>),
+    TextWait(Option<serde_json::Value>, bool),
+    Settle(Option<serde_json::Value>, usize, usize, bool),
+    Query(Option<serde_json::Value>, Vec<u8>),
+    Cwd(CwdSource, Result<String, String>),
+    Env(String, Result<Option<String>, String>),
+    Processes(Result<Vec<process::ProcessInfo>, String>),
+    KillProcess(u32, String, Result<(), String>),
+    Refresh(Option<serde_json::Value>, Result<Option<bool>, String>),
+    InputPending(usize),
+    Error(String),
+    InputChunked(usize, usize),
+    CommandResult(usize, String, String, Option<i32>),
+    Pid(u32),
+    SaveScrollback(String, Result<usize, String>),
+    Bell(f64),
+    Muted(f64, bool, String),
+    WideCharWrap(char, usize),
+}
+
+/// Text changed by a chunk of output, carried by `output` events in cooked
+/// mode.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cooked {
+    /// Lines which scrolled off the top of the screen, oldest first, with the
+    /// text they had then, unless it's the text last reported for their row.
+    pub scrolled: Vec<String>,
+    /// Rows whose text changed, top to bottom.
+    pub lines: Vec<(usize, String)>,
+}
+
+impl Cooked {
+    pub fn is_empty(&self) -> bool {
+        self.scrolled.is_empty() && self.lines.is_empty()
+    }
+}
+
+/// Screen text last reported in cooked mode, and the number of lines which
+/// had scrolled off the screen by then (`avt::Vt::scrolled_off`).
+struct CookedView {
+    lines: Vec<String>,
+    scrolled_off: usize,
+}
+
+/// Output text seen so far by a pending `TextWait`.
+struct TextWatch {
+    wait: TextWait,
+    deadline: Instant,
+    scanner: escape::Scanner,
+    text: String,
+}
+
+/// Copy of the terminal emulator, the link tracker and the scroll region,
+/// which output and resizes keep being applied to while the display is
+/// frozen, so that it replaces the frozen one when unfrozen.
+struct Live {
+    vt: avt::Vt,
+    links: Option<links::Tracker>,
+    scroll_region: ScrollRegion,
+}
+
+/// Output read from the terminal but not processed yet: it's held until
+/// `interval` has passed since the first of it arrived, or `max_bytes` of it
+/// are pending, so that it's processed (and `output` events are sent) in
+/// larger batches.
+struct OutputBatch {
+    max_bytes: usize,
+    interval: Duration,
+    pending: Vec<u8>,
+    deadline: Instant,
+}
+
+/// Most recent raw output, as read from the terminal. `end` counts all the
+/// bytes ever pushed, so that offsets into the whole output (markers) stay
+/// valid while old bytes are dropped.
+struct OutputTail {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+    end: u64,
+}
+
+/// Pending `Settle`: done once no output arrives until `quiet_until`, which
+/// every output pushes back. A `refresh` one is followed by a snapshot.
+struct SettleWatch {
+    settle: Settle,
+    quiet_until: Instant,
+    deadline: Instant,
+    refresh: bool,
+}
+
+/// Command of a `--script` being run.
+struct ScriptWatch {
+    index: usize,
+    command: String,
+    capture: script::Capture,
+    reply: oneshot::Sender<Option<i32>>,
+}
+
+/// Pending `Query`: raw output seen since the query was sent.
+struct QueryWatch {
+    id: Option<serde_json::Value>,
+    deadline: Instant,
+    response: Vec<u8>,
+}
+
+/// Pending `EchoWait`: answered by any output until its deadline.
+struct EchoWatch {
+    deadline: Instant,
+    reply: oneshot::Sender<bool>,
+}
+
+/// Bounded history of the session, from which a reconnecting client can
+/// rebuild the terminal: the screen at the start of the window (`base`),
+/// followed by the output and resize events since, in their original order.
+///
+/// Output is kept up to a number of bytes and resizes up to a number of
+/// events. Events pushed out of the window are applied to `base`, exactly as
+/// they were applied to the session's terminal, so a client replaying the
+/// window ends up where it would be had it been connected since its start.
+struct Replay {
+    base: avt::Vt,
+    base_time: f64,
+    events: VecDeque<Event>,
+    output_size: usize,
+    output_limit: usize,
+    resize_count: usize,
+    resize_limit: usize,
+}
+
+/// Rendered screen, reused by snapshots until the screen changes.
+struct SnapshotCache {
+    generation: u64,
+    seq: String,
+    lines: Vec<String>,
+}
+
+// avt's own cursor type isn't exported
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cursor {
+    pub col: usize,
+    pub row: usize,
+    pub visible: bool,
+}
+
+/// Scroll region (DECSTBM) as 0-based, inclusive row numbers.
+///
+/// avt keeps the margins to itself, so they're mirrored here by following the
+/// same sequences avt acts on: DECSTBM sets them, while resets and height
+/// changes restore the full screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrollRegion {
+    pub top: usize,
+    pub bottom: usize,
+    rows: usize,
+}
+
+/// Where the directory of a `cwd` event comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CwdSource {
+    /// Looked up for the `getCwd` command.
+    GetCwd,
+
+    /// Reported by the program with an OSC 7 sequence.
+    Osc7,
+}
+
+/// Character sets designated to G0 and G1, and whether G1 is shifted in
+/// (SO) instead of G0.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Charsets {
+    pub g0: Charset,
+    pub g1: Charset,
+    pub shifted: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Charset {
+    #[default]
+    Ascii,
+    /// DEC special graphics, drawing e.g. `q` as a horizontal line.
+    Drawing,
 }
 
-pub struct Client(oneshot::Sender<Subscription>);
+/// Screen contents at one point in time, as returned by `Handle::snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub cols: usize,
+    pub rows: usize,
+    pub cursor: Cursor,
+    /// Text of the visible screen, lines separated with `\n`.
+    pub text: String,
+    /// Text of each row of the visible screen, padded to `cols`.
+    pub lines: Vec<String>,
+    /// Sequence which reproduces the screen when fed to a terminal.
+    pub seq: String,
+}
+
+/// Handle for driving a running session from other tasks.
+///
+/// The session itself is owned by the task running its event loop, which
+/// serves the handle's requests in between processing output, the same way
+/// it serves the STDIO and HTTP APIs. The handle is cheap to clone and is
+/// `Send` and `Sync`, so any number of tasks can use it concurrently. Its
+/// requests are queued with the commands of the STDIO API and served in
+/// order, except for `snapshot`.
+#[derive(Debug, Clone)]
+pub struct Handle {
+    command_tx: mpsc::Sender<Command>,
+    snapshot_tx: mpsc::Sender<SnapshotRequest>,
+}
+
+/// Request for a `Handle::snapshot`, answered with the screen.
+pub type SnapshotRequest = oneshot::Sender<Result<Snapshot, EmulatorDisabled>>;
 
+pub struct Client {
+    subscription_tx: oneshot::Sender<Subscription>,
+    replay: bool,
+    grid: bool,
+}
+
+/// Stream of the session's events for one consumer, starting with an `init`
+/// event describing the screen at that point.
+///
+/// All consumers (the STDIO API, WebSocket clients, the recorder) subscribe
+/// to the same broadcast, so they get the same events, in the order they were
+/// sent. Each subscription reads at its own pace: the session never waits for
+/// one, and a slow one doesn't hold up the others. A subscription falling more
+/// than `EVENT_BUFFER_SIZE` events behind misses the oldest ones, which its
+/// stream reports with a `Lagged` error before carrying on.
 pub struct Subscription {
     init: Event,
+    pid: Option<u32>,
+    replay: Vec<Event>,
     broadcast_rx: broadcast::Receiver<Event>,
+    render_cursor: bool,
+    links: Vec<Link>,
+    emulator: bool,
+    muted: bool,
+    grid: Option<GridSubscription>,
+}
+
+/// Grid frames for a `/ws/grid` client (see `grid`): a full frame of the
+/// screen at the time it subscribed, then the deltas sent since.
+struct GridSubscription {
+    full: Vec<u8>,
+    frames_rx: broadcast::Receiver<Arc<[u8]>>,
+}
+
+/// Error for requests needing the screen of a session whose terminal
+/// emulator is disabled (see `Session::set_emulator`).
+#[derive(Debug)]
+pub struct EmulatorDisabled;
+
+impl std::fmt::Display for EmulatorDisabled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("terminal emulator disabled (--no-emulator)")
+    }
+}
+
+impl std::error::Error for EmulatorDisabled {}
+
+/// Copy of the session's screen for rendering, see `view`.
+pub struct View {
+    pub vt: avt::Vt,
+    /// Whether rendered images should show the cursor (see
+    /// `Session::set_render_cursor`).
+    pub render_cursor: bool,
+    /// OSC 8 hyperlinks on the screen.
+    pub links: Vec<Link>,
 }
 
 impl Session {
-    pub fn new(cols: usize, rows: usize) -> Self {
-        let (broadcast_tx, _) = broadcast::channel(1024);
-        let now = Instant::now();
+    pub fn new(cols: usize, rows: usize, theme: Theme) -> Self {
+        let (broadcast_tx, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        let (grid_tx, _) = broadcast::channel(EVENT_BUFFER_SIZE);
 
         Self {
             vt: build_vt(cols, rows),
+            theme,
+            scanner: escape::Scanner::new(),
+            unknown_sequences: 0,
+            debug_sequences: false,
+            scroll_region: ScrollRegion::new(rows),
+            cooked_view: None,
+            grid: None,
+            grid_tx,
+            input_newline: Newline::None,
+            output_tail: OutputTail::new(DEFAULT_OUTPUT_TAIL_SIZE),
+            output_batch: OutputBatch {
+                max_bytes: DEFAULT_FLUSH_BYTES,
+                interval: Duration::ZERO,
+                pending: Vec::new(),
+                deadline: Instant::now(),
+            },
+            output_log: None,
+            script_watch: None,
+            generation: 0,
+            snapshot_cache: None,
+            text_watches: Vec::new(),
+            settle_watches: Vec::new(),
+            query_watches: Vec::new(),
+            echo_watches: Vec::new(),
+            replay: None,
+            pid: None,
+            render_cursor: true,
+            emulator: true,
+            muted: false,
+            frozen: None,
+            links: None,
+            last_bell: None,
+            reported_cwd: None,
+            last_reported_cwd: None,
             broadcast_tx,
-            stream_time: 0.0,
-            start_time: now,
-            last_event_time: now,
+            clock: Box::new(MonotonicClock::new()),
+            command_hook: None,
         }
     }
 
+    /// Replaces the source of event timestamps, which is the monotonic clock
+    /// started along with the session by default.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sets a hook deciding about every command the STDIO API parses, which
+    /// can let it through, drop it or replace it with another one, e.g. to
+    /// expand aliases or keep an audit log (see `filter_command`). It's given
+    /// to the STDIO API when it starts (`command_hook`), and sees commands as
+    /// they were parsed, after `--allow-commands` / `--deny-commands`
+    /// filtering, before large input is split up. Commands sent through a
+    /// `Handle` don't go through it.
+    ///
+    /// The hook runs on the task reading commands, which it holds up until it
+    /// returns, so it must not block. It has no access to the session, and
+    /// commands it sends (e.g. through a `Handle`) are queued behind the one
+    /// being decided about.
+    #[allow(dead_code)]
+    pub fn set_command_hook(&mut self, hook: Option<CommandHook>) {
+        self.command_hook = hook;
+    }
+
+    pub fn command_hook(&self) -> Option<CommandHook> {
+        self.command_hook.clone()
+    }
+
+    /// Enables `unknownSequence` events for escape sequences which the
+    /// virtual terminal ignores.
+    pub fn set_debug_sequences(&mut self, enabled: bool) {
+        self.debug_sequences = enabled;
+    }
+
+    /// Disables the terminal emulator: output is passed on without being
+    /// parsed, for pure passthrough and recording. The screen stays blank,
+    /// so whatever needs it (snapshots, state, screenshots, scrollback, cooked
+    /// output, replay, link tracking, bell and unknown sequence events) isn't
+    /// available, see `has_emulator`.
+    pub fn set_emulator(&mut self, enabled: bool) {
+        self.emulator = enabled;
+    }
+
+    pub fn has_emulator(&self) -> bool {
+        self.emulator
+    }
+
+    /// Enables cooked output: `output` events then carry the rows of visible
+    /// text changed by each chunk of output instead of the raw sequence.
+    pub fn set_cooked_output(&mut self, enabled: bool) {
+        self.cooked_view = enabled.then(|| self.cooked_view());
+    }
+
+    /// Sets the newline sequence which line breaks in input are translated to.
+    pub fn set_input_newline(&mut self, newline: Newline) {
+        self.input_newline = newline;
+    }
+
+    /// Encodes input for the process, according to the terminal's current
+    /// cursor key mode and `--input-newline`.
+    pub fn input_bytes(&self, seqs: &[InputSeq]) -> Vec<u8> {
+        command::seqs_to_bytes(seqs, self.cursor_key_app_mode(), self.input_newline)
+    }
+
+    /// Sets how many of the most recent raw output bytes are kept for
+    /// `output_tail` (0 disables it).
+    pub fn set_output_tail_size(&mut self, size: usize) {
+        self.output_tail = OutputTail::new(size);
+    }
+
+    /// Makes output passed to `push_output` held for up to `interval` after
+    /// the first of it arrives, or until `max_bytes` of it are pending, to be
+    /// processed in a single batch (a zero interval processes it right away).
+    pub fn set_output_batching(&mut self, max_bytes: usize, interval: Duration) {
+        self.output_batch.max_bytes = max_bytes;
+        self.output_batch.interval = interval;
+    }
+
+    /// Takes in output read from the terminal, processing it right away or
+    /// holding it for the next batch (see `set_output_batching`).
+    pub fn push_output(&mut self, data: Vec<u8>) {
+        let batch = &mut self.output_batch;
+
+        if batch.pending.is_empty() {
+            batch.deadline = Instant::now() + batch.interval;
+            batch.pending = data;
+        } else {
+            batch.pending.extend_from_slice(&data);
+        }
+
+        if batch.interval.is_zero() || batch.pending.len() >= batch.max_bytes {
+            self.flush_output();
+        }
+    }
+
+    /// Processes held output. Done before anything depending on the output
+    /// read so far, e.g. a command or a resize.
+    pub fn flush_output(&mut self) {
+        if !self.output_batch.pending.is_empty() {
+            let data = std::mem::take(&mut self.output_batch.pending);
+            self.output_bytes(&data);
+        }
+    }
+
+    /// When held output is due to be processed, if there's any.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        let batch = &self.output_batch;
+
+        (!batch.pending.is_empty()).then_some(batch.deadline)
+    }
+
+    /// Sets the file raw output is written to, as it's read from the terminal.
+    pub fn set_output_log(&mut self, log: OutputLog) {
+        self.output_log = Some(log);
+    }
+
+    /// Enables the replay buffer, keeping up to `output_bytes` of output and
+    /// `resize_events` resizes (0 bytes disables it).
+    pub fn set_replay_limits(&mut self, output_bytes: usize, resize_events: usize) {
+        self.replay = (output_bytes > 0).then(|| {
+            let (cols, rows) = self.vt.size();
+            let mut base = build_vt(cols, rows);
+            base.feed_str(&self.vt.dump());
+
+            Replay {
+                base,
+                base_time: self.elapsed_time(),
+                events: VecDeque::new(),
+                output_size: 0,
+                output_limit: output_bytes,
+                resize_count: 0,
+                resize_limit: resize_events,
+            }
+        });
+    }
+
+    /// Processes raw output of the process, keeping the bytes as they were
+    /// read in the output tail.
+    pub fn output_bytes(&mut self, data: &[u8]) {
+        self.output_tail.push(data);
+
+        if let Some(output_log) = &mut self.output_log {
+            if let Err(e) = output_log.write(data) {
+                log::warning!("writing output log failed, no longer logging output: {e:#}");
+                self.output_log = None;
+            }
+        }
+
+        for watch in &mut self.query_watches {
+            watch.response.extend_from_slice(data);
+        }
+
+        self.output(String::from_utf8_lossy(data).to_string());
+    }
+
     pub fn output(&mut self, data: String) {
-        self.vt.feed_str(&data);
-        let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Output(time, data));
-        self.stream_time = time;
-        self.last_event_time = Instant::now();
+        let dirty_rows = if self.emulator {
+            self.feed(&data)
+        } else {
+            Vec::new()
+        };
+
+        self.update_grid(Some(&dirty_rows));
+
+        let cooked = self.cook();
+        let time = self.elapsed_time();
+        let event = Event::Output(time, data.clone(), cooked, dirty_rows);
+
+        if let Some(replay) = &mut self.replay {
+            replay.push(event.clone());
+        }
+
+        let _ = self.broadcast_tx.send(event);
+        self.watch_output(&data);
+        self.watch_script(&data);
+        self.ring_bell(time);
+        self.report_cwd();
+        self.report_wide_char_wraps();
     }
 
-    pub fn resize(&mut self, cols: usize, rows: usize) {
-        resize_vt(&mut self.vt, cols, rows);
-        let time = self.start_time.elapsed().as_secs_f64();
-        let _ = self.broadcast_tx.send(Event::Resize(time, cols, rows));
-        self.stream_time = time;
-        self.last_event_time = Instant::now();
+    /// Broadcasts a `wideCharWrap` event for every wide character which
+    /// didn't fit in the last column of a row, with the row it went to.
+    fn report_wide_char_wraps(&mut self) {
+        let vt = match &mut self.frozen {
+            Some(live) => &mut live.vt,
+            None => &mut self.vt,
+        };
+
+        for (ch, row) in vt.take_wrapped_wide_chars() {
+            let _ = self.broadcast_tx.send(Event::WideCharWrap(ch, row));
+        }
     }
 
-    pub fn snapshot(&self) {
-        let (cols, rows) = self.vt.size();
+    /// Broadcasts a `cwd` event when the output reported a directory (OSC 7)
+    /// other than the previously reported one.
+    fn report_cwd(&mut self) {
+        let Some(cwd) = self.reported_cwd.take() else {
+            return;
+        };
 
-        let _ = self.broadcast_tx.send(Event::Snapshot(
-            cols,
-            rows,
-            self.vt.dump(),
-            self.text_view(),
-        ));
+        if self.last_reported_cwd.as_ref() == Some(&cwd) {
+            return;
+        }
+
+        self.last_reported_cwd = Some(cwd.clone());
+        let _ = self.broadcast_tx.send(Event::Cwd(CwdSource::Osc7, Ok(cwd)));
     }
 
-    pub fn cursor_key_app_mode(&self) -> bool {
-        self.vt.arrow_key_app_mode()
+    /// Fills the screen with content (text with escape sequences) before the
+    /// process starts, e.g. to begin from a captured screen. Only the terminal
+    /// emulator sees it, the process doesn't, and no events are sent for it:
+    /// subscribers get the resulting screen with their `init` event.
+    ///
+    /// Content which would resize the terminal is refused, as the process
+    /// would still get the terminal's original size.
+    pub fn prefill(&mut self, data: &str) -> Result<()> {
+        let resizes = escape::Scanner::new().feed(data).iter().any(|seq| {
+            matches!(seq, escape::Sequence::Csi(params, i, 't')
+                if i.is_empty() && params.split(';').next() == Some("8"))
+        });
+
+        if resizes {
+            anyhow::bail!("content resizes the terminal");
+        }
+
+        self.feed(data);
+        self.scanner.take_bells();
+        self.vt.take_wrapped_wide_chars();
+        self.reported_cwd = None;
+
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    /// Get the current terminal text content
-    pub fn get_text(&self) -> String {
-        self.text_view()
+    /// Feeds data to the terminal emulator and the trackers running along
+    /// with it, returning the rows of the display it changed (none while it's
+    /// frozen).
+    fn feed(&mut self, data: &str) -> Vec<usize> {
+        self.scan_sequences(data);
+
+        match &mut self.frozen {
+            Some(live) => {
+                apply(&mut live.vt, &mut live.links, data);
+
+                Vec::new()
+            }
+
+            None => {
+                self.generation += 1;
+
+                apply(&mut self.vt, &mut self.links, data)
+            }
+        }
     }
 
-    pub fn subscribe(&self) -> Subscription {
-        let (cols, rows) = self.vt.size();
+    /// Broadcasts a `bell` event when the output rang the bell, unless one
+    /// was sent within `BELL_COALESCE_WINDOW`.
+    fn ring_bell(&mut self, time: f64) {
+        if self.scanner.take_bells() == 0 {
+            return;
+        }
 
-        let init = Event::Init(
-            self.elapsed_time(),
-            cols,
-            rows,
-            self.vt.dump(),
-            self.text_view(),
-        );
+        let now = Instant::now();
 
-        let broadcast_rx = self.broadcast_tx.subscribe();
+        if self
+            .last_bell
+            .is_some_and(|last| now.duration_since(last) < BELL_COALESCE_WINDOW)
+        {
+            return;
+        }
 
-        Subscription { init, broadcast_rx }
+        self.last_bell = Some(now);
+        let _ = self.broadcast_tx.send(Event::Bell(time));
     }
 
-    fn elapsed_time(&self) -> f64 {
-        self.stream_time + self.last_event_time.elapsed().as_secs_f64()
+    /// Starts watching output for the awaited text. Only output processed
+    /// from now on counts, with escape sequences left out, so the text can't
+    /// be matched by what was already on the screen.
+    pub fn watch_text(&mut self, wait: TextWait) {
+        self.text_watches.push(TextWatch {
+            deadline: Instant::now() + wait.timeout,
+            wait,
+            scanner: escape::Scanner::new(),
+            text: String::new(),
+        });
     }
 
-    fn text_view(&self) -> String {
-        self.vt
-            .view()
+    /// Starts waiting for output to settle, i.e. pause for `settle.quiet`.
+    /// Output processed before doesn't count, so the wait lasts at least
+    /// that long.
+    pub fn watch_settle(&mut self, settle: Settle) {
+        self.push_settle_watch(settle, false);
+    }
+
+    fn push_settle_watch(&mut self, settle: Settle, refresh: bool) {
+        let now = Instant::now();
+
+        self.settle_watches.push(SettleWatch {
+            quiet_until: now + settle.quiet,
+            deadline: now + settle.timeout,
+            settle,
+            refresh,
+        });
+    }
+
+    /// Sends SIGWINCH to the program in the foreground, without changing the
+    /// size, to have it repaint the screen. The outcome is broadcast right
+    /// away, or with `snapshot` once output settles, followed by a snapshot.
+    pub fn refresh(&mut self, settle: Settle, snapshot: bool) {
+        let result = self.process_info(process::redraw);
+
+        match result {
+            Ok(()) if snapshot => self.push_settle_watch(settle, true),
+
+            result => {
+                let event = Event::Refresh(settle.id, result.map(|_| None));
+                let _ = self.broadcast_tx.send(event);
+            }
+        }
+    }
+
+    /// Starts collecting the response to a query, i.e. all the raw output
+    /// until its timeout. The query itself is to be sent right after.
+    pub fn watch_query(&mut self, query: &Query) {
+        self.query_watches.push(QueryWatch {
+            id: query.id.clone(),
+            deadline: Instant::now() + query.timeout,
+            response: Vec::new(),
+        });
+    }
+
+    /// Starts waiting for the echo of input, i.e. any output. The input is to
+    /// be sent right after.
+    pub fn watch_echo(&mut self, wait: EchoWait) {
+        self.echo_watches.push(EchoWatch {
+            deadline: Instant::now() + wait.timeout,
+            reply: wait.reply,
+        });
+    }
+
+    /// Time at which the earliest pending text wait, settle wait, query or
+    /// echo wait is due.
+    pub fn watch_deadline(&self) -> Option<Instant> {
+        let text = self.text_watches.iter().map(|w| w.deadline);
+        let settle = self
+            .settle_watches
             .iter()
-            .map(|l| l.text())
-            .collect::<Vec<_>>()
-            .join("\n")
+            .map(|w| w.quiet_until.min(w.deadline));
+        let query = self.query_watches.iter().map(|w| w.deadline);
+        let echo = self.echo_watches.iter().map(|w| w.deadline);
+
+        text.chain(settle).chain(query).chain(echo).min()
     }
-}
 
-impl Event {
-    pub fn to_json(&self) -> serde_json::Value {
-        match self {
-            Event::Init(_time, cols, rows, seq, text) => json!({
-                "type": "init",
-                "data": json!({
-                    "cols": cols,
-                    "rows": rows,
-                    "seq": seq,
-                    "text": text,
-                })
-            }),
+    /// Reports waits which are due: text waits whose timeout has passed,
+    /// settle waits whose output paused long enough or which timed out,
+    /// queries whose response window is over, and echo waits which saw no
+    /// output.
+    pub fn expire_watches(&mut self) {
+        // held output may complete a wait yet
+        self.flush_output();
+        let now = Instant::now();
+        let (cols, rows) = self.vt.size();
+        let broadcast_tx = &self.broadcast_tx;
+        let mut refreshed = Vec::new();
 
-            Event::Output(_time, seq) => json!({
-                "type": "output",
-                "data": json!({
-                    "seq": seq
-                })
-            }),
+        self.text_watches.retain(|watch| {
+            if watch.deadline > now {
+                return true;
+            }
 
-            Event::Resize(_time, cols, rows) => json!({
-                "type": "resize",
-                "data": json!({
-                    "cols": cols,
-                    "rows": rows,
-                })
-            }),
+            let _ = broadcast_tx.send(Event::TextWait(watch.wait.id.clone(), false));
 
-            Event::Snapshot(cols, rows, seq, text) => json!({
-                "type": "snapshot",
-                "data": json!({
-                    "cols": cols,
-                    "rows": rows,
-                    "seq": seq,
-                    "text": text,
-                })
-            }),
+            false
+        });
+
+        self.settle_watches.retain(|watch| {
+            let settled = watch.quiet_until <= now;
+
+            if !settled && watch.deadline > now {
+                return true;
+            }
+
+            let id = watch.settle.id.clone();
+
+            if watch.refresh {
+                refreshed.push(Event::Refresh(id, Ok(Some(settled))));
+            } else {
+                let _ = broadcast_tx.send(Event::Settle(id, cols, rows, settled));
+            }
+
+            false
+        });
+
+        self.query_watches.retain_mut(|watch| {
+            if watch.deadline > now {
+                return true;
+            }
+
+            let response = std::mem::take(&mut watch.response);
+            let _ = broadcast_tx.send(Event::Query(watch.id.clone(), response));
+
+            false
+        });
+
+        let (expired, pending) = std::mem::take(&mut self.echo_watches)
+            .into_iter()
+            .partition(|watch| watch.deadline <= now);
+
+        self.echo_watches = pending;
+
+        for watch in expired {
+            let _ = watch.reply.send(false);
+        }
+
+        for event in refreshed {
+            let _ = self.broadcast_tx.send(event);
+            self.snapshot(false, ScreenBuffer::Active);
         }
     }
-}
 
-fn build_vt(cols: usize, rows: usize) -> avt::Vt {
-    avt::Vt::builder().size(cols, rows).resizable(true).build()
-}
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        // held output was written for the old size
+        self.flush_output();
 
-fn resize_vt(vt: &mut avt::Vt, cols: usize, rows: usize) {
-    vt.feed_str(&format!("\x1b[8;{rows};{cols}t"));
-}
+        if let Some(live) = &mut self.frozen {
+            resize_vt(&mut live.vt, cols, rows);
+            live.scroll_region.resize(rows);
+
+            if let Some(links) = &mut live.links {
+                links.resize(cols, rows);
+            }
+        } else {
+            resize_vt(&mut self.vt, cols, rows);
+            self.scroll_region.resize(rows);
+
+            if let Some(links) = &mut self.links {
+                links.resize(cols, rows);
+            }
+
+            self.generation += 1;
+
+            if self.cooked_view.is_some() {
+                self.cooked_view = Some(self.cooked_view());
+            }
+
+            self.update_grid(None);
+        }
+
+        let time = self.elapsed_time();
+        let event = Event::Resize(time, cols, rows);
 
-impl Client {
-    pub fn accept(self, subscription: Subscription) {
-        let _ = self.0.send(subscription);
+        if let Some(replay) = &mut self.replay {
+            replay.push(event.clone());
+        }
+
+        let _ = self.broadcast_tx.send(event);
     }
-}
 
-pub async fn stream(
-    clients_tx: &mpsc::Sender<Client>,
-) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
-    let (sub_tx, sub_rx) = oneshot::channel();
-    clients_tx.send(Client(sub_tx)).await?;
-    let sub = tokio::time::timeout(Duration::from_secs(5), sub_rx).await??;
-    let init = stream::once(future::ready(Ok(sub.init)));
-    let events = BroadcastStream::new(sub.broadcast_rx);
+    /// Broadcasts a snapshot of the screen. With `scroll_region` set, the
+    /// text covers only the rows of the current scroll region.
+    ///
+    /// The screen is rendered at most once per generation, which changes
+    /// whenever output or a resize reaches the terminal, so repeated
+    /// snapshots of a static screen are cheap.
+    pub fn snapshot(&mut self, scroll_region: bool, buffer: ScreenBuffer) {
+        let (cols, rows) = self.vt.size();
+        let region = scroll_region.then_some(self.scroll_region);
+        let mut links = self.links();
+
+        if let Some(region) = region {
+            links.retain(|link| (region.top..=region.bottom).contains(&link.row));
+        }
+
+        let active_cursor = cursor(&self.vt);
+        let alt_active = self.vt.alternate_screen_active();
+        let cache = self.snapshot_cache();
+
+        // the inactive buffer is only reachable by switching to it, in a copy
+        let switch = match buffer {
+            ScreenBuffer::Main if alt_active => Some("\x1b[?1049l"),
+            ScreenBuffer::Alt if !alt_active => Some("\x1b[?1049h"),
+            _ => None,
+        };
+
+        let (seq, lines, cursor) = match switch {
+            Some(switch) => {
+                let mut vt = build_vt(cols, rows);
+                vt.feed_str(&cache.seq);
+                vt.feed_str(switch);
+                links.clear();
+
+                let lines = vt.view().iter().map(|l| l.text()).collect();
+
+                (vt.dump(), lines, cursor(&vt))
+            }
+
+            None => (cache.seq.clone(), cache.lines.clone(), active_cursor),
+        };
+
+        let text = match region {
+            Some(region) => lines[region.top..=region.bottom].join("\n"),
+            None => lines.join("\n"),
+        };
 
-    Ok(init.chain(events))
+        let event = Event::Snapshot(
+            cols,
+            rows,
+            seq,
+            text,
+            cursor,
+            region,
+            cache.generation,
+            links,
+        );
+        let _ = self.broadcast_tx.send(event);
+    }
+
+    /// Broadcasts up to `size` of the most recent raw output bytes (all that
+    /// are kept if `None`).
+    pub fn output_tail(&self, size: Option<usize>) {
+        let tail = self.output_tail.last(size.unwrap_or(usize::MAX));
+        let _ = self.broadcast_tx.send(Event::OutputTail(tail));
+    }
+
+    /// Broadcasts the number of input bytes not written to the terminal yet.
+    pub fn input_pending(&self, bytes: usize) {
+        let _ = self.broadcast_tx.send(Event::InputPending(bytes));
+    }
+
+    /// Broadcasts a marker: the offset of the next output byte, for use with
+    /// `output_since`.
+    pub fn mark(&self) {
+        let _ = self.broadcast_tx.send(Event::Marker(self.output_tail.end));
+    }
+
+    /// Broadcasts the output emitted after marker `seq`, as long as the
+    /// output tail still has all of it.
+    pub fn output_since(&self, seq: u64, format: OutputSinceFormat) {
+        let event = Event::OutputSince(
+            seq,
+            self.output_tail.end,
+            self.output_tail.start(),
+            format,
+            self.output_tail.since(seq),
+        );
+
+        let _ = self.broadcast_tx.send(event);
+    }
+
+    /// Broadcasts the current screen as a single-frame asciicast.
+    pub fn export_frame(&mut self) {
+        let (cols, rows) = self.vt.size();
+        let cast = record::frame(cols, rows, &self.snapshot_cache().seq);
+        let _ = self.broadcast_tx.send(Event::Frame(cast));
+    }
+
+    pub fn state(&self) {
+        let (cols, rows) = self.vt.size();
+        let cursor = cursor(&self.vt);
+
+        let _ = self.broadcast_tx.send(Event::State(
+            cols,
+            rows,
+            cursor,
+            self.scroll_region,
+            self.vt.tab_stops().to_vec(),
+            charsets(&self.vt),
+        ));
+    }
+
+    /// Broadcasts every cell of the screen, with its character, colors and
+    /// text attributes, unless the screen is larger than `MAX_GRID_CELLS`.
+    pub fn grid(&self) {
+        let (cols, rows) = self.vt.size();
+
+        let cells = if cols * rows > MAX_GRID_CELLS {
+            Err(format!(
+                "screen too large: {} cells, at most {MAX_GRID_CELLS}",
+                cols * rows
+            ))
+        } else {
+            Ok(self
+                .vt
+                .view()
+                .iter()
+                .map(|l| l.cells().copied().collect())
+                .collect())
+        };
+
+        let _ = self.broadcast_tx.send(Event::Grid(cols, rows, cells));
+    }
+
+    /// Sets the ID of the process running in the terminal, for `cwd` and
+    /// `env`, and broadcasts it. Later subscriptions get it after `init`.
+    pub fn set_pid(&mut self, pid: u32) {
+        self.pid = Some(pid);
+        let _ = self.broadcast_tx.send(Event::Pid(pid));
+    }
+
+    /// Broadcasts the process's current working directory.
+    pub fn cwd(&self) {
+        let cwd = self
+            .process_info(process::cwd)
+            .map(|path| path.to_string_lossy().into_owned());
+
+        let _ = self.broadcast_tx.send(Event::Cwd(CwdSource::GetCwd, cwd));
+    }
+
+    /// Broadcasts the value of one of the process's environment variables.
+    pub fn env(&self, name: String) {
+        let value = self.process_info(|pid| process::env(pid, &name));
+        let _ = self.broadcast_tx.send(Event::Env(name, value));
+    }
+
+    /// Broadcasts the processes started in the terminal, including
+    /// background jobs.
+    pub fn processes(&self) {
+        let processes = self.process_info(process::processes);
+        let _ = self.broadcast_tx.send(Event::Processes(processes));
+    }
+
+    /// Signals one of the processes started in the terminal, and broadcasts
+    /// the outcome.
+    pub fn kill_process(&self, target: u32, signal: String) {
+        let result = self.process_info(|pid| process::kill(pid, target, &signal));
+        let _ = self
+            .broadcast_tx
+            .send(Event::KillProcess(target, signal, result));
+    }
+
+    /// Signals the process ht started, broadcasting an error if it couldn't
+    /// be, e.g. for an unknown signal.
+    pub fn signal(&self, signal: String) {
+        if let Err(error) = self.process_info(|pid| process::signal(pid, &signal)) {
+            self.error(format!("cannot send signal {signal}: {error}"));
+        }
+    }
+
+    /// Broadcasts that an input of `total_bytes` was split into `chunks`
+    /// pieces on its way to the process.
+    pub fn input_chunked(&self, total_bytes: usize, chunks: usize) {
+        let _ = self
+            .broadcast_tx
+            .send(Event::InputChunked(total_bytes, chunks));
+    }
+
+    /// Starts watching the output for the outcome of the command at `index`
+    /// of a `--script`, returning the line to type to run it. The outcome is
+    /// broadcast once the command finished, or the process exited, and the
+    /// exit status (`None` for the latter) sent to `reply`.
+    pub fn watch_script_command(
+        &mut self,
+        index: usize,
+        command: String,
+        reply: oneshot::Sender<Option<i32>>,
+    ) -> String {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let line = script::typed_line(&command, &id);
+
+        self.script_watch = Some(ScriptWatch {
+            index,
+            command,
+            capture: script::Capture::new(&id),
+            reply,
+        });
+
+        line
+    }
+
+    fn watch_script(&mut self, data: &str) {
+        let Some(watch) = &mut self.script_watch else {
+            return;
+        };
+
+        if let Some((output, status)) = watch.capture.feed(data) {
+            let watch = self.script_watch.take().unwrap();
+            let event = Event::CommandResult(watch.index, watch.command, output, Some(status));
+            let _ = self.broadcast_tx.send(event);
+            let _ = watch.reply.send(Some(status));
+        }
+    }
+
+    /// Broadcasts an error which isn't a reply to any command.
+    pub fn error(&self, message: String) {
+        let _ = self.broadcast_tx.send(Event::Error(message));
+    }
+
+    /// Writes all lines of the terminal, the scrollback followed by the
+    /// visible screen, to a file, and broadcasts the outcome. The lines are
+    /// taken right away, and written in a task of their own, so that a large
+    /// scrollback or a slow file system doesn't hold up the session.
+    pub fn save_scrollback(&self, path: PathBuf, format: ScrollbackFormat) {
+        let lines = self.vt.lines();
+
+        let content = match format {
+            ScrollbackFormat::Text => lines
+                .iter()
+                .map(|l| format!("{}\n", l.text().trim_end()))
+                .collect(),
+
+            ScrollbackFormat::Ansi => render::ansi(lines),
+        };
+
+        let count = lines.len();
+        let broadcast_tx = self.broadcast_tx.clone();
+
+        tokio::spawn(async move {
+            let result = tokio::fs::write(&path, content)
+                .await
+                .map(|_| count)
+                .map_err(|e| e.to_string());
+
+            let path = path.to_string_lossy().into_owned();
+            let _ = broadcast_tx.send(Event::SaveScrollback(path, result));
+        });
+    }
+
+    /// OSC 8 hyperlinks on the visible screen.
+    pub fn links(&self) -> Vec<Link> {
+        self.links
+            .as_ref()
+            .map(|links| links.links())
+            .unwrap_or_default()
+    }
+
+    fn process_info<T>(&self, f: impl FnOnce(u32) -> io::Result<T>) -> Result<T, String> {
+        let pid = self.pid.ok_or("process not started")?;
+
+        f(pid).map_err(|e| match e.kind() {
+            io::ErrorKind::Unsupported => "unsupported".to_owned(),
+            _ => e.to_string(),
+        })
+    }
+
+    pub fn exit(&mut self, status: ExitStatus) {
+        if let Some(watch) = self.script_watch.take() {
+            let output = watch.capture.output();
+            let event = Event::CommandResult(watch.index, watch.command, output, None);
+            let _ = self.broadcast_tx.send(event);
+            let _ = watch.reply.send(None);
+        }
+
+        let time = self.elapsed_time();
+        let _ = self.broadcast_tx.send(Event::Exit(time, status));
+    }
+
+    /// Sets whether rendered images (screenshots, `/snapshot.svg`) show the
+    /// cursor. This is independent of the cursor visibility set by the
+    /// program, which is still tracked and reported as is, and nothing is
+    /// sent to the program.
+    pub fn set_render_cursor(&mut self, visible: bool) {
+        self.render_cursor = visible;
+    }
+
+    /// Freezes or unfreezes the display: while frozen, output (and resizes)
+    /// still reach the process's clients and the recording as usual, but
+    /// snapshots, screenshots and the state show the screen as it was when
+    /// frozen. The output keeps being applied to a copy of the terminal
+    /// emulator, which takes over when unfrozen, catching the screen up with
+    /// it. The rows this changed are then sent with an `output` event without
+    /// data, for the clients following the screen's rows.
+    pub fn set_frozen(&mut self, frozen: bool) {
+        if frozen {
+            if self.frozen.is_none() {
+                self.frozen = Some(Live {
+                    vt: self.vt.clone(),
+                    links: self.links.clone(),
+                    scroll_region: self.scroll_region,
+                });
+            }
+
+            return;
+        }
+
+        let Some(live) = self.frozen.take() else {
+            return;
+        };
+
+        let dirty_rows = changed_rows(&self.vt, &live.vt);
+        let resized = self.vt.size() != live.vt.size();
+        self.vt = live.vt;
+        self.links = live.links;
+        self.scroll_region = live.scroll_region;
+        self.generation += 1;
+        self.update_grid((!resized).then_some(&dirty_rows[..]));
+
+        if !dirty_rows.is_empty() {
+            let cooked = self.cook();
+            let event = Event::Output(self.elapsed_time(), String::new(), cooked, dirty_rows);
+            let _ = self.broadcast_tx.send(event);
+        }
+    }
+
+    /// Mutes or unmutes `output` events for API clients (see
+    /// `Subscription::into_api_stream`). Everything else keeps getting the
+    /// output: the terminal emulator, the live preview and the recording
+    /// (unless it pauses on mute, see `record::Recorder`), so a snapshot
+    /// taken after unmuting shows the outcome of what was muted.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        let seq = self.snapshot_cache().seq.clone();
+        let event = Event::Muted(self.elapsed_time(), muted, seq);
+        let _ = self.broadcast_tx.send(event);
+    }
+
+    pub fn screenshot(&self, format: ImageFormat) {
+        let image = render::render(
+            &self.vt,
+            format,
+            &self.theme,
+            self.render_cursor,
+            &self.links(),
+        );
+        let _ = self.broadcast_tx.send(Event::Screenshot(format, image));
+    }
+
+    /// Cursor key mode input is encoded for, the current one even while the
+    /// display is frozen.
+    pub fn cursor_key_app_mode(&self) -> bool {
+        match &self.frozen {
+            Some(live) => live.vt.arrow_key_app_mode(),
+            None => self.vt.arrow_key_app_mode(),
+        }
+    }
+
+    #[allow(dead_code)]
+    /// Get the current terminal text content
+    pub fn get_text(&self) -> String {
+        self.text_view()
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        // the events which follow build on the live screen, not the frozen one
+        let vt = self.frozen.as_ref().map_or(&self.vt, |live| &live.vt);
+        let init = init_event(self.elapsed_time(), vt);
+
+        let broadcast_rx = self.broadcast_tx.subscribe();
+
+        Subscription {
+            init,
+            pid: self.pid,
+            replay: Vec::new(),
+            broadcast_rx,
+            render_cursor: self.render_cursor,
+            emulator: self.emulator,
+            muted: self.muted,
+            links: self.links(),
+            grid: None,
+        }
+    }
+
+    /// Subscribes starting from the replay buffer: the `init` event describes
+    /// the screen at the start of the buffered window and is followed by the
+    /// buffered events, then by live ones. Without a replay buffer this is
+    /// the same as `subscribe`.
+    pub fn subscribe_with_replay(&self) -> Subscription {
+        let Some(replay) = &self.replay else {
+            return self.subscribe();
+        };
+
+        Subscription {
+            init: init_event(replay.base_time, &replay.base),
+            pid: self.pid,
+            replay: replay.events.iter().cloned().collect(),
+            broadcast_rx: self.broadcast_tx.subscribe(),
+            render_cursor: self.render_cursor,
+            emulator: self.emulator,
+            muted: self.muted,
+            links: self.links(),
+            grid: None,
+        }
+    }
+
+    /// Accepts a client, with a subscription starting from the replay buffer
+    /// if it asked for one, and to grid frames if it asked for them.
+    pub fn accept(&mut self, client: Client) {
+        let mut subscription = if client.replay {
+            self.subscribe_with_replay()
+        } else {
+            self.subscribe()
+        };
+
+        if client.grid && self.emulator {
+            let encoder = self.grid.get_or_insert_with(grid::Encoder::default);
+
+            subscription.grid = Some(GridSubscription {
+                full: encoder.full(&self.vt),
+                frames_rx: self.grid_tx.subscribe(),
+            });
+        }
+
+        let _ = client.subscription_tx.send(subscription);
+    }
+
+    /// Sends `/ws/grid` clients the change made to the screen: a delta frame
+    /// for the rows given, or a full frame when there are none (after a
+    /// resize). The encoder stops once the last client is gone.
+    fn update_grid(&mut self, dirty_rows: Option<&[usize]>) {
+        if self.grid_tx.receiver_count() == 0 {
+            self.grid = None;
+        }
+
+        let Some(encoder) = &mut self.grid else {
+            return;
+        };
+
+        let frame = match dirty_rows {
+            Some(dirty_rows) => encoder.delta(&self.vt, dirty_rows),
+            None => Some(encoder.full(&self.vt)),
+        };
+
+        if let Some(frame) = frame {
+            let _ = self.grid_tx.send(frame.into());
+        }
+    }
+
+    /// Captures the screen, reusing the snapshot taken last if the screen
+    /// hasn't changed since.
+    pub fn screen_snapshot(&mut self) -> Result<Snapshot, EmulatorDisabled> {
+        if !self.emulator {
+            return Err(EmulatorDisabled);
+        }
+
+        let (cols, rows) = self.vt.size();
+        let cursor = cursor(&self.vt);
+        let cache = self.snapshot_cache();
+
+        Ok(Snapshot {
+            cols,
+            rows,
+            cursor,
+            text: cache.lines.join("\n"),
+            lines: cache.lines.clone(),
+            seq: cache.seq.clone(),
+        })
+    }
+
+    fn snapshot_cache(&mut self) -> &SnapshotCache {
+        let generation = self.generation;
+
+        if self
+            .snapshot_cache
+            .as_ref()
+            .is_none_or(|cache| cache.generation != generation)
+        {
+            self.snapshot_cache = Some(SnapshotCache {
+                generation,
+                seq: self.vt.dump(),
+                lines: self.vt.view().iter().map(|l| l.text()).collect(),
+            });
+        }
+
+        self.snapshot_cache.as_ref().unwrap()
+    }
+
+    fn watch_output(&mut self, data: &str) {
+        let quiet_since = Instant::now();
+
+        for watch in &mut self.settle_watches {
+            watch.quiet_until = quiet_since + watch.settle.quiet;
+        }
+
+        for watch in self.echo_watches.drain(..) {
+            let _ = watch.reply.send(true);
+        }
+
+        let broadcast_tx = &self.broadcast_tx;
+
+        self.text_watches.retain_mut(|watch| {
+            if !watch.feed(data) {
+                return true;
+            }
+
+            let _ = broadcast_tx.send(Event::TextWait(watch.wait.id.clone(), true));
+
+            false
+        });
+    }
+
+    fn scan_sequences(&mut self, data: &str) {
+        for seq in self.scanner.feed(data) {
+            match &mut self.frozen {
+                Some(live) => live.scroll_region.track(&seq),
+                None => self.scroll_region.track(&seq),
+            }
+
+            if let escape::Sequence::Osc(data) = &seq {
+                if let Some(path) = osc7_path(data) {
+                    self.reported_cwd = Some(path);
+                    continue;
+                }
+            }
+
+            if seq.is_handled_by_vt() {
+                continue;
+            }
+
+            self.unknown_sequences += 1;
+
+            if self.debug_sequences {
+                let _ = self.broadcast_tx.send(Event::UnknownSequence(
+                    seq.to_string(),
+                    self.unknown_sequences,
+                ));
+            }
+        }
+    }
+
+    /// Returns the text changed since the previous call: the lines which
+    /// scrolled off the screen meanwhile, and the rows whose text differs, in
+    /// top to bottom order. A line which scrolled off is compared with the
+    /// row it was at, as n lines scrolling off were rows 0 to n - 1 before
+    /// (those past the screen's height were never seen).
+    fn cook(&mut self) -> Option<Cooked> {
+        let prev = self.cooked_view.as_ref()?;
+        let view = self.cooked_view();
+
+        // the lines which scrolled off are right above the screen, unless
+        // they were cleared from the scrollback since
+        let lines = self.vt.lines();
+        let top = lines.len() - self.vt.size().1;
+        let count = view.scrolled_off.saturating_sub(prev.scrolled_off).min(top);
+
+        let scrolled = lines[top - count..top]
+            .iter()
+            .map(|line| line.text().trim_end().to_owned())
+            .enumerate()
+            .filter(|(row, text)| prev.lines.get(*row) != Some(text))
+            .map(|(_, text)| text)
+            .collect();
+
+        let lines = view
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(row, text)| prev.lines.get(*row) != Some(text))
+            .map(|(row, text)| (row, text.clone()))
+            .collect();
+
+        self.cooked_view = Some(view);
+
+        Some(Cooked { scrolled, lines })
+    }
+
+    fn cooked_view(&self) -> CookedView {
+        let lines = self
+            .vt
+            .view()
+            .iter()
+            .map(|l| l.text().trim_end().to_owned())
+            .collect();
+
+        CookedView {
+            lines,
+            scrolled_off: self.vt.scrolled_off(),
+        }
+    }
+
+    fn elapsed_time(&self) -> f64 {
+        self.clock.elapsed().as_secs_f64()
+    }
+
+    fn text_view(&self) -> String {
+        text(&self.vt)
+    }
+}
+
+impl OutputTail {
+    fn new(capacity: usize) -> Self {
+        Self {
+            bytes: VecDeque::with_capacity(capacity),
+            capacity,
+            end: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        let len = data.len();
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.bytes.len() + data.len()).saturating_sub(self.capacity);
+        self.bytes.drain(..overflow);
+        self.bytes.extend(data);
+        self.end += len as u64;
+    }
+
+    /// Offset of the oldest byte kept.
+    fn start(&self) -> u64 {
+        self.end - self.bytes.len() as u64
+    }
+
+    /// Returns the bytes pushed after offset `seq`, or why they can't be.
+    fn since(&self, seq: u64) -> Result<Vec<u8>, String> {
+        if seq > self.end {
+            return Err(format!(
+                "seq {seq} is ahead of the output, which is at {}",
+                self.end
+            ));
+        }
+
+        if seq < self.start() {
+            return Err(format!(
+                "output since seq {seq} is no longer kept, the oldest kept is {}",
+                self.start()
+            ));
+        }
+
+        let skip = (seq - self.start()) as usize;
+
+        Ok(self.bytes.range(skip..).copied().collect())
+    }
+
+    fn last(&self, size: usize) -> Vec<u8> {
+        let start = self.bytes.len().saturating_sub(size);
+
+        self.bytes.range(start..).copied().collect()
+    }
+}
+
+impl Replay {
+    fn push(&mut self, event: Event) {
+        match &event {
+            Event::Output(_, data, _, _) => self.output_size += data.len(),
+            Event::Resize(_, _, _) => self.resize_count += 1,
+            _ => (),
+        }
+
+        self.events.push_back(event);
+
+        while self.output_size > self.output_limit || self.resize_count > self.resize_limit {
+            match self.events.pop_front() {
+                Some(Event::Output(time, data, _, _)) => {
+                    self.base.feed_str(&data);
+                    self.base_time = time;
+                    self.output_size -= data.len();
+                }
+
+                Some(Event::Resize(time, cols, rows)) => {
+                    resize_vt(&mut self.base, cols, rows);
+                    self.base_time = time;
+                    self.resize_count -= 1;
+                }
+
+                Some(_) => (),
+                None => break,
+            }
+        }
+    }
+}
+
+impl TextWatch {
+    /// Adds output to the text seen so far, telling whether the awaited text
+    /// has appeared.
+    fn feed(&mut self, data: &str) -> bool {
+        let expect = &self.wait.expect;
+        self.text.push_str(&self.scanner.feed_text(data));
+
+        if self.text.contains(expect.as_str()) {
+            return true;
+        }
+
+        // only the last few characters can start a match completed by later
+        // output
+        let mut start = self.text.len().saturating_sub(expect.len());
+
+        while !self.text.is_char_boundary(start) {
+            start -= 1;
+        }
+
+        self.text.drain(..start);
+
+        false
+    }
+}
+
+impl ScrollRegion {
+    fn new(rows: usize) -> Self {
+        Self {
+            top: 0,
+            bottom: rows.saturating_sub(1),
+            rows,
+        }
+    }
+
+    fn track(&mut self, seq: &escape::Sequence) {
+        use escape::Sequence::*;
+
+        match seq {
+            Csi(params, i, 'r') if i.is_empty() && !params.starts_with('?') => {
+                let mut params = params.split(';').map(param);
+                let top = params.next().flatten().unwrap_or(1) - 1;
+                let bottom = params.next().flatten().unwrap_or(self.rows) - 1;
+
+                // same validation as avt: invalid regions are ignored
+                if top < bottom && bottom < self.rows {
+                    self.top = top;
+                    self.bottom = bottom;
+                }
+            }
+
+            // XTWINOPS resize: CSI 8 ; rows ; cols t
+            Csi(params, i, 't') if i.is_empty() => {
+                let mut params = params.split(';').map(param);
+
+                if params.next().flatten() == Some(8) {
+                    if let Some(rows) = params.next().flatten() {
+                        self.resize(rows);
+                    }
+                }
+            }
+
+            // RIS and DECSTR
+            Esc(i, 'c') if i.is_empty() => *self = Self::new(self.rows),
+            Csi(params, i, 'p') if params.is_empty() && i == "!" => *self = Self::new(self.rows),
+
+            _ => (),
+        }
+    }
+
+    fn resize(&mut self, rows: usize) {
+        if rows != self.rows {
+            *self = Self::new(rows);
+        }
+    }
+}
+
+/// Parses a numeric CSI parameter, where 0 means the same as a missing one.
+fn param(p: &str) -> Option<usize> {
+    p.parse().ok().filter(|&n| n > 0)
+}
+
+impl Event {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Event::Init(..) | Event::Output(..) => {
+                serde_json::to_value(self).expect("events are always serializable")
+            }
+
+            Event::Resize(_time, cols, rows) => json!({
+                "type": "resize",
+                "data": json!({
+                    "cols": cols,
+                    "rows": rows,
+                })
+            }),
+
+            Event::Snapshot(cols, rows, seq, text, cursor, region, generation, links) => {
+                let mut data = json!({
+                    "cols": cols,
+                    "rows": rows,
+                    "seq": seq,
+                    "text": text,
+                    "cursor": cursor_json(cursor),
+                    "generation": generation,
+                });
+
+                if let Some(region) = region {
+                    data["scrollRegion"] = scroll_region_json(region);
+                }
+
+                if !links.is_empty() {
+                    data["links"] = links
+                        .iter()
+                        .map(|link| {
+                            json!({
+                                "row": link.row,
+                                "col": link.start,
+                                "len": link.end - link.start,
+                                "url": link.url,
+                            })
+                        })
+                        .collect();
+                }
+
+                json!({
+                    "type": "snapshot",
+                    "data": data,
+                })
+            }
+
+            Event::Screenshot(format, image) => json!({
+                "type": "screenshot",
+                "data": json!({
+                    "format": format.as_str(),
+                    "image": image,
+                })
+            }),
+
+            Event::Bell(_time) => json!({
+                "type": "bell",
+                "data": json!({}),
+            }),
+
+            Event::WideCharWrap(ch, row) => json!({
+                "type": "wideCharWrap",
+                "data": json!({
+                    "char": ch.to_string(),
+                    "row": row,
+                })
+            }),
+
+            Event::Muted(_time, muted, _seq) => json!({
+                "type": "muted",
+                "data": json!({
+                    "muted": muted,
+                })
+            }),
+
+            Event::Exit(_time, status) => json!({
+                "type": "exit",
+                "data": exit_status_json(status),
+            }),
+
+            Event::State(cols, rows, cursor, region, tab_stops, charsets) => json!({
+                "type": "state",
+                "data": json!({
+                    "cols": cols,
+                    "rows": rows,
+                    "cursor": cursor_json(cursor),
+                    "scrollRegion": scroll_region_json(region),
+                    "tabStops": tab_stops,
+                    "charsets": charsets_json(charsets),
+                })
+            }),
+
+            Event::Grid(cols, rows, Ok(cells)) => json!({
+                "type": "grid",
+                "data": json!({
+                    "cols": cols,
+                    "rows": rows,
+                    "cells": cells
+                        .iter()
+                        .map(|line| line.iter().map(cell_json).collect::<Vec<_>>())
+                        .collect::<Vec<_>>(),
+                })
+            }),
+
+            Event::Grid(cols, rows, Err(error)) => json!({
+                "type": "grid",
+                "data": json!({
+                    "cols": cols,
+                    "rows": rows,
+                    "error": error,
+                })
+            }),
+
+            Event::Frame(cast) => json!({
+                "type": "frame",
+                "data": json!({
+                    "cast": cast,
+                })
+            }),
+
+            Event::OutputTail(bytes) => json!({
+                "type": "outputTail",
+                "data": json!({
+                    "size": bytes.len(),
+                    "data": base64::engine::general_purpose::STANDARD.encode(bytes),
+                })
+            }),
+
+            Event::Marker(seq) => json!({
+                "type": "marker",
+                "data": json!({
+                    "seq": seq,
+                })
+            }),
+
+            Event::OutputSince(seq, next, oldest, format, result) => {
+                let mut data = json!({
+                    "seq": seq,
+                    "next": next,
+                    "oldest": oldest,
+                });
+
+                match (result, format) {
+                    (Ok(bytes), OutputSinceFormat::Raw) => {
+                        data["size"] = json!(bytes.len());
+                        data["data"] =
+                            json!(base64::engine::general_purpose::STANDARD.encode(bytes));
+                    }
+
+                    (Ok(bytes), OutputSinceFormat::Text) => {
+                        let text =
+                            escape::Scanner::new().feed_text(&String::from_utf8_lossy(bytes));
+
+                        data["text"] = json!(text);
+                    }
+
+                    (Err(message), _) => data["error"] = json!(message),
+                }
+
+                json!({
+                    "type": "outputSince",
+                    "data": data,
+                })
+            }
+
+            Event::TextWait(id, matched) => json!({
+                "type": "inputAndWait",
+                "data": json!({
+                    "result": if *matched { "matched" } else { "timeout" },
+                    "id": id,
+                })
+            }),
+
+            Event::Settle(id, cols, rows, settled) => json!({
+                "type": "resizeAndSettle",
+                "data": json!({
+                    "result": if *settled { "settled" } else { "timeout" },
+                    "cols": cols,
+                    "rows": rows,
+                    "id": id,
+                })
+            }),
+
+            Event::Query(id, response) => json!({
+                "type": "query",
+                "data": json!({
+                    "size": response.len(),
+                    "response": base64::engine::general_purpose::STANDARD.encode(response),
+                    "id": id,
+                })
+            }),
+
+            Event::Cwd(source, Ok(cwd)) => json!({
+                "type": "cwd",
+                "data": json!({
+                    "cwd": cwd,
+                    "source": cwd_source_name(*source),
+                })
+            }),
+
+            Event::Cwd(source, Err(error)) => json!({
+                "type": "cwd",
+                "data": json!({
+                    "cwd": null,
+                    "error": error,
+                    "source": cwd_source_name(*source),
+                })
+            }),
+
+            Event::Env(name, Ok(value)) => json!({
+                "type": "env",
+                "data": json!({
+                    "name": name,
+                    "value": value,
+                })
+            }),
+
+            Event::Env(name, Err(error)) => json!({
+                "type": "env",
+                "data": json!({
+                    "name": name,
+                    "value": null,
+                    "error": error,
+                })
+            }),
+
+            Event::Processes(Ok(processes)) => json!({
+                "type": "processes",
+                "data": json!({
+                    "processes": processes
+                        .iter()
+                        .map(|p| json!({
+                            "pid": p.pid,
+                            "ppid": p.ppid,
+                            "pgid": p.pgid,
+                            "command": p.command,
+                        }))
+                        .collect::<Vec<_>>(),
+                })
+            }),
+
+            Event::Processes(Err(error)) => json!({
+                "type": "processes",
+                "data": json!({
+                    "processes": null,
+                    "error": error,
+                })
+            }),
+
+            Event::KillProcess(pid, signal, Ok(())) => json!({
+                "type": "killProcess",
+                "data": json!({
+                    "pid": pid,
+                    "signal": signal,
+                })
+            }),
+
+            Event::KillProcess(pid, signal, Err(error)) => json!({
+                "type": "killProcess",
+                "data": json!({
+                    "pid": pid,
+                    "signal": signal,
+                    "error": error,
+                })
+            }),
+
+            Event::Refresh(id, Ok(settled)) => json!({
+                "type": "refresh",
+                "data": json!({
+                    "result": match settled {
+                        None => "signaled",
+                        Some(true) => "settled",
+                        Some(false) => "timeout",
+                    },
+                    "id": id,
+                })
+            }),
+
+            Event::Refresh(id, Err(error)) => json!({
+                "type": "refresh",
+                "data": json!({
+                    "id": id,
+                    "error": error,
+                })
+            }),
+
+            Event::InputPending(bytes) => json!({
+                "type": "inputPending",
+                "data": json!({
+                    "pendingBytes": bytes,
+                })
+            }),
+
+            Event::CommandResult(index, command, output, status) => json!({
+                "type": "commandResult",
+                "data": json!({
+                    "index": index,
+                    "command": command,
+                    "output": output,
+                    "exitCode": status,
+                })
+            }),
+
+            Event::Pid(pid) => json!({
+                "type": "pid",
+                "data": json!({
+                    "pid": pid,
+                })
+            }),
+
+            Event::InputChunked(total_bytes, chunks) => json!({
+                "type": "inputChunked",
+                "data": json!({
+                    "totalBytes": total_bytes,
+                    "chunks": chunks,
+                })
+            }),
+
+            Event::SaveScrollback(path, Ok(lines)) => json!({
+                "type": "saveScrollback",
+                "data": json!({
+                    "path": path,
+                    "lines": lines,
+                })
+            }),
+
+            Event::SaveScrollback(path, Err(error)) => json!({
+                "type": "saveScrollback",
+                "data": json!({
+                    "path": path,
+                    "error": error,
+                })
+            }),
+
+            Event::Error(message) => json!({
+                "type": "error",
+                "data": json!({
+                    "message": message,
+                })
+            }),
+
+            Event::UnknownSequence(seq, count) => json!({
+                "type": "unknownSequence",
+                "data": json!({
+                    "seq": seq,
+                    "count": count,
+                })
+            }),
+        }
+    }
+}
+
+/// Events which carry the terminal's output (`init`, `output`), the bulk of
+/// what's sent to clients, are serialized directly, without building a
+/// `serde_json::Value` first. Other events are serialized through `to_json`.
+impl serde::Serialize for Event {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_with(false, serializer)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Message<T> {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    data: T,
+}
+
+#[derive(serde::Serialize)]
+struct InitData<'a> {
+    cols: usize,
+    rows: usize,
+    seq: &'a str,
+    text: &'a str,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutputData<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scrolled: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lines: Option<Vec<LineData<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dirty_rows: Option<&'a [usize]>,
+}
+
+#[derive(serde::Serialize)]
+struct LineData<'a> {
+    row: usize,
+    text: &'a str,
+}
+
+impl Event {
+    /// Serializes the event, including the rows of the screen changed by an
+    /// `output` event (`dirtyRows`) when `dirty_rows` is set.
+    pub fn serialize_with<S: serde::Serializer>(
+        &self,
+        dirty_rows: bool,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        use serde::Serialize;
+
+        match self {
+            Event::Init(_time, cols, rows, seq, text) => Message {
+                type_: "init",
+                data: InitData {
+                    cols: *cols,
+                    rows: *rows,
+                    seq,
+                    text,
+                },
+            }
+            .serialize(serializer),
+
+            Event::Output(_time, seq, cooked, rows) => Message {
+                type_: "output",
+                data: OutputData {
+                    seq: cooked.is_none().then_some(seq.as_str()),
+                    scrolled: cooked
+                        .as_ref()
+                        .map(|cooked| cooked.scrolled.as_slice())
+                        .filter(|scrolled| !scrolled.is_empty()),
+                    lines: cooked.as_ref().map(|cooked| {
+                        cooked
+                            .lines
+                            .iter()
+                            .map(|(row, text)| LineData { row: *row, text })
+                            .collect()
+                    }),
+                    dirty_rows: dirty_rows.then_some(rows.as_slice()),
+                },
+            }
+            .serialize(serializer),
+
+            event => event.to_json().serialize(serializer),
+        }
+    }
+}
+
+impl Event {
+    /// Re-renders the screen carried by an `init` or `snapshot` event at
+    /// `cols` columns, for a client displaying the terminal at a different
+    /// width. Only the copy is resized, never the terminal itself. Other
+    /// events, and snapshots limited to the scroll region (whose rows don't
+    /// survive reflow), are returned as they are.
+    pub fn reflow(self, cols: usize) -> Event {
+        match self {
+            Event::Init(time, old_cols, rows, seq, _text) => {
+                let vt = reflowed_vt(&seq, old_cols, rows, cols);
+
+                Event::Init(time, cols, rows, vt.dump(), text(&vt))
+            }
+
+            // link positions don't survive reflow
+            Event::Snapshot(old_cols, rows, seq, _text, _cursor, None, generation, _links) => {
+                let vt = reflowed_vt(&seq, old_cols, rows, cols);
+
+                Event::Snapshot(
+                    cols,
+                    rows,
+                    vt.dump(),
+                    text(&vt),
+                    cursor(&vt),
+                    None,
+                    generation,
+                    Vec::new(),
+                )
+            }
+
+            event => event,
+        }
+    }
+}
+
+fn cursor_json(cursor: &Cursor) -> serde_json::Value {
+    json!({
+        "col": cursor.col,
+        "row": cursor.row,
+        "visible": cursor.visible,
+    })
+}
+
+fn cell_json(cell: &avt::Cell) -> serde_json::Value {
+    let pen = cell.pen();
+
+    let mut json = json!({
+        "char": cell.chars().collect::<String>(),
+        "width": cell.width(),
+        "fg": pen.foreground().map(color_json),
+        "bg": pen.background().map(color_json),
+        "bold": pen.is_bold(),
+        "faint": pen.is_faint(),
+        "italic": pen.is_italic(),
+        "underline": pen.is_underline(),
+        "strikethrough": pen.is_strikethrough(),
+        "blink": pen.is_blink(),
+        "inverse": pen.is_inverse(),
+    });
+
+    if cell.is_continuation() {
+        json["continuation"] = json!(true);
+    }
+
+    json
+}
+
+/// A palette color as its index, an RGB one as `#rrggbb`.
+fn color_json(color: avt::Color) -> serde_json::Value {
+    match color {
+        avt::Color::Indexed(c) => json!(c),
+        avt::Color::RGB(c) => json!(format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)),
+    }
+}
+
+fn scroll_region_json(region: &ScrollRegion) -> serde_json::Value {
+    json!({
+        "top": region.top,
+        "bottom": region.bottom,
+    })
+}
+
+fn cwd_source_name(source: CwdSource) -> &'static str {
+    match source {
+        CwdSource::GetCwd => "getCwd",
+        CwdSource::Osc7 => "osc7",
+    }
+}
+
+fn charsets_json(charsets: &Charsets) -> serde_json::Value {
+    let name = |charset| match charset {
+        Charset::Ascii => "ascii",
+        Charset::Drawing => "drawing",
+    };
+
+    json!({
+        "g0": name(charsets.g0),
+        "g1": name(charsets.g1),
+        "active": if charsets.shifted { "g1" } else { "g0" },
+    })
+}
+
+/// Character sets designated in the terminal, and which one is in use.
+fn charsets(vt: &avt::Vt) -> Charsets {
+    let charset = |charset: &avt::Charset| match charset {
+        avt::Charset::Ascii => Charset::Ascii,
+        avt::Charset::Drawing => Charset::Drawing,
+    };
+    let [g0, g1] = vt.charsets();
+
+    Charsets {
+        g0: charset(g0),
+        g1: charset(g1),
+        shifted: vt.active_charset() == 1,
+    }
+}
+
+/// Extracts the directory from the data of an OSC 7 sequence
+/// (`7;file://host/path`), decoding percent-encoded bytes. The host isn't
+/// checked, as it's normally the one ht runs on.
+fn osc7_path(data: &str) -> Option<String> {
+    let url = data.strip_prefix("7;")?.strip_prefix("file://")?;
+    let path = &url[url.find('/')?..];
+
+    Some(percent_decode(path))
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+
+    while let Some((&b, tail)) = rest.split_first() {
+        let decoded = match tail {
+            [hi, lo, ..] if b == b'%' && hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                let digit = |d: u8| (d as char).to_digit(16).unwrap() as u8;
+                Some(digit(*hi) << 4 | digit(*lo))
+            }
+
+            _ => None,
+        };
+
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+
+            None => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn exit_status_json(status: &ExitStatus) -> serde_json::Value {
+    match status {
+        ExitStatus::Exited(code) => json!({ "code": code }),
+
+        ExitStatus::Signaled {
+            signal,
+            name,
+            core_dumped,
+        } => json!({
+            "signal": name,
+            "signalNum": signal,
+            "coreDumped": core_dumped,
+        }),
+
+        ExitStatus::Unknown => json!({ "code": null }),
+    }
+}
+
+fn build_vt(cols: usize, rows: usize) -> avt::Vt {
+    let mut vt = avt::Vt::builder().size(cols, rows).resizable(true).build();
+    // all rows of a new terminal start out as changed
+    vt.feed_str("");
+
+    vt
+}
+
+/// Feeds already scanned data to a terminal emulator and its link tracker,
+/// returning the rows it changed.
+fn apply(vt: &mut avt::Vt, links: &mut Option<links::Tracker>, data: &str) -> Vec<usize> {
+    // links are tracked from the first one on, sparing the cost otherwise
+    if links.is_none() && links::has_link(data) {
+        *links = Some(links::Tracker::new(vt));
+    }
+
+    if let Some(links) = links {
+        links.feed(data);
+    }
+
+    let (dirty_rows, _) = vt.feed_str(data);
+
+    dirty_rows
+}
+
+/// Rows of the screen of `new` which differ from `old`'s, all of them when
+/// the size differs.
+fn changed_rows(old: &avt::Vt, new: &avt::Vt) -> Vec<usize> {
+    let (old_view, new_view) = (old.view(), new.view());
+
+    (0..new_view.len())
+        .filter(|&row| old.size() != new.size() || old_view[row] != new_view[row])
+        .collect()
+}
+
+fn resize_vt(vt: &mut avt::Vt, cols: usize, rows: usize) {
+    vt.feed_str(&format!("\x1b[8;{rows};{cols}t"));
+}
+
+/// Changes the width of a copy of the session's virtual terminal, re-wrapping
+/// its content the way a terminal does when resized.
+///
+/// Lines soft-wrapped at the right margin are joined and wrapped again at the
+/// new width, while hard line breaks are kept, so a narrower view gets more
+/// rows per logical line and a wider one fewer. The height is unchanged: rows
+/// pushed off the top when narrowing go to the scrollback and out of the view.
+pub fn reflow(vt: &mut avt::Vt, cols: usize) {
+    let (_cols, rows) = vt.size();
+    resize_vt(vt, cols, rows);
+}
+
+// the dump keeps soft wraps (no line break after a wrapped line), so they're
+// restored when it's fed to a terminal of the original width
+fn reflowed_vt(seq: &str, cols: usize, rows: usize, new_cols: usize) -> avt::Vt {
+    let mut vt = build_vt(cols, rows);
+    vt.feed_str(seq);
+    reflow(&mut vt, new_cols);
+
+    vt
+}
+
+pub(crate) fn cursor(vt: &avt::Vt) -> Cursor {
+    let cursor = vt.cursor();
+
+    Cursor {
+        col: cursor.col,
+        row: cursor.row,
+        visible: cursor.visible,
+    }
+}
+
+fn init_event(time: f64, vt: &avt::Vt) -> Event {
+    let (cols, rows) = vt.size();
+
+    Event::Init(time, cols, rows, vt.dump(), text(vt))
+}
+
+fn text(vt: &avt::Vt) -> String {
+    vt.view()
+        .iter()
+        .map(|l| l.text())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl Subscription {
+    pub fn into_stream(self) -> impl Stream<Item = Result<Event, BroadcastStreamRecvError>> {
+        let init = stream::once(future::ready(Ok(self.init)));
+        let pid = stream::iter(self.pid.map(|pid| Ok(Event::Pid(pid))));
+        let replay = stream::iter(self.replay.into_iter().map(Ok));
+        let events = BroadcastStream::new(self.broadcast_rx);
+
+        init.chain(pid).chain(replay).chain(events)
+    }
+
+    /// Streams the grid frames of a subscription requested with
+    /// `grid_stream`: a full frame, then deltas. Nothing when the terminal
+    /// emulator is disabled.
+    pub fn into_grid_stream(
+        self,
+    ) -> impl Stream<Item = Result<Arc<[u8]>, BroadcastStreamRecvError>> {
+        let (full, frames) = match self.grid {
+            Some(grid) => (
+                Some(Ok(grid.full.into())),
+                Some(BroadcastStream::new(grid.frames_rx)),
+            ),
+
+            None => (None, None),
+        };
+
+        stream::iter(full).chain(stream::iter(frames).flatten())
+    }
+
+    /// Like `into_stream`, without the `output` events sent while output is
+    /// muted (see `Session::set_muted`). For API clients, not for the ones
+    /// keeping their own copy of the screen, which need every output.
+    pub fn into_api_stream(self) -> impl Stream<Item = Result<Event, BroadcastStreamRecvError>> {
+        let muted = self.muted;
+
+        self.into_stream()
+            .scan(muted, |muted, event| {
+                if let Ok(Event::Muted(_, m, _)) = &event {
+                    *muted = *m;
+                }
+
+                let skip = *muted && matches!(event, Ok(Event::Output(..)));
+
+                future::ready(Some((!skip).then_some(event)))
+            })
+            .filter_map(future::ready)
+    }
+}
+
+/// Passes a command through a command hook (see `Session::set_command_hook`),
+/// returning the command to apply (`None` if the hook dropped it).
+pub fn filter_command(hook: Option<&CommandHook>, command: Command) -> Option<Command> {
+    let Some(hook) = hook else {
+        return Some(command);
+    };
+
+    match hook(&command) {
+        CommandAction::Allow => Some(command),
+        CommandAction::Deny => None,
+        CommandAction::Rewrite(command) => Some(command),
+    }
+}
+
+/// Streams session events, starting with the replay buffer if `replay` is
+/// set (see `Session::subscribe_with_replay`).
+pub async fn stream(
+    clients_tx: &mpsc::Sender<Client>,
+    replay: bool,
+) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
+    Ok(request_subscription(clients_tx, replay)
+        .await?
+        .into_stream())
+}
+
+/// Streams the grid frames for a `/ws/grid` client, see
+/// `Subscription::into_grid_stream`.
+pub async fn grid_stream(
+    clients_tx: &mpsc::Sender<Client>,
+) -> Result<impl Stream<Item = Result<Arc<[u8]>, BroadcastStreamRecvError>>> {
+    Ok(request(clients_tx, false, true).await?.into_grid_stream())
+}
+
+/// Streams session events for an API client, see `Subscription::into_api_stream`.
+pub async fn api_stream(
+    clients_tx: &mpsc::Sender<Client>,
+    replay: bool,
+) -> Result<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
+    Ok(request_subscription(clients_tx, replay)
+        .await?
+        .into_api_stream())
+}
+
+/// Rebuilds a copy of the session's virtual terminal along with the session's
+/// rendering settings.
+pub async fn view(clients_tx: &mpsc::Sender<Client>) -> Result<View> {
+    let subscription = request_subscription(clients_tx, false).await?;
+
+    if !subscription.emulator {
+        return Err(EmulatorDisabled.into());
+    }
+
+    match subscription.init {
+        Event::Init(_time, cols, rows, seq, _text) => {
+            let mut vt = build_vt(cols, rows);
+            vt.feed_str(&seq);
+
+            Ok(View {
+                vt,
+                render_cursor: subscription.render_cursor,
+                links: subscription.links,
+            })
+        }
+
+        _ => unreachable!("subscription always starts with init event"),
+    }
+}
+
+impl Handle {
+    /// Creates a handle from the command and snapshot request channels the
+    /// session's event loop serves.
+    pub fn new(
+        command_tx: mpsc::Sender<Command>,
+        snapshot_tx: mpsc::Sender<SnapshotRequest>,
+    ) -> Self {
+        Self {
+            command_tx,
+            snapshot_tx,
+        }
+    }
+
+    /// Captures the screen (see `Session::screen_snapshot`). The request is
+    /// served apart from the commands, so it's answered even while they're
+    /// held up by input the program doesn't read (see `setBackpressure`), but
+    /// it doesn't wait for the input sent before either: to see the effect of
+    /// input, wait for its output (e.g. with the `inputAndWait` command) first.
+    pub async fn snapshot(&self) -> Result<Snapshot> {
+        let (reply, snapshot) = oneshot::channel();
+        self.snapshot_tx.send(reply).await?;
+
+        Ok(snapshot.await??)
+    }
+
+    /// Sends text to the process, as the `input` command does (including
+    /// newline translation).
+    #[cfg_attr(windows, allow(dead_code))]
+    pub async fn send_input(&self, text: &str) -> Result<()> {
+        let seqs = vec![InputSeq::Text(text.to_owned())];
+
+        self.send(Command::Input(seqs)).await
+    }
+
+    /// Resizes the terminal, as the `resize` command does (including the
+    /// debounce delay).
+    #[cfg_attr(windows, allow(dead_code))]
+    pub async fn resize(&self, cols: usize, rows: usize) -> Result<()> {
+        self.send(Command::Resize(cols, rows, true)).await
+    }
+
+    /// Queues a command, as if it came from the STDIO API.
+    pub async fn send(&self, command: Command) -> Result<()> {
+        self.command_tx.send(command).await?;
+
+        Ok(())
+    }
+}
+
+async fn request_subscription(
+    clients_tx: &mpsc::Sender<Client>,
+    replay: bool,
+) -> Result<Subscription> {
+    request(clients_tx, replay, false).await
+}
+
+async fn request(
+    clients_tx: &mpsc::Sender<Client>,
+    replay: bool,
+    grid: bool,
+) -> Result<Subscription> {
+    let (subscription_tx, sub_rx) = oneshot::channel();
+
+    clients_tx
+        .send(Client {
+            subscription_tx,
+            replay,
+            grid,
+        })
+        .await?;
+
+    Ok(tokio::time::timeout(Duration::from_secs(5), sub_rx).await??)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        BELL_COALESCE_WINDOW, Charset, Charsets, Client, CommandAction, Cursor, CwdSource,
+        EVENT_BUFFER_SIZE, Event, GridSubscription, Handle, OutputTail, ScrollRegion, Session,
+        Subscription, charsets, cursor, filter_command, osc7_path, percent_decode,
+    };
+    use crate::clock::ManualClock;
+    use crate::command::{
+        Command, EchoWait, OutputSinceFormat, Query, ScreenBuffer, Settle, TextWait,
+    };
+    use crate::escape::Scanner;
+    use crate::grid::Encoder;
+    use crate::record::Recorder;
+    use crate::theme::Theme;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::TryRecvError;
+    use tokio::sync::oneshot;
+
+    fn track(region: &mut ScrollRegion, input: &str) -> (usize, usize) {
+        for seq in Scanner::new().feed(input) {
+            region.track(&seq);
+        }
+
+        (region.top, region.bottom)
+    }
+
+    #[test]
+    fn track_scroll_region() {
+        let mut region = ScrollRegion::new(24);
+
+        assert_eq!(track(&mut region, "\x1b[2;23r"), (1, 22));
+        assert_eq!(track(&mut region, "\x1b[5r"), (4, 23));
+        assert_eq!(track(&mut region, "\x1b[;10r"), (0, 9));
+
+        // invalid regions are ignored
+        assert_eq!(track(&mut region, "\x1b[10;5r\x1b[1;30r"), (0, 9));
+
+        assert_eq!(track(&mut region, "\x1b[r"), (0, 23));
+    }
+
+    #[test]
+    fn reset_scroll_region() {
+        let mut region = ScrollRegion::new(24);
+
+        track(&mut region, "\x1b[2;23r");
+        assert_eq!(track(&mut region, "\x1bc"), (0, 23));
+
+        track(&mut region, "\x1b[2;23r");
+        assert_eq!(track(&mut region, "\x1b[!p"), (0, 23));
+
+        track(&mut region, "\x1b[2;23r");
+        assert_eq!(track(&mut region, "\x1b[8;24;100t"), (1, 22));
+        assert_eq!(track(&mut region, "\x1b[8;30;100t"), (0, 29));
+    }
+
+    #[test]
+    fn cooked_output() {
+        let mut session = Session::new(10, 3, Theme::default());
+        session.set_cooked_output(true);
+        let mut sub = session.subscribe();
+
+        let mut cook = |data: &str| {
+            session.output(data.to_owned());
+
+            match sub.broadcast_rx.try_recv() {
+                Ok(Event::Output(_, seq, Some(cooked), _)) => {
+                    assert_eq!(seq, data);
+                    cooked
+                }
+
+                _ => panic!("expected cooked output event"),
+            }
+        };
+
+        let line = |row, text: &str| (row, text.to_owned());
+
+        assert_eq!(cook("abc\x08X").lines, [line(0, "abX")]);
+        assert_eq!(cook("\rZ\r\n").lines, [line(0, "ZbX")]);
+        assert!(cook("\x1b[1m").is_empty());
+        assert_eq!(cook("1\r\n2").lines, [line(1, "1"), line(2, "2")]);
+
+        // lines which scrolled off within a chunk, except the ones shown
+        // before it unchanged
+        let cooked = cook("\r\n3\r\n4\r\n5\r\n6");
+        assert_eq!(cooked.scrolled, ["3"]);
+        assert_eq!(cooked.lines, [line(0, "4"), line(1, "5"), line(2, "6")]);
+
+        let cooked = cook("\x1b[1;1HY\x1b[3;1H\r\n");
+        assert_eq!(cooked.scrolled, ["Y"]);
+        assert_eq!(cooked.lines, [line(0, "5"), line(1, "6"), line(2, "")]);
+
+        // the alternate screen has no scrollback
+        cook("\x1b[?1049h");
+        assert!(cook("a\r\nb\r\nc\r\nd").scrolled.is_empty());
+        assert!(cook("\x1b[?1049l").scrolled.is_empty());
+    }
+
+    #[test]
+    fn output_dirty_rows() {
+        let mut session = Session::new(10, 3, Theme::default());
+        let mut sub = session.subscribe();
+
+        let mut dirty_rows = |data: &str| {
+            session.output(data.to_owned());
+
+            match sub.broadcast_rx.try_recv() {
+                Ok(Event::Output(_, _, _, rows)) => rows,
+                _ => panic!("expected output event"),
+            }
+        };
+
+        assert_eq!(dirty_rows("a"), vec![0]);
+        assert_eq!(dirty_rows("\x1b[3;1Hb"), vec![2]);
+        assert!(dirty_rows("\x1b[?25l").is_empty());
+        assert_eq!(dirty_rows("\r\n"), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn timestamps_from_manual_clock() {
+        let clock = ManualClock::new();
+        let mut session = Session::new(10, 3, Theme::default());
+        session.set_clock(Box::new(clock.clone()));
+        let mut sub = session.subscribe();
+        let mut recorder = Recorder::new(Vec::new(), None);
+
+        session.output("a".to_owned());
+        clock.advance(Duration::from_millis(250));
+        session.resize(20, 5);
+        clock.advance(Duration::from_secs(2));
+        session.output("b".to_owned());
+
+        recorder
+            .event(&Event::Init(0.0, 10, 3, String::new(), String::new()))
+            .unwrap();
+
+        while let Ok(event) = sub.broadcast_rx.try_recv() {
+            recorder.event(&event).unwrap();
+        }
+
+        let cast = String::from_utf8(recorder.into_writer()).unwrap();
+        let events: Vec<&str> = cast.lines().skip(1).collect();
+
+        assert_eq!(
+            events,
+            vec![
+                r#"[0.0,"o","a"]"#,
+                r#"[0.25,"r","20x5"]"#,
+                r#"[2.25,"o","b"]"#
+            ]
+        );
+    }
+
+    #[test]
+    fn tab_stops_and_charsets() {
+        let mut vt = avt::Vt::new(30, 3);
+
+        assert_eq!(vt.tab_stops(), [8, 16, 24]);
+        assert_eq!(charsets(&vt), Charsets::default());
+
+        // clear all, set at 4 and 10, draw with G1 shifted in
+        vt.feed_str("\x1b[3g\x1b[5G\x1bH\x1b[11G\x1bH\x1b)0\x0eqx");
+
+        assert_eq!(vt.tab_stops(), [4, 10]);
+        assert_eq!(
+            charsets(&vt),
+            Charsets {
+                g0: Charset::Ascii,
+                g1: Charset::Drawing,
+                shifted: true,
+            }
+        );
+
+        // glyphs are stored translated
+        assert_eq!(vt.text()[0].trim(), "─│");
+
+        vt.feed_str("\x0f\x1b[3g");
+
+        assert!(vt.tab_stops().is_empty());
+        assert!(!charsets(&vt).shifted);
+    }
+
+    #[test]
+    fn prefill_screen() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        let mut sub = session.subscribe();
+
+        session
+            .prefill("\x1b[1;32mready\x1b[0m\x07\x1b[3;5H")
+            .unwrap();
+
+        let subscription = session.subscribe();
+        let Event::Init(_, _, _, _, text) = &subscription.init else {
+            unreachable!()
+        };
+
+        assert_eq!(text.lines().next().map(str::trim_end), Some("ready"));
+        assert_eq!((session.vt.cursor().row, session.vt.cursor().col), (2, 4));
+
+        // no output or bell event for the content
+        session.output("x".to_owned());
+
+        assert!(matches!(
+            sub.broadcast_rx.try_recv(),
+            Ok(Event::Output(_, data, _, _)) if data == "x"
+        ));
+        assert!(sub.broadcast_rx.try_recv().is_err());
+
+        assert!(session.prefill("\x1b[8;50;100t").is_err());
+        assert_eq!(session.vt.size(), (10, 3));
+    }
+
+    #[test]
+    fn slow_subscriber_does_not_hold_up_others() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        let mut fast = session.subscribe();
+        let mut slow = session.subscribe();
+        let mut received = Vec::new();
+
+        for i in 0..EVENT_BUFFER_SIZE * 2 {
+            session.output(i.to_string());
+
+            while let Ok(Event::Output(_, data, _, _)) = fast.broadcast_rx.try_recv() {
+                received.push(data);
+            }
+        }
+
+        let expected: Vec<String> = (0..EVENT_BUFFER_SIZE * 2).map(|i| i.to_string()).collect();
+        assert_eq!(received, expected);
+
+        assert!(matches!(
+            slow.broadcast_rx.try_recv(),
+            Err(TryRecvError::Lagged(n)) if n == EVENT_BUFFER_SIZE as u64
+        ));
+        assert!(matches!(
+            slow.broadcast_rx.try_recv(),
+            Ok(Event::Output(_, data, _, _)) if data == EVENT_BUFFER_SIZE.to_string()
+        ));
+    }
+
+    #[test]
+    fn disabled_emulator() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        session.set_emulator(false);
+        let mut sub = session.subscribe();
+
+        session.output("hello\x07".to_owned());
+
+        assert!(matches!(
+            sub.broadcast_rx.try_recv(),
+            Ok(Event::Output(_, data, None, rows)) if data == "hello\x07" && rows.is_empty()
+        ));
+        assert!(sub.broadcast_rx.try_recv().is_err());
+        assert_eq!(session.get_text().trim(), "");
+    }
+
+    #[test]
+    fn parse_osc7_path() {
+        assert_eq!(
+            osc7_path("7;file://host/home/me/my%20dir"),
+            Some("/home/me/my dir".to_owned())
+        );
+        assert_eq!(osc7_path("7;file:///tmp"), Some("/tmp".to_owned()));
+        assert_eq!(osc7_path("7;file://host"), None);
+        assert_eq!(osc7_path("0;title"), None);
+        assert_eq!(percent_decode("a%zz%e2%82%ac%+f%"), "a%zz€%+f%");
+    }
+
+    #[test]
+    fn report_cwd_from_osc7() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        let mut sub = session.subscribe();
+
+        let mut reported = |data: &str| {
+            session.output(data.to_owned());
+
+            std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+                .filter_map(|e| match e {
+                    Event::Cwd(CwdSource::Osc7, Ok(cwd)) => Some(cwd),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(reported("\x1b]7;file://host/tmp\x07$ "), vec!["/tmp"]);
+        assert!(reported("\x1b]7;file://host/tmp\x1b\\$ ").is_empty());
+        assert_eq!(
+            reported("\x1b]7;file://host/a\x07\x1b]7;file://host/b\x07"),
+            vec!["/b"]
+        );
+        assert_eq!(reported("\x1b]7;file://host/tmp\x07"), vec!["/tmp"]);
+        assert_eq!(session.unknown_sequences, 0);
+    }
+
+    #[test]
+    fn command_hook() {
+        let mut session = Session::new(10, 3, Theme::default());
+        assert!(filter_command(session.command_hook().as_ref(), Command::State).is_some());
+
+        session.set_command_hook(Some(Arc::new(|command| match command {
+            Command::State => CommandAction::Deny,
+            Command::Cwd => CommandAction::Rewrite(Command::Env("PWD".to_owned())),
+            _ => CommandAction::Allow,
+        })));
+
+        let hook = session.command_hook();
+        assert!(filter_command(hook.as_ref(), Command::State).is_none());
+        assert!(matches!(
+            filter_command(hook.as_ref(), Command::Cwd),
+            Some(Command::Env(name)) if name == "PWD"
+        ));
+        assert!(matches!(
+            filter_command(
+                hook.as_ref(),
+                Command::Snapshot(false, ScreenBuffer::Active)
+            ),
+            Some(Command::Snapshot(false, ScreenBuffer::Active))
+        ));
+
+        session.set_command_hook(None);
+        assert!(filter_command(session.command_hook().as_ref(), Command::State).is_some());
+    }
+
+    #[test]
+    fn coalesce_bells() {
+        let mut session = Session::new(10, 3, Theme::default());
+        let mut sub = session.subscribe();
+
+        let mut bells = |data: &str| {
+            session.output(data.to_owned());
+
+            std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+                .filter(|e| matches!(e, Event::Bell(_)))
+                .count()
+        };
+
+        assert_eq!(bells("a\x07\x07b"), 1);
+        assert_eq!(bells("\x07"), 0);
+        assert_eq!(bells("\x1b]0;title\x07"), 0);
+
+        std::thread::sleep(BELL_COALESCE_WINDOW);
+        assert_eq!(bells("\x07"), 1);
+    }
+
+    #[test]
+    fn snapshot_screen_buffers() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        let mut sub = session.subscribe();
+
+        let mut snapshot = |session: &mut Session, buffer| {
+            session.snapshot(false, buffer);
+
+            std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+                .find_map(|e| match e {
+                    Event::Snapshot(_, _, _, text, _, _, _, _) => Some(text),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        session.output("$ less\r\n".to_owned());
+        session.output("\x1b[?1049h\x1b[Hpage 1".to_owned());
+
+        assert!(snapshot(&mut session, ScreenBuffer::Active).starts_with("page 1"));
+        assert!(snapshot(&mut session, ScreenBuffer::Alt).starts_with("page 1"));
+        assert!(snapshot(&mut session, ScreenBuffer::Main).starts_with("$ less"));
+
+        // the main screen is still active after a peek at it
+        assert!(snapshot(&mut session, ScreenBuffer::Active).starts_with("page 1"));
+
+        session.output("\x1b[?1049l$ ".to_owned());
+
+        let restored = "$ less    \n$         \n          ";
+        assert_eq!(snapshot(&mut session, ScreenBuffer::Active), restored);
+        assert_eq!(snapshot(&mut session, ScreenBuffer::Main), restored);
+        assert_eq!(snapshot(&mut session, ScreenBuffer::Alt).trim(), "");
+    }
+
+    fn take_snapshot(session: &mut Session, sub: &mut Subscription) -> (String, u64) {
+        session.snapshot(false, ScreenBuffer::Active);
+
+        loop {
+            match sub.broadcast_rx.try_recv() {
+                Ok(Event::Snapshot(_, _, _, text, _, _, generation, _)) => {
+                    return (text, generation);
+                }
+                Ok(_) => continue,
+                Err(_) => panic!("expected snapshot event"),
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_generation() {
+        let mut session = Session::new(10, 3, Theme::default());
+        let mut sub = session.subscribe();
+
+        let (_, first) = take_snapshot(&mut session, &mut sub);
+        session.output("hello".to_owned());
+        let (text, second) = take_snapshot(&mut session, &mut sub);
+        let (cached_text, third) = take_snapshot(&mut session, &mut sub);
+        session.resize(20, 3);
+        let (_, fourth) = take_snapshot(&mut session, &mut sub);
+
+        assert_ne!(first, second);
+        assert!(text.starts_with("hello"));
+        assert_eq!(text, cached_text);
+        assert_eq!(second, third);
+        assert_ne!(third, fourth);
+    }
+
+    #[test]
+    fn output_tail_keeps_last_bytes() {
+        let mut tail = OutputTail::new(8);
+
+        tail.push(b"abc");
+        assert_eq!(tail.last(100), b"abc");
+
+        tail.push(b"\x1b[31mxyz");
+        assert_eq!(tail.last(100), b"\x1b[31mxyz");
+        assert_eq!(tail.last(3), b"xyz");
+
+        tail.push(b"0123456789");
+        assert_eq!(tail.last(100), b"23456789");
+
+        let mut disabled = OutputTail::new(0);
+        disabled.push(b"abc");
+        assert!(disabled.last(100).is_empty());
+    }
+
+    #[test]
+    fn output_batching() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        session.set_output_batching(8, Duration::from_secs(3600));
+        let mut sub = session.subscribe();
+
+        let mut events = || -> Vec<String> {
+            std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+                .map(|event| match event {
+                    Event::Output(_, data, _, _) => format!("output {data}"),
+                    Event::Resize(_, cols, rows) => format!("resize {cols}x{rows}"),
+                    event => event.to_json()["type"].as_str().unwrap().to_owned(),
+                })
+                .collect()
+        };
+
+        session.push_output(b"abc".to_vec());
+        assert!(events().is_empty());
+        assert!(session.flush_deadline().is_some());
+
+        // enough bytes pending
+        session.push_output(b"defgh".to_vec());
+        assert_eq!(events(), ["output abcdefgh"]);
+        assert!(session.flush_deadline().is_none());
+
+        // a resize processes held output first, at the old size
+        session.push_output(b"ij".to_vec());
+        session.resize(20, 3);
+        assert_eq!(events(), ["output ij", "resize 20x3"]);
+
+        // and so do expiring watches
+        session.push_output(b"kl".to_vec());
+        session.expire_watches();
+        assert_eq!(events(), ["output kl"]);
+
+        session.set_output_batching(8, Duration::ZERO);
+        session.push_output(b"m".to_vec());
+        assert_eq!(events(), ["output m"]);
+    }
+
+    #[test]
+    fn output_tail_since_offset() {
+        let mut tail = OutputTail::new(8);
+
+        tail.push(b"abc");
+        assert_eq!(tail.since(0).unwrap(), b"abc");
+        assert_eq!(tail.since(2).unwrap(), b"c");
+        assert!(tail.since(3).unwrap().is_empty());
+        tail.since(4).expect_err("ahead of the output");
+
+        tail.push(b"0123456789");
+        assert_eq!(tail.end, 13);
+        assert_eq!(tail.start(), 5);
+        assert_eq!(tail.since(10).unwrap(), b"789");
+        tail.since(4).expect_err("aged out");
+    }
+
+    #[test]
+    fn output_since_marker() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        session.set_output_tail_size(24);
+        let mut sub = session.subscribe();
+
+        session.output_bytes(b"$ ");
+        session.mark();
+        session.output_bytes(b"ls\r\n\x1b[1mfoo\x1b[0m\r\n$ ");
+        session.output_since(2, OutputSinceFormat::Text);
+        session.output_since(2, OutputSinceFormat::Raw);
+        session.output_bytes(b"0123456789");
+        session.output_since(2, OutputSinceFormat::Raw);
+
+        let events: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .map(|e| e.to_json())
+            .filter(|e| e["type"] == "marker" || e["type"] == "outputSince")
+            .collect();
+
+        assert_eq!(events[0]["data"]["seq"], 2);
+        assert_eq!(events[1]["data"]["text"], "ls\nfoo\n$ ");
+        assert_eq!(events[1]["data"]["next"], 21);
+        assert_eq!(events[2]["data"]["size"], 19);
+        assert_eq!(events[2]["data"]["oldest"], 0);
+        assert_eq!(events[3]["data"]["oldest"], 7);
+        assert!(events[3]["data"]["error"].is_string());
+        assert!(events[3]["data"].get("data").is_none());
+    }
+
+    #[test]
+    fn reflow_snapshot() {
+        let mut session = Session::new(10, 4, Theme::dark());
+        let mut sub = session.subscribe();
+        session.output("abcdefghijklmno\r\nxy".to_owned());
+        session.snapshot(false, ScreenBuffer::Active);
+
+        let snapshot = loop {
+            if let Ok(event @ Event::Snapshot(..)) = sub.broadcast_rx.try_recv() {
+                break event;
+            }
+        };
+
+        let lines = |event: Event| match event {
+            Event::Snapshot(cols, _, _, text, _, _, _, _) => (
+                cols,
+                text.lines()
+                    .map(|l| l.trim_end().to_owned())
+                    .collect::<Vec<_>>(),
+            ),
+            _ => unreachable!(),
+        };
+
+        assert_eq!(
+            lines(snapshot.clone().reflow(20)),
+            (
+                20,
+                vec!["abcdefghijklmno", "xy", "", ""]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+
+        // the height is kept, so the first row scrolls out of view
+        assert_eq!(
+            lines(snapshot.reflow(6)),
+            (
+                6,
+                vec!["ghijkl", "mno", "xy", ""]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+
+        assert_eq!(session.vt.size(), (10, 4));
+    }
+
+    #[test]
+    fn text_wait() {
+        let mut session = Session::new(20, 4, Theme::dark());
+        let mut sub = session.subscribe();
+        session.output("$ ".to_owned());
+
+        let wait = |id: &str, expect: &str, timeout_ms| TextWait {
+            id: Some(serde_json::json!(id)),
+            expect: expect.to_owned(),
+            timeout: Duration::from_millis(timeout_ms),
+        };
+
+        session.watch_text(wait("prompt", "$ ", 60_000));
+        session.watch_text(wait("never", "nope", 0));
+        session.output("ls\r\nfoo\r\n\x1b[32m$".to_owned());
+        session.output("\x1b[0m ".to_owned());
+        session.expire_watches();
+
+        let results: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .filter_map(|event| match event {
+                Event::TextWait(id, matched) => Some((id.unwrap(), matched)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![
+                (serde_json::json!("prompt"), true),
+                (serde_json::json!("never"), false)
+            ]
+        );
+        assert!(session.watch_deadline().is_none());
+    }
+
+    #[test]
+    fn replay_rebuilds_screen() {
+        let mut session = Session::new(20, 4, Theme::dark());
+        session.set_replay_limits(16, 1);
+
+        for i in 0..10 {
+            session.output(format!("line {i}\r\n"));
+        }
+
+        session.resize(12, 4);
+        session.output("\x1b[31mred".to_owned());
+        // growing the height would bring back scrollback lines, which the
+        // replayed init (like any init) doesn't carry
+        session.resize(30, 4);
+        session.output(" tail".to_owned());
+
+        let subscription = session.subscribe_with_replay();
+
+        let Event::Init(_, cols, rows, seq, _) = subscription.init else {
+            unreachable!()
+        };
+
+        let mut vt = super::build_vt(cols, rows);
+        vt.feed_str(&seq);
+        let mut output_size = 0;
+        let mut resizes = 0;
+
+        for event in subscription.replay {
+            match event {
+                Event::Output(_, data, _, _) => {
+                    output_size += data.len();
+                    vt.feed_str(&data);
+                }
+
+                Event::Resize(_, cols, rows) => {
+                    resizes += 1;
+                    super::resize_vt(&mut vt, cols, rows);
+                }
+
+                _ => unreachable!(),
+            }
+        }
+
+        assert!(output_size <= 16);
+        assert_eq!(resizes, 1);
+        assert_eq!(vt.size(), (30, 4));
+        assert_eq!(vt.dump(), session.vt.dump());
+    }
+
+    #[test]
+    fn settle_wait() {
+        let mut session = Session::new(20, 4, Theme::dark());
+        let mut sub = session.subscribe();
+
+        let settle = |id: &str, quiet_ms, timeout_ms| Settle {
+            id: Some(serde_json::json!(id)),
+            quiet: Duration::from_millis(quiet_ms),
+            timeout: Duration::from_millis(timeout_ms),
+        };
+
+        session.watch_settle(settle("quiet", 30, 60_000));
+        session.watch_settle(settle("busy", 60_000, 0));
+        session.expire_watches();
+        std::thread::sleep(Duration::from_millis(20));
+        session.output("redraw".to_owned());
+        std::thread::sleep(Duration::from_millis(20));
+        session.expire_watches();
+        std::thread::sleep(Duration::from_millis(20));
+        session.expire_watches();
+
+        let results: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .filter_map(|event| match event {
+                Event::Settle(id, _, _, settled) => Some((id.unwrap(), settled)),
+                _ => None,
+            })
+            .collect();
+
+        // the output 20 ms in pushed the quiet one's settling back to 50 ms
+        assert_eq!(
+            results,
+            vec![
+                (serde_json::json!("busy"), false),
+                (serde_json::json!("quiet"), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn query_response() {
+        let mut session = Session::new(20, 4, Theme::dark());
+        let mut sub = session.subscribe();
+
+        let query = |id: &str, timeout_ms| Query {
+            id: Some(serde_json::json!(id)),
+            send: "\x1b[6n".to_owned(),
+            timeout: Duration::from_millis(timeout_ms),
+        };
+
+        session.output_bytes(b"before");
+        session.watch_query(&query("slow", 60_000));
+        session.watch_query(&query("fast", 0));
+        session.output_bytes(b"\x1b[1;1R");
+        session.expire_watches();
+        session.output_bytes(b"after");
+        session.expire_watches();
+
+        let responses: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .filter_map(|event| match event {
+                Event::Query(id, response) => Some((id.unwrap(), response)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            responses,
+            vec![(serde_json::json!("fast"), b"\x1b[1;1R".to_vec())]
+        );
+        assert_eq!(session.query_watches[0].response, b"\x1b[1;1Rafter");
+    }
+
+    #[test]
+    fn grid() {
+        let mut session = Session::new(3, 2, Theme::dark());
+        let mut sub = session.subscribe();
+        session.output("\x1b[1;31ma\x1b[22;4;48;2;1;2;3mb\x1b[0m\r\n\x1b[7mc".to_owned());
+        session.grid();
+
+        let grid = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+            .map(|event| event.to_json())
+            .find(|event| event["type"] == "grid")
+            .unwrap();
+
+        let cell = |ch: &str, fg, bg, bold, underline, inverse| {
+            serde_json::json!({
+                "char": ch,
+                "width": 1,
+                "fg": fg,
+                "bg": bg,
+                "bold": bold,
+                "faint": false,
+                "italic": false,
+                "underline": underline,
+                "strikethrough": false,
+                "blink": false,
+                "inverse": inverse,
+            })
+        };
+
+        let null = serde_json::Value::Null;
+        let blank = cell(" ", null.clone(), null.clone(), false, false, false);
+
+        assert_eq!(
+            grid["data"],
+            serde_json::json!({
+                "cols": 3,
+                "rows": 2,
+                "cells": [
+                    [
+                        cell("a", 1.into(), null.clone(), true, false, false),
+                        cell("b", 1.into(), "#010203".into(), false, true, false),
+                        blank,
+                    ],
+                    [
+                        cell("c", null.clone(), null.clone(), false, false, true),
+                        blank,
+                        blank,
+                    ],
+                ],
+            })
+        );
+
+        let session = Session::new(1000, 101, Theme::dark());
+        let mut sub = session.subscribe();
+        session.grid();
+
+        let Ok(Event::Grid(1000, 101, Err(error))) = sub.broadcast_rx.try_recv() else {
+            panic!("expected a grid error");
+        };
+
+        assert_eq!(error, "screen too large: 101000 cells, at most 100000");
+    }
+
+    #[test]
+    fn grid_wide_characters() {
+        let grid = |cols, rows, output: &str| {
+            let mut session = Session::new(cols, rows, Theme::dark());
+            let mut sub = session.subscribe();
+            session.output(output.to_owned());
+            session.grid();
+
+            let events: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok())
+                .map(|event| event.to_json())
+                .collect();
+
+            let wraps: Vec<_> = events
+                .iter()
+                .filter(|event| event["type"] == "wideCharWrap")
+                .map(|event| event["data"].clone())
+                .collect();
+
+            let grid = events.iter().find(|event| event["type"] == "grid").unwrap();
+
+            let cells: Vec<Vec<_>> = grid["data"]["cells"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|row| {
+                    row.as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|cell| {
+                            let ch = cell["char"].as_str().unwrap().to_owned();
+                            (
+                                ch,
+                                cell["width"].as_u64().unwrap(),
+                                cell["continuation"] == true,
+                            )
+                        })
+                        .collect()
+                })
+                .collect();
+
+            (cells, wraps)
+        };
+
+        let cell = |ch: &str, width| (ch.to_owned(), width, false);
+        let blank = || cell(" ", 1);
+        let continuation = || (String::new(), 0, true);
+
+        let (cells, wraps) = grid(10, 1, "a\u{4e2d}b\u{1f600}e\u{301}");
+
+        assert_eq!(
+            cells,
+            vec![vec![
+                cell("a", 1),
+                cell("\u{4e2d}", 2),
+                continuation(),
+                cell("b", 1),
+                cell("\u{1f600}", 2),
+                continuation(),
+                cell("e\u{301}", 1),
+                blank(),
+                blank(),
+                blank(),
+            ]]
+        );
+
+        assert!(wraps.is_empty());
+
+        // the cursor moves by display width, and printing over half of a
+        // wide character blanks the other half
+        let (cells, _) = grid(6, 1, "\u{4e2d}\x1b[3Gx\x1b[2Gy");
+
+        assert_eq!(
+            cells,
+            vec![vec![
+                blank(),
+                cell("y", 1),
+                cell("x", 1),
+                blank(),
+                blank(),
+                blank()
+            ]]
+        );
+
+        // a wide character which doesn't fit in the row goes to the next one
+        let (cells, wraps) = grid(4, 2, "\u{4e2d}\u{4e2d}\u{4e2d}");
+
+        assert_eq!(
+            cells,
+            vec![
+                vec![
+                    cell("\u{4e2d}", 2),
+                    continuation(),
+                    cell("\u{4e2d}", 2),
+                    continuation(),
+                ],
+                vec![cell("\u{4e2d}", 2), continuation(), blank(), blank()],
+            ]
+        );
+
+        assert!(wraps.is_empty());
+
+        let (cells, wraps) = grid(4, 2, "abc\u{4e2d}");
+
+        assert_eq!(
+            cells,
+            vec![
+                vec![cell("a", 1), cell("b", 1), cell("c", 1), blank()],
+                vec![cell("\u{4e2d}", 2), continuation(), blank(), blank()],
+            ]
+        );
+
+        assert_eq!(wraps, [serde_json::json!({"char": "\u{4e2d}", "row": 1})]);
+    }
+
+    #[test]
+    fn echo_wait() {
+        let mut session = Session::new(20, 4, Theme::dark());
+
+        let mut wait = |timeout_ms| {
+            let (reply, rx) = tokio::sync::oneshot::channel();
+
+            session.watch_echo(EchoWait {
+                timeout: Duration::from_millis(timeout_ms),
+                reply,
+            });
+
+            rx
+        };
+
+        let mut echoed = wait(60_000);
+        let mut silent = wait(0);
+
+        session.expire_watches();
+        assert_eq!(silent.try_recv(), Ok(false));
+        assert!(echoed.try_recv().is_err());
+
+        session.output("a".to_owned());
+        assert_eq!(echoed.try_recv(), Ok(true));
+        assert!(session.watch_deadline().is_none());
+    }
+
+    #[tokio::test]
+    async fn muted_output_left_out_of_api_stream() {
+        use futures_util::StreamExt;
+
+        let mut session = Session::new(10, 2, Theme::dark());
+        session.set_muted(true);
+        let sub = session.subscribe();
+        let mut session_sub = session.subscribe();
+
+        session.output("noise".to_owned());
+        session.set_muted(false);
+        session.output("ok".to_owned());
+        drop(session);
+
+        let types: Vec<_> = sub
+            .into_api_stream()
+            .map(|e| e.unwrap().to_json()["type"].as_str().unwrap().to_owned())
+            .collect()
+            .await;
+
+        assert_eq!(types, ["init", "muted", "output"]);
+
+        // the screen is kept up to date regardless
+        let outputs = std::iter::from_fn(|| session_sub.broadcast_rx.try_recv().ok())
+            .filter(|e| matches!(e, Event::Output(..)))
+            .count();
+
+        assert_eq!(outputs, 2);
+    }
+
+    #[tokio::test]
+    async fn pid_sent_to_early_and_late_subscribers() {
+        use futures_util::StreamExt;
+
+        let mut session = Session::new(10, 2, Theme::dark());
+        let early = session.subscribe();
+        session.set_pid(42);
+        let late = session.subscribe();
+        session.output("ok".to_owned());
+        drop(session);
+
+        for sub in [early, late] {
+            let events: Vec<_> = sub
+                .into_stream()
+                .map(|e| e.unwrap().to_json())
+                .map(|e| (e["type"].clone(), e["data"]["pid"].clone()))
+                .collect()
+                .await;
+
+            assert_eq!(
+                events,
+                [
+                    ("init".into(), serde_json::Value::Null),
+                    ("pid".into(), 42.into()),
+                    ("output".into(), serde_json::Value::Null),
+                ]
+            );
+        }
+    }
+
+    fn grid_client(session: &mut Session) -> Option<GridSubscription> {
+        let (subscription_tx, mut subscription_rx) = oneshot::channel();
+
+        session.accept(Client {
+            subscription_tx,
+            replay: false,
+            grid: true,
+        });
+
+        subscription_rx.try_recv().unwrap().grid
+    }
+
+    #[test]
+    fn grid_frames() {
+        let mut session = Session::new(4, 2, Theme::dark());
+
+        let mut first = grid_client(&mut session).unwrap();
+        assert_eq!(first.full[0], 0);
+
+        session.output("ab".to_owned());
+        assert_eq!(first.frames_rx.try_recv().unwrap()[0], 1);
+
+        // a client joining later starts from the screen the deltas build on,
+        // and gets the same frames from then on
+        let mut second = grid_client(&mut session).unwrap();
+        assert_eq!(second.full, Encoder::default().full(&session.vt));
+
+        session.resize(6, 3);
+        let full = first.frames_rx.try_recv().unwrap();
+        assert_eq!(full[0], 0);
+        assert_eq!(second.frames_rx.try_recv().unwrap(), full);
+
+        // the encoder stops with the last client gone
+        drop((first, second));
+        session.output("c".to_owned());
+        assert!(session.grid.is_none());
+
+        session.set_emulator(false);
+        assert!(grid_client(&mut session).is_none());
+    }
+
+    #[test]
+    fn frozen_display_catches_up_on_unfreeze() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        let mut live = Session::new(10, 3, Theme::dark());
+        let mut sub = session.subscribe();
+
+        session.output("one\r\n".to_owned());
+        session.set_frozen(true);
+        live.output("one\r\n".to_owned());
+
+        for session in [&mut session, &mut live] {
+            session.output("two\r\n".to_owned());
+            session.resize(8, 4);
+            session.output("\x1b[1;1Hx\x1b[2;2H".to_owned());
+        }
+
+        assert_eq!(session.vt.size(), (10, 3));
+        assert_eq!(session.text_view(), "one       \n          \n          ");
+
+        // clients get the output and resize regardless
+        let events: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok()).collect();
+        assert!(matches!(
+            &events[..],
+            [
+                Event::Output(..),
+                Event::Output(_, two, _, dirty),
+                Event::Resize(_, 8, 4),
+                Event::Output(..)
+            ] if two == "two\r\n" && dirty.is_empty()
+        ));
+
+        // and a new client gets the screen which the events build on
+        let Event::Init(_, 8, 4, _, text) = session.subscribe().init else {
+            panic!("expected init at the live size");
+        };
+        assert_eq!(text, live.text_view());
+
+        session.set_frozen(false);
+
+        assert_eq!(session.vt.size(), (8, 4));
+        assert_eq!(session.text_view(), live.text_view());
+        assert_eq!(session.vt.dump(), live.vt.dump());
+        assert_eq!(cursor(&session.vt), cursor(&live.vt));
+
+        // the rows caught up with are reported, all of them after a resize
+        let Ok(Event::Output(_, data, None, dirty)) = sub.broadcast_rx.try_recv() else {
+            panic!("expected an output event");
+        };
+        assert_eq!(data, "");
+        assert_eq!(dirty, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn frozen_display_follows_modes_and_reports_changes() {
+        let mut session = Session::new(10, 3, Theme::dark());
+        session.set_cooked_output(true);
+        session.output("one\r\ntwo".to_owned());
+        let mut sub = session.subscribe();
+
+        session.set_frozen(true);
+        session.output("\x1b[?1h\x1b[1;1Hsix".to_owned());
+
+        // input is encoded for the mode the program set meanwhile
+        assert!(session.cursor_key_app_mode());
+        assert!(!session.vt.arrow_key_app_mode());
+
+        let Ok(Event::Output(_, _, Some(cooked), _)) = sub.broadcast_rx.try_recv() else {
+            panic!("expected an output event");
+        };
+        assert!(cooked.is_empty());
+
+        session.set_frozen(false);
+
+        let Ok(Event::Output(_, data, Some(cooked), dirty)) = sub.broadcast_rx.try_recv() else {
+            panic!("expected an output event");
+        };
+        assert_eq!(data, "");
+        assert_eq!(cooked.lines, [(0, "six".to_owned())]);
+        assert_eq!(dirty, [0]);
+
+        // unfreezing without changes sends nothing
+        session.set_frozen(true);
+        session.set_frozen(false);
+        assert!(sub.broadcast_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn frozen_display_keeps_its_scroll_region() {
+        let mut session = Session::new(80, 24, Theme::dark());
+        let mut sub = session.subscribe();
+
+        session.set_frozen(true);
+        session.resize(80, 30);
+        session.output("\x1b[2;28r".to_owned());
+        session.snapshot(true, ScreenBuffer::Active);
+
+        let events: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok()).collect();
+        let Some(Event::Snapshot(80, 24, _, text, _, Some(region), ..)) = events.last() else {
+            panic!("expected a snapshot of the frozen screen");
+        };
+        assert_eq!((region.top, region.bottom), (0, 23));
+        assert_eq!(text.lines().count(), 24);
+
+        session.set_frozen(false);
+        session.snapshot(true, ScreenBuffer::Active);
+
+        let events: Vec<_> = std::iter::from_fn(|| sub.broadcast_rx.try_recv().ok()).collect();
+        let Some(Event::Snapshot(80, 30, _, text, _, Some(region), ..)) = events.last() else {
+            panic!("expected a snapshot of the live screen");
+        };
+        assert_eq!((region.top, region.bottom), (1, 27));
+        assert_eq!(text.lines().count(), 27);
+    }
+
+    #[tokio::test]
+    async fn handle() {
+        let (command_tx, mut command_rx) = tokio::sync::mpsc::channel(1);
+        let (snapshot_tx, mut snapshot_rx) = tokio::sync::mpsc::channel(1);
+        let handle = Handle::new(command_tx, snapshot_tx);
+        let mut session = Session::new(10, 2, Theme::dark());
+        session.output("hello\r\nworld".to_owned());
+
+        let snapshot = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.snapshot().await.unwrap() }
+        });
+
+        let Some(reply) = snapshot_rx.recv().await else {
+            panic!("expected a snapshot request");
+        };
+        reply.send(session.screen_snapshot()).unwrap();
+        let snapshot = snapshot.await.unwrap();
+
+        // served from the snapshot cache
+        let cache = session.snapshot_cache.as_ref().unwrap();
+        assert_eq!(cache.generation, session.generation);
+        assert_eq!(snapshot.seq, cache.seq);
+
+        assert_eq!((snapshot.cols, snapshot.rows), (10, 2));
+        assert_eq!(snapshot.text, "hello     \nworld     ");
+        assert_eq!(snapshot.lines, vec!["hello     ", "world     "]);
+        assert_eq!(
+            snapshot.cursor,
+            Cursor {
+                col: 5,
+                row: 1,
+                visible: true
+            }
+        );
+
+        handle.send_input("ls\n").await.unwrap();
+        assert!(matches!(command_rx.recv().await, Some(Command::Input(_))));
+
+        handle.resize(80, 24).await.unwrap();
+        assert!(matches!(
+            command_rx.recv().await,
+            Some(Command::Resize(80, 24, true))
+        ));
+    }
 }
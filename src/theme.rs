@@ -0,0 +1,248 @@
+use anyhow::{Context, Result, bail};
+use avt::Color;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Theme {
+    pub foreground: Rgb,
+    pub background: Rgb,
+    pub palette: [Rgb; 16],
+}
+
+impl Theme {
+    // asciinema's default palette
+    pub fn dark() -> Self {
+        Self {
+            foreground: Rgb(0xcc, 0xcc, 0xcc),
+            background: Rgb(0x12, 0x13, 0x14),
+            palette: [
+                Rgb(0x00, 0x00, 0x00),
+                Rgb(0xdd, 0x3c, 0x69),
+                Rgb(0x4e, 0xbf, 0x22),
+                Rgb(0xdd, 0xaf, 0x3c),
+                Rgb(0x26, 0xb0, 0xd7),
+                Rgb(0xb9, 0x54, 0xe1),
+                Rgb(0x54, 0xe1, 0xb9),
+                Rgb(0xd9, 0xd9, 0xd9),
+                Rgb(0x4d, 0x4d, 0x4d),
+                Rgb(0xdd, 0x3c, 0x69),
+                Rgb(0x4e, 0xbf, 0x22),
+                Rgb(0xdd, 0xaf, 0x3c),
+                Rgb(0x26, 0xb0, 0xd7),
+                Rgb(0xb9, 0x54, 0xe1),
+                Rgb(0x54, 0xe1, 0xb9),
+                Rgb(0xff, 0xff, 0xff),
+            ],
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            foreground: Rgb(0x1e, 0x1e, 0x1e),
+            background: Rgb(0xff, 0xff, 0xff),
+            palette: [
+                Rgb(0x00, 0x00, 0x00),
+                Rgb(0xc9, 0x1b, 0x00),
+                Rgb(0x00, 0xa6, 0x00),
+                Rgb(0xa6, 0x8b, 0x00),
+                Rgb(0x02, 0x25, 0xc7),
+                Rgb(0xca, 0x30, 0xc7),
+                Rgb(0x00, 0xa5, 0xa7),
+                Rgb(0xbf, 0xbf, 0xbf),
+                Rgb(0x68, 0x68, 0x68),
+                Rgb(0xff, 0x6e, 0x67),
+                Rgb(0x3c, 0xc8, 0x46),
+                Rgb(0xd6, 0xb8, 0x00),
+                Rgb(0x68, 0x71, 0xff),
+                Rgb(0xff, 0x77, 0xff),
+                Rgb(0x3c, 0xcf, 0xd1),
+                Rgb(0xff, 0xff, 0xff),
+            ],
+        }
+    }
+
+    pub fn solarized() -> Self {
+        Self {
+            foreground: Rgb(0x83, 0x94, 0x96),
+            background: Rgb(0x00, 0x2b, 0x36),
+            palette: [
+                Rgb(0x07, 0x36, 0x42),
+                Rgb(0xdc, 0x32, 0x2f),
+                Rgb(0x85, 0x99, 0x00),
+                Rgb(0xb5, 0x89, 0x00),
+                Rgb(0x26, 0x8b, 0xd2),
+                Rgb(0xd3, 0x36, 0x82),
+                Rgb(0x2a, 0xa1, 0x98),
+                Rgb(0xee, 0xe8, 0xd5),
+                Rgb(0x00, 0x2b, 0x36),
+                Rgb(0xcb, 0x4b, 0x16),
+                Rgb(0x58, 0x6e, 0x75),
+                Rgb(0x65, 0x7b, 0x83),
+                Rgb(0x83, 0x94, 0x96),
+                Rgb(0x6c, 0x71, 0xc4),
+                Rgb(0x93, 0xa1, 0xa1),
+                Rgb(0xfd, 0xf6, 0xe3),
+            ],
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "solarized" => Some(Self::solarized()),
+            _ => None,
+        }
+    }
+
+    /// Resolves a `--theme` value, which is either a built-in theme name or a
+    /// path to a JSON file with `foreground`, `background` and a 16 color
+    /// `palette`, all given as `#rrggbb` strings.
+    pub fn load(spec: &str) -> Result<Self> {
+        match Self::builtin(spec) {
+            Some(theme) => Ok(theme),
+            None => Self::from_file(spec),
+        }
+    }
+
+    fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read theme file {}", path.display()))?;
+
+        serde_json::from_str(&json)
+            .with_context(|| format!("invalid theme file {}", path.display()))
+    }
+
+    pub fn color(&self, color: Color) -> Rgb {
+        match color {
+            Color::RGB(c) => Rgb(c.r, c.g, c.b),
+            Color::Indexed(i) if i < 16 => self.palette[i as usize],
+
+            Color::Indexed(i) if i < 232 => {
+                let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+                let i = i - 16;
+
+                Rgb(level(i / 36), level((i / 6) % 6), level(i % 6))
+            }
+
+            Color::Indexed(i) => {
+                let gray = 8 + (i - 232) * 10;
+
+                Rgb(gray, gray, gray)
+            }
+        }
+    }
+
+    /// Renders the theme as an asciinema player theme class, for the live
+    /// preview page.
+    pub fn to_css(&self, name: &str) -> String {
+        let mut css = format!(
+            ".asciinema-player-theme-{name} {{\n  --term-color-foreground: {};\n  --term-color-background: {};\n",
+            self.foreground, self.background
+        );
+
+        for (i, color) in self.palette.iter().enumerate() {
+            let _ = writeln!(css, "  --term-color-{i}: {color};");
+        }
+
+        css.push_str("}\n");
+
+        css
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl TryFrom<String> for Rgb {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let hex = s.strip_prefix('#').unwrap_or(&s);
+
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid color: {s} (expected #rrggbb)");
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+
+        Ok(Rgb(channel(0)?, channel(2)?, channel(4)?))
+    }
+}
+
+impl std::fmt::Display for Rgb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rgb, Theme};
+    use avt::Color;
+
+    #[test]
+    fn builtin_themes() {
+        assert_eq!(Theme::load("dark").unwrap(), Theme::dark());
+        assert_eq!(Theme::load("solarized").unwrap(), Theme::solarized());
+        assert!(Theme::builtin("nope").is_none());
+    }
+
+    #[test]
+    fn parse_theme_json() {
+        let palette: Vec<String> = (0..16).map(|i| format!("#0000{i:02x}")).collect();
+
+        let json = serde_json::json!({
+            "foreground": "#ffffff",
+            "background": "#000000",
+            "palette": palette,
+        });
+
+        let theme: Theme = serde_json::from_value(json).unwrap();
+
+        assert_eq!(theme.foreground, Rgb(255, 255, 255));
+        assert_eq!(theme.palette[15], Rgb(0, 0, 15));
+    }
+
+    #[test]
+    fn reject_invalid_theme_json() {
+        let short = serde_json::json!({
+            "foreground": "#ffffff",
+            "background": "#000000",
+            "palette": ["#000000"],
+        });
+
+        let bad_color = serde_json::json!({
+            "foreground": "white",
+            "background": "#000000",
+            "palette": vec!["#000000"; 16],
+        });
+
+        assert!(serde_json::from_value::<Theme>(short).is_err());
+        assert!(serde_json::from_value::<Theme>(bad_color).is_err());
+        assert!(Theme::load("/nonexistent/theme.json").is_err());
+    }
+
+    #[test]
+    fn indexed_colors_to_rgb() {
+        let theme = Theme::dark();
+
+        assert_eq!(theme.color(Color::Indexed(1)), theme.palette[1]);
+        assert_eq!(theme.color(Color::Indexed(16)), Rgb(0, 0, 0));
+        assert_eq!(theme.color(Color::Indexed(196)), Rgb(255, 0, 0));
+        assert_eq!(theme.color(Color::Indexed(232)), Rgb(8, 8, 8));
+        assert_eq!(theme.color(Color::Indexed(255)), Rgb(238, 238, 238));
+    }
+}
@@ -84,7 +84,8 @@ EOF
 
     // Spawn HT
     let mut child = Command::new(ht_binary)
-        .arg("/bin/sh")
+        .args(["--subscribe", "output,inputChunked", "/bin/sh"])
+        .env("SHELL", "/bin/sh")
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -119,9 +120,19 @@ EOF
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
 
-    // Check for chunking message in stderr (for large inputs)
-    if size >= 1500 && stderr.contains("Large input detected") {
+    // Chunking of large inputs is reported with an inputChunked event
+    let chunked = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|event| event["type"] == "inputChunked");
+
+    if heredoc_cmd.len() >= 1500 {
+        let chunked = chunked.expect("no inputChunked event for large input");
+        assert_eq!(chunked["data"]["totalBytes"], heredoc_cmd.len());
+        assert!(chunked["data"]["chunks"].as_u64().unwrap() > 1);
         println!("  ✓ Chunking activated");
+    } else {
+        assert!(chunked.is_none(), "small input was chunked");
     }
 
     // Parse output events to verify data was received
@@ -19,7 +19,7 @@
 /// 1. Send increasingly large heredoc commands through the PTY
 /// 2. Verify that output is received in the correct order
 /// 3. Demonstrate failure when size exceeds PTY buffer capacity
-use ht_core::command::{Command, InputSeq};
+use ht_core::command::{Command, InputSeq, Newline};
 use ht_core::pty;
 use nix::pty::Winsize;
 use std::time::Duration;
@@ -241,7 +241,7 @@ async fn test_input_command_large_payload() {
 
     // Convert to bytes as the PTY would
     let bytes = match command {
-        Command::Input(seqs) => ht_core::command::seqs_to_bytes(&seqs, false),
+        Command::Input(seqs) => ht_core::command::seqs_to_bytes(&seqs, false, Newline::None),
         _ => panic!("Expected Input command"),
     };
 
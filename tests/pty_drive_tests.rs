@@ -0,0 +1,312 @@
+#![cfg(unix)]
+//! Tests for driving a PTY allocated outside of ht.
+
+use ht_core::pty;
+use nix::pty::openpty;
+use nix::unistd::Pid;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// the child is reaped by pty::drive
+#[allow(clippy::zombie_processes)]
+#[tokio::test]
+async fn drive_existing_pty() {
+    let pty = openpty(None, None).unwrap();
+
+    let child = Command::new("/bin/sh")
+        .args(["-c", "read line; echo got:$line"])
+        .stdin(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stdout(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stderr(Stdio::from(pty.slave))
+        .spawn()
+        .unwrap();
+
+    let (input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let pid = Pid::from_raw(child.id() as i32);
+    let driver = tokio::spawn(pty::drive(pty.master, pid, input_rx, output_tx));
+
+    input_tx.send(b"hello\n".to_vec()).await.unwrap();
+
+    let mut output = String::new();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.push_str(&String::from_utf8_lossy(&data));
+    }
+
+    assert!(
+        output.contains("got:hello"),
+        "unexpected output: {output:?}"
+    );
+
+    tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .expect("driver didn't finish after the child exited")
+        .unwrap()
+        .unwrap();
+}
+
+// the child is reaped by pty::drive
+#[allow(clippy::zombie_processes)]
+#[tokio::test]
+async fn input_pending_when_channel_closes_is_written() {
+    let pty = openpty(None, None).unwrap();
+    // keeps the terminal from being hung up once the driver closes its master
+    let master = pty.master.try_clone().unwrap();
+    let path = std::env::temp_dir().join(format!("ht-line-{}", uuid::Uuid::new_v4()));
+
+    // HUP, sent once the driver is done, is ignored so that the line gets saved
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!(
+            "trap '' HUP; printf ready; read -r line; echo \"$line\" > {}",
+            path.display()
+        ))
+        .stdin(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stdout(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stderr(Stdio::from(pty.slave))
+        .spawn()
+        .unwrap();
+
+    let (input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let pid = Pid::from_raw(child.id() as i32);
+    let driver = tokio::spawn(pty::drive(pty.master, pid, input_rx, output_tx));
+
+    let mut output = Vec::new();
+
+    while !output.ends_with(b"ready") {
+        let data = tokio::time::timeout(Duration::from_secs(5), output_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        output.extend(data);
+    }
+
+    // closing right after sending, likely before the driver wrote it
+    input_tx.send(b"hello\n".to_vec()).await.unwrap();
+    drop(input_tx);
+
+    let result = tokio::time::timeout(Duration::from_secs(5), driver).await;
+
+    if result.is_err() {
+        // the child still waits for the input, which got lost
+        child.kill().unwrap();
+    }
+
+    result.expect("driver didn't finish").unwrap().unwrap();
+    drop(master);
+    let line = std::fs::read_to_string(&path).unwrap_or_default();
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(line, "hello\n");
+}
+
+// On Linux, reading the master of a PTY whose slave side is closed fails
+// with EIO, which is a clean end if the child exited. The child is reaped by
+// pty::drive.
+#[cfg(target_os = "linux")]
+#[allow(clippy::zombie_processes)]
+#[tokio::test]
+async fn eio_after_child_exits_is_a_clean_exit() {
+    let pty = openpty(None, None).unwrap();
+
+    let child = Command::new("/bin/sh")
+        .args(["-c", "echo bye; exit 3"])
+        .stdin(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stdout(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stderr(Stdio::from(pty.slave))
+        .spawn()
+        .unwrap();
+
+    let (_input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let pid = Pid::from_raw(child.id() as i32);
+    let driver = tokio::spawn(pty::drive(pty.master, pid, input_rx, output_tx));
+
+    let mut output = String::new();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.push_str(&String::from_utf8_lossy(&data));
+    }
+
+    let outcome = tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .expect("driver didn't finish after the child exited")
+        .unwrap()
+        .expect("EIO after the child exited reported as an error");
+
+    assert!(output.contains("bye"), "unexpected output: {output:?}");
+    assert_eq!(outcome.status, pty::ExitStatus::Exited(3));
+}
+
+// The output queue is full when the child exits, which leaves its last output
+// in the terminal, to be read before the session ends however long the
+// output channel stays full.
+#[cfg(target_os = "linux")]
+#[allow(clippy::zombie_processes)]
+#[tokio::test]
+async fn output_left_in_the_terminal_at_exit_is_read() {
+    // more than is queued before reading stops, plus what the terminal holds
+    const SIZE: usize = 1024 * 1024 + 16 * 1024;
+
+    let pty = openpty(None, None).unwrap();
+
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("head -c {SIZE} /dev/zero | tr '\\0' x"))
+        .stdin(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stdout(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stderr(Stdio::from(pty.slave))
+        .spawn()
+        .unwrap();
+
+    let (_input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(1);
+    let pid = Pid::from_raw(child.id() as i32);
+    let driver = tokio::spawn(pty::drive(pty.master, pid, input_rx, output_tx));
+
+    // well past the drain timeout after the child exited
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut size = 0;
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        size += data.len();
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .expect("driver didn't finish after the child exited")
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(size, SIZE);
+}
+
+// the child is reaped by pty::drive, once hung up
+#[cfg(target_os = "linux")]
+#[allow(clippy::zombie_processes)]
+#[tokio::test]
+async fn eio_while_child_runs_is_an_error() {
+    use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+    use std::os::fd::AsRawFd;
+
+    let pty = openpty(None, None).unwrap();
+
+    // or else the child inherits them, keeping the terminal open
+    for fd in [&pty.master, &pty.slave] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).unwrap();
+    }
+
+    // closes its side of the terminal, and carries on
+    let child = Command::new("/bin/sh")
+        .args(["-c", "exec </dev/null >/dev/null 2>&1; sleep 10"])
+        .stdin(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stdout(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stderr(Stdio::from(pty.slave))
+        .spawn()
+        .unwrap();
+
+    let (_input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, _output_rx) = mpsc::channel(10);
+    let pid = Pid::from_raw(child.id() as i32);
+    let driver = tokio::spawn(pty::drive(pty.master, pid, input_rx, output_tx));
+
+    let error = tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .expect("driver didn't finish after the terminal was closed")
+        .unwrap()
+        .expect_err("EIO with the child running reported as a clean exit");
+
+    assert!(
+        error.to_string().contains("still running"),
+        "unexpected error: {error:#}"
+    );
+}
+
+/// CPU time used so far by a thread.
+#[cfg(target_os = "linux")]
+fn thread_cpu_time(thread: &std::thread::JoinHandle<()>) -> Duration {
+    use nix::libc;
+    use std::os::unix::thread::JoinHandleExt;
+
+    let mut clock = 0;
+    let mut time = unsafe { std::mem::zeroed::<libc::timespec>() };
+
+    unsafe {
+        assert_eq!(
+            libc::pthread_getcpuclockid(thread.as_pthread_t(), &mut clock),
+            0
+        );
+        assert_eq!(libc::clock_gettime(clock, &mut time), 0);
+    }
+
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+// Input the program doesn't read fills the terminal's buffer, after which the
+// driver has to wait for it to drain rather than retry writing in a loop. It
+// runs on a thread of its own, whose CPU time is then measured.
+#[cfg(target_os = "linux")]
+#[allow(clippy::zombie_processes)]
+#[tokio::test]
+async fn stalled_reader_does_not_make_driver_spin() {
+    let pty = openpty(None, None).unwrap();
+
+    let mut child = Command::new("/bin/sh")
+        .args(["-c", "stty raw -echo; printf ready; exec sleep 30"])
+        .stdin(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stdout(Stdio::from(pty.slave.try_clone().unwrap()))
+        .stderr(Stdio::from(pty.slave))
+        .spawn()
+        .unwrap();
+
+    let (input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let pid = Pid::from_raw(child.id() as i32);
+
+    let driver = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let _ = runtime.block_on(pty::drive(pty.master, pid, input_rx, output_tx));
+    });
+
+    let mut output = Vec::new();
+
+    while !output.ends_with(b"ready") {
+        let data = tokio::time::timeout(Duration::from_secs(5), output_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        output.extend(data);
+    }
+
+    // far more than the terminal buffers
+    input_tx.send(vec![b'x'; 1024 * 1024]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let cpu_time = thread_cpu_time(&driver);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let cpu_time = thread_cpu_time(&driver) - cpu_time;
+
+    // the driver ends once the terminal is closed
+    child.kill().unwrap();
+
+    assert!(
+        cpu_time < Duration::from_millis(100),
+        "driver used {cpu_time:?} of CPU in 1s while the input couldn't be written"
+    );
+
+    drop(input_tx);
+    driver.join().unwrap();
+}
@@ -0,0 +1,447 @@
+#![cfg(unix)]
+//! Tests for the options of spawning a command in a PTY.
+
+use ht_core::pty::{self, SpawnOptions};
+use nix::fcntl::OFlag;
+use nix::pty::Winsize;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Runs the command to completion, returning its output.
+async fn run(command: String, options: SpawnOptions) -> String {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let (_input_tx, input_rx) = mpsc::channel(1);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+    let (_pid, driver) = pty::spawn_with_options(
+        command, winsize, input_rx, output_tx, resize_rx, None, options,
+    )
+    .unwrap();
+
+    let driver = tokio::spawn(driver);
+    let mut output = Vec::new();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.extend(data);
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// A pipe whose write end is closed in ht once the child is started, so that
+/// reading it ends when the child is done with it too.
+fn pipe() -> (File, OwnedFd) {
+    // close-on-exec, like Rust opens files
+    let (reader, writer) = nix::unistd::pipe2(OFlag::O_CLOEXEC).unwrap();
+
+    (File::from(reader), writer)
+}
+
+fn read_all(mut reader: File) -> String {
+    let mut data = String::new();
+    reader.read_to_string(&mut data).unwrap();
+
+    data
+}
+
+#[tokio::test]
+async fn passed_fd_is_inherited() {
+    let (reader, writer) = pipe();
+    let fd = writer.as_raw_fd();
+
+    let options = SpawnOptions {
+        pass_fds: vec![fd],
+        ..Default::default()
+    };
+
+    let output = run(format!("echo hello > /dev/fd/{fd} && echo done"), options).await;
+    drop(writer);
+
+    assert!(output.contains("done"), "unexpected output: {output:?}");
+    assert_eq!(read_all(reader), "hello\n");
+}
+
+#[tokio::test]
+async fn other_fds_are_closed() {
+    let (reader, writer) = pipe();
+    let fd = writer.as_raw_fd();
+    // inheritable, like a descriptor ht itself got from its parent
+    nix::fcntl::fcntl(
+        fd,
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()),
+    )
+    .unwrap();
+
+    let output = run(
+        format!("echo hello > /dev/fd/{fd} || echo failed"),
+        SpawnOptions::default(),
+    )
+    .await;
+    drop(writer);
+
+    assert!(output.contains("failed"), "unexpected output: {output:?}");
+    assert_eq!(read_all(reader), "");
+}
+
+#[tokio::test]
+async fn missing_cwd_is_reported() {
+    let options = SpawnOptions {
+        cwd: Some("/nonexistent/ht-cwd".into()),
+        ..Default::default()
+    };
+
+    let output = run("echo started".to_owned(), options).await;
+
+    assert!(
+        output.contains("cannot change to directory /nonexistent/ht-cwd"),
+        "unexpected output: {output:?}"
+    );
+    assert!(!output.contains("started"), "unexpected output: {output:?}");
+}
+
+#[tokio::test]
+async fn small_buffers_pass_large_bursts() {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let options = SpawnOptions {
+        read_buffer_size: Some(4096),
+        write_chunk_size: Some(4096),
+        pty_mode: pty::PtyMode::Raw,
+        ..Default::default()
+    };
+
+    let (input_tx, input_rx) = mpsc::channel(1);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+    // the input goes through unaltered, and so does the output apart from
+    // line feeds becoming CR LF
+    let (_pid, driver) = pty::spawn_with_options(
+        "echo ready; head -c 100000 | wc -c | tr -d ' '; seq 20000".to_owned(),
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        None,
+        options,
+    )
+    .unwrap();
+
+    let driver = tokio::spawn(driver);
+    let mut output = Vec::new();
+
+    // the terminal is set up by then, so the input isn't echoed
+    while !output.ends_with(b"ready\r\n") {
+        output.extend(output_rx.recv().await.unwrap());
+    }
+
+    input_tx.send(vec![b'x'; 100_000]).await.unwrap();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        assert!(data.len() <= 4096);
+        output.extend(data);
+    }
+
+    let outcome = tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    let expected: String = ["ready".to_owned(), "100000".to_owned()]
+        .into_iter()
+        .chain((1..=20000).map(|n| n.to_string()))
+        .map(|line| line + "\r\n")
+        .collect();
+
+    assert_eq!(String::from_utf8_lossy(&output), expected);
+    assert_eq!(outcome.bytes_in, 100_000);
+}
+
+// More input than the terminal takes at once (4 KiB) is written as the
+// program reads it, in order, including input received in the meantime.
+#[tokio::test]
+async fn input_larger_than_terminal_buffer_is_written_in_full() {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let (input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+    let (_pid, driver) = pty::spawn_with_options(
+        "stty -echo; echo ready; cat".to_owned(),
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        None,
+        SpawnOptions::default(),
+    )
+    .unwrap();
+
+    let driver = tokio::spawn(driver);
+    let mut output = Vec::new();
+
+    while !output.ends_with(b"ready\r\n") {
+        output.extend(output_rx.recv().await.unwrap());
+    }
+
+    // 8000 bytes, in lines short enough for the terminal's line editing
+    let line = |n: usize| format!("{n:04}{}\n", "x".repeat(75));
+    let input: String = (0..100).map(line).collect();
+    input_tx.send(input[..5000].into()).await.unwrap();
+    input_tx.send(input[5000..].into()).await.unwrap();
+    input_tx.send(b"\x04".to_vec()).await.unwrap();
+    output.clear();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.extend(data);
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(input.len(), 8000);
+    assert_eq!(
+        String::from_utf8_lossy(&output),
+        input.replace('\n', "\r\n")
+    );
+}
+
+// The terminal echoes the input once it's written, before the session ends.
+#[tokio::test]
+async fn input_sent_before_closing_is_written() {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let (input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+    let (_pid, driver) = pty::spawn_with_options(
+        "cat".to_owned(),
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        None,
+        SpawnOptions::default(),
+    )
+    .unwrap();
+
+    let driver = tokio::spawn(driver);
+    input_tx.send(b"echo hi\n".to_vec()).await.unwrap();
+    drop(input_tx);
+    let mut output = Vec::new();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.extend(data);
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.contains("echo hi"), "unexpected output: {output:?}");
+}
+
+// Output the receiver doesn't take yet mustn't hold up the input the program
+// waits for after writing it.
+#[tokio::test]
+async fn input_gets_through_while_output_is_not_received() {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let path = std::env::temp_dir().join(format!("ht-answer-{}", std::process::id()));
+    let (input_tx, input_rx) = mpsc::channel(10);
+    let (output_tx, mut output_rx) = mpsc::channel(1);
+    let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+    // far more output than the terminal holds
+    let (_pid, driver) = pty::spawn_with_options(
+        format!(
+            "head -c 300000 /dev/zero | tr '\\0' y; read answer; echo $answer > {}",
+            path.display()
+        ),
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        None,
+        SpawnOptions::default(),
+    )
+    .unwrap();
+
+    let driver = tokio::spawn(driver);
+    input_tx.send(b"42\n".to_vec()).await.unwrap();
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+
+    while !path.exists() && std::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert!(path.exists(), "no answer before the output was received");
+
+    let mut output = Vec::new();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.extend(data);
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    let answer = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(answer.unwrap(), "42\n");
+    assert_eq!(output.iter().filter(|&&b| b == b'y').count(), 300_000);
+}
+
+// A resize sets the size of the terminal and signals the program.
+#[tokio::test]
+async fn resize_reaches_the_program() {
+    let winsize = Winsize {
+        ws_row: 40,
+        ws_col: 120,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let (_input_tx, input_rx) = mpsc::channel(1);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let (resize_tx, resize_rx) = mpsc::channel(1);
+
+    let (_pid, driver) = pty::spawn_with_options(
+        "trap 'echo winch $(stty size); exit' WINCH; echo ready; while :; do sleep 0.05; done"
+            .to_owned(),
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        None,
+        SpawnOptions::default(),
+    )
+    .unwrap();
+
+    let driver = tokio::spawn(driver);
+    let mut output = Vec::new();
+
+    while !output.ends_with(b"ready\r\n") {
+        output.extend(output_rx.recv().await.unwrap());
+    }
+
+    resize_tx.send((100, 30, true)).await.unwrap();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.extend(data);
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    let output = String::from_utf8_lossy(&output);
+    assert!(
+        output.contains("winch 30 100"),
+        "unexpected output: {output:?}"
+    );
+}
+
+#[tokio::test]
+async fn outcome_has_exit_code_and_traffic() {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let (input_tx, input_rx) = mpsc::channel(1);
+    let (output_tx, mut output_rx) = mpsc::channel(10);
+    let (_resize_tx, resize_rx) = mpsc::channel(1);
+
+    let (_pid, driver) = pty::spawn_with_options(
+        "stty -echo; read -r line; printf 'got %s' \"$line\"; exit 42".to_owned(),
+        winsize,
+        input_rx,
+        output_tx,
+        resize_rx,
+        None,
+        SpawnOptions::default(),
+    )
+    .unwrap();
+
+    let driver = tokio::spawn(driver);
+    input_tx.send(b"hello\n".to_vec()).await.unwrap();
+    let mut output = Vec::new();
+
+    while let Ok(Some(data)) = tokio::time::timeout(Duration::from_secs(5), output_rx.recv()).await
+    {
+        output.extend(data);
+    }
+
+    let outcome = tokio::time::timeout(Duration::from_secs(5), driver)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(outcome.status, pty::ExitStatus::Exited(42));
+    assert_eq!(outcome.status.code(), Some(42));
+    assert_eq!(outcome.bytes_in, 6);
+    assert_eq!(outcome.bytes_out, output.len() as u64);
+    assert!(String::from_utf8_lossy(&output).contains("got hello"));
+    assert!(outcome.duration < Duration::from_secs(5));
+}
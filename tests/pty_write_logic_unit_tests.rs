@@ -115,14 +115,16 @@ mod pty_write_logic_tests {
         assert_eq!(total_written, 5000, "Should eventually write all data");
     }
 
-    /// This test demonstrates the issue: if we don't wait for buffer to drain,
-    /// data is lost
+    /// A single pass of the write loop only gets as far as the buffer takes.
+    /// ht keeps the rest queued and writes it once the terminal is writable
+    /// again (see `input_larger_than_terminal_buffer_is_written_in_full` in
+    /// pty_spawn_tests.rs), so this is what would be lost without that.
     #[test]
     fn test_mock_pty_data_loss_without_retry() {
         let mut pty = MockPtyMaster::new(4096);
         let data = "w".repeat(8000);
 
-        // Try to write all at once (like current HT behavior)
+        // Try to write all at once
         let mut buf = data.as_bytes();
         let mut total_written = 0;
 
@@ -138,8 +140,8 @@ mod pty_write_logic_tests {
                     }
                 }
                 None => {
-                    // In the actual code, this clears the ready flag and breaks
-                    // Data remaining in buf is lost!
+                    // ht clears the ready flag here and breaks, keeping what's
+                    // left in buf for the next time the terminal is writable
                     println!(
                         "Write would block. {} bytes written, {} bytes lost",
                         total_written,
@@ -154,10 +156,9 @@ mod pty_write_logic_tests {
             total_written, 4096,
             "Only buffer capacity should be written"
         );
-        assert_eq!(buf.len(), 8000 - 4096, "Remaining data is lost");
+        assert_eq!(buf.len(), 8000 - 4096, "Remaining data is left over");
 
-        println!("\n⚠️  DATA LOSS: {} bytes were not written!", buf.len());
-        println!("This reproduces the heredoc buffer overflow issue.");
+        println!("{} bytes left for the next pass", buf.len());
     }
 
     /// Test the chunking strategy
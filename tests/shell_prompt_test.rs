@@ -27,7 +27,7 @@ mod unix {
 
         let (input_tx, input_rx) = mpsc::channel::<Vec<u8>>(100);
         let (output_tx, mut output_rx) = mpsc::channel::<Vec<u8>>(100);
-        let (_resize_tx, resize_rx) = mpsc::channel::<(u16, u16)>(1);
+        let (_resize_tx, resize_rx) = mpsc::channel::<(u16, u16, bool)>(1);
 
         let mut vt = avt::Vt::builder().size(80, 24).resizable(true).build();
 
@@ -76,7 +76,9 @@ mod unix {
         let _ = input_tx.send(b"exit\n".to_vec()).await;
         drop(input_tx);
         match timeout(Duration::from_secs(2), pty_handle).await {
-            Ok(Ok(result)) => result.expect("PTY task returned an error"),
+            Ok(Ok(result)) => {
+                result.expect("PTY task returned an error");
+            }
             Ok(Err(join_err)) => panic!("PTY task panicked: {join_err}"),
             Err(_) => panic!("timed out waiting for PTY task to finish"),
         }
@@ -84,14 +86,15 @@ mod unix {
 }
 
 #[cfg(windows)]
-mod windows_scrape {
+mod windows_conpty {
     use std::process::Stdio;
     use std::time::Duration;
-    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+    use tokio::process::{ChildStdin, ChildStdout};
     use tokio::time::timeout;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    async fn shell_prompt_appears_via_scrape() {
+    async fn shell_prompt_appears_via_conpty() {
         // Build the binary path (cargo test puts it in target/debug/)
         let bin = env!("CARGO_BIN_EXE_ht");
 
@@ -145,4 +148,134 @@ mod windows_scrape {
             panic!("Prompt '{prompt_marker}' not found within {deadline:?}");
         }
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn console_has_the_requested_size() {
+        let bin = env!("CARGO_BIN_EXE_ht");
+
+        let mut child = tokio::process::Command::new(bin)
+            .args([
+                "--subscribe",
+                "output",
+                "--size",
+                "80x25",
+                "powershell",
+                "-NoProfile",
+                "-Command",
+                "$s = $Host.UI.RawUI.WindowSize; 'size ' + $s.Width + 'x' + $s.Height",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to spawn ht");
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut output = String::new();
+
+        let _ = timeout(Duration::from_secs(15), async {
+            while let Ok(Some(line)) = stdout.next_line().await {
+                output.push_str(&line);
+
+                if output.contains("size ") {
+                    break;
+                }
+            }
+        })
+        .await;
+
+        let _ = child.kill().await;
+        assert!(output.contains("size 80x25"), "unexpected output: {output}");
+    }
+
+    /// Value `mode con` reports for `name` (e.g. `Columns`) on the screen,
+    /// the last one if it was run more than once.
+    fn mode_con_value(text: &str, name: &str) -> Option<u16> {
+        text.lines()
+            .filter_map(|line| line.trim().strip_prefix(name)?.strip_prefix(':'))
+            .next_back()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Waits for `mode con` to have printed its report `times` times, and
+    /// returns the screen text then.
+    async fn mode_con_screen(
+        stdout: &mut Lines<BufReader<ChildStdout>>,
+        stdin: &mut ChildStdin,
+        output: &mut String,
+        times: usize,
+    ) -> String {
+        let _ = timeout(Duration::from_secs(15), async {
+            while output.matches("Columns").count() < times {
+                match stdout.next_line().await {
+                    Ok(Some(line)) => output.push_str(&line),
+                    _ => break,
+                }
+            }
+        })
+        .await;
+
+        // the report may still be being drawn
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let _ = stdin.write_all(b"{\"type\":\"takeSnapshot\"}\n").await;
+
+        timeout(Duration::from_secs(5), async {
+            while let Ok(Some(line)) = stdout.next_line().await {
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+                if event["type"] == "snapshot" {
+                    return event["data"]["text"].as_str().unwrap().to_owned();
+                }
+            }
+
+            panic!("ht exited before the snapshot");
+        })
+        .await
+        .expect("no snapshot")
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn mode_con_reports_terminal_size() {
+        let bin = env!("CARGO_BIN_EXE_ht");
+
+        let mut child = tokio::process::Command::new(bin)
+            .args([
+                "--subscribe",
+                "output,snapshot",
+                "--size",
+                "100x30",
+                "cmd.exe",
+                "/k",
+                "mode con",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to spawn ht");
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut stdin = child.stdin.take().unwrap();
+
+        let mut output = String::new();
+
+        let text = mode_con_screen(&mut stdout, &mut stdin, &mut output, 1).await;
+        assert_eq!(mode_con_value(&text, "Columns"), Some(100), "{text}");
+        assert_eq!(mode_con_value(&text, "Lines"), Some(30), "{text}");
+
+        let _ = stdin
+            .write_all(b"{\"type\":\"resize\",\"cols\":120,\"rows\":40}\n")
+            .await;
+        let _ = stdin
+            .write_all(b"{\"type\":\"input\",\"payload\":\"mode con\\r\"}\n")
+            .await;
+
+        let text = mode_con_screen(&mut stdout, &mut stdin, &mut output, 2).await;
+        assert_eq!(mode_con_value(&text, "Columns"), Some(120), "{text}");
+        assert_eq!(mode_con_value(&text, "Lines"), Some(40), "{text}");
+
+        let _ = child.kill().await;
+    }
 }
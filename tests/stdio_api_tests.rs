@@ -0,0 +1,2064 @@
+#![cfg(unix)]
+//! End-to-end tests of the STDIO API, driving the ht binary with JSON
+//! commands and reading events from its STDOUT.
+
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Ht {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    events: mpsc::Receiver<Value>,
+}
+
+impl Ht {
+    fn spawn(args: &[&str]) -> Self {
+        Self::spawn_with_env(args, &[])
+    }
+
+    /// Runs ht with `env` set, and `SHELL` set to `/bin/sh` unless `env`
+    /// sets it, so that commands run the same whatever the user's shell is.
+    fn spawn_with_env(args: &[&str], env: &[(&str, &str)]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ht"))
+            .args(args)
+            .env("SHELL", "/bin/sh")
+            .envs(env.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to spawn ht");
+
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, events) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if let Ok(event) = serde_json::from_str(&line) {
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            child,
+            stdin,
+            events,
+        }
+    }
+
+    fn send(&mut self, command: Value) {
+        let stdin = self.stdin.as_mut().unwrap();
+        writeln!(stdin, "{command}").unwrap();
+        stdin.flush().unwrap();
+    }
+
+    fn close_stdin(&mut self) {
+        self.stdin.take();
+    }
+
+    fn wait(&mut self, timeout: Duration) -> bool {
+        self.wait_status(timeout).is_some()
+    }
+
+    fn wait_status(&mut self, timeout: Duration) -> Option<std::process::ExitStatus> {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Some(status);
+            }
+
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        None
+    }
+
+    /// Returns the next event of the given type, skipping others.
+    fn next_event(&self, event_type: &str, timeout: Duration) -> Option<Value> {
+        let deadline = Instant::now() + timeout;
+
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.events.recv_timeout(left) {
+                Ok(event) if event["type"] == event_type => return Some(event),
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for Ht {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn started_event_comes_first() {
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "started,init,output",
+        "--listen",
+        "127.0.0.1:0",
+        "--size",
+        "80x24",
+        "echo hello; sleep 10",
+    ]);
+
+    let started = ht
+        .events
+        .recv_timeout(Duration::from_secs(5))
+        .expect("no event");
+    assert_eq!(started["type"], "started");
+    assert_eq!(started["data"]["cols"], 80);
+    assert_eq!(started["data"]["rows"], 24);
+    assert!(started["data"]["pid"].as_u64().unwrap() > 0);
+
+    let addr = started["data"]["listenAddr"].as_str().unwrap();
+    assert!(!addr.ends_with(":0"));
+    std::net::TcpStream::connect(addr).expect("HTTP server not listening");
+
+    assert_eq!(
+        ht.events.recv_timeout(Duration::from_secs(5)).unwrap()["type"],
+        "init"
+    );
+}
+
+#[test]
+fn listens_on_multiple_addresses() {
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "started",
+        "--listen",
+        "127.0.0.1:0",
+        "--listen",
+        "127.0.0.1:0",
+        "sleep 10",
+    ]);
+
+    let started = ht.next_event("started", Duration::from_secs(5)).unwrap();
+    let addrs = started["data"]["listenAddrs"].as_array().unwrap();
+
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0], addrs[1]);
+    assert_eq!(started["data"]["listenAddr"], addrs[0]);
+
+    for addr in addrs {
+        std::net::TcpStream::connect(addr.as_str().unwrap()).expect("HTTP server not listening");
+    }
+}
+
+#[test]
+fn failed_listen_address_stops_startup() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken = taken.local_addr().unwrap().to_string();
+
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "started",
+        "--listen",
+        "127.0.0.1:0",
+        "--listen",
+        &taken,
+        "sleep 10",
+    ]);
+
+    let status = ht
+        .wait_status(Duration::from_secs(5))
+        .expect("ht didn't exit");
+    assert!(!status.success());
+    assert!(
+        ht.next_event("started", Duration::from_millis(100))
+            .is_none()
+    );
+}
+
+#[test]
+fn initial_screen_is_shown_before_output() {
+    let path = std::env::temp_dir().join(format!("ht-initial-{}.ans", std::process::id()));
+    std::fs::write(&path, "\x1b[1mWelcome\x1b[0m\r\n\x1b[3;1H").unwrap();
+
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "init",
+        "--initial-screen",
+        path.to_str().unwrap(),
+        "sleep 10",
+    ]);
+
+    let init = ht
+        .next_event("init", Duration::from_secs(5))
+        .expect("no init event");
+    let _ = std::fs::remove_file(&path);
+    let text = init["data"]["text"].as_str().unwrap();
+
+    assert_eq!(text.lines().next().map(str::trim_end), Some("Welcome"));
+
+    let mut ht = Ht::spawn(&["--initial-screen", "/nonexistent/screen.ans", "sleep 10"]);
+    let status = ht
+        .wait_status(Duration::from_secs(5))
+        .expect("ht didn't exit");
+    assert!(!status.success());
+}
+
+#[test]
+fn pid_event_comes_before_output() {
+    let ht = Ht::spawn(&["--subscribe", "started,pid,output", "echo hello; sleep 10"]);
+
+    let started = ht.next_event("started", Duration::from_secs(5)).unwrap();
+    let event = ht.events.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(event["type"], "pid", "{event}");
+
+    let pid = event["data"]["pid"].as_u64().unwrap();
+    assert!(pid > 0);
+    assert_eq!(started["data"]["pid"].as_u64(), Some(pid));
+    assert!(ht.next_event("output", Duration::from_secs(5)).is_some());
+}
+
+#[test]
+fn no_emulator_passes_output_through() {
+    let mut ht = Ht::spawn(&[
+        "--no-emulator",
+        "--subscribe",
+        "output,snapshot,error",
+        "echo passthrough; sleep 10",
+    ]);
+
+    let output = output_until(&ht, "passthrough");
+    assert!(output.contains("passthrough"), "{output:?}");
+
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+    let error = ht.next_event("error", Duration::from_secs(5)).unwrap();
+
+    assert_eq!(
+        error["data"]["message"],
+        "command not available, terminal emulator disabled (--no-emulator)"
+    );
+
+    let mut ht = Ht::spawn(&["--no-emulator", "--output-mode", "cooked", "sleep 10"]);
+    let status = ht
+        .wait_status(Duration::from_secs(5))
+        .expect("ht didn't exit");
+    assert!(!status.success());
+}
+
+#[test]
+fn options_from_env() {
+    let env = [("HT_SIZE", "50x10"), ("HT_SUBSCRIBE", "init")];
+
+    let ht = Ht::spawn_with_env(&["cat"], &env);
+    let init = ht
+        .next_event("init", Duration::from_secs(5))
+        .expect("no init event");
+    assert_eq!(init["data"]["cols"], 50);
+    assert_eq!(init["data"]["rows"], 10);
+
+    // command line options take precedence
+    let ht = Ht::spawn_with_env(&["--size", "60x12", "cat"], &env);
+    let init = ht
+        .next_event("init", Duration::from_secs(5))
+        .expect("no init event");
+    assert_eq!(init["data"]["cols"], 60);
+    assert_eq!(init["data"]["rows"], 12);
+}
+
+#[test]
+fn rapid_resizes_are_coalesced() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "init,resize,snapshot",
+        "--size",
+        "80x24",
+        "cat",
+    ]);
+    ht.next_event("init", Duration::from_secs(5))
+        .expect("no init event");
+
+    for (cols, rows) in [(81, 25), (82, 26), (90, 30)] {
+        ht.send(serde_json::json!({ "type": "resize", "cols": cols, "rows": rows }));
+    }
+
+    let resize = ht
+        .next_event("resize", Duration::from_secs(5))
+        .expect("no resize event");
+    assert_eq!(resize["data"]["cols"], 90);
+    assert_eq!(resize["data"]["rows"], 30);
+    assert!(
+        ht.next_event("resize", Duration::from_millis(300))
+            .is_none()
+    );
+}
+
+#[test]
+fn snapshot_sees_pending_resize() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "snapshot",
+        "--resize-debounce",
+        "10000",
+        "cat",
+    ]);
+
+    ht.send(serde_json::json!({ "type": "resize", "cols": 70, "rows": 20 }));
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+
+    let snapshot = ht
+        .next_event("snapshot", Duration::from_secs(5))
+        .expect("no snapshot");
+    assert_eq!(snapshot["data"]["cols"], 70);
+    assert_eq!(snapshot["data"]["rows"], 20);
+}
+
+#[test]
+fn output_is_held_until_flush_interval() {
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "output",
+        "--flush-interval-ms",
+        "1000",
+        "printf a; sleep 0.2; printf b; sleep 10",
+    ]);
+
+    let output = ht
+        .next_event("output", Duration::from_secs(5))
+        .expect("no output event");
+    assert_eq!(output["data"]["seq"], "ab");
+}
+
+#[test]
+fn output_is_flushed_at_flush_bytes() {
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "output",
+        "--flush-interval-ms",
+        "60000",
+        "--flush-bytes",
+        "3",
+        "printf ab; sleep 0.2; printf cd; sleep 10",
+    ]);
+
+    let output = ht
+        .next_event("output", Duration::from_secs(5))
+        .expect("no output event");
+    assert_eq!(output["data"]["seq"], "abcd");
+}
+
+#[test]
+fn snapshot_sees_held_output() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "snapshot",
+        "--flush-interval-ms",
+        "60000",
+        "printf held; sleep 10",
+    ]);
+    thread::sleep(Duration::from_millis(500));
+
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+
+    let snapshot = ht
+        .next_event("snapshot", Duration::from_secs(5))
+        .expect("no snapshot");
+    assert!(
+        snapshot["data"]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("held")
+    );
+}
+
+#[test]
+fn resize_reaches_the_child() {
+    let mut ht = Ht::spawn(&["--subscribe", "snapshot", "--resize-debounce", "0", "sh"]);
+
+    ht.send(serde_json::json!({ "type": "resize", "cols": 100, "rows": 30 }));
+    thread::sleep(Duration::from_millis(200));
+    ht.send(serde_json::json!({ "type": "input", "payload": "stty size\r" }));
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+        ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+        let snapshot = ht.next_event("snapshot", Duration::from_secs(1)).unwrap();
+
+        if snapshot["data"]["text"]
+            .as_str()
+            .unwrap()
+            .contains("30 100")
+        {
+            return;
+        }
+    }
+
+    panic!("child didn't see the new terminal size");
+}
+
+#[test]
+fn state_reports_scroll_region() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "state,snapshot",
+        "--size",
+        "80x24",
+        "printf '\\033[2;23r\\033[2;1Hinside\\033[24;1Hstatus'; sleep 10",
+    ]);
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    loop {
+        ht.send(serde_json::json!({ "type": "getState" }));
+        let state = ht.next_event("state", Duration::from_secs(1)).unwrap();
+
+        if state["data"]["scrollRegion"] == serde_json::json!({ "top": 1, "bottom": 22 }) {
+            assert_eq!(state["data"]["cursor"]["row"], 23);
+            break;
+        }
+
+        assert!(Instant::now() < deadline, "scroll region not reported");
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    ht.send(serde_json::json!({ "type": "takeSnapshot", "scrollRegion": true }));
+    let snapshot = ht.next_event("snapshot", Duration::from_secs(5)).unwrap();
+    let text = snapshot["data"]["text"].as_str().unwrap();
+
+    assert_eq!(snapshot["data"]["rows"], 24);
+    assert_eq!(text.lines().count(), 22);
+    assert!(text.starts_with("inside"));
+    assert!(!text.contains("status"));
+
+    ht.send(serde_json::json!({ "type": "resize", "cols": 80, "rows": 30 }));
+    ht.send(serde_json::json!({ "type": "getState" }));
+    let state = ht.next_event("state", Duration::from_secs(5)).unwrap();
+
+    assert_eq!(
+        state["data"]["scrollRegion"],
+        serde_json::json!({ "top": 0, "bottom": 29 })
+    );
+}
+
+#[test]
+fn cooked_output_reports_overwrites_in_order() {
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "output",
+        "--output-mode",
+        "cooked",
+        "printf 'loading'; sleep 0.2; printf '\\rdone   \\b\\b\\b!\\n'; sleep 10",
+    ]);
+    let mut texts = Vec::new();
+
+    while let Some(event) = ht.next_event("output", Duration::from_secs(2)) {
+        assert!(event["data"].get("seq").is_none());
+
+        for line in event["data"]["lines"].as_array().unwrap() {
+            if line["row"] == 0 {
+                texts.push(line["text"].as_str().unwrap().to_owned());
+            }
+        }
+
+        if texts.last().is_some_and(|t| t == "done!") {
+            break;
+        }
+    }
+
+    assert_eq!(texts.first().map(String::as_str), Some("loading"));
+    assert_eq!(texts.last().map(String::as_str), Some("done!"));
+}
+
+#[test]
+fn denied_command_is_rejected() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "error,snapshot",
+        "--deny-commands",
+        "input,sendKeys",
+        "cat",
+    ]);
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "hello\r" }));
+    let error = ht
+        .next_event("error", Duration::from_secs(5))
+        .expect("no error event");
+    assert_eq!(error["data"]["message"], "command not allowed: input");
+
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+    let snapshot = ht
+        .next_event("snapshot", Duration::from_secs(5))
+        .expect("no snapshot");
+    assert!(!snapshot["data"]["text"].as_str().unwrap().contains("hello"));
+}
+
+#[test]
+fn oversized_command_is_rejected() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "error,output,snapshot",
+        "--max-command-bytes",
+        "1000",
+        "cat",
+    ]);
+
+    let payload = "x".repeat(100_000);
+    ht.send(serde_json::json!({ "type": "input", "payload": payload }));
+    let error = ht
+        .next_event("error", Duration::from_secs(5))
+        .expect("no error event");
+    let message = error["data"]["message"].as_str().unwrap();
+    assert!(message.starts_with("command too large"), "{message}");
+
+    // the next line is read as usual
+    ht.send(serde_json::json!({ "type": "input", "payload": "hello\r" }));
+    let mut output = String::new();
+
+    while !output.contains("hello") {
+        let event = ht.next_event("output", Duration::from_secs(5)).unwrap();
+        output.push_str(event["data"]["seq"].as_str().unwrap());
+    }
+
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+    let snapshot = ht
+        .next_event("snapshot", Duration::from_secs(5))
+        .expect("no snapshot");
+    let text = snapshot["data"]["text"].as_str().unwrap();
+    assert!(text.contains("hello") && !text.contains('x'), "{text}");
+}
+
+#[test]
+fn invalid_utf8_command_is_rejected() {
+    let mut ht = Ht::spawn(&["--subscribe", "error,output", "cat"]);
+
+    // a truncated multibyte sequence, as a corrupted payload would have
+    let stdin = ht.stdin.as_mut().unwrap();
+    stdin
+        .write_all(b"{ \"type\": \"input\", \"payload\": \"bad\xc5\\r\" }\n")
+        .unwrap();
+    stdin.flush().unwrap();
+
+    let error = ht
+        .next_event("error", Duration::from_secs(5))
+        .expect("no error event");
+    assert_eq!(error["data"]["message"], "invalid UTF-8 at byte offset 34");
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "good\r" }));
+    let output = output_until(&ht, "good");
+    assert!(!output.contains("bad"), "{output}");
+}
+
+#[test]
+fn sleep_delays_following_commands_only() {
+    let mut ht = Ht::spawn(&["--subscribe", "output,snapshot", "cat"]);
+    let start = Instant::now();
+
+    ht.send(serde_json::json!({ "type": "sleep", "ms": 500 }));
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+    ht.send(serde_json::json!({ "type": "input", "payload": "hello" }));
+
+    ht.next_event("snapshot", Duration::from_secs(5))
+        .expect("no snapshot");
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    ht.next_event("output", Duration::from_secs(5))
+        .expect("no output");
+}
+
+#[test]
+fn input_and_wait_reports_match_and_timeout() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "inputAndWait",
+        "read x; sleep 0.2; echo \"done:$x\"; sleep 10",
+    ]);
+
+    ht.send(serde_json::json!({
+        "type": "inputAndWait",
+        "text": "hi\n",
+        "expect": "done:hi",
+        "timeoutMs": 5000,
+        "id": 1,
+    }));
+
+    let event = ht
+        .next_event("inputAndWait", Duration::from_secs(10))
+        .expect("no inputAndWait event");
+    assert_eq!(event["data"]["result"], "matched");
+    assert_eq!(event["data"]["id"], 1);
+
+    ht.send(serde_json::json!({
+        "type": "inputAndWait",
+        "text": "\n",
+        "expect": "never printed",
+        "timeoutMs": 300,
+        "id": "second",
+    }));
+
+    let event = ht
+        .next_event("inputAndWait", Duration::from_secs(10))
+        .expect("no inputAndWait event");
+    assert_eq!(event["data"]["result"], "timeout");
+    assert_eq!(event["data"]["id"], "second");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn cwd_and_env_of_child() {
+    let mut ht = Ht::spawn_with_env(
+        &["--subscribe", "inputAndWait,cwd,env", "sh"],
+        &[("HT_TEST_VAR", "hello")],
+    );
+
+    ht.send(serde_json::json!({
+        "type": "inputAndWait",
+        "text": "cd /tmp && echo cd-$((1 + 1))\n",
+        "expect": "cd-2",
+    }));
+    ht.next_event("inputAndWait", Duration::from_secs(10))
+        .expect("no inputAndWait event");
+
+    ht.send(serde_json::json!({ "type": "getCwd" }));
+    let event = ht
+        .next_event("cwd", Duration::from_secs(5))
+        .expect("no cwd event");
+    assert_eq!(event["data"]["cwd"], "/tmp");
+
+    ht.send(serde_json::json!({ "type": "getEnv", "name": "HT_TEST_VAR" }));
+    let event = ht
+        .next_event("env", Duration::from_secs(5))
+        .expect("no env event");
+    assert_eq!(event["data"]["name"], "HT_TEST_VAR");
+    assert_eq!(event["data"]["value"], "hello");
+
+    ht.send(serde_json::json!({ "type": "getEnv", "name": "HT_UNSET_VAR" }));
+    let event = ht
+        .next_event("env", Duration::from_secs(5))
+        .expect("no env event");
+    assert_eq!(event["data"]["value"], serde_json::Value::Null);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn list_and_kill_background_jobs() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,processes,killProcess",
+        "sleep 301 & echo started; sleep 10",
+    ]);
+
+    ht.next_event("output", Duration::from_secs(5))
+        .expect("no output event");
+
+    let sleep_pid = |ht: &mut Ht| {
+        ht.send(serde_json::json!({ "type": "listProcesses" }));
+        let event = ht
+            .next_event("processes", Duration::from_secs(5))
+            .expect("no processes event");
+
+        event["data"]["processes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|p| p["command"] == "sleep 301")
+            .map(|p| p["pid"].as_u64().unwrap())
+    };
+
+    // the job may not have started sleeping yet
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let pid = loop {
+        match sleep_pid(&mut ht) {
+            Some(pid) => break pid,
+            None if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            None => panic!("background job not listed"),
+        }
+    };
+
+    ht.send(serde_json::json!({ "type": "killProcess", "pid": pid }));
+    let event = ht
+        .next_event("killProcess", Duration::from_secs(5))
+        .expect("no killProcess event");
+    assert_eq!(event["data"]["signal"], "TERM");
+    assert!(event["data"].get("error").is_none(), "{event}");
+    assert_eq!(sleep_pid(&mut ht), None);
+
+    // processes outside of the session can't be signaled
+    ht.send(serde_json::json!({ "type": "killProcess", "pid": 1, "signal": "KILL" }));
+    let event = ht
+        .next_event("killProcess", Duration::from_secs(5))
+        .expect("no killProcess event");
+    assert!(event["data"]["error"].is_string(), "{event}");
+}
+
+#[test]
+fn signal_reaches_the_process() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,error",
+        "trap 'echo got-''usr1' USR1; echo ready; while true; do sleep 0.05; done",
+    ]);
+
+    output_until(&ht, "ready");
+
+    ht.send(serde_json::json!({ "type": "signal", "signal": "SIGUSR1" }));
+    assert!(output_until(&ht, "got-usr1").contains("got-usr1"));
+
+    ht.send(serde_json::json!({ "type": "signal", "signal": "NOPE" }));
+    let error = ht
+        .next_event("error", Duration::from_secs(5))
+        .expect("no error event");
+    assert!(
+        error["data"]["message"].as_str().unwrap().contains("NOPE"),
+        "{error}"
+    );
+}
+
+#[test]
+fn resize_and_settle_waits_for_redraw() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,resizeAndSettle",
+        "trap 'echo redrawing; sleep 0.1; echo redrawn' WINCH; echo ready; while true; do sleep 0.05; done",
+    ]);
+
+    let ready = ht
+        .next_event("output", Duration::from_secs(5))
+        .expect("no output");
+    assert!(ready["data"]["seq"].as_str().unwrap().contains("ready"));
+
+    ht.send(serde_json::json!({
+        "type": "resizeAndSettle",
+        "cols": 60,
+        "rows": 10,
+        "settleMs": 200,
+        "id": "tui",
+    }));
+
+    let mut output = String::new();
+
+    let event = loop {
+        let event = ht
+            .events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("no resizeAndSettle event");
+
+        match event["type"].as_str() {
+            Some("output") => output.push_str(event["data"]["seq"].as_str().unwrap()),
+            Some("resizeAndSettle") => break event,
+            _ => (),
+        }
+    };
+
+    assert!(
+        output.contains("redrawn"),
+        "settled before redraw: {output:?}"
+    );
+    assert_eq!(event["data"]["result"], "settled");
+    assert_eq!(event["data"]["cols"], 60);
+    assert_eq!(event["data"]["rows"], 10);
+    assert_eq!(event["data"]["id"], "tui");
+}
+
+#[test]
+fn closing_stdin_cancels_sleep() {
+    let mut ht = Ht::spawn(&["cat"]);
+
+    ht.send(serde_json::json!({ "type": "sleep", "ms": 60000 }));
+    thread::sleep(Duration::from_millis(100));
+    ht.close_stdin();
+
+    assert!(ht.wait(Duration::from_secs(5)), "ht didn't exit");
+}
+
+#[test]
+fn recording_clamps_idle_time() {
+    let path = std::env::temp_dir().join(format!("ht-idle-{}.cast", std::process::id()));
+
+    let mut ht = Ht::spawn(&[
+        "--record",
+        path.to_str().unwrap(),
+        "--record-idle-limit",
+        "0.5",
+        "--size",
+        "80x24",
+        "printf a; sleep 2; printf b",
+    ]);
+    assert!(ht.wait(Duration::from_secs(10)), "ht didn't exit");
+
+    let cast = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let mut lines = cast
+        .lines()
+        .map(|l| serde_json::from_str::<Value>(l).unwrap());
+    let header = lines.next().unwrap();
+
+    assert_eq!(header["version"], 2);
+    assert_eq!(header["width"], 80);
+
+    let times: Vec<(f64, String)> = lines
+        .map(|e| (e[0].as_f64().unwrap(), e[2].as_str().unwrap().to_owned()))
+        .collect();
+    let time_of = |s: &str| times.iter().find(|(_, d)| d.contains(s)).unwrap().0;
+
+    assert!(time_of("b") - time_of("a") <= 0.5 + 1e-6, "{times:?}");
+}
+
+#[test]
+fn bell_triggers_event() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "bell,snapshot",
+        r"printf 'done\a\a\033]0;title\007'; sleep 10",
+    ]);
+
+    let event = ht.next_event("bell", Duration::from_secs(5)).unwrap();
+    assert_eq!(event["data"], serde_json::json!({}));
+
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+    let snapshot = ht.next_event("snapshot", Duration::from_secs(5)).unwrap();
+    let text = snapshot["data"]["text"].as_str().unwrap();
+
+    assert_eq!(text.lines().next().map(str::trim_end), Some("done"));
+    assert!(!snapshot["data"]["seq"].as_str().unwrap().contains('\x07'));
+    assert!(ht.next_event("bell", Duration::from_millis(500)).is_none());
+}
+
+#[test]
+fn save_scrollback_writes_all_lines() {
+    let path = std::env::temp_dir().join(format!("ht-scrollback-{}.log", std::process::id()));
+
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,saveScrollback",
+        "--size",
+        "20x3",
+        r"printf '\033[31mred\033[0m\n'; seq 2 8; sleep 10",
+    ]);
+
+    let mut output = String::new();
+
+    while !output.contains('8') {
+        let event = ht.next_event("output", Duration::from_secs(5)).unwrap();
+        output.push_str(event["data"]["seq"].as_str().unwrap());
+    }
+
+    ht.send(serde_json::json!({ "type": "saveScrollback", "path": path }));
+    let event = ht
+        .next_event("saveScrollback", Duration::from_secs(5))
+        .unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+
+    assert!(event["data"]["lines"].as_u64().unwrap() >= 8, "{event}");
+    assert!(text.starts_with("red\n2\n3\n4\n5\n6\n7\n8\n"), "{text:?}");
+
+    ht.send(serde_json::json!({ "type": "saveScrollback", "path": path, "format": "ansi" }));
+    ht.next_event("saveScrollback", Duration::from_secs(5))
+        .unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert!(text.starts_with("\x1b[0;31mred\x1b[0m\n2\n"), "{text:?}");
+
+    let missing = path.join("nested/session.log");
+    ht.send(serde_json::json!({ "type": "saveScrollback", "path": missing }));
+    let event = ht
+        .next_event("saveScrollback", Duration::from_secs(5))
+        .unwrap();
+
+    assert!(event["data"]["error"].is_string(), "{event}");
+    assert!(event["data"].get("lines").is_none());
+}
+
+#[test]
+fn output_tail_returns_raw_bytes() {
+    use base64::Engine;
+
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "outputTail",
+        "printf '\\033[31mred\\033[0m'; sleep 10",
+    ]);
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    loop {
+        ht.send(serde_json::json!({ "type": "getOutputTail", "bytes": 9 }));
+        let tail = ht.next_event("outputTail", Duration::from_secs(1)).unwrap();
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(tail["data"]["data"].as_str().unwrap())
+            .unwrap();
+
+        if bytes.ends_with(b"\x1b[0m") {
+            assert_eq!(bytes, b"1mred\x1b[0m");
+            assert_eq!(tail["data"]["size"], 9);
+            break;
+        }
+
+        assert!(Instant::now() < deadline, "output not in tail");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Collects output until it contains `text`, or nothing more comes.
+fn output_until(ht: &Ht, text: &str) -> String {
+    let mut output = String::new();
+
+    while !output.contains(text) {
+        match ht.next_event("output", Duration::from_secs(5)) {
+            Some(event) => output.push_str(event["data"]["seq"].as_str().unwrap()),
+            None => break,
+        }
+    }
+
+    output
+}
+
+#[test]
+fn query_returns_the_response() {
+    use base64::Engine;
+
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "query,output",
+        "stty -echo; printf ready; read -r q; printf 'got:%s' \"$q\"; sleep 10",
+    ]);
+    output_until(&ht, "ready");
+
+    ht.send(
+        serde_json::json!({ "type": "query", "send": "ping\r", "readTimeoutMs": 1000, "id": "q" }),
+    );
+
+    let query = ht
+        .next_event("query", Duration::from_secs(5))
+        .expect("no query event");
+    let response = base64::engine::general_purpose::STANDARD
+        .decode(query["data"]["response"].as_str().unwrap())
+        .unwrap();
+
+    assert_eq!(query["data"]["id"], "q");
+    assert!(response.ends_with(b"got:ping"), "{response:?}");
+}
+
+#[test]
+fn muted_output_is_left_out_but_reaches_the_screen() {
+    let mut ht = Ht::spawn(&["--subscribe", "output,muted,snapshot", "sh"]);
+    ht.send(serde_json::json!({ "type": "input", "payload": "PS1='$ '; echo ready\n" }));
+    output_until(&ht, "ready");
+
+    ht.send(serde_json::json!({ "type": "mute" }));
+    ht.next_event("muted", Duration::from_secs(5))
+        .expect("no muted event");
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "echo noi''se\n" }));
+    thread::sleep(Duration::from_millis(500));
+    ht.send(serde_json::json!({ "type": "unmute", "snapshot": true }));
+
+    let unmuted = ht
+        .next_event("muted", Duration::from_secs(5))
+        .expect("no muted event");
+    assert_eq!(unmuted["data"]["muted"], false);
+
+    let snapshot = ht
+        .next_event("snapshot", Duration::from_secs(5))
+        .expect("no snapshot");
+    let text = snapshot["data"]["text"].as_str().unwrap();
+    assert!(text.contains("noise"), "{text:?}");
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "echo after\n" }));
+    let output = output_until(&ht, "after");
+    assert!(!output.contains("noise"), "{output:?}");
+}
+
+#[test]
+fn output_since_marker_captures_command_output() {
+    let mut ht = Ht::spawn(&["--subscribe", "output,marker,outputSince", "sh"]);
+    ht.send(serde_json::json!({ "type": "input", "payload": "PS1='$ '; echo rea''dy\n" }));
+    output_until(&ht, "ready");
+
+    ht.send(serde_json::json!({ "type": "mark" }));
+    let marker = ht.next_event("marker", Duration::from_secs(5)).unwrap();
+    let seq = marker["data"]["seq"].as_u64().unwrap();
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "echo one; echo t''wo\n" }));
+    output_until(&ht, "two");
+
+    ht.send(serde_json::json!({ "type": "getOutputSince", "seq": seq, "format": "text" }));
+    let since = ht
+        .next_event("outputSince", Duration::from_secs(5))
+        .unwrap();
+    let text = since["data"]["text"].as_str().unwrap();
+
+    assert!(text.contains("one\ntwo\n"), "{text:?}");
+    assert!(!text.contains("ready"), "{text:?}");
+    assert!(since["data"]["next"].as_u64().unwrap() > seq);
+}
+
+const CAT_ALL: &str = r#"printf '[%s]\n' "$(cat)"; sleep 10"#;
+
+#[test]
+fn eof_ends_input_of_cat() {
+    let mut ht = Ht::spawn(&["--subscribe", "output", CAT_ALL]);
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "hello\r" }));
+    ht.send(serde_json::json!({ "type": "eof" }));
+
+    let output = output_until(&ht, "[hello]");
+    assert!(output.contains("[hello]"), "{output:?}");
+}
+
+#[test]
+fn close_stdin_ends_partial_line_and_further_input() {
+    let mut ht = Ht::spawn(&["--subscribe", "output,error", CAT_ALL]);
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "abc" }));
+    ht.send(serde_json::json!({ "type": "closeStdin" }));
+
+    let output = output_until(&ht, "[abc]");
+    assert!(output.contains("[abc]"), "{output:?}");
+
+    ht.send(serde_json::json!({ "type": "input", "payload": "more\r" }));
+    let error = ht.next_event("error", Duration::from_secs(5)).unwrap();
+
+    assert_eq!(error["data"]["message"], "input ignored, stdin is closed");
+}
+
+// Input read a character at a time doesn't leave a line pending, so a single
+// VEOF ends the input of the next program reading lines: a second one would
+// end the input of the program after it too.
+#[cfg(target_os = "linux")]
+#[test]
+fn close_stdin_after_raw_mode_input() {
+    let script = "stty -icanon; echo ready; dd bs=1 count=3 >/dev/null 2>&1; \
+                  stty icanon; echo set; cat >/dev/null; \
+                  timeout --foreground 1 cat >/dev/null; echo \"[$?]\"; sleep 10";
+    let mut ht = Ht::spawn(&["--subscribe", "output", script]);
+
+    output_until(&ht, "ready");
+    ht.send(serde_json::json!({ "type": "input", "payload": "abc" }));
+    output_until(&ht, "set");
+    ht.send(serde_json::json!({ "type": "closeStdin" }));
+
+    let output = output_until(&ht, "]");
+    assert!(output.contains("[124]"), "{output:?}");
+}
+
+#[test]
+fn exit_event_reports_exit_code() {
+    for (command, code) in [("exit 3", 3), ("sh -c 'exit 42'", 42)] {
+        let ht = Ht::spawn(&["--subscribe", "exit", command]);
+
+        let exit = ht
+            .next_event("exit", Duration::from_secs(5))
+            .expect("no exit event");
+        assert_eq!(exit["data"], serde_json::json!({ "code": code }));
+    }
+}
+
+// A background process keeps the terminal open after the shell exited, which
+// mustn't hold up the exit event. It ignores SIGHUP, so it survives the hangup
+// of the terminal when the shell, the session leader, exits.
+#[cfg(target_os = "linux")]
+#[test]
+fn exit_event_is_sent_promptly() {
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "output,exit",
+        "(trap '' HUP; sleep 10) & printf 'b''ye'; exit 3",
+    ]);
+
+    output_until(&ht, "bye");
+    let start = Instant::now();
+
+    let exit = ht
+        .next_event("exit", Duration::from_secs(5))
+        .expect("no exit event");
+    let latency = start.elapsed();
+
+    assert_eq!(exit["data"], serde_json::json!({ "code": 3 }));
+    assert!(latency < Duration::from_secs(1), "{latency:?}");
+}
+
+#[test]
+fn exit_event_reports_segfault() {
+    let ht = Ht::spawn(&["--subscribe", "exit", "ulimit -c 0; kill -SEGV $$"]);
+
+    let exit = ht
+        .next_event("exit", Duration::from_secs(5))
+        .expect("no exit event");
+    assert_eq!(exit["data"]["signal"], "SEGV");
+    assert_eq!(exit["data"]["signalNum"], 11);
+    assert_eq!(exit["data"]["coreDumped"], false);
+    assert!(exit["data"].get("code").is_none());
+}
+
+#[test]
+fn exit_event_reports_sigkill() {
+    let ht = Ht::spawn(&["--subscribe", "exit", "kill -KILL $$"]);
+
+    let exit = ht
+        .next_event("exit", Duration::from_secs(5))
+        .expect("no exit event");
+    assert_eq!(exit["data"]["signal"], "KILL");
+    assert_eq!(exit["data"]["signalNum"], 9);
+}
+
+fn exit_code(args: &[&str]) -> Option<i32> {
+    let mut ht = Ht::spawn(args);
+
+    ht.wait_status(Duration::from_secs(5))
+        .expect("ht didn't exit")
+        .code()
+}
+
+#[test]
+fn exit_code_is_propagated() {
+    assert_eq!(exit_code(&["--propagate-exit-code", "false"]), Some(1));
+    assert_eq!(exit_code(&["--propagate-exit-code", "exit 42"]), Some(42));
+    assert_eq!(
+        exit_code(&["--propagate-exit-code", "kill -KILL $$"]),
+        Some(137)
+    );
+    assert_eq!(exit_code(&["--propagate-exit-code", "true"]), Some(0));
+}
+
+#[test]
+fn exit_code_is_not_propagated_by_default() {
+    assert_eq!(exit_code(&["false"]), Some(0));
+}
+
+#[cfg(target_os = "linux")]
+fn is_running(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+        // the state field follows the parenthesized command name
+        Ok(stat) => !stat.rsplit_once(") ").unwrap().1.starts_with('Z'),
+        Err(_) => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn background_job_is_cleaned_up(args: &[&str]) {
+    let mut args = args.to_vec();
+    args.extend(["--subscribe", "snapshot", "sleep 60 & echo pid:$!; wait"]);
+    let mut ht = Ht::spawn(&args);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut pid = None;
+
+    while pid.is_none() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+        ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+        let snapshot = ht.next_event("snapshot", Duration::from_secs(1)).unwrap();
+        let text = snapshot["data"]["text"].as_str().unwrap().to_owned();
+
+        pid = text
+            .split_once("pid:")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .map(|pid| pid.to_owned());
+    }
+
+    let pid = pid.expect("background job pid not printed");
+    assert!(is_running(&pid));
+
+    ht.close_stdin();
+    assert!(ht.wait(Duration::from_secs(5)), "ht didn't exit");
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+
+    while is_running(&pid) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(!is_running(&pid), "background job {pid} outlived ht");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn background_job_is_cleaned_up_with_setsid() {
+    background_job_is_cleaned_up(&["--setsid"]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn background_job_is_cleaned_up_with_process_group() {
+    background_job_is_cleaned_up(&["--process-group"]);
+}
+
+/// Sends ^C to a program reading one byte of input, returning the output
+/// once it shows whether the program got SIGINT or the byte.
+fn ctrl_c_output(mode: &str) -> String {
+    let mut ht = Ht::spawn(&[
+        "--pty-mode",
+        mode,
+        "--subscribe",
+        "output",
+        "trap 'echo sigint; exit' INT; echo ready; head -c 1 | od -An -tx1; sleep 10",
+    ]);
+
+    let mut output = String::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    // ^C is repeated as it's lost when it arrives while the shell is still
+    // starting `head`, before it resets its signal handlers
+    while !output.contains("sigint") && !output.contains(" 03") && Instant::now() < deadline {
+        if let Some(event) = ht.next_event("output", Duration::from_millis(200)) {
+            output.push_str(event["data"]["seq"].as_str().unwrap());
+        }
+
+        if output.contains("ready") {
+            ht.send(serde_json::json!({ "type": "input", "payload": "\u{3}" }));
+        }
+    }
+
+    output
+}
+
+#[test]
+fn ctrl_c_interrupts_in_cooked_and_cbreak_mode() {
+    for mode in ["cooked", "cbreak"] {
+        let output = ctrl_c_output(mode);
+        assert!(output.contains("sigint"), "{mode}: {output:?}");
+    }
+}
+
+#[test]
+fn ctrl_c_is_data_in_raw_mode() {
+    let output = ctrl_c_output("raw");
+    assert!(output.contains(" 03"), "{output:?}");
+    assert!(!output.contains("sigint"), "{output:?}");
+}
+
+#[test]
+fn snapshot_reports_hyperlinks() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,snapshot,screenshot",
+        "--size",
+        "40x5",
+        r"printf 'see \033]8;;https://example.com/docs\033\\the docs\033]8;;\033\\ now\n'; sleep 10",
+    ]);
+
+    let mut output = String::new();
+
+    while !output.contains("now") {
+        let event = ht.next_event("output", Duration::from_secs(5)).unwrap();
+        output.push_str(event["data"]["seq"].as_str().unwrap());
+    }
+
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+    let snapshot = ht.next_event("snapshot", Duration::from_secs(5)).unwrap();
+
+    assert_eq!(
+        snapshot["data"]["links"],
+        serde_json::json!([{ "row": 0, "col": 4, "len": 8, "url": "https://example.com/docs" }])
+    );
+
+    ht.send(serde_json::json!({ "type": "screenshot" }));
+    let screenshot = ht.next_event("screenshot", Duration::from_secs(5)).unwrap();
+    let image = screenshot["data"]["image"].as_str().unwrap();
+
+    assert!(image.contains(r#"<a href="https://example.com/docs">"#));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn refresh_signals_without_resizing() {
+    let mut ht = Ht::spawn(&[
+        "--size",
+        "40x10",
+        "--subscribe",
+        "output,resize,snapshot,refresh",
+        "trap 'echo \"win''ch $(stty size)\"' WINCH; printf re''ady; while :; do sleep 0.05; done",
+    ]);
+    output_until(&ht, "ready");
+
+    ht.send(serde_json::json!({ "type": "refresh", "snapshot": true, "settleMs": 300, "id": 1 }));
+
+    let output = output_until(&ht, "winch 10 40");
+    assert!(output.contains("winch 10 40"), "{output:?}");
+
+    let refresh = ht.next_event("refresh", Duration::from_secs(5)).unwrap();
+    assert_eq!(refresh["data"]["result"], "settled");
+    assert_eq!(refresh["data"]["id"], 1);
+
+    let snapshot = ht.next_event("snapshot", Duration::from_secs(5)).unwrap();
+    assert_eq!(snapshot["data"]["cols"], 40);
+    assert_eq!(snapshot["data"]["rows"], 10);
+    assert!(
+        ht.next_event("resize", Duration::from_millis(200))
+            .is_none()
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn resize_without_signal() {
+    let script =
+        "trap 'echo \"win''ch $(stty size)\"' WINCH; printf re''ady; while :; do sleep 0.05; done";
+
+    let mut ht = Ht::spawn(&[
+        "--size",
+        "40x10",
+        "--resize-debounce",
+        "0",
+        "--process-group",
+        "--subscribe",
+        "output,resize",
+        script,
+    ]);
+    output_until(&ht, "ready");
+
+    ht.send(serde_json::json!({ "type": "resize", "cols": 50, "rows": 12, "signal": false }));
+    let resize = ht.next_event("resize", Duration::from_secs(5)).unwrap();
+    assert_eq!(resize["data"]["cols"], 50);
+
+    ht.send(serde_json::json!({ "type": "resize", "cols": 60, "rows": 14 }));
+    let output = output_until(&ht, "winch 14 60");
+    assert!(output.contains("winch 14 60"), "{output:?}");
+    assert!(!output.contains("winch 12 50"), "{output:?}");
+
+    // the kernel signals a program the terminal is the controlling terminal of
+    let mut ht = Ht::spawn(&["--subscribe", "error,resize", script]);
+    ht.send(serde_json::json!({ "type": "resize", "cols": 50, "rows": 12, "signal": false }));
+
+    let error = ht.next_event("error", Duration::from_secs(5)).unwrap();
+    assert!(
+        error["data"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("SIGWINCH")
+    );
+    assert!(
+        ht.next_event("resize", Duration::from_millis(200))
+            .is_none()
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn coalesced_resizes_signal_if_any_asks() {
+    let script =
+        "trap 'echo \"win''ch $(stty size)\"' WINCH; printf re''ady; while :; do sleep 0.05; done";
+
+    let mut ht = Ht::spawn(&[
+        "--size",
+        "40x10",
+        "--resize-debounce",
+        "500",
+        "--process-group",
+        "--subscribe",
+        "output,resize",
+        script,
+    ]);
+    output_until(&ht, "ready");
+
+    ht.send(serde_json::json!({ "type": "resize", "cols": 50, "rows": 12 }));
+    ht.send(serde_json::json!({ "type": "resize", "cols": 60, "rows": 14, "signal": false }));
+
+    let resize = ht.next_event("resize", Duration::from_secs(5)).unwrap();
+    assert_eq!(resize["data"]["cols"], 60);
+
+    let output = output_until(&ht, "winch 14 60");
+    assert!(output.contains("winch 14 60"), "{output:?}");
+}
+
+#[cfg(unix)]
+#[test]
+fn input_pending_has_unwritten_input() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,inputPending",
+        "stty -echo -icanon; printf re''ady; sleep 30",
+    ]);
+    output_until(&ht, "ready");
+
+    let pending = |ht: &mut Ht| {
+        ht.send(serde_json::json!({ "type": "getInputPending" }));
+        let event = ht
+            .next_event("inputPending", Duration::from_secs(5))
+            .unwrap();
+
+        event["data"]["pendingBytes"].as_u64().unwrap()
+    };
+
+    assert_eq!(pending(&mut ht), 0);
+
+    // more than the terminal's buffers hold, with nothing reading it (sent in
+    // chunks, which the next command waits for)
+    let size = 100 * 1024;
+    ht.send(serde_json::json!({ "type": "input", "payload": "x".repeat(size) }));
+
+    // until the terminal's buffers are full, and what's left stays
+    let start = Instant::now();
+    let mut left = pending(&mut ht);
+
+    while start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(200));
+        let now = pending(&mut ht);
+
+        if now > 0 && now == left {
+            break;
+        }
+
+        left = now;
+    }
+
+    assert!(left > 0 && left < size as u64, "{left}");
+    assert_eq!(pending(&mut ht), left);
+}
+
+#[test]
+fn commands_are_handled_while_input_is_blocked() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,inputPending",
+        "stty -echo -icanon; printf re''ady; sleep 30",
+    ]);
+    output_until(&ht, "ready");
+
+    // more input commands than ht's input channel holds, with nothing reading
+    // the terminal and input blocked once 500 kB are pending, but less than
+    // the queue behind it holds
+    let mut stdin = ht.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        let commands = std::iter::once(
+            serde_json::json!({ "type": "setBackpressure", "policy": "block", "maxBytes": 500_000 }),
+        )
+        .chain(
+            (0..1800).map(|_| serde_json::json!({ "type": "input", "payload": "x".repeat(1000) })),
+        )
+        .chain(std::iter::once(
+            serde_json::json!({ "type": "getInputPending" }),
+        ));
+
+        for command in commands {
+            if writeln!(stdin, "{command}").is_err() {
+                break;
+            }
+        }
+
+        stdin
+    });
+
+    let event = ht
+        .next_event("inputPending", Duration::from_secs(10))
+        .expect("commands held up by blocked input");
+    assert!(
+        event["data"]["pendingBytes"].as_u64().unwrap() > 500_000,
+        "{event}"
+    );
+
+    ht.stdin = Some(writer.join().unwrap());
+}
+
+#[test]
+fn blocked_input_holds_up_the_controller() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,inputPending",
+        "stty -echo -icanon; printf re''ady; sleep 3; cat >/dev/null",
+    ]);
+    output_until(&ht, "ready");
+
+    // far more input than ht takes in with input blocked once 1000 bytes are
+    // pending, until the program starts reading
+    let mut stdin = ht.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        let commands = std::iter::once(
+            serde_json::json!({ "type": "setBackpressure", "policy": "block", "maxBytes": 1000 }),
+        )
+        .chain(
+            (0..8000).map(|_| serde_json::json!({ "type": "input", "payload": "x".repeat(1000) })),
+        )
+        .chain(std::iter::once(
+            serde_json::json!({ "type": "getInputPending" }),
+        ));
+
+        for command in commands {
+            if writeln!(stdin, "{command}").is_err() {
+                break;
+            }
+        }
+
+        stdin
+    });
+
+    thread::sleep(Duration::from_secs(1));
+    assert!(
+        !writer.is_finished(),
+        "commands taken in while input is blocked"
+    );
+
+    ht.next_event("inputPending", Duration::from_secs(30))
+        .expect("commands handled once the program reads");
+    ht.stdin = Some(writer.join().unwrap());
+}
+
+#[test]
+fn snapshot_is_served_while_commands_are_held_up() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "started,output",
+        "--listen",
+        "127.0.0.1:0",
+        "stty -echo -icanon; printf re''ady; sleep 3; cat >/dev/null",
+    ]);
+    let started = ht.next_event("started", Duration::from_secs(5)).unwrap();
+    let addr = started["data"]["listenAddr"].as_str().unwrap().to_owned();
+    output_until(&ht, "ready");
+
+    // far more input than ht takes in with input blocked once 1000 bytes are
+    // pending, until the program starts reading
+    let mut stdin = ht.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        let commands = std::iter::once(
+            serde_json::json!({ "type": "setBackpressure", "policy": "block", "maxBytes": 1000 }),
+        )
+        .chain(
+            (0..8000).map(|_| serde_json::json!({ "type": "input", "payload": "x".repeat(1000) })),
+        );
+
+        for command in commands {
+            if writeln!(stdin, "{command}").is_err() {
+                break;
+            }
+        }
+
+        stdin
+    });
+
+    thread::sleep(Duration::from_secs(1));
+    assert!(!writer.is_finished(), "commands not held up");
+
+    let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /frame.cast HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("snapshot held up by the commands");
+
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.contains("ready"), "{response}");
+
+    ht.stdin = Some(writer.join().unwrap());
+}
+
+/// Blocks input once 1000 bytes are pending, and sends more input commands
+/// than ht's input channel (1024) and the terminal's buffers hold, so that
+/// some are held in ht.
+fn block_input(ht: &mut Ht) {
+    ht.send(serde_json::json!({ "type": "setBackpressure", "policy": "block", "maxBytes": 1000 }));
+
+    for _ in 0..1200 {
+        ht.send(serde_json::json!({ "type": "input", "payload": "x".repeat(1000) }));
+    }
+}
+
+/// Waits for the pending input to drop to `max_bytes`, returning how much is
+/// left then.
+fn input_pending_drops_to(ht: &mut Ht, max_bytes: u64) -> u64 {
+    let start = Instant::now();
+
+    loop {
+        ht.send(serde_json::json!({ "type": "getInputPending" }));
+        let event = ht
+            .next_event("inputPending", Duration::from_secs(5))
+            .expect("commands handled while input commands are held");
+        let pending = event["data"]["pendingBytes"].as_u64().unwrap();
+
+        if pending <= max_bytes || start.elapsed() > Duration::from_secs(10) {
+            return pending;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn blocked_input_is_released_by_a_dropping_policy() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,inputPending",
+        "stty -echo -icanon; printf re''ady; sleep 30",
+    ]);
+    output_until(&ht, "ready");
+
+    block_input(&mut ht);
+    ht.send(
+        serde_json::json!({ "type": "setBackpressure", "policy": "dropNewest", "maxBytes": 1000 }),
+    );
+
+    // what was already pending stays, the rest is dropped
+    let pending = input_pending_drops_to(&mut ht, 5000);
+    assert!(pending <= 5000, "{pending}");
+}
+
+#[cfg(unix)]
+#[test]
+fn blocked_input_is_released_by_a_signal() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,inputPending",
+        "stty -echo -icanon; trap 'cat >/dev/null' USR1; printf re''ady; sleep 30 & wait",
+    ]);
+    output_until(&ht, "ready");
+
+    block_input(&mut ht);
+    ht.send(serde_json::json!({ "type": "signal", "signal": "USR1" }));
+
+    assert_eq!(input_pending_drops_to(&mut ht, 0), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn enter_key_is_not_translated() {
+    for newline in ["lf", "crlf", "cr", "none"] {
+        let mut ht = Ht::spawn(&[
+            "--subscribe",
+            "output",
+            "--input-newline",
+            newline,
+            "stty -echo -icanon -icrnl; printf re''ady; head -c 2 | od -An -tx1",
+        ]);
+        output_until(&ht, "ready");
+
+        ht.send(serde_json::json!({ "type": "sendKeys", "keys": ["Enter", "C-m"] }));
+        let output = output_until(&ht, "0d 0d");
+
+        assert!(output.contains("0d 0d"), "{newline}: {output:?}");
+    }
+}
+
+#[test]
+fn setup_command_output_is_left_out() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "started,init,output",
+        "--setup-command",
+        "echo no''ise; cd /tmp",
+        "sh",
+    ]);
+
+    let started = ht.next_event("started", Duration::from_secs(10)).unwrap();
+    assert!(started["data"]["pid"].is_u64());
+
+    let init = ht.next_event("init", Duration::from_secs(5)).unwrap();
+    ht.send(serde_json::json!({ "type": "input", "payload": "pwd\r" }));
+    let output = output_until(&ht, "/tmp\r\n");
+
+    assert!(output.contains("/tmp\r\n"), "{output:?}");
+    assert!(!output.contains("noise") && !output.contains("ht-setup"));
+    assert!(!init["data"]["text"].as_str().unwrap().contains("noise"));
+}
+
+#[test]
+fn stderr_only_has_messages_asked_for() {
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ht"))
+            .args(["--listen", "--subscribe", "started", "--size", "20x3"])
+            .args(args)
+            .arg("sleep 10")
+            .env("SHELL", "/bin/sh")
+            .stdin(Stdio::null())
+            .output()
+            .expect("failed to run ht");
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let started: Value = serde_json::from_str(stdout.lines().next().unwrap()).unwrap();
+        let addr = started["data"]["listenAddr"].as_str().unwrap().to_owned();
+
+        (addr, String::from_utf8(output.stderr).unwrap())
+    };
+
+    let (_, stderr) = run(&[]);
+    assert_eq!(stderr, "");
+
+    let (addr, stderr) = run(&["--banner"]);
+    assert!(stderr.contains(&addr), "{stderr}");
+
+    let (_, stderr) = run(&["--theme", "no-such-theme"]);
+    assert_ne!(stderr, "");
+
+    let (_, stderr) = run(&["--quiet", "--theme", "no-such-theme"]);
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn msgpack_protocol() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ht"))
+        .args([
+            "--protocol",
+            "msgpack",
+            "--subscribe",
+            "init,snapshot",
+            "--size",
+            "20x3",
+            "printf 'h''i'; sleep 10",
+        ])
+        .env("SHELL", "/bin/sh")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to spawn ht");
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let (tx, events) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut size = [0; 4];
+
+        while std::io::Read::read_exact(&mut stdout, &mut size).is_ok() {
+            let mut frame = vec![0; u32::from_be_bytes(size) as usize];
+
+            if std::io::Read::read_exact(&mut stdout, &mut frame).is_err() {
+                break;
+            }
+
+            let event: Value = rmp_serde::from_slice(&frame).unwrap();
+
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    let init = events.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(init["type"], "init");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    let text = loop {
+        assert!(Instant::now() < deadline, "no snapshot with the output");
+        thread::sleep(Duration::from_millis(50));
+
+        let command =
+            rmp_serde::to_vec_named(&serde_json::json!({ "type": "takeSnapshot" })).unwrap();
+        stdin
+            .write_all(&(command.len() as u32).to_be_bytes())
+            .unwrap();
+        stdin.write_all(&command).unwrap();
+        stdin.flush().unwrap();
+
+        let snapshot = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(snapshot["type"], "snapshot");
+        let text = snapshot["data"]["text"].as_str().unwrap().to_owned();
+
+        if text.contains("hi") {
+            break text;
+        }
+    };
+
+    assert!(text.starts_with("hi"), "{text:?}");
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn type_with_echo_types_a_character_at_a_time() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output",
+        "printf ready; read -r a; echo \"got:$a\"; sleep 10",
+    ]);
+    output_until(&ht, "ready");
+
+    ht.send(serde_json::json!({ "type": "typeWithEcho", "text": "hello\r" }));
+    let output = output_until(&ht, "got:hello");
+
+    assert!(output.contains("got:hello"), "{output:?}");
+    // each character echoed on its own
+    assert!(output.starts_with('h'), "{output:?}");
+}
+
+#[test]
+fn type_with_echo_falls_back_to_a_delay_without_echo() {
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "output,snapshot",
+        "stty -echo; printf ready; read -r a; echo \"got:$a\"; sleep 10",
+    ]);
+    output_until(&ht, "ready");
+
+    let start = Instant::now();
+    ht.send(serde_json::json!({
+        "type": "typeWithEcho",
+        "text": "abc\r",
+        "echoTimeoutMs": 100,
+        "fallbackDelayMs": 100,
+    }));
+    // queued behind the typing
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+
+    ht.next_event("snapshot", Duration::from_secs(5)).unwrap();
+    let elapsed = start.elapsed();
+
+    // the echo timeout after "a", then the delays after "b" and "c"
+    assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+
+    let output = output_until(&ht, "got:abc");
+    assert!(output.contains("got:abc"), "{output:?}");
+}
+
+#[cfg(unix)]
+#[test]
+fn command_is_found_on_prepended_path() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("ht-path-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("ht-path-probe");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\necho \"found with PATH=$PATH\"\nsleep 5\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let ht = Ht::spawn_with_env(
+        &[
+            "--subscribe",
+            "init,output",
+            "--size",
+            "200x10",
+            "--path-prepend",
+            dir.to_str().unwrap(),
+            "ht-path-probe",
+        ],
+        &[("PATH", "/usr/bin:/bin")],
+    );
+
+    ht.next_event("init", Duration::from_secs(10)).unwrap();
+    let output = output_until(&ht, "/bin\r\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let expected = format!("found with PATH={}:/usr/bin:/bin\r\n", dir.display());
+    assert!(output.contains(&expected), "{output:?}");
+}
+
+#[test]
+fn command_runs_in_cwd() {
+    let dir = std::env::temp_dir().join(format!("ht-cwd-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // the shell's idea of the directory, as a temp dir may be behind a symlink
+    let dir = dir.canonicalize().unwrap();
+
+    let mut ht = Ht::spawn(&[
+        "--subscribe",
+        "init,output,snapshot",
+        "--size",
+        "200x10",
+        "--cwd",
+        dir.to_str().unwrap(),
+        "pwd; echo pwd-''done; sleep 5",
+    ]);
+
+    ht.next_event("init", Duration::from_secs(10)).unwrap();
+    output_until(&ht, "pwd-done");
+    ht.send(serde_json::json!({ "type": "takeSnapshot" }));
+    let snapshot = ht.next_event("snapshot", Duration::from_secs(5)).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let text = snapshot["data"]["text"].as_str().unwrap();
+    assert!(
+        text.lines()
+            .any(|line| line.trim_end() == dir.to_str().unwrap()),
+        "{text:?}"
+    );
+}
+
+#[test]
+fn env_is_set_for_the_command() {
+    let ht = Ht::spawn_with_env(
+        &[
+            "--subscribe",
+            "init,output",
+            "--size",
+            "200x20",
+            "--env",
+            "HT_TEST_VAR=hello",
+            "--env",
+            "HT_TEST_EMPTY=",
+            "--unset-env",
+            "HT_TEST_INHERITED",
+            "--env",
+            "TERM=dumb",
+            "env; echo env-''done; sleep 5",
+        ],
+        &[("HT_TEST_INHERITED", "gone"), ("HT_TEST_KEPT", "kept")],
+    );
+
+    ht.next_event("init", Duration::from_secs(10)).unwrap();
+    let output = output_until(&ht, "env-done");
+
+    assert!(output.contains("HT_TEST_VAR=hello\r\n"), "{output:?}");
+    assert!(output.contains("HT_TEST_EMPTY=\r\n"), "{output:?}");
+    assert!(output.contains("HT_TEST_KEPT=kept\r\n"), "{output:?}");
+    assert!(output.contains("TERM=dumb\r\n"), "{output:?}");
+    assert!(!output.contains("HT_TEST_INHERITED"), "{output:?}");
+}
+
+#[test]
+fn output_log_has_raw_output() {
+    let dir = std::env::temp_dir();
+    let raw = dir.join(format!("ht-output-{}.log", std::process::id()));
+    let text = dir.join(format!("ht-output-{}.txt", std::process::id()));
+    // the terminal turns line feeds into CR LF
+    let command = "printf '\\033[1mbo''ld\\033[0m\\nd\\303\\251j\\303\\240\\tvu\\n'; sleep 5";
+
+    for (path, strip) in [(&raw, false), (&text, true)] {
+        std::fs::write(path, "earlier\n").unwrap();
+        let mut args = vec!["--subscribe", "output", "--output-log"];
+        args.push(path.to_str().unwrap());
+        if strip {
+            args.push("--strip-ansi");
+        }
+        args.push(command);
+
+        let ht = Ht::spawn(&args);
+        output_until(&ht, "vu");
+    }
+
+    let raw_log = std::fs::read(&raw).unwrap();
+    let text_log = std::fs::read_to_string(&text).unwrap();
+    std::fs::remove_file(&raw).unwrap();
+    std::fs::remove_file(&text).unwrap();
+
+    assert_eq!(
+        raw_log,
+        "earlier\n\x1b[1mbold\x1b[0m\r\nd\u{e9}j\u{e0}\tvu\r\n".as_bytes()
+    );
+    assert_eq!(text_log, "earlier\nbold\nd\u{e9}j\u{e0}vu\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn script_reports_each_command() {
+    let path = std::env::temp_dir().join(format!("ht-script-{}.sh", std::process::id()));
+    let script = "echo 'one' # first\n\n# skipped\ncd /tmp && pwd\nfalse\nprintf 'no newline'\nexit 3\necho never\n";
+    std::fs::write(&path, script).unwrap();
+
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "commandResult,exit",
+        "--script",
+        path.to_str().unwrap(),
+        "sh",
+    ]);
+
+    let mut results = Vec::new();
+
+    for _ in 0..5 {
+        let event = ht
+            .next_event("commandResult", Duration::from_secs(10))
+            .unwrap();
+        let data = &event["data"];
+
+        results.push((
+            data["index"].as_u64().unwrap(),
+            data["command"].as_str().unwrap().to_owned(),
+            data["output"].as_str().unwrap().to_owned(),
+            data["exitCode"].as_i64(),
+        ));
+    }
+
+    // the shell exited in the middle of the last one
+    let exit = ht.next_event("exit", Duration::from_secs(5)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let expected = [
+        (0, "echo 'one' # first", "one\n", Some(0)),
+        (1, "cd /tmp && pwd", "/tmp\n", Some(0)),
+        (2, "false", "", Some(1)),
+        (3, "printf 'no newline'", "no newline", Some(0)),
+        (4, "exit 3", "", None),
+    ];
+
+    for (result, expected) in results.iter().zip(expected) {
+        assert_eq!(
+            (result.0, result.1.as_str(), result.2.as_str(), result.3),
+            expected
+        );
+    }
+
+    assert_eq!(exit["data"]["code"], 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn command_runs_without_shell() {
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "output",
+        "--no-shell",
+        "--",
+        "echo",
+        "a  b",
+        "$HOME",
+        "it's",
+    ]);
+
+    let output = output_until(&ht, "\r\n");
+    assert_eq!(output, "a  b $HOME it's\r\n");
+
+    // the program is looked up on PATH
+    let ht = Ht::spawn(&[
+        "--subscribe",
+        "output",
+        "--no-shell",
+        "--",
+        "sh",
+        "-c",
+        "printf '[%s]' \"$@\"; echo",
+        "sh",
+        "a b",
+        "*",
+    ]);
+
+    let output = output_until(&ht, "\r\n");
+    assert_eq!(output, "[a b][*]\r\n");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn command_line_runs_in_the_shell_asked_for() {
+    let interpreter = |args: &[&str], shell: &str| {
+        let mut all = vec!["--subscribe", "output"];
+        all.extend(args);
+        all.push("echo \"ran by $(readlink /proc/$$/exe)\"; sleep 5");
+
+        let ht = Ht::spawn_with_env(&all, &[("SHELL", shell)]);
+        let output = output_until(&ht, "\r\n");
+
+        output
+            .split_once("ran by ")
+            .map(|(_, rest)| rest.trim_end().to_owned())
+            .unwrap_or_default()
+    };
+
+    let bash = std::fs::canonicalize("/bin/bash").unwrap();
+    let sh = std::fs::canonicalize("/bin/sh").unwrap();
+
+    assert_eq!(interpreter(&[], "/bin/bash"), bash.to_str().unwrap());
+    assert_eq!(interpreter(&[], ""), sh.to_str().unwrap());
+    assert_eq!(
+        interpreter(&["--shell", "/bin/sh"], "/bin/bash"),
+        sh.to_str().unwrap()
+    );
+}
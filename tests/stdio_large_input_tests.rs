@@ -7,7 +7,7 @@
 /// ## Issue
 /// When large heredocs (>~1500 chars) are sent via the `input` command,
 /// they can cause PTY buffer overflow leading to data corruption.
-use ht_core::command::{Command, InputSeq};
+use ht_core::command::{Command, InputSeq, Newline};
 use serde_json::json;
 
 /// Test parsing large input commands
@@ -100,7 +100,7 @@ fn test_input_seq_to_bytes_conversion() {
     for size in sizes {
         let text = "y".repeat(size);
         let input_seqs = vec![InputSeq::Standard(text.clone())];
-        let bytes = ht_core::command::seqs_to_bytes(&input_seqs, false);
+        let bytes = ht_core::command::seqs_to_bytes(&input_seqs, false, Newline::None);
 
         assert_eq!(
             bytes.len(),